@@ -0,0 +1,101 @@
+use libp2p::{
+    core::{transport::PortUse, Endpoint},
+    swarm::{
+        dummy, AddressChange, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll},
+};
+
+/// The remote address of an established connection changed, such as when a connection migrated to
+/// a new network path after a mobile peer switched networks
+#[derive(Clone, Debug)]
+pub struct AddressChanged {
+    /// The peer the connection is to
+    pub peer_id: PeerId,
+    /// The connection whose address changed
+    pub connection_id: ConnectionId,
+    /// The remote address before the change
+    pub old: Multiaddr,
+    /// The remote address after the change
+    pub new: Multiaddr,
+}
+
+/// A behaviour reporting the address changes of established connections.
+///
+/// The swarm only tells behaviours about a connection changing its remote address, it doesn't
+/// emit a swarm event for it, so this turns each change into an [`AddressChanged`] event. The
+/// connection stays the same connection, so state kept per connection only needs its address
+/// updated instead of being torn down and rebuilt.
+///
+/// The change is reported by the connection's stream muxer. The QUIC transport of libp2p 0.55
+/// (libp2p-quic 0.12) disables connection migration and its muxer never reports a change, so a
+/// QUIC peer that switches networks still shows up as a closed connection followed by a new one.
+/// Only transports whose muxer reports address changes produce events here.
+#[derive(Debug, Default)]
+pub struct AddressChangeTracker {
+    events: VecDeque<AddressChanged>,
+}
+
+impl NetworkBehaviour for AddressChangeTracker {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = AddressChanged;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::AddressChange(AddressChange {
+            peer_id,
+            connection_id,
+            old,
+            new,
+        }) = event
+        {
+            self.events.push_back(AddressChanged {
+                peer_id,
+                connection_id,
+                old: old.get_remote_address().clone(),
+                new: new.get_remote_address().clone(),
+            });
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(ToSwarm::GenerateEvent(event)),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -1,64 +1,248 @@
 use rust_libp2p_webrtc_peer::prelude::*;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
 use clap::Parser;
-use libp2p::{identity, PeerId};
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
 use libp2p_webrtc::tokio::Certificate;
-use std::path::{Path, PathBuf};
+use std::{io, path::Path};
 use tokio::{fs, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{info, warn};
+
+/// The exit code when the peer failed, whether or not the UI did too
+const EXIT_PEER_FAILED: i32 = 1;
+
+/// The exit code when only the UI failed. 2 is left to clap for usage errors.
+const EXIT_UI_FAILED: i32 = 3;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // parse the command line arguments
     let opt = Options::parse();
 
-    // initialize the tracing logger and get the receiver for log messages
-    let from_log = Log::init();
+    // provision the identity and certificates and exit, without starting the peer
+    if let Some(Command::Init { force }) = opt.command.as_ref() {
+        return init(&opt, *force).await;
+    }
+
+    // initialize the tracing logger and get the receiver for log messages and their control
+    let (from_log, log_control) = Log::init();
 
     // create a shutdown token
     let shutdown = CancellationToken::new();
 
     // load the identity and certificate
-    let local_key = read_or_create_identity(&opt.local_key_path).await?;
-    let webrtc_cert = read_or_create_certificate(&opt.local_cert_path).await?;
+    let retries = opt.key_io_retries;
+    let local_key = load_identity(&opt).await?;
+    let webrtc_cert = load_certificate(&opt).await?;
+    let mut extra_webrtc_certs = Vec::new();
+    for path in opt.extra_cert_paths.iter() {
+        extra_webrtc_certs
+            .push(read_or_create_certificate(path, opt.regen_corrupt_cert, retries).await?);
+    }
 
     // create the ui and the channels to communicate with it
     let (mut ui, to_ui, from_ui) = if opt.headless {
-        Headless::build(local_key.public().into(), from_log, shutdown.clone())
+        Headless::build(
+            local_key.public().into(),
+            from_log,
+            log_control,
+            shutdown.clone(),
+        )
     } else {
-        Tui::build(local_key.public().into(), from_log, shutdown.clone())
+        Tui::build(
+            local_key.public().into(),
+            from_log,
+            log_control,
+            shutdown.clone(),
+        )
     };
 
     // create the peer, connecting it to the ui
-    let mut peer = Peer::new(local_key, webrtc_cert, to_ui, from_ui, shutdown.clone()).await?;
+    let mut peer = Peer::new(
+        local_key,
+        webrtc_cert,
+        extra_webrtc_certs,
+        to_ui,
+        from_ui,
+        shutdown.clone(),
+    )
+    .await?;
+
+    // spawn tasks for both the swarm and the ui. Whichever way a task ends, even by panicking,
+    // it cancels the shutdown token: the peer then finishes its transfers in flight and
+    // unsubscribes, and the ui renders the final state once the peer has stopped.
+    let peer_shutdown = shutdown.clone().drop_guard();
+    let peer_task: JoinHandle<Result<()>> = tokio::spawn(async move {
+        let _shutdown = peer_shutdown;
+        peer.run().await
+    });
+    let ui_shutdown = shutdown.drop_guard();
+    let ui_task: JoinHandle<Result<()>> = tokio::spawn(async move {
+        let _shutdown = ui_shutdown;
+        ui.run().await
+    });
+
+    // wait for both tasks to finish, a panic counts as a failure of its task
+    let (peer_result, ui_result) = tokio::join!(peer_task, ui_task);
+    let peer_result = peer_result.map_err(anyhow::Error::from).and_then(|r| r);
+    let ui_result = ui_result.map_err(anyhow::Error::from).and_then(|r| r);
+
+    // the logger fed the ui, so the errors go to stderr
+    if let Err(e) = ui_result.as_ref() {
+        eprintln!("UI failed: {e:#}");
+    }
+    if let Err(e) = peer_result.as_ref() {
+        eprintln!("Peer failed: {e:#}");
+        std::process::exit(EXIT_PEER_FAILED);
+    }
+    if ui_result.is_err() {
+        std::process::exit(EXIT_UI_FAILED);
+    }
+
+    Ok(())
+}
+
+/// Create the identity and certificates if they don't exist, replacing them first if `force` is
+/// set, and print the peer id and the certhash of each certificate
+async fn init(opt: &Options, force: bool) -> Result<()> {
+    let retries = opt.key_io_retries;
+    let (key_path, peer_id_path) = identity_paths(&opt.local_key_path);
+    // an externally managed certificate is never generated, so it is never replaced either
+    let external_cert = opt.cert_pem.is_some() || opt.cert_pem_env.is_some();
+    let mut cert_paths = Vec::new();
+    if !external_cert {
+        cert_paths.push(opt.local_cert_path.clone());
+    }
+    cert_paths.extend(opt.extra_cert_paths.iter().cloned());
+
+    if force {
+        for path in [&key_path, &peer_id_path]
+            .into_iter()
+            .chain(cert_paths.iter())
+        {
+            match fs::remove_file(path).await {
+                Ok(()) => println!("Removed {}", path.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+                }
+            }
+        }
+    }
 
-    // spawn tasks for both the swarm and the ui
-    let peer_task: JoinHandle<Result<()>> = tokio::spawn(async move { peer.run().await });
-    let ui_task: JoinHandle<Result<()>> = tokio::spawn(async move { ui.run().await });
+    let existed = fs::try_exists(&key_path).await?;
+    // a forced init replaces the identity, so it doesn't fall back to the legacy key either
+    let local_key = if force {
+        read_or_create_identity(&opt.local_key_path, None, opt.regen_corrupt_cert, retries).await?
+    } else {
+        load_identity(opt).await?
+    };
+    println!(
+        "Identity {} ({}): {}",
+        key_path.display(),
+        if existed { "existing" } else { "generated" },
+        PeerId::from(local_key.public())
+    );
 
-    // wait for the tasks to finish
-    let (ui_result, peer_result) = tokio::try_join!(peer_task, ui_task)?;
+    let existed = external_cert || fs::try_exists(&opt.local_cert_path).await?;
+    let cert = load_certificate(opt).await?;
+    let source = match (opt.cert_pem.as_ref(), opt.cert_pem_env.as_ref()) {
+        (Some(path), _) => path.display().to_string(),
+        (None, Some(var)) => format!("${var}"),
+        (None, None) => opt.local_cert_path.display().to_string(),
+    };
+    println!(
+        "Certificate {source} ({}): {}",
+        if existed { "existing" } else { "generated" },
+        certhash(&cert)
+    );
 
-    // check the inner results
-    ui_result?;
-    peer_result?;
+    for path in opt.extra_cert_paths.iter() {
+        let existed = fs::try_exists(path).await?;
+        let cert = read_or_create_certificate(path, opt.regen_corrupt_cert, retries).await?;
+        println!(
+            "Certificate {} ({}): {}",
+            path.display(),
+            if existed { "existing" } else { "generated" },
+            certhash(&cert)
+        );
+    }
 
     Ok(())
 }
 
-async fn read_or_create_certificate(path: &Path) -> Result<Certificate> {
-    if path.exists() {
-        let pem = fs::read_to_string(&path).await?;
+/// Load the identity at --local-key-path, migrating the legacy key file at --legacy-key-path to it
+/// first if --migrate-legacy-key is set
+async fn load_identity(opt: &Options) -> Result<identity::Keypair> {
+    let retries = opt.key_io_retries;
+    if opt.migrate_legacy_key {
+        migrate_legacy_identity(&opt.local_key_path, &opt.legacy_key_path, retries).await?;
+    }
+    read_or_create_identity(
+        &opt.local_key_path,
+        Some(&opt.legacy_key_path),
+        opt.regen_corrupt_cert,
+        retries,
+    )
+    .await
+}
+
+/// The certhash of a certificate as it appears in the peer's WebRTC addresses
+fn certhash(cert: &Certificate) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Certhash(cert.fingerprint().to_multihash()))
+}
 
-        info!("Using existing certificate from {}", path.display());
+/// Load the primary WebRTC certificate from --cert-pem or --cert-pem-env if one is given, or
+/// read or create it at --local-cert-path otherwise
+async fn load_certificate(opt: &Options) -> Result<Certificate> {
+    let retries = opt.key_io_retries;
+    if let Some(path) = opt.cert_pem.as_ref() {
+        read_certificate(path, retries)
+            .await
+            .with_context(|| format!("Failed to load certificate {}", path.display()))
+    } else if let Some(var) = opt.cert_pem_env.as_ref() {
+        certificate_from_env(var)
+    } else {
+        read_or_create_certificate(&opt.local_cert_path, opt.regen_corrupt_cert, retries).await
+    }
+}
 
-        return Ok(Certificate::from_pem(&pem)?);
+async fn read_or_create_certificate(
+    path: &Path,
+    regen_corrupt: bool,
+    retries: u32,
+) -> Result<Certificate> {
+    if retry_io(retries, || fs::try_exists(path)).await? {
+        match read_certificate(path, retries).await {
+            Ok(cert) => {
+                info!("Using existing certificate from {}", path.display());
+                return Ok(cert);
+            }
+            Err(e) if regen_corrupt => {
+                warn!(
+                    "Certificate {} is corrupt ({e}), generating a new one",
+                    path.display()
+                );
+                fs::remove_file(path).await?;
+            }
+            Err(e) => bail!(
+                "Certificate {} is corrupt: {e}. Delete it or run with --regen-corrupt-cert to generate a new one",
+                path.display()
+            ),
+        }
     }
 
     let cert = Certificate::generate(&mut rand::thread_rng())?;
-    fs::write(&path, &cert.serialize_pem().as_bytes()).await?;
+    if !create_new(path, cert.serialize_pem().as_bytes(), retries).await? {
+        // another instance started at the same time and won the race, use its certificate
+        info!(
+            "Certificate {} was created by another instance, using it",
+            path.display()
+        );
+        return read_certificate(path, retries).await;
+    }
 
     info!(
         "Generated new certificate and wrote it to {}",
@@ -68,43 +252,118 @@ async fn read_or_create_certificate(path: &Path) -> Result<Certificate> {
     Ok(cert)
 }
 
-async fn read_or_create_identity(path: &Path) -> Result<identity::Keypair> {
-    let mut key_path = PathBuf::from(path);
-    let is_key = key_path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| ext == "key")
-        .unwrap_or(false);
-    if !is_key {
-        key_path.set_extension("key");
-    }
+async fn read_certificate(path: &Path, retries: u32) -> Result<Certificate> {
+    let bytes = retry_io(retries, || fs::read(path)).await?;
+    let pem = String::from_utf8(bytes)?;
+    Ok(Certificate::from_pem(&pem)?)
+}
+
+fn certificate_from_env(var: &str) -> Result<Certificate> {
+    let encoded = std::env::var(var)
+        .with_context(|| format!("Failed to read the certificate from ${var}"))?;
+    let pem = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .with_context(|| format!("${var} is not valid base64"))?;
+    let pem = String::from_utf8(pem).with_context(|| format!("${var} is not a PEM file"))?;
+    let cert = Certificate::from_pem(&pem)
+        .with_context(|| format!("${var} does not contain a valid certificate"))?;
+    info!("Using certificate from ${var}");
+    Ok(cert)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// The number of fresh paths each race is run on, to give the creators a fair chance to
+    /// actually interleave
+    const RACES: usize = 16;
 
-    let mut peer_id_path = PathBuf::from(path);
-    let is_peer_id = peer_id_path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| ext == "peerid")
-        .unwrap_or(false);
-    if !is_peer_id {
-        peer_id_path.set_extension("peerid");
+    #[tokio::test]
+    async fn created_certificate_round_trips_through_pem() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cert.pem");
+
+        let created = read_or_create_certificate(&path, false, 0).await.unwrap();
+        let pem = fs::read_to_string(&path).await.unwrap();
+        let parsed = Certificate::from_pem(&pem).unwrap();
+        assert_eq!(certhash(&parsed), certhash(&created));
+
+        // a restart reads the same certificate back, so the advertised certhash stays stable
+        let reread = read_or_create_certificate(&path, false, 0).await.unwrap();
+        assert_eq!(certhash(&reread), certhash(&created));
     }
 
-    if key_path.exists() {
-        let bytes = fs::read(&key_path).await?;
-        info!("Using existing identity from {}", key_path.display());
-        // This only works for ed25519 but that is what we are using
-        return Ok(identity::Keypair::from_protobuf_encoding(&bytes)?);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_identity_creators_agree_on_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        for race in 0..RACES {
+            let path = dir.path().join(format!("identity-{race}"));
+            let creator = |path: PathBuf| {
+                tokio::spawn(async move { read_or_create_identity(&path, None, false, 0).await })
+            };
+            let (a, b) = tokio::join!(creator(path.clone()), creator(path.clone()));
+            let a = a.unwrap().unwrap();
+            let b = b.unwrap().unwrap();
+            assert_eq!(a.public(), b.public());
+
+            // the identity on disk is the one both creators returned, with its peer id next to it
+            let (key_path, peer_id_path) = identity_paths(&path);
+            let on_disk =
+                identity::Keypair::from_protobuf_encoding(&fs::read(&key_path).await.unwrap())
+                    .unwrap();
+            assert_eq!(on_disk.public(), a.public());
+            assert_eq!(
+                fs::read_to_string(&peer_id_path).await.unwrap(),
+                PeerId::from(a.public()).to_string()
+            );
+        }
+
+        // no temporary files are left behind
+        let mut entries = fs::read_dir(dir.path()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(".tmp"));
+        }
     }
 
-    let identity = identity::Keypair::generate_ed25519();
-    fs::write(&key_path, &identity.to_protobuf_encoding()?).await?;
-    let peer_id: PeerId = identity.public().into();
-    fs::write(&peer_id_path, peer_id.to_string()).await?;
+    #[tokio::test]
+    async fn a_stale_peer_id_file_is_replaced_with_the_new_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+        let (_, peer_id_path) = identity_paths(&path);
+        // the .peerid of an identity whose key was deleted
+        let stale = PeerId::from(identity::Keypair::generate_ed25519().public());
+        fs::write(&peer_id_path, stale.to_string()).await.unwrap();
 
-    info!(
-        "Generated new identity and wrote it to {}",
-        key_path.display()
-    );
+        let identity = read_or_create_identity(&path, None, false, 0).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&peer_id_path).await.unwrap(),
+            PeerId::from(identity.public()).to_string()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_certificate_creators_agree_on_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        for race in 0..RACES {
+            let path = dir.path().join(format!("cert-{race}.pem"));
+            let creator = |path: PathBuf| {
+                tokio::spawn(async move { read_or_create_certificate(&path, false, 0).await })
+            };
+            let (a, b) = tokio::join!(creator(path.clone()), creator(path.clone()));
+            let a = a.unwrap().unwrap();
+            let b = b.unwrap().unwrap();
+            assert_eq!(certhash(&a), certhash(&b));
+            assert_eq!(
+                certhash(&read_certificate(&path, 0).await.unwrap()),
+                certhash(&a)
+            );
+        }
 
-    Ok(identity)
+        let mut entries = fs::read_dir(dir.path()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(".tmp"));
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//! Serving WebRTC with several certificates at once so the certificate can be rotated without
+//! breaking the clients that know the old certhash.
+//!
+//! A WebRTC listener only has a single certificate, so each certificate gets a listener on its own
+//! UDP port and every one of them is advertised. The certificate at `--local-cert-path` is served
+//! on port 9090 and the i-th `--extra-cert-paths` certificate on port 9093 + i. A certificate never
+//! changes port, so a client's certhash and port always stay paired.
+//!
+//! To rotate with two slots, run with one extra certificate. Both certificates are in use at all
+//! times: one current and one previous. To rotate, delete the older of the two certificate files
+//! and restart; a fresh certificate is generated in its place and advertised alongside the other.
+//! Once the rotation window has passed and clients have picked up the new certhash, repeat with the
+//! other slot.
+
+use futures::future::Either;
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, DialOpts, ListenerId, TransportError, TransportEvent},
+        Transport,
+    },
+    identity::Keypair,
+    multiaddr::{Multiaddr, Protocol},
+    PeerId,
+};
+use libp2p_webrtc as webrtc;
+use libp2p_webrtc::tokio::Certificate;
+use std::{
+    error::Error,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The UDP port of the WebRTC listener for the first extra certificate
+pub const PORT_WEBRTC_EXTRA: u16 = 9093;
+
+/// Check if an inbound WebRTC connection on `local_addr` failed its handshake because of the
+/// certificate, which is what a client dialing with a stale certhash sees after the certificate
+/// was regenerated or rotated out: the DTLS handshake or the Noise handshake, whose prologue
+/// contains both certificate fingerprints, fails.
+pub fn is_certificate_mismatch(local_addr: &Multiaddr, error: &(dyn Error + 'static)) -> bool {
+    if !local_addr
+        .iter()
+        .any(|p| matches!(p, Protocol::WebRTCDirect))
+    {
+        return false;
+    }
+    // the transport errors are boxed into io::Errors along the way, which hides them from
+    // downcasting, so match on the messages instead
+    let mut next = Some(error);
+    while let Some(e) = next {
+        let message = e.to_string().to_lowercase();
+        if ["dtls", "fingerprint", "certificate", "prologue"]
+            .iter()
+            .any(|needle| message.contains(needle))
+        {
+            return true;
+        }
+        next = e.source();
+    }
+    false
+}
+
+/// Build a WebRTC transport serving `primary` on `primary_port` and each of the `extras` on its
+/// own port starting at [`PORT_WEBRTC_EXTRA`]. Outbound dials use the primary certificate.
+///
+/// The data channel buffers can't be sized: `libp2p_webrtc::tokio::Transport` only takes the
+/// identity and the certificate, and the message size and the buffering of the data channels are
+/// fixed inside libp2p-webrtc and webrtc-rs. Large file and git transfers over WebRTC use those
+/// defaults until the dependency exposes them.
+// TODO: add --webrtc-* buffer options once libp2p-webrtc makes the data channel buffers configurable
+pub fn webrtc_transport(
+    keypair: &Keypair,
+    primary: Certificate,
+    primary_port: u16,
+    extras: &[Certificate],
+) -> Boxed<(PeerId, StreamMuxerBox)> {
+    let mut transport = PortFilter::new(
+        webrtc::tokio::Transport::new(keypair.clone(), primary),
+        primary_port,
+    )
+    .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+    .boxed();
+
+    for (port, cert) in (PORT_WEBRTC_EXTRA..).zip(extras.iter()) {
+        let extra = PortFilter::new(
+            webrtc::tokio::Transport::new(keypair.clone(), cert.clone()),
+            port,
+        )
+        .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)));
+        transport = transport
+            .or_transport(extra)
+            .map(|output, _| match output {
+                Either::Left(output) | Either::Right(output) => output,
+            })
+            .boxed();
+    }
+
+    transport
+}
+
+/// A transport that only listens on addresses with the given UDP port, leaving the other
+/// addresses to the next transport. Dials are passed through unchanged.
+struct PortFilter<T> {
+    inner: T,
+    port: u16,
+}
+
+impl<T> PortFilter<T> {
+    fn new(inner: T, port: u16) -> Self {
+        Self { inner, port }
+    }
+}
+
+impl<T: Transport + Unpin> Transport for PortFilter<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        if !addr.iter().any(|p| p == Protocol::Udp(self.port)) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr, opts)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The content type of plain text chat messages
+pub const TEXT_PLAIN: &str = "text/plain";
+
+/// The version of the envelope format, published in its `chat_envelope` field so a plain text
+/// message that happens to be JSON is not mistaken for an envelope
+pub const ENVELOPE_VERSION: u32 = 1;
+
+/// A chat message with the content type its sender chose, such as `text/markdown` or
+/// `application/json` for structured commands, so other formats can share the chat topic.
+///
+/// Other implementations publish chat messages as bare UTF-8 text. A plain text message is still
+/// published that way so every implementation understands it, any other content type is published
+/// as JSON with the [`ENVELOPE_VERSION`]. A message that isn't a valid envelope is plain text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatEnvelope {
+    /// The MIME type of the payload
+    pub content_type: String,
+    /// The message itself
+    pub payload: String,
+}
+
+// An envelope as it is published
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WireEnvelope {
+    chat_envelope: u32,
+    content_type: String,
+    payload: String,
+}
+
+impl ChatEnvelope {
+    /// Create a plain text message
+    pub fn text(payload: impl Into<String>) -> Self {
+        Self {
+            content_type: TEXT_PLAIN.to_string(),
+            payload: payload.into(),
+        }
+    }
+
+    /// Create a message with a content type, which must look like `type/subtype`
+    pub fn new(content_type: &str, payload: impl Into<String>) -> anyhow::Result<Self> {
+        let valid = content_type
+            .split_once('/')
+            .is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+            && !content_type.contains(char::is_whitespace);
+        if !valid {
+            anyhow::bail!("Invalid content type {content_type}, expected type/subtype");
+        }
+        Ok(Self {
+            content_type: content_type.to_ascii_lowercase(),
+            payload: payload.into(),
+        })
+    }
+
+    /// Decode a message, either an envelope or bare text. Anything that isn't an envelope of this
+    /// version with a valid content type is bare text. Invalid UTF-8 in bare text is replaced
+    /// rather than dropping the message.
+    pub fn decode(data: &[u8]) -> Self {
+        let envelope = serde_json::from_slice::<WireEnvelope>(data)
+            .ok()
+            .filter(|wire| wire.chat_envelope == ENVELOPE_VERSION)
+            .and_then(|wire| Self::new(&wire.content_type, wire.payload).ok());
+        envelope.unwrap_or_else(|| Self::text(String::from_utf8_lossy(data)))
+    }
+
+    /// Encode a message, as bare text if it is plain text so every implementation understands it
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        match self.is_plain_text() {
+            true => Ok(self.payload.clone().into_bytes()),
+            false => Ok(serde_json::to_vec(&WireEnvelope {
+                chat_envelope: ENVELOPE_VERSION,
+                content_type: self.content_type.clone(),
+                payload: self.payload.clone(),
+            })?),
+        }
+    }
+
+    /// Check if the message is plain text
+    pub fn is_plain_text(&self) -> bool {
+        self.content_type == TEXT_PLAIN
+    }
+}
+
+impl fmt::Display for ChatEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_plain_text() {
+            true => write!(f, "{}", self.payload),
+            false => write!(f, "[{}] {}", self.content_type, self.payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelopes_round_trip() {
+        let envelope = ChatEnvelope::new("Text/Markdown", "*hi*\n\"quoted\"").unwrap();
+        assert_eq!(envelope.content_type, "text/markdown");
+        assert_eq!(ChatEnvelope::decode(&envelope.encode().unwrap()), envelope);
+
+        let text = ChatEnvelope::text("hello");
+        assert_eq!(text.encode().unwrap(), b"hello");
+        assert_eq!(ChatEnvelope::decode(b"hello"), text);
+    }
+
+    #[test]
+    fn legacy_text_that_looks_like_an_envelope_stays_text() {
+        let unversioned = br#"{"content_type":"text/markdown","payload":"*hi*"}"#;
+        let other_version =
+            br#"{"chat_envelope":2,"content_type":"text/markdown","payload":"*hi*"}"#;
+        for data in [&unversioned[..], &other_version[..]] {
+            let decoded = ChatEnvelope::decode(data);
+            assert!(decoded.is_plain_text());
+            assert_eq!(decoded.payload.as_bytes(), data);
+        }
+    }
+
+    #[test]
+    fn envelopes_with_an_invalid_content_type_are_text() {
+        let data = br#"{"chat_envelope":1,"content_type":"not a type","payload":"x"}"#;
+        assert!(ChatEnvelope::decode(data).is_plain_text());
+
+        let data = br#"{"chat_envelope":1,"content_type":"Text/Markdown","payload":"x"}"#;
+        assert_eq!(ChatEnvelope::decode(data).content_type, "text/markdown");
+    }
+}
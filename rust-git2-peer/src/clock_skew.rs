@@ -0,0 +1,94 @@
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// The weight of a new sample in the running skew estimate of a peer
+const SAMPLE_WEIGHT: f64 = 0.25;
+
+/// Estimates how far the clocks of other peers are off from ours.
+///
+/// Each sample compares the time a peer says it published a message at with the time we received
+/// it. The difference includes the propagation delay, which is small next to the skews worth
+/// warning about, and the samples of a peer are smoothed into a running estimate.
+#[derive(Debug)]
+pub struct ClockSkew {
+    threshold: Duration,
+    // the clock of each peer minus ours, in milliseconds
+    peers: HashMap<PeerId, f64>,
+}
+
+impl ClockSkew {
+    /// Create an estimator that considers peers off by more than `threshold` skewed
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record that `peer` published a message at `sent_ms` (milliseconds since the unix epoch by
+    /// its clock) that we received at `received`. Returns the updated skew estimate of the peer in
+    /// milliseconds, positive when its clock is ahead of ours.
+    pub fn record(&mut self, peer: PeerId, sent_ms: u64, received: SystemTime) -> i64 {
+        let sample = sent_ms as f64 - unix_millis(received) as f64;
+        let skew = self
+            .peers
+            .entry(peer)
+            .and_modify(|skew| *skew += SAMPLE_WEIGHT * (sample - *skew))
+            .or_insert(sample);
+        *skew as i64
+    }
+
+    /// The skew estimate of a peer in milliseconds, positive when its clock is ahead of ours
+    pub fn skew(&self, peer: &PeerId) -> Option<i64> {
+        self.peers.get(peer).map(|skew| *skew as i64)
+    }
+
+    /// Check if the clock of a peer is off from ours by more than the threshold
+    pub fn is_skewed(&self, peer: &PeerId) -> bool {
+        self.skew(peer)
+            .is_some_and(|skew| skew.unsigned_abs() > self.threshold.as_millis() as u64)
+    }
+
+    /// Forget the estimate of a peer
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Describe the skewed peers, one per line
+    pub fn report(&self) -> Result<String, fmt::Error> {
+        let mut skewed: Vec<(&PeerId, &f64)> = self
+            .peers
+            .iter()
+            .filter(|(peer, _)| self.is_skewed(peer))
+            .collect();
+        skewed.sort_by_key(|(peer, _)| peer.to_base58());
+
+        let mut out = format!(
+            "{} peers estimated, {} skewed by more than {}s",
+            self.peers.len(),
+            skewed.len(),
+            self.threshold.as_secs()
+        );
+        for (peer, skew) in skewed {
+            write!(out, "\n\t{peer}: {}", describe_skew(*skew as i64))?;
+        }
+        Ok(out)
+    }
+}
+
+/// Describe a skew in milliseconds, e.g. "42.0s ahead"
+pub fn describe_skew(skew_ms: i64) -> String {
+    let direction = if skew_ms >= 0 { "ahead" } else { "behind" };
+    format!("{:.1}s {direction}", skew_ms.unsigned_abs() as f64 / 1000.0)
+}
+
+/// Milliseconds since the unix epoch, as published in messages
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,105 @@
+use cid::{Cid, Version};
+use clap::ValueEnum;
+use multihash::Multihash;
+use sha2::{Digest, Sha256};
+
+// The multicodec of raw bytes that content ids are built with
+const RAW_CODEC: u64 = 0x55;
+
+// The largest digest a content id holds, in bytes, the size of the `cid` crate's default
+const MAX_DIGEST_LEN: usize = 64;
+
+/// The hash content is addressed with, for file ids and gossipsub message ids.
+///
+/// Content ids are CIDv1 of raw bytes in base32, like `bafkrei...`, so they are the ids IPFS and
+/// the JS and Go multiformats libraries compute for the same bytes. sha2-256 is the default hash
+/// of those, so it is the one to use to interoperate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContentHash {
+    /// sha2-256, multihash code 0x12
+    #[default]
+    Sha256,
+    /// BLAKE3 with a 32 byte digest, multihash code 0x1e
+    Blake3,
+}
+
+impl ContentHash {
+    /// The multihash code of the hash
+    pub fn code(&self) -> u64 {
+        match self {
+            ContentHash::Sha256 => 0x12,
+            ContentHash::Blake3 => 0x1e,
+        }
+    }
+
+    /// Hash `data`
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ContentHash::Sha256 => Sha256::digest(data).to_vec(),
+            ContentHash::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+
+    /// Hash `data` into a multihash
+    pub fn multihash(&self, data: &[u8]) -> Multihash<MAX_DIGEST_LEN> {
+        Multihash::wrap(self.code(), &self.digest(data)).expect("the digests fit in a multihash")
+    }
+
+    /// The content id of `data`, a base32 CIDv1 of raw bytes
+    pub fn content_id(&self, data: &[u8]) -> String {
+        Cid::new_v1(RAW_CODEC, self.multihash(data)).to_string()
+    }
+
+    /// Check `data` against a content id, whichever of the hashes it was made with. Returns
+    /// `None` if `id` isn't a CIDv1 of raw bytes hashed with one of the hashes, since file ids can
+    /// also be names, and a name that only looks like a content id says nothing about the data.
+    pub fn verify(id: &str, data: &[u8]) -> Option<bool> {
+        let cid = Cid::try_from(id).ok()?;
+        if cid.version() != Version::V1 || cid.codec() != RAW_CODEC {
+            return None;
+        }
+        ContentHash::value_variants()
+            .iter()
+            .find(|hash| hash.code() == cid.hash().code())
+            .map(|hash| hash.multihash(data) == *cid.hash())
+    }
+
+    /// The gossipsub message id of `data`, the hex of its digest
+    pub fn message_id(&self, data: &[u8]) -> String {
+        hex::encode(self.digest(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_id_of_the_empty_string_is_the_ipfs_one() {
+        assert_eq!(
+            ContentHash::Sha256.content_id(b""),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+        let blake3 = ContentHash::Blake3.content_id(b"");
+        assert!(blake3.starts_with("bafkr4i"), "{blake3}");
+    }
+
+    #[test]
+    fn verify_checks_content_ids_of_either_hash() {
+        for hash in ContentHash::value_variants() {
+            let id = hash.content_id(b"data");
+            assert_eq!(ContentHash::verify(&id, b"data"), Some(true));
+            assert_eq!(ContentHash::verify(&id, b"other"), Some(false));
+        }
+    }
+
+    #[test]
+    fn verify_ignores_ids_that_only_look_like_content_ids() {
+        // a legacy file id sharing the prefix of a sha2-256 content id
+        assert_eq!(ContentHash::verify("bafkreimyfile", b"data"), None);
+        assert_eq!(ContentHash::verify("notes.txt", b"data"), None);
+        // a CID of another codec, dag-pb
+        let dag_pb = Cid::new_v1(0x70, ContentHash::Sha256.multihash(b"data")).to_string();
+        assert_eq!(ContentHash::verify(&dag_pb, b"data"), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! The control socket, which runs the commands the UI accepts for local scripts and admin tools.
+//!
+//! A client writes one command per line and gets the reply the UI would show, followed by an
+//! empty line. Output a command produces later, like the progress of a clone, goes to the UI as
+//! usual. There is no authentication, so serve it on a Unix socket or on localhost.
+
+use crate::{ServeListener, ServeStream};
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+use tokio_util::codec::{FramedRead, LinesCodec};
+use tracing::{debug, warn};
+
+/// The longest command accepted, longer lines close the connection
+const MAX_COMMAND_LENGTH: usize = 4096;
+
+// How many commands can wait for the event loop before the clients have to wait
+const COMMAND_QUEUE_SIZE: usize = 16;
+
+// How long to wait before accepting again after accepting a connection failed, so running out of
+// file descriptors doesn't turn into a busy loop
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// A command received on the control socket, with the channel its reply is sent back on
+#[derive(Debug)]
+pub struct ControlCommand {
+    /// The command line, as it would be typed in the UI
+    pub command: String,
+    /// Where the reply to the command goes
+    pub reply: oneshot::Sender<String>,
+}
+
+/// The commands received on the control socket, if it is served
+#[derive(Debug, Default)]
+pub struct ControlCommands {
+    receiver: Option<mpsc::Receiver<ControlCommand>>,
+}
+
+impl ControlCommands {
+    /// Serve the control socket on `listener`, receiving the commands its clients send
+    pub fn serve(listener: ServeListener) -> Self {
+        let (sender, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        tokio::spawn(serve(listener, sender));
+        Self {
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Wait for the next command, forever if the control socket isn't served
+    pub async fn recv(&mut self) -> Option<ControlCommand> {
+        match self.receiver.as_mut() {
+            Some(receiver) => receiver.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+// Accept control connections, handling each one on its own task
+async fn serve(listener: ServeListener, commands: mpsc::Sender<ControlCommand>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept control connection: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        debug!("Accepted a control connection from {addr}");
+
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, commands).await {
+                debug!("Control connection from {addr} closed: {e}");
+            }
+        });
+    }
+}
+
+// Run the commands of one connection in order, writing back each reply
+async fn handle_connection(
+    stream: Box<dyn ServeStream>,
+    commands: mpsc::Sender<ControlCommand>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_COMMAND_LENGTH));
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        // a browser can be made to send a request to a localhost port, its lines must not be run
+        if is_http_request_line(command) {
+            anyhow::bail!("refusing an HTTP request");
+        }
+
+        let (reply, response) = oneshot::channel();
+        commands
+            .send(ControlCommand {
+                command: command.to_string(),
+                reply,
+            })
+            .await?;
+        let response = response.await?;
+        writer
+            .write_all(format!("{}\n\n", response.trim_end()).as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+// Check if a line is the request line of an HTTP request
+fn is_http_request_line(line: &str) -> bool {
+    line.rsplit(' ')
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ServeAddr;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[test]
+    fn http_request_lines_are_recognized() {
+        assert!(is_http_request_line("POST / HTTP/1.1"));
+        assert!(is_http_request_line("GET /status HTTP/1.0"));
+        assert!(!is_http_request_line("status"));
+        assert!(!is_http_request_line("clone 12D3KooW repo"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn commands_are_answered_in_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let addr = ServeAddr::Unix(dir.path().join("control.sock"));
+        let mut control = ControlCommands::serve(addr.bind().await.unwrap());
+        tokio::spawn(async move {
+            while let Some(ControlCommand { command, reply }) = control.recv().await {
+                let _ = reply.send(format!("ran {command}\nsecond line"));
+            }
+        });
+
+        let ServeAddr::Unix(path) = addr else {
+            unreachable!()
+        };
+        let stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"status\n\npeers\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let mut replies = Vec::new();
+        for _ in 0..6 {
+            replies.push(lines.next_line().await.unwrap().unwrap());
+        }
+        assert_eq!(
+            replies,
+            [
+                "ran status",
+                "second line",
+                "",
+                "ran peers",
+                "second line",
+                ""
+            ]
+        );
+
+        // an HTTP request closes the connection without running anything
+        writer
+            .write_all(b"POST / HTTP/1.1\nstatus\n")
+            .await
+            .unwrap();
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+}
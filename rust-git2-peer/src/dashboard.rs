@@ -0,0 +1,218 @@
+use crate::{ServeListener, ServeStream};
+use std::{io, net::IpAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+};
+use tracing::{debug, warn};
+
+/// How often the peer publishes a new status snapshot to the dashboard
+pub const DASHBOARD_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// The status page, which polls the status endpoint and renders it
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+// The path of the JSON status endpoint
+const STATUS_PATH: &str = "/status.json";
+
+// The paths of the status page
+const PAGE_PATHS: [&str; 2] = ["/", "/index.html"];
+
+// The largest request line and headers accepted
+const MAX_REQUEST_HEAD: usize = 8192;
+
+// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait before accepting again after accepting a connection failed, so running out of
+// file descriptors doesn't turn into a busy loop
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Serve the status dashboard over HTTP: a static HTML page on `/`, and the latest status snapshot
+/// of the peer as JSON on `/status.json` which the page polls. Other paths are not found.
+///
+/// There is no authentication, bind it to localhost or a Unix socket unless the status may be
+/// public. Requests must name the server by an IP address or `localhost` in their `Host` header,
+/// so a web page can't read the status through a DNS name rebound to a local address.
+pub async fn serve(listener: ServeListener, status: watch::Receiver<serde_json::Value>) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept dashboard connection: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        debug!("Serving the dashboard to {addr}");
+
+        let status = status.clone();
+        tokio::spawn(async move {
+            let response = match read_request_head(&mut stream).await {
+                Ok(head) => respond(&head, &status),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Response::error("431 Request Header Fields Too Large")
+                }
+                Err(e) => {
+                    debug!("Failed to read the dashboard request from {addr}: {e}");
+                    return;
+                }
+            };
+            let _ = stream.write_all(&response.encode()).await;
+        });
+    }
+}
+
+// A response to a dashboard request
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{status}\n"),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+// Read the request line and headers, up to the empty line ending them. The request body, if any,
+// is ignored.
+async fn read_request_head(stream: &mut Box<dyn ServeStream>) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read = async {
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            if head.len() > MAX_REQUEST_HEAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request head too large",
+                ));
+            }
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            head.extend_from_slice(&buf[..read]);
+        }
+        Ok(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+// Answer a request given its head
+fn respond(head: &str, status: &watch::Receiver<serde_json::Value>) -> Response {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Response::error("400 Bad Request");
+    };
+    let host = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim());
+    if !host.is_some_and(is_allowed_host) {
+        return Response::error("403 Forbidden");
+    }
+    if method != "GET" {
+        return Response::error("405 Method Not Allowed");
+    }
+
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    if path == STATUS_PATH {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: serde_json::to_string(&*status.borrow()).unwrap_or_default(),
+        }
+    } else if PAGE_PATHS.contains(&path) {
+        Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: DASHBOARD_HTML.to_string(),
+        }
+    } else {
+        Response::error("404 Not Found")
+    }
+}
+
+// Check that the Host header names the server by an IP address or as localhost, with an optional
+// port. Any other name could have been rebound to a local address by a malicious page.
+fn is_allowed_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        // an IPv6 address, with an optional port after the brackets
+        Some(rest) => match rest.split_once(']') {
+            Some((address, port)) if port.is_empty() || port.starts_with(':') => address,
+            _ => return false,
+        },
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, host: &str) -> Response {
+        let (_, status) = watch::channel(serde_json::json!({ "connections": 1 }));
+        respond(
+            &format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nAccept: */*\r\n\r\n"),
+            &status,
+        )
+    }
+
+    #[test]
+    fn serves_the_page_and_the_status() {
+        let page = request("/", "127.0.0.1:9101");
+        assert_eq!(page.status, "200 OK");
+        assert!(page.content_type.starts_with("text/html"));
+
+        let status = request("/status.json?t=1", "localhost:9101");
+        assert_eq!(status.status, "200 OK");
+        assert_eq!(status.body, r#"{"connections":1}"#);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        assert_eq!(request("/favicon.ico", "localhost").status, "404 Not Found");
+        assert_eq!(request("/status", "localhost").status, "404 Not Found");
+    }
+
+    #[test]
+    fn foreign_hosts_are_refused() {
+        assert_eq!(request("/", "evil.example:9101").status, "403 Forbidden");
+        assert_eq!(request("/", "[::1]:9101").status, "200 OK");
+        assert_eq!(request("/", "[::1]x").status, "403 Forbidden");
+
+        let (_, status) = watch::channel(serde_json::Value::Null);
+        let missing = respond("GET / HTTP/1.1\r\n\r\n", &status);
+        assert_eq!(missing.status, "403 Forbidden");
+    }
+
+    #[test]
+    fn only_gets_are_served() {
+        let (_, status) = watch::channel(serde_json::Value::Null);
+        let post = respond("POST / HTTP/1.1\r\nHost: localhost\r\n\r\n", &status);
+        assert_eq!(post.status, "405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,230 @@
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// Suppresses redundant dials to a peer. Peer discovery, identify and Kademlia can all ask to dial
+/// the same peer at once: only the first dial goes ahead while it is pending, and after a failed
+/// dial the peer isn't dialed again until the minimum redial interval has passed.
+///
+/// It also acts as a circuit breaker for peers that are permanently unreachable, such as stale
+/// bootstrap entries: after a number of consecutive failed dials the peer isn't dialed again until
+/// the cooldown has passed or it connects to us.
+#[derive(Debug)]
+pub struct DialCoalescer {
+    min_interval: Duration,
+    max_failures: u32,
+    cooldown: Duration,
+    pending: HashSet<PeerId>,
+    failed: HashMap<PeerId, Instant>,
+    // the consecutive failed dials of each peer
+    failures: HashMap<PeerId, u32>,
+    // the peers whose breaker is open and when it opened
+    tripped: HashMap<PeerId, Instant>,
+}
+
+impl DialCoalescer {
+    /// Create a coalescer waiting at least `min_interval` before redialing a peer after a failure,
+    /// and `cooldown` after `max_failures` consecutive failures
+    pub fn new(min_interval: Duration, max_failures: u32, cooldown: Duration) -> Self {
+        Self {
+            min_interval,
+            max_failures,
+            cooldown,
+            pending: HashSet::new(),
+            failed: HashMap::new(),
+            failures: HashMap::new(),
+            tripped: HashMap::new(),
+        }
+    }
+
+    /// Check if a peer may be dialed at `now`, recording the dial as pending if so
+    pub fn try_dial(&mut self, peer: PeerId, now: Instant) -> bool {
+        let min_interval = self.min_interval;
+        self.failed
+            .retain(|_, failed| now.saturating_duration_since(*failed) < min_interval);
+        // a peer whose cooldown passed gets one more attempt, a failure trips the breaker again
+        let cooldown = self.cooldown;
+        self.tripped
+            .retain(|_, tripped| now.saturating_duration_since(*tripped) < cooldown);
+        if self.pending.contains(&peer)
+            || self.failed.contains_key(&peer)
+            || self.tripped.contains_key(&peer)
+        {
+            return false;
+        }
+        self.pending.insert(peer)
+    }
+
+    /// Record that a connection to a peer was established, in either direction
+    pub fn connected(&mut self, peer: &PeerId) {
+        self.pending.remove(peer);
+        self.failed.remove(peer);
+        self.failures.remove(peer);
+        if self.tripped.remove(peer).is_some() {
+            info!("Dial breaker of {peer} closed: the peer connected");
+        }
+    }
+
+    /// Record that dialing a peer failed at `now`
+    pub fn failed(&mut self, peer: PeerId, now: Instant) {
+        self.pending.remove(&peer);
+        self.failed.insert(peer, now);
+        let failures = self.failures.entry(peer).or_default();
+        *failures += 1;
+        if *failures >= self.max_failures && !self.tripped.contains_key(&peer) {
+            info!(
+                "Dial breaker of {peer} opened: {failures} consecutive dials failed, not dialing it for {}s",
+                self.cooldown.as_secs()
+            );
+            self.tripped.insert(peer, now);
+        }
+    }
+
+    /// Forget a pending dial that was never started, e.g. because the swarm refused it
+    pub fn cancel(&mut self, peer: &PeerId) {
+        self.pending.remove(peer);
+    }
+
+    /// Check if a dial to a peer is pending
+    pub fn is_pending(&self, peer: &PeerId) -> bool {
+        self.pending.contains(peer)
+    }
+
+    /// Check if the breaker of a peer is open at `now`
+    pub fn is_tripped(&self, peer: &PeerId, now: Instant) -> bool {
+        self.tripped
+            .get(peer)
+            .is_some_and(|tripped| now.saturating_duration_since(*tripped) < self.cooldown)
+    }
+
+    /// Describe the peers whose breaker is open at `now`, one per line
+    pub fn report(&self, now: Instant) -> Result<String, fmt::Error> {
+        let mut tripped: Vec<(&PeerId, Duration)> = self
+            .tripped
+            .iter()
+            .map(|(peer, tripped)| (peer, now.saturating_duration_since(*tripped)))
+            .filter(|(_, open)| *open < self.cooldown)
+            .collect();
+        tripped.sort_by_key(|(peer, _)| peer.to_base58());
+
+        let mut out = format!(
+            "{} peers open after {} consecutive failures, {} failing",
+            tripped.len(),
+            self.max_failures,
+            self.failures.len()
+        );
+        for (peer, open) in tripped {
+            write!(
+                out,
+                "\n\t{peer}: {} failures, retried in {}s",
+                self.failures.get(peer).copied().unwrap_or_default(),
+                (self.cooldown - open).as_secs()
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const MIN_INTERVAL: Duration = Duration::from_secs(5);
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    fn peer() -> PeerId {
+        Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    fn coalescer() -> DialCoalescer {
+        DialCoalescer::new(MIN_INTERVAL, 3, COOLDOWN)
+    }
+
+    #[test]
+    fn concurrent_dials_to_a_peer_coalesce() {
+        let coalescer = Arc::new(Mutex::new(coalescer()));
+        let peer = peer();
+        let now = Instant::now();
+        let dials: Vec<bool> = (0..8)
+            .map(|_| {
+                let coalescer = coalescer.clone();
+                thread::spawn(move || coalescer.lock().unwrap().try_dial(peer, now))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|dial| dial.join().unwrap())
+            .collect();
+
+        assert_eq!(dials.iter().filter(|dialed| **dialed).count(), 1);
+        let mut coalescer = coalescer.lock().unwrap();
+        assert!(coalescer.is_pending(&peer));
+        // another peer is dialed independently
+        assert!(coalescer.try_dial(self::peer(), now));
+    }
+
+    #[test]
+    fn failed_dial_waits_for_the_min_interval() {
+        let mut coalescer = coalescer();
+        let peer = peer();
+        let now = Instant::now();
+        assert!(coalescer.try_dial(peer, now));
+        coalescer.failed(peer, now);
+        assert!(!coalescer.is_pending(&peer));
+
+        assert!(!coalescer.try_dial(peer, now + MIN_INTERVAL / 2));
+        assert!(coalescer.try_dial(peer, now + MIN_INTERVAL));
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let mut coalescer = coalescer();
+        let peer = peer();
+        let mut now = Instant::now();
+        for _ in 0..3 {
+            assert!(coalescer.try_dial(peer, now));
+            coalescer.failed(peer, now);
+            now += MIN_INTERVAL;
+        }
+        let tripped = now - MIN_INTERVAL;
+        assert!(coalescer.is_tripped(&peer, now));
+
+        // the breaker holds past the min interval, until the cooldown passed
+        assert!(!coalescer.try_dial(peer, now));
+        assert!(!coalescer.try_dial(peer, tripped + COOLDOWN / 2));
+        assert!(coalescer.try_dial(peer, tripped + COOLDOWN));
+
+        // the one attempt after the cooldown trips it again when it fails
+        coalescer.failed(peer, tripped + COOLDOWN);
+        assert!(coalescer.is_tripped(&peer, tripped + COOLDOWN + MIN_INTERVAL));
+        assert!(!coalescer.try_dial(peer, tripped + COOLDOWN + MIN_INTERVAL));
+    }
+
+    #[test]
+    fn connection_resets_the_interval_and_the_breaker() {
+        let mut coalescer = coalescer();
+        let peer = peer();
+        let now = Instant::now();
+        for _ in 0..3 {
+            coalescer.failed(peer, now);
+        }
+        assert!(coalescer.is_tripped(&peer, now));
+
+        coalescer.connected(&peer);
+        assert!(!coalescer.is_tripped(&peer, now));
+        assert!(coalescer.try_dial(peer, now));
+
+        // the failures were forgotten, so a single failure doesn't trip the breaker
+        coalescer.failed(peer, now);
+        assert!(!coalescer.is_tripped(&peer, now));
+        assert!(coalescer.try_dial(peer, now + MIN_INTERVAL));
+    }
+}
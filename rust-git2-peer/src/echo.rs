@@ -0,0 +1,91 @@
+use crate::file_exchange::{read_length_prefixed, write_length_prefixed};
+use async_trait::async_trait;
+use futures::{io, AsyncRead, AsyncWrite};
+use libp2p::{request_response, StreamProtocol};
+
+// Simple echo protocol for diagnosing application-layer reachability. The responder sends back
+// exactly the bytes it received, so the requester can measure the round trip through the whole
+// request_response stack and check that the payload arrived intact.
+//
+// Request and Response:
+//  varuint - payload length
+//  bytes - payload
+//
+
+/// The largest payload the echo protocol accepts.
+pub const MAX_ECHO_SIZE: usize = 1 << 20; // 1MiB
+
+/// The codec for the echo protocol.
+#[derive(Default, Clone)]
+pub struct EchoCodec;
+
+/// The request message for the echo protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoRequest {
+    /// The bytes to be echoed back.
+    pub payload: Vec<u8>,
+}
+
+/// The response message for the echo protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoResponse {
+    /// The bytes from the request.
+    pub payload: Vec<u8>,
+}
+
+#[async_trait]
+impl request_response::Codec for EchoCodec {
+    type Protocol = StreamProtocol;
+    type Request = EchoRequest;
+    type Response = EchoResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let payload = read_length_prefixed(io, MAX_ECHO_SIZE).await?;
+
+        Ok(EchoRequest { payload })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let payload = read_length_prefixed(io, MAX_ECHO_SIZE).await?;
+
+        Ok(EchoResponse { payload })
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        EchoRequest { payload }: EchoRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, payload).await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        EchoResponse { payload }: EchoResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, payload).await?;
+
+        Ok(())
+    }
+}
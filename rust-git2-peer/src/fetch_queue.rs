@@ -0,0 +1,191 @@
+use libp2p::PeerId;
+use std::collections::VecDeque;
+
+/// A file to fetch from the peer that offered it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileFetch {
+    /// The peer that offered the file
+    pub peer: PeerId,
+    /// The id of the file
+    pub file_id: String,
+}
+
+/// What to do with an offered file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FetchDecision {
+    /// Fetch it now
+    Start(FileFetch),
+    /// It was queued until there is capacity to fetch it
+    Queued,
+    /// It was skipped, the queue is full
+    Skipped,
+}
+
+/// Throttles the files fetched automatically when they are offered, so a burst of offers can't
+/// saturate the bandwidth and memory of the peer. A file is fetched right away while fewer than
+/// `max_active` fetches are in flight and the file cache is below `max_cache_bytes`, otherwise it
+/// is queued until a fetch finishes or the cache shrinks. Once `max_queued` fetches wait, further
+/// offers are skipped.
+#[derive(Debug)]
+pub struct FetchQueue {
+    max_active: usize,
+    max_queued: usize,
+    max_cache_bytes: Option<u64>,
+    queued: VecDeque<FileFetch>,
+}
+
+impl FetchQueue {
+    /// Create a queue allowing at most `max_active` fetches in flight and `max_queued` waiting,
+    /// and deferring fetches while the file cache takes at least `max_cache_bytes`
+    pub fn new(max_active: usize, max_queued: usize, max_cache_bytes: Option<u64>) -> Self {
+        Self {
+            max_active,
+            max_queued,
+            max_cache_bytes,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Decide what to do with an offered file, with `active` fetches in flight and the file cache
+    /// taking `cache_bytes`. A file that is already queued is queued once.
+    pub fn push(&mut self, fetch: FileFetch, active: usize, cache_bytes: u64) -> FetchDecision {
+        if self.has_capacity(active, cache_bytes) && self.queued.is_empty() {
+            return FetchDecision::Start(fetch);
+        }
+        if self
+            .queued
+            .iter()
+            .any(|queued| queued.file_id == fetch.file_id)
+        {
+            return FetchDecision::Queued;
+        }
+        if self.queued.len() >= self.max_queued {
+            return FetchDecision::Skipped;
+        }
+        self.queued.push_back(fetch);
+        FetchDecision::Queued
+    }
+
+    /// Take the next queued fetch if there is capacity for it with `active` fetches in flight and
+    /// the file cache taking `cache_bytes`
+    pub fn pop(&mut self, active: usize, cache_bytes: u64) -> Option<FileFetch> {
+        if self.has_capacity(active, cache_bytes) {
+            self.queued.pop_front()
+        } else {
+            None
+        }
+    }
+
+    // Check if another fetch can start
+    fn has_capacity(&self, active: usize, cache_bytes: u64) -> bool {
+        active < self.max_active && self.max_cache_bytes.is_none_or(|max| cache_bytes < max)
+    }
+
+    /// The maximum number of fetches in flight
+    pub fn max_active(&self) -> usize {
+        self.max_active
+    }
+
+    /// The number of queued fetches
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Check if no fetches are queued
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    const MAX_ACTIVE: usize = 2;
+    const MAX_QUEUED: usize = 2;
+    const MAX_CACHE_BYTES: u64 = 1000;
+
+    fn queue() -> FetchQueue {
+        FetchQueue::new(MAX_ACTIVE, MAX_QUEUED, Some(MAX_CACHE_BYTES))
+    }
+
+    fn fetch(file_id: &str) -> FileFetch {
+        FileFetch {
+            peer: Keypair::generate_ed25519().public().to_peer_id(),
+            file_id: file_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn fetches_start_right_away_with_capacity() {
+        let mut queue = queue();
+        let a = fetch("a");
+        assert_eq!(queue.push(a.clone(), 0, 0), FetchDecision::Start(a));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn a_file_is_queued_once() {
+        let mut queue = queue();
+        assert_eq!(queue.push(fetch("a"), MAX_ACTIVE, 0), FetchDecision::Queued);
+        // offered again, by the same or another peer
+        assert_eq!(queue.push(fetch("a"), MAX_ACTIVE, 0), FetchDecision::Queued);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn offers_are_skipped_when_the_queue_is_full() {
+        let mut queue = queue();
+        for file_id in ["a", "b"] {
+            assert_eq!(
+                queue.push(fetch(file_id), MAX_ACTIVE, 0),
+                FetchDecision::Queued
+            );
+        }
+        assert_eq!(
+            queue.push(fetch("c"), MAX_ACTIVE, 0),
+            FetchDecision::Skipped
+        );
+        assert_eq!(queue.len(), MAX_QUEUED);
+
+        // a file that is already queued isn't skipped
+        assert_eq!(queue.push(fetch("a"), MAX_ACTIVE, 0), FetchDecision::Queued);
+    }
+
+    #[test]
+    fn queued_fetches_go_first_once_there_is_capacity() {
+        let mut queue = queue();
+        let a = fetch("a");
+        assert_eq!(queue.push(a.clone(), MAX_ACTIVE, 0), FetchDecision::Queued);
+        // a new offer doesn't jump the queue even though a fetch finished
+        assert_eq!(queue.push(fetch("b"), 0, 0), FetchDecision::Queued);
+        assert_eq!(queue.pop(MAX_ACTIVE, 0), None);
+        assert_eq!(queue.pop(MAX_ACTIVE - 1, 0), Some(a));
+        assert_eq!(
+            queue.pop(0, 0).map(|fetch| fetch.file_id),
+            Some("b".to_string())
+        );
+        assert_eq!(queue.pop(0, 0), None);
+    }
+
+    #[test]
+    fn fetches_wait_while_the_cache_is_over_its_threshold() {
+        let mut queue = queue();
+        let a = fetch("a");
+        assert_eq!(
+            queue.push(a.clone(), 0, MAX_CACHE_BYTES),
+            FetchDecision::Queued
+        );
+        assert_eq!(queue.pop(0, MAX_CACHE_BYTES), None);
+        assert_eq!(queue.pop(0, MAX_CACHE_BYTES - 1), Some(a));
+
+        // without a cache limit only the fetches in flight count
+        let mut unbounded = FetchQueue::new(MAX_ACTIVE, MAX_QUEUED, None);
+        let b = fetch("b");
+        assert_eq!(
+            unbounded.push(b.clone(), 0, u64::MAX),
+            FetchDecision::Start(b)
+        );
+    }
+}
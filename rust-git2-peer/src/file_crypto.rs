@@ -0,0 +1,117 @@
+//! End-to-end encryption of file payloads.
+//!
+//! A file body is encrypted to the libp2p identity of the peer receiving it, so relays and other
+//! intermediaries only ever see ciphertext. This only works for peers with ed25519 identities:
+//! their peer id embeds the public key, so the sender needs nothing beyond the peer id.
+//!
+//! Key derivation:
+//!
+//! 1. The recipient's ed25519 public key is taken from its peer id and converted to its X25519
+//!    (Montgomery) form. The recipient derives the matching X25519 secret from its ed25519 secret
+//!    key the way ed25519 derives its signing scalar, from the first half of the SHA-512 hash of
+//!    the secret key.
+//! 2. The sender generates an ephemeral X25519 key pair and computes the Diffie-Hellman shared
+//!    secret with the recipient's X25519 public key.
+//! 3. The symmetric key is the SHA-256 hash of [`KEY_CONTEXT`], the shared secret, the ephemeral
+//!    public key and the recipient's X25519 public key.
+//!
+//! The body is then sealed with ChaCha20-Poly1305. Every body is encrypted under a fresh key, so
+//! the nonce is always zero. The encrypted body is the ephemeral public key followed by the
+//! ciphertext and its authentication tag.
+
+use anyhow::Context;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::{identity, PeerId};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The context string mixed into every derived key
+pub const KEY_CONTEXT: &[u8] = b"universal-connectivity-file-encryption-v1";
+
+// The length of an X25519 public key
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// Get the ed25519 public key a peer id embeds, if the peer has an ed25519 identity
+pub fn ed25519_public_key(peer: &PeerId) -> Option<identity::ed25519::PublicKey> {
+    let multihash = peer.as_ref();
+    // peer ids of small keys such as ed25519 are the identity hash of the encoded key
+    if multihash.code() != 0 {
+        return None;
+    }
+    identity::PublicKey::try_decode_protobuf(multihash.digest())
+        .ok()?
+        .try_into_ed25519()
+        .ok()
+}
+
+// Convert an ed25519 public key to its X25519 form
+fn x25519_public_key(key: &identity::ed25519::PublicKey) -> Option<PublicKey> {
+    let point = CompressedEdwardsY(key.to_bytes()).decompress()?;
+    Some(PublicKey::from(point.to_montgomery().to_bytes()))
+}
+
+// Derive the symmetric key from a shared secret and both public keys
+fn derive_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain_update(KEY_CONTEXT)
+        .chain_update(shared)
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize();
+    ChaCha20Poly1305::new(&key)
+}
+
+/// Encrypt a file body to a peer. Fails if the peer doesn't have an ed25519 identity.
+pub fn encrypt_for(peer: &PeerId, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let recipient = ed25519_public_key(peer)
+        .and_then(|key| x25519_public_key(&key))
+        .with_context(|| format!("{peer} doesn't have an ed25519 identity"))?;
+
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    let ciphertext = derive_key(shared.as_bytes(), &ephemeral_public, &recipient)
+        .encrypt(&Nonce::default(), body)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the file body"))?;
+
+    let mut out = Vec::with_capacity(PUBLIC_KEY_LEN + ciphertext.len());
+    out.extend_from_slice(ephemeral_public.as_bytes());
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypts the file bodies encrypted to our identity
+pub struct FileDecryptor {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl FileDecryptor {
+    /// Create a decryptor for our identity. Fails if it isn't an ed25519 key pair.
+    pub fn new(keypair: &identity::Keypair) -> anyhow::Result<Self> {
+        let keypair = keypair
+            .clone()
+            .try_into_ed25519()
+            .context("File encryption requires an ed25519 identity")?;
+        let hash = Sha512::digest(keypair.secret().as_ref());
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&hash[..32]);
+        let secret = StaticSecret::from(scalar);
+        let public = PublicKey::from(&secret);
+        Ok(Self { secret, public })
+    }
+
+    /// Decrypt a file body encrypted to us with [`encrypt_for`]
+    pub fn decrypt(&self, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some((ephemeral, ciphertext)) = body.split_first_chunk::<PUBLIC_KEY_LEN>() else {
+            anyhow::bail!("Encrypted file body is too short");
+        };
+        let ephemeral = PublicKey::from(*ephemeral);
+        let shared = self.secret.diffie_hellman(&ephemeral);
+        derive_key(shared.as_bytes(), &ephemeral, &self.public)
+            .decrypt(&Nonce::default(), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt the file body"))
+    }
+}
@@ -1,6 +1,8 @@
+use crate::{file_crypto, FileStore};
 use async_trait::async_trait;
 use futures::{io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use libp2p::{request_response, StreamProtocol};
+use libp2p::{request_response, PeerId, StreamProtocol};
+use tracing::{debug, warn};
 
 // Simple file exchange protocol. The format that the peers support consists of two different
 // messages, one to request a file and one to receive the file.
@@ -12,12 +14,87 @@ use libp2p::{request_response, StreamProtocol};
 //  varuint - file id length
 //  bytes - file id
 //
+// The request may be followed by an optional idempotency nonce chosen by the requester. Retries of
+// a request reuse its nonce so that the responder can collapse concurrent duplicates and the
+// requester can ignore a late duplicate response. Peers that don't send a nonce are unaffected.
+//
+//  varuint - nonce length (8)
+//  bytes - nonce, big endian u64
+//
 // The file response message consists of a varuint length followed by the contents of the file.
 //
 // Response:
 //  varuint - file contents length
 //  bytes - file contents
 //
+// Version 2 of the protocol, /universal-connectivity-file/2, uses the same messages with two
+// changes: the nonce is required, and a peer that doesn't have the requested file responds with
+// empty file contents instead of an error, so the requester learns it was not found. Empty files
+// can't be exchanged with either version.
+//
+// Either version may ask for the file contents to be encrypted to the requester, see
+// `file_crypto`. The request then follows the nonce, which is required, with a flags byte, and the
+// response follows the file contents with a flags byte telling if they are encrypted. Peers that
+// don't know about encryption ignore the flags byte of a request and answer in the clear.
+//
+//  varuint - flags length (1)
+//  u8 - flags, 1 if the contents are (to be) encrypted
+//
+// A version 2 response for an unknown file has empty file contents followed by flags with the not
+// found flag, 2, set, which older peers ignore and still read as an empty response. An empty
+// version 2 response without the flag also means the file was not found.
+//
+// Version 3 of the protocol, /universal-connectivity-file/3, transfers a file in ranges so that an
+// interrupted transfer can be resumed. The request always has the nonce and the flags, followed by
+// the requested range: the session id the requester chose for the transfer, the offset of the
+// range in the file and its length.
+//
+//  varuint - range length (24)
+//  bytes - session id, offset and length, big endian u64 each
+//
+// The response is the contents of the range and the flags, always present, followed by the
+// session id, the offset of the range and the size of the whole file. A not found response is the
+// same as in version 2.
+//
+//  varuint - range length (24)
+//  bytes - session id, offset and file size, big endian u64 each
+//
+// The responder holds no state for a session, each range is served from the file and the session
+// id is echoed so the requester can match the range to its transfer. A range may be served shorter
+// than requested, at most FILE_RANGE_SIZE bytes, and a range starting past the end of the file is
+// answered not found. Each range is encrypted on its own. A range request negotiated down to
+// version 1 or 2 is sent as a request for the whole file.
+//
+
+/// The most bytes of a file served for a range request
+pub const FILE_RANGE_SIZE: u64 = 1 << 20;
+
+/// The largest file that can be exchanged, whole or in ranges
+pub const MAX_FILE_SIZE: u64 = 500_000_000;
+
+/// The version of the file exchange protocol negotiated for a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// `/universal-connectivity-file/1`
+    V1,
+    /// `/universal-connectivity-file/2`
+    V2,
+    /// `/universal-connectivity-file/3`
+    V3,
+}
+
+impl Version {
+    /// Get the version of a file exchange protocol from its name.
+    pub fn of(protocol: &StreamProtocol) -> Self {
+        if protocol.as_ref().ends_with("/3") {
+            Version::V3
+        } else if protocol.as_ref().ends_with("/2") {
+            Version::V2
+        } else {
+            Version::V1
+        }
+    }
+}
 
 /// The codec for the file exchange protocol.
 #[derive(Default, Clone)]
@@ -28,13 +105,114 @@ pub struct Codec;
 pub struct Request {
     /// The identifier of the file that is being requested.
     pub file_id: String,
+    /// The idempotency nonce shared by all retries of the request, if the requester sent one.
+    pub nonce: Option<u64>,
+    /// Set if the requester asks for the file contents to be encrypted to it.
+    pub encrypt: bool,
+    /// The range of the file requested, if the whole file isn't. Only version 3 of the protocol
+    /// can express it, the whole file is requested over the older versions.
+    pub range: Option<Range>,
+}
+
+/// A range of a file requested over version 3 of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    /// The id the requester chose for the transfer, shared by all of its ranges.
+    pub session: u64,
+    /// The offset of the range in the file.
+    pub offset: u64,
+    /// The length of the range, the responder may send less.
+    pub length: u64,
 }
 
 /// The response message for the file exchange protocol.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Response {
-    /// The contents of the file that is being sent.
-    pub file_body: Vec<u8>,
+pub enum Response {
+    /// The requested file.
+    File {
+        /// The contents of the file, never empty.
+        file_body: Vec<u8>,
+        /// Set if `file_body` is encrypted to the requester.
+        encrypted: bool,
+    },
+    /// A range of the requested file, in response to a request for a range.
+    Range {
+        /// The session id of the request.
+        session: u64,
+        /// The offset of the range in the file.
+        offset: u64,
+        /// The size of the whole file.
+        total_size: u64,
+        /// The contents of the range, never empty.
+        data: Vec<u8>,
+        /// Set if `data` is encrypted to the requester.
+        encrypted: bool,
+    },
+    /// The responder doesn't have the file, or can't send it the way it was asked for. This is a
+    /// definitive answer, asking again won't get the file. Only version 2 and later of the
+    /// protocol can express it, a version 1 requester sees the request fail.
+    NotFound,
+}
+
+/// Answer a file request from `peer` out of `files`. Not found tells a version 2 or 3 requester
+/// that we don't have the file, or can't send it the way it was asked for, and fails the request of
+/// a version 1 requester.
+pub fn respond(files: &mut FileStore, peer: &PeerId, request: &Request) -> Response {
+    files.touch(&request.file_id);
+    let Some(body) = files.get(&request.file_id) else {
+        debug!("{peer} requested unknown file {}", request.file_id);
+        return Response::NotFound;
+    };
+    let total_size = body.len() as u64;
+
+    let data = match request.range {
+        None => body,
+        Some(range) => {
+            let start = range.offset.min(total_size);
+            let end = range
+                .offset
+                .saturating_add(range.length.min(FILE_RANGE_SIZE))
+                .min(total_size);
+            if start >= end {
+                debug!(
+                    "{peer} requested range {range:?} of {}, which is outside of it",
+                    request.file_id
+                );
+                return Response::NotFound;
+            }
+            &body[start as usize..end as usize]
+        }
+    };
+
+    // a file asked for encrypted is never sent in the clear
+    let (data, encrypted) = if request.encrypt {
+        match file_crypto::encrypt_for(peer, data) {
+            Ok(data) => (data, true),
+            Err(e) => {
+                warn!(
+                    "Can't send file {} to {peer} encrypted: {e}",
+                    request.file_id
+                );
+                return Response::NotFound;
+            }
+        }
+    } else {
+        (data.to_vec(), false)
+    };
+
+    match request.range {
+        None => Response::File {
+            file_body: data,
+            encrypted,
+        },
+        Some(range) => Response::Range {
+            session: range.session,
+            offset: range.offset,
+            total_size,
+            data,
+            encrypted,
+        },
+    }
 }
 
 #[async_trait]
@@ -43,7 +221,11 @@ impl request_response::Codec for Codec {
     type Request = Request;
     type Response = Response;
 
-    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Unpin + Send,
     {
@@ -53,57 +235,240 @@ impl request_response::Codec for Codec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
+        // the nonce is optional in version 1, an empty read means the requester didn't send one
+        let nonce = read_length_prefixed(io, 8).await?;
+        let nonce = match <[u8; 8]>::try_from(nonce.as_slice()) {
+            Ok(nonce) => Some(u64::from_be_bytes(nonce)),
+            Err(_) if nonce.is_empty() && Version::of(protocol) == Version::V1 => None,
+            Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+        };
+
+        let encrypt = read_flags(io).await? & FLAG_ENCRYPTED != 0;
+
+        let range = match Version::of(protocol) {
+            Version::V3 => {
+                let [session, offset, length] = read_range(io).await?;
+                Some(Range {
+                    session,
+                    offset,
+                    length,
+                })
+            }
+            Version::V1 | Version::V2 => None,
+        };
+
         Ok(Request {
             file_id: String::from_utf8(vec).unwrap(),
+            nonce,
+            encrypt,
+            range,
         })
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &StreamProtocol,
+        protocol: &StreamProtocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        let vec = read_length_prefixed(io, 500_000_000).await?; // update transfer maximum
+        let vec = read_length_prefixed(io, MAX_FILE_SIZE as usize).await?;
 
-        if vec.is_empty() {
+        // version 2 answers a request for an unknown file with an empty response
+        if vec.is_empty() && Version::of(protocol) == Version::V1 {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        Ok(Response { file_body: vec })
+        let flags = read_flags(io).await?;
+        if vec.is_empty() {
+            return Ok(Response::NotFound);
+        }
+        if flags & FLAG_NOT_FOUND != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not found response with file contents",
+            ));
+        }
+
+        if Version::of(protocol) == Version::V3 {
+            let [session, offset, total_size] = read_range(io).await?;
+            return Ok(Response::Range {
+                session,
+                offset,
+                total_size,
+                data: vec,
+                encrypted: flags & FLAG_ENCRYPTED != 0,
+            });
+        }
+
+        Ok(Response::File {
+            file_body: vec,
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+        })
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &StreamProtocol,
+        protocol: &StreamProtocol,
         io: &mut T,
-        Request { file_id }: Request,
+        Request {
+            file_id,
+            nonce,
+            encrypt,
+            range,
+        }: Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
         write_length_prefixed(io, file_id).await?;
+        match nonce {
+            Some(nonce) => write_length_prefixed(io, nonce.to_be_bytes()).await?,
+            None if Version::of(protocol) >= Version::V2 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "version 2 and 3 file requests require a nonce",
+                ))
+            }
+            None if encrypt => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "encrypted file requests require a nonce",
+                ))
+            }
+            None => {}
+        }
+        let flags = if encrypt { FLAG_ENCRYPTED } else { 0 };
+        match (Version::of(protocol), range) {
+            (Version::V3, Some(range)) => {
+                write_length_prefixed(io, [flags]).await?;
+                write_range(io, [range.session, range.offset, range.length]).await?;
+            }
+            (Version::V3, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "version 3 file requests require a range",
+                ))
+            }
+            // a range negotiated down to an older version requests the whole file
+            (Version::V1 | Version::V2, _) => {
+                if encrypt {
+                    write_length_prefixed(io, [flags]).await?;
+                }
+            }
+        }
 
         Ok(())
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &StreamProtocol,
+        protocol: &StreamProtocol,
         io: &mut T,
-        Response { file_body }: Response,
+        response: Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        write_length_prefixed(io, file_body).await?;
+        // version 3 sends every file as ranges, the older versions can't express them
+        let range = matches!(response, Response::Range { .. });
+        if !matches!(response, Response::NotFound)
+            && range != (Version::of(protocol) == Version::V3)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only version 3 file responses are ranges, and all of them are",
+            ));
+        }
+
+        match response {
+            Response::File {
+                file_body,
+                encrypted,
+            } => {
+                if file_body.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "empty files can't be exchanged",
+                    ));
+                }
+                write_length_prefixed(io, file_body).await?;
+                if encrypted {
+                    write_length_prefixed(io, [FLAG_ENCRYPTED]).await?;
+                }
+            }
+            Response::Range {
+                session,
+                offset,
+                total_size,
+                data,
+                encrypted,
+            } => {
+                if data.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "empty ranges can't be exchanged",
+                    ));
+                }
+                write_length_prefixed(io, data).await?;
+                let flags = if encrypted { FLAG_ENCRYPTED } else { 0 };
+                write_length_prefixed(io, [flags]).await?;
+                write_range(io, [session, offset, total_size]).await?;
+            }
+            // the empty response fails the request of a version 1 requester
+            Response::NotFound => {
+                write_length_prefixed(io, []).await?;
+                if Version::of(protocol) >= Version::V2 {
+                    write_length_prefixed(io, [FLAG_NOT_FOUND]).await?;
+                }
+            }
+        }
 
         Ok(())
     }
 }
 
+// The flag marking encrypted file contents
+const FLAG_ENCRYPTED: u8 = 1;
+// The flag marking a response for an unknown file
+const FLAG_NOT_FOUND: u8 = 2;
+
+// The length of the range message of version 3, three big endian u64
+const RANGE_LEN: usize = 24;
+
+// Reads the range message of version 3: the session id, the offset and the length of the range in
+// a request, or the size of the file in a response
+async fn read_range(io: &mut (impl AsyncRead + Unpin)) -> io::Result<[u64; 3]> {
+    let bytes = read_length_prefixed(io, RANGE_LEN).await?;
+    if bytes.len() != RANGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "version 3 file messages require a range",
+        ));
+    }
+    let field = |i: usize| u64::from_be_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+    Ok([field(0), field(1), field(2)])
+}
+
+// Writes the range message of version 3
+async fn write_range(io: &mut (impl AsyncWrite + Unpin), fields: [u64; 3]) -> io::Result<()> {
+    let bytes: Vec<u8> = fields
+        .iter()
+        .flat_map(|field| field.to_be_bytes())
+        .collect();
+    write_length_prefixed(io, bytes).await
+}
+
+// Reads the optional flags byte following a message, 0 if there is none
+async fn read_flags(io: &mut (impl AsyncRead + Unpin)) -> io::Result<u8> {
+    match read_length_prefixed(io, 1).await?.as_slice() {
+        [] => Ok(0),
+        [flags] => Ok(*flags),
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
 /// Writes a message to the given socket with a length prefix appended to it. Also flushes the socket.
 ///
 /// > **Note**: Prepends a variable-length prefix indicate the length of the message. This is
@@ -184,7 +549,7 @@ async fn read_varint(socket: &mut (impl AsyncRead + Unpin)) -> Result<usize, io:
 ///
 /// > **Note**: Assumes that a variable-length prefix indicates the length of the message. This is
 /// >           compatible with what [`write_length_prefixed`] does.
-async fn read_length_prefixed(
+pub(crate) async fn read_length_prefixed(
     socket: &mut (impl AsyncRead + Unpin),
     max_size: usize,
 ) -> io::Result<Vec<u8>> {
@@ -200,3 +565,277 @@ async fn read_length_prefixed(
 
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileDecryptor, OutstandingRequests, ProtocolNames};
+    use futures::{executor::block_on, io::Cursor, AsyncWriteExt};
+    use libp2p::{identity::Keypair, request_response::Codec as _};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    const V1: StreamProtocol = StreamProtocol::new("/universal-connectivity-file/1");
+    const V2: StreamProtocol = StreamProtocol::new("/universal-connectivity-file/2");
+    const V3: StreamProtocol = StreamProtocol::new("/universal-connectivity-file/3");
+
+    fn request(file_id: &str, encrypt: bool) -> Request {
+        Request {
+            file_id: file_id.to_string(),
+            nonce: Some(7),
+            encrypt,
+            range: None,
+        }
+    }
+
+    fn range_request(file_id: &str, offset: u64, length: u64) -> Request {
+        Request {
+            range: Some(Range {
+                session: 9,
+                offset,
+                length,
+            }),
+            ..request(file_id, false)
+        }
+    }
+
+    // Send a request over `protocol` and read it back like the responder
+    fn request_round_trip(protocol: &StreamProtocol, request: Request) -> io::Result<Request> {
+        let mut codec = Codec;
+        block_on(async {
+            let mut buf = Vec::new();
+            codec.write_request(protocol, &mut buf, request).await?;
+            codec.read_request(protocol, &mut Cursor::new(buf)).await
+        })
+    }
+
+    // Negotiate a protocol between a requester proposing `dialer` and a responder supporting
+    // `listener` like multistream-select does on a new stream, then send a request over it.
+    // Returns the protocols both sides negotiated and the request the responder read.
+    async fn negotiate_request(
+        dialer: &[StreamProtocol],
+        listener: &[StreamProtocol],
+        request: Request,
+    ) -> io::Result<(StreamProtocol, StreamProtocol, Request)> {
+        let (dialer_io, listener_io) = tokio::io::duplex(4096);
+        let ((dialed, mut dialer_io), (listened, mut listener_io)) = futures::try_join!(
+            multistream_select::dialer_select_proto(
+                dialer_io.compat(),
+                dialer.iter().cloned(),
+                multistream_select::Version::V1,
+            ),
+            multistream_select::listener_select_proto(
+                listener_io.compat(),
+                listener.iter().cloned()
+            ),
+        )
+        .map_err(io::Error::other)?;
+        let mut codec = Codec;
+        codec
+            .write_request(&dialed, &mut dialer_io, request)
+            .await?;
+        dialer_io.close().await?;
+        let read = codec.read_request(&listened, &mut listener_io).await?;
+        Ok((dialed, listened, read))
+    }
+
+    #[tokio::test]
+    async fn a_v1_only_peer_and_a_newer_peer_negotiate_v1() {
+        let versions = ProtocolNames::new(None).unwrap().file_exchange;
+        assert_eq!(versions, [V3, V2, V1]);
+
+        // either side may be the one that only speaks version 1
+        for (dialer, listener) in [(&versions[..], &[V1][..]), (&[V1][..], &versions[..])] {
+            let (dialed, listened, read) =
+                negotiate_request(dialer, listener, request("file", false))
+                    .await
+                    .unwrap();
+            assert_eq!((dialed, listened), (V1, V1));
+            assert_eq!(read, request("file", false));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_v2_peer_and_a_newer_peer_negotiate_v2() {
+        let versions = ProtocolNames::new(None).unwrap().file_exchange;
+        let (dialed, listened, read) =
+            negotiate_request(&versions, &[V2, V1], range_request("file", 5, 10))
+                .await
+                .unwrap();
+        assert_eq!((dialed, listened), (V2, V2));
+        // the range doesn't go over version 2, so the whole file is requested
+        assert_eq!(read, request("file", false));
+    }
+
+    // Send a response over `protocol` and read it back like the requester
+    fn round_trip(protocol: &StreamProtocol, response: Response) -> io::Result<Response> {
+        let mut codec = Codec;
+        block_on(async {
+            let mut buf = Vec::new();
+            codec.write_response(protocol, &mut buf, response).await?;
+            codec.read_response(protocol, &mut Cursor::new(buf)).await
+        })
+    }
+
+    #[test]
+    fn unknown_file_is_answered_not_found_and_settles_the_request() {
+        let mut files = FileStore::default();
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+        let mut outstanding = OutstandingRequests::<u64>::default();
+        outstanding.start(1, "missing".to_string(), 7);
+
+        let response = respond(&mut files, &peer, &request("missing", false));
+        assert_eq!(response, Response::NotFound);
+        // a version 2 requester gets it as a response rather than a failed request
+        assert_eq!(round_trip(&V2, response).unwrap(), Response::NotFound);
+
+        // the answer is definitive, nothing is left outstanding to retry
+        assert_eq!(
+            outstanding.answered(&1),
+            Some(("missing".to_string(), true))
+        );
+        assert!(outstanding.is_empty());
+        assert_eq!(outstanding.failed(&1), None);
+    }
+
+    #[test]
+    fn not_found_fails_a_version_1_request() {
+        assert!(round_trip(&V1, Response::NotFound).is_err());
+    }
+
+    #[test]
+    fn known_file_is_sent() {
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), b"contents".to_vec());
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+
+        let response = respond(&mut files, &peer, &request("file", false));
+        assert_eq!(
+            round_trip(&V2, response).unwrap(),
+            Response::File {
+                file_body: b"contents".to_vec(),
+                encrypted: false,
+            }
+        );
+    }
+
+    #[test]
+    fn file_asked_for_encrypted_is_encrypted_to_the_requester() {
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), b"contents".to_vec());
+        let keypair = Keypair::generate_ed25519();
+
+        let response = respond(
+            &mut files,
+            &keypair.public().to_peer_id(),
+            &request("file", true),
+        );
+        let Response::File {
+            file_body,
+            encrypted: true,
+        } = response
+        else {
+            panic!("expected an encrypted file, got {response:?}");
+        };
+        let decryptor = FileDecryptor::new(&keypair).unwrap();
+        assert_eq!(decryptor.decrypt(&file_body).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn range_request_round_trips_over_version_3() {
+        let ranged = range_request("file", 5, 10);
+        assert_eq!(request_round_trip(&V3, ranged.clone()).unwrap(), ranged);
+
+        // version 3 only requests ranges
+        assert!(request_round_trip(&V3, request("file", false)).is_err());
+    }
+
+    #[test]
+    fn range_request_negotiated_down_requests_the_whole_file() {
+        for protocol in [V1, V2] {
+            assert_eq!(
+                request_round_trip(&protocol, range_request("file", 5, 10)).unwrap(),
+                request("file", false)
+            );
+        }
+    }
+
+    #[test]
+    fn range_of_a_file_is_sent_over_version_3() {
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), b"contents".to_vec());
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+
+        let response = respond(&mut files, &peer, &range_request("file", 2, 4));
+        assert_eq!(
+            round_trip(&V3, response).unwrap(),
+            Response::Range {
+                session: 9,
+                offset: 2,
+                total_size: 8,
+                data: b"nten".to_vec(),
+                encrypted: false,
+            }
+        );
+
+        // a range running past the end of the file is cut short
+        let response = respond(&mut files, &peer, &range_request("file", 6, 100));
+        let Response::Range { data, .. } = round_trip(&V3, response).unwrap() else {
+            panic!("expected a range");
+        };
+        assert_eq!(data, b"ts");
+    }
+
+    #[test]
+    fn range_outside_of_the_file_is_not_found() {
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), b"contents".to_vec());
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+
+        for (offset, length) in [(8, 4), (u64::MAX, u64::MAX), (0, 0)] {
+            let response = respond(&mut files, &peer, &range_request("file", offset, length));
+            assert_eq!(response, Response::NotFound);
+            assert_eq!(round_trip(&V3, response).unwrap(), Response::NotFound);
+        }
+    }
+
+    #[test]
+    fn range_is_encrypted_on_its_own() {
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), b"contents".to_vec());
+        let keypair = Keypair::generate_ed25519();
+        let request = Request {
+            encrypt: true,
+            ..range_request("file", 4, 4)
+        };
+
+        let response = respond(&mut files, &keypair.public().to_peer_id(), &request);
+        let Response::Range {
+            data,
+            encrypted: true,
+            ..
+        } = round_trip(&V3, response).unwrap()
+        else {
+            panic!("expected an encrypted range");
+        };
+        let decryptor = FileDecryptor::new(&keypair).unwrap();
+        assert_eq!(decryptor.decrypt(&data).unwrap(), b"ents");
+    }
+
+    #[test]
+    fn whole_files_and_ranges_only_go_over_their_versions() {
+        let file = Response::File {
+            file_body: b"contents".to_vec(),
+            encrypted: false,
+        };
+        let range = Response::Range {
+            session: 9,
+            offset: 0,
+            total_size: 8,
+            data: b"contents".to_vec(),
+            encrypted: false,
+        };
+        assert!(round_trip(&V3, file).is_err());
+        assert!(round_trip(&V2, range.clone()).is_err());
+        assert!(round_trip(&V1, range).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! An optional SQLite index of the files this peer holds and the provider records it announced,
+//! so the files can be reloaded and re-provided after a restart.
+//!
+//! Files served from a directory are indexed at their path in the directory. Received files only
+//! live in memory otherwise, so they are written to a directory next to the database, named after
+//! the SHA-256 hash of their file id, and indexed there.
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        file_id TEXT PRIMARY KEY NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        namespace TEXT,
+        name TEXT
+    );
+    CREATE TABLE IF NOT EXISTS provided (
+        file_id TEXT PRIMARY KEY NOT NULL,
+        announced_at INTEGER NOT NULL
+    );
+";
+
+/// A file recorded in the index
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedFile {
+    /// The id of the file
+    pub file_id: String,
+    /// Where the contents of the file are stored
+    pub path: PathBuf,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The namespace of a file served from a directory
+    pub namespace: Option<String>,
+    /// The path relative to its directory of a file served from a directory
+    pub name: Option<String>,
+}
+
+impl IndexedFile {
+    /// Read the contents of the file, checking that it still has its indexed size
+    pub fn read(&self) -> anyhow::Result<Vec<u8>> {
+        let body = fs::read(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        if body.len() as u64 != self.size {
+            anyhow::bail!(
+                "{} is {} bytes, {} were indexed",
+                self.path.display(),
+                body.len(),
+                self.size
+            );
+        }
+        Ok(body)
+    }
+}
+
+/// The SQLite index of the files this peer holds and the provider records it announced
+pub struct FileIndex {
+    conn: Connection,
+    files_dir: PathBuf,
+}
+
+impl FileIndex {
+    /// Open the index at `path`, creating it if needed. Received files are stored in the
+    /// directory with the same name and a `files` extension.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open the file index {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to create the file index {}", path.display()))?;
+        let files_dir = path.with_extension("files");
+        fs::create_dir_all(&files_dir)
+            .with_context(|| format!("Failed to create {}", files_dir.display()))?;
+        Ok(Self { conn, files_dir })
+    }
+
+    /// Record a file served from a directory under `namespace`, stored at `path`
+    pub fn insert_served(
+        &self,
+        file_id: &str,
+        path: &Path,
+        size: u64,
+        namespace: &str,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        self.upsert(file_id, path, size, Some(namespace), Some(name))
+    }
+
+    /// Store a received file next to the index and record it
+    pub fn insert_received(&self, file_id: &str, body: &[u8]) -> anyhow::Result<()> {
+        let path = self
+            .files_dir
+            .join(hex::encode(Sha256::digest(file_id.as_bytes())));
+        fs::write(&path, body).with_context(|| format!("Failed to write {}", path.display()))?;
+        self.upsert(file_id, &path, body.len() as u64, None, None)
+    }
+
+    fn upsert(
+        &self,
+        file_id: &str,
+        path: &Path,
+        size: u64,
+        namespace: Option<&str>,
+        name: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO files (file_id, path, size, namespace, name)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![file_id, path.to_string_lossy(), size, namespace, name],
+        )?;
+        Ok(())
+    }
+
+    /// Look up a file
+    pub fn get(&self, file_id: &str) -> anyhow::Result<Option<IndexedFile>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT file_id, path, size, namespace, name FROM files WHERE file_id = ?1",
+                params![file_id],
+                indexed_file,
+            )
+            .optional()?)
+    }
+
+    /// All of the indexed files, ordered by file id
+    pub fn files(&self) -> anyhow::Result<Vec<IndexedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT file_id, path, size, namespace, name FROM files ORDER BY file_id")?;
+        let files = stmt
+            .query_map([], indexed_file)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(files)
+    }
+
+    /// The number of indexed files
+    pub fn len(&self) -> anyhow::Result<u64> {
+        Ok(self
+            .conn
+            .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?)
+    }
+
+    /// Check if the index holds no files
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Remove a file and its provider record, deleting the stored copy of a received file.
+    /// Returns true if the file was indexed.
+    pub fn remove(&self, file_id: &str) -> anyhow::Result<bool> {
+        let file = self.get(file_id)?;
+        self.conn
+            .execute("DELETE FROM files WHERE file_id = ?1", params![file_id])?;
+        self.conn
+            .execute("DELETE FROM provided WHERE file_id = ?1", params![file_id])?;
+        let Some(file) = file else {
+            return Ok(false);
+        };
+        // served files belong to their directory, only our own copies are deleted
+        if file.path.starts_with(&self.files_dir) {
+            let _ = fs::remove_file(&file.path);
+        }
+        Ok(true)
+    }
+
+    /// Remove the files served from a namespace other than `namespaces`, whose directories are no
+    /// longer served, with their provider records so that they aren't provided again. Returns the
+    /// ids of the removed files.
+    pub fn remove_namespaces_except(&self, namespaces: &[&str]) -> anyhow::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for file in self.files()? {
+            let Some(namespace) = file.namespace.as_deref() else {
+                continue;
+            };
+            if !namespaces.contains(&namespace) {
+                self.remove(&file.file_id)?;
+                removed.push(file.file_id);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Record that the provider record of a file was announced now
+    pub fn record_provided(&self, file_id: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO provided (file_id, announced_at) VALUES (?1, ?2)",
+            params![file_id, now],
+        )?;
+        Ok(())
+    }
+
+    /// The ids of the files whose provider records were announced, with when they last were in
+    /// seconds since the Unix epoch
+    pub fn provided(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT file_id, announced_at FROM provided ORDER BY file_id")?;
+        let provided = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(provided)
+    }
+}
+
+// Read an indexed file from a row of the files table
+fn indexed_file(row: &rusqlite::Row<'_>) -> rusqlite::Result<IndexedFile> {
+    Ok(IndexedFile {
+        file_id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        size: row.get(2)?,
+        namespace: row.get(3)?,
+        name: row.get(4)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn files_of_removed_namespaces_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let index = FileIndex::open(&dir.path().join("index.db")).unwrap();
+        let served = dir.path().join("served");
+        fs::write(&served, b"served").unwrap();
+        index
+            .insert_served("kept/a", &served, 6, "kept", "a")
+            .unwrap();
+        index
+            .insert_served("gone/a", &served, 6, "gone", "a")
+            .unwrap();
+        index.insert_received("received", b"received").unwrap();
+        for file_id in ["kept/a", "gone/a", "received"] {
+            index.record_provided(file_id).unwrap();
+        }
+        drop(index);
+
+        // the directory of the `gone` namespace is no longer served after the restart
+        let index = FileIndex::open(&dir.path().join("index.db")).unwrap();
+        assert_eq!(
+            index.remove_namespaces_except(&["kept"]).unwrap(),
+            ["gone/a"]
+        );
+        let files: Vec<String> = index
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.file_id)
+            .collect();
+        assert_eq!(files, ["kept/a", "received"]);
+        let provided: Vec<String> = index
+            .provided()
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(provided, ["kept/a", "received"]);
+        // the served file belongs to its directory and isn't deleted
+        assert!(served.exists());
+    }
+}
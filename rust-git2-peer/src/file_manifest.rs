@@ -0,0 +1,113 @@
+use crate::file_exchange::{read_length_prefixed, write_length_prefixed};
+use async_trait::async_trait;
+use futures::{io, AsyncRead, AsyncWrite};
+use libp2p::{request_response, StreamProtocol};
+use serde::{Deserialize, Serialize};
+
+// File manifest protocol, for listing the files a peer holds. The manifest is paginated so that a
+// peer holding many files never sends an unbounded response; the requester asks for each page in
+// turn until a response has no next page.
+//
+// Request and Response:
+//  varuint - JSON length
+//  bytes - JSON encoded ManifestRequest or ManifestResponse
+//
+
+/// The number of entries in each page of a manifest.
+pub const MANIFEST_PAGE_SIZE: usize = 100;
+
+// A page of entries is small, this only guards against a misbehaving peer
+const MAX_MANIFEST_SIZE: usize = 1_000_000;
+
+/// The codec for the file manifest protocol.
+#[derive(Default, Clone)]
+pub struct ManifestCodec;
+
+/// Requests a page of a peer's file manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestRequest {
+    /// The page of the manifest to send, starting at 0.
+    pub page: u32,
+}
+
+/// A file in a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The identifier used to request the file.
+    pub file_id: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The name of the file, if known.
+    pub name: Option<String>,
+    /// The namespace of the directory the file is served from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// A page of a peer's file manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestResponse {
+    /// The files on this page, ordered by file id.
+    pub entries: Vec<ManifestEntry>,
+    /// The next page to request, if there is one.
+    pub next_page: Option<u32>,
+}
+
+#[async_trait]
+impl request_response::Codec for ManifestCodec {
+    type Protocol = StreamProtocol;
+    type Request = ManifestRequest;
+    type Response = ManifestResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, MAX_MANIFEST_SIZE).await?;
+        serde_json::from_slice(&vec).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, MAX_MANIFEST_SIZE).await?;
+        serde_json::from_slice(&vec).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: ManifestRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let vec = serde_json::to_vec(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_length_prefixed(io, vec).await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: ManifestResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let vec = serde_json::to_vec(&response)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_length_prefixed(io, vec).await?;
+
+        Ok(())
+    }
+}
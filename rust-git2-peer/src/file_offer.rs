@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/// An offer of a file published on the file topic, which peers interested in it fetch.
+///
+/// Other implementations offer a file by publishing its bare id. An offer can also be scoped to
+/// the topic, or channel, the file belongs to, and is then published as JSON so that peers only
+/// fetch the files of the topics they are interested in. Peers that don't understand scoped offers
+/// see a file id they can't fetch and drop it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileOffer {
+    /// The id of the offered file
+    pub file_id: String,
+    /// The topic the file belongs to, unscoped if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+impl FileOffer {
+    /// Decode an offer, either a scoped JSON offer or a bare file id
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        if let Ok(offer) = serde_json::from_slice::<FileOffer>(data) {
+            return Ok(offer);
+        }
+        Ok(Self {
+            file_id: String::from_utf8(data.to_vec())?,
+            topic: None,
+        })
+    }
+
+    /// Encode an offer, as a bare file id if it is unscoped so every implementation understands it
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        match self.topic {
+            Some(_) => Ok(serde_json::to_vec(self)?),
+            None => Ok(self.file_id.clone().into_bytes()),
+        }
+    }
+
+    /// Check if the offer is for one of `topics`. Every offer is of interest when `topics` is
+    /// empty, otherwise unscoped offers aren't.
+    pub fn is_of_interest(&self, topics: &[String]) -> bool {
+        topics.is_empty()
+            || self
+                .topic
+                .as_ref()
+                .is_some_and(|topic| topics.contains(topic))
+    }
+}
@@ -0,0 +1,486 @@
+use crate::file_manifest::{ManifestEntry, ManifestResponse, MANIFEST_PAGE_SIZE};
+use libp2p::{
+    request_response::{InboundRequestId, OutboundRequestId},
+    PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// An in-memory store of the files this peer holds and provides via Kademlia
+#[derive(Debug, Default)]
+pub struct FileStore {
+    files: HashMap<String, Vec<u8>>,
+    // the namespace and relative path of the files served from a --serve-dir
+    served: HashMap<String, (String, String)>,
+    // when each file was last inserted or served, by the tick of `clock`
+    last_used: HashMap<String, u64>,
+    clock: u64,
+    // the total size of the files
+    bytes: u64,
+    // the most bytes the files may take, unbounded if None
+    max_bytes: Option<u64>,
+}
+
+impl FileStore {
+    /// Create a store whose files may take at most `max_bytes`, unbounded if `None`. The store
+    /// only goes over the limit until [`FileStore::evict`] is called.
+    pub fn with_max_bytes(max_bytes: Option<u64>) -> Self {
+        Self {
+            max_bytes,
+            ..Self::default()
+        }
+    }
+
+    /// Add a file to the store, returning true if it is new. A file larger than the byte limit on
+    /// its own is refused, leaving the store as it was, since evicting would drop it and every
+    /// other file.
+    pub fn insert(&mut self, file_id: String, body: Vec<u8>) -> bool {
+        if !self.fits(body.len() as u64) {
+            return false;
+        }
+        self.bytes += body.len() as u64;
+        let old = self.files.insert(file_id.clone(), body);
+        if let Some(old) = old.as_ref() {
+            self.bytes -= old.len() as u64;
+        }
+        self.touch(&file_id);
+        old.is_none()
+    }
+
+    /// Check if a file of `size` bytes fits within the byte limit on its own
+    pub fn fits(&self, size: u64) -> bool {
+        self.max_bytes.is_none_or(|max_bytes| size <= max_bytes)
+    }
+
+    /// Mark a file as used, so it is evicted after the files that were used less recently
+    pub fn touch(&mut self, file_id: &str) {
+        if !self.files.contains_key(file_id) {
+            return;
+        }
+        self.clock += 1;
+        self.last_used.insert(file_id.to_string(), self.clock);
+    }
+
+    /// Add a file served from a directory under `namespace`, with its path relative to the
+    /// directory as its name, returning true if it is new. Refused like [`FileStore::insert`].
+    pub fn insert_served(
+        &mut self,
+        file_id: String,
+        namespace: String,
+        name: String,
+        body: Vec<u8>,
+    ) -> bool {
+        if !self.fits(body.len() as u64) {
+            return false;
+        }
+        self.served.insert(file_id.clone(), (namespace, name));
+        self.insert(file_id, body)
+    }
+
+    /// The namespace of a file served from a directory
+    pub fn namespace(&self, file_id: &str) -> Option<&str> {
+        self.served
+            .get(file_id)
+            .map(|(namespace, _)| namespace.as_str())
+    }
+
+    /// Get the contents of a file
+    pub fn get(&self, file_id: &str) -> Option<&[u8]> {
+        self.files.get(file_id).map(Vec::as_slice)
+    }
+
+    /// Check if the store holds a file
+    pub fn contains(&self, file_id: &str) -> bool {
+        self.files.contains_key(file_id)
+    }
+
+    /// Remove a file from the store, returning its contents
+    pub fn remove(&mut self, file_id: &str) -> Option<Vec<u8>> {
+        self.served.remove(file_id);
+        self.last_used.remove(file_id);
+        let body = self.files.remove(file_id)?;
+        self.bytes -= body.len() as u64;
+        Some(body)
+    }
+
+    /// Remove the least recently used files until the files are within the byte limit, returning
+    /// the ids of the removed files, least recently used first
+    pub fn evict(&mut self) -> Vec<String> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Vec::new();
+        };
+        if self.bytes <= max_bytes {
+            return Vec::new();
+        }
+        let mut by_use: Vec<(u64, String)> = self
+            .last_used
+            .iter()
+            .map(|(file_id, tick)| (*tick, file_id.clone()))
+            .collect();
+        by_use.sort();
+
+        let mut evicted = Vec::new();
+        for (_, file_id) in by_use {
+            if self.bytes <= max_bytes {
+                break;
+            }
+            self.remove(&file_id);
+            evicted.push(file_id);
+        }
+        evicted
+    }
+
+    /// The total size of the files in the store
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The most bytes the files may take, unbounded if `None`
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    /// The ids of all of the files in the store
+    pub fn file_ids(&self) -> impl Iterator<Item = &String> {
+        self.files.keys()
+    }
+
+    /// A page of the manifest of the files in the store, ordered by file id
+    pub fn manifest(&self, page: u32) -> ManifestResponse {
+        let mut file_ids: Vec<&String> = self.files.keys().collect();
+        file_ids.sort();
+
+        let start = (page as usize).saturating_mul(MANIFEST_PAGE_SIZE);
+        let entries = file_ids
+            .iter()
+            .skip(start)
+            .take(MANIFEST_PAGE_SIZE)
+            .map(|file_id| {
+                let served = self.served.get(*file_id);
+                ManifestEntry {
+                    file_id: file_id.to_string(),
+                    size: self.files[*file_id].len() as u64,
+                    name: served.map(|(_, name)| name.clone()),
+                    namespace: served.map(|(namespace, _)| namespace.clone()),
+                }
+            })
+            .collect();
+        let next_page = (start.saturating_add(MANIFEST_PAGE_SIZE) < file_ids.len())
+            .then(|| page + 1);
+
+        ManifestResponse { entries, next_page }
+    }
+
+    /// The number of files in the store
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Check if the store is empty
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Schedules the re-announcement of provider records before they expire in the DHT
+#[derive(Debug)]
+pub struct Reprovider {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Reprovider {
+    /// Create a scheduler that is first due one `interval` after `now`
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            last: now,
+        }
+    }
+
+    /// The interval between re-announcements
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Check if a re-announcement is due at `now`, restarting the interval if it is
+    pub fn due(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.last) < self.interval {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+}
+
+/// Tracks the file requests being answered by their idempotency nonce, so that concurrent
+/// identical requests from a peer are only served once
+#[derive(Debug)]
+pub struct InflightRequests<I = InboundRequestId> {
+    requests: HashMap<I, (PeerId, u64)>,
+}
+
+impl<I> Default for InflightRequests<I> {
+    fn default() -> Self {
+        Self {
+            requests: HashMap::new(),
+        }
+    }
+}
+
+impl<I: Eq + Hash> InflightRequests<I> {
+    /// Start answering a request, returning false if the same request from the peer is already
+    /// being answered
+    pub fn start(&mut self, request_id: I, peer: PeerId, nonce: u64) -> bool {
+        if self.requests.values().any(|key| *key == (peer, nonce)) {
+            return false;
+        }
+        self.requests.insert(request_id, (peer, nonce));
+        true
+    }
+
+    /// Finish answering a request, successfully or not
+    pub fn finish(&mut self, request_id: &I) {
+        self.requests.remove(request_id);
+    }
+
+    /// Check if a request from a peer is being answered
+    pub fn has_peer(&self, peer: &PeerId) -> bool {
+        self.requests.values().any(|(p, _)| p == peer)
+    }
+
+    /// The number of requests being answered
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Check if no request is being answered
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/// Tracks the file requests sent to other peers with their idempotency nonce. A request may be
+/// sent again with the same nonce, and only the first response for a nonce is used: a late
+/// duplicate of it is ignored.
+#[derive(Debug)]
+pub struct OutstandingRequests<I = OutboundRequestId> {
+    requests: HashMap<I, (String, u64)>,
+    // the nonces that haven't been answered yet
+    nonces: HashSet<u64>,
+}
+
+impl<I> Default for OutstandingRequests<I> {
+    fn default() -> Self {
+        Self {
+            requests: HashMap::new(),
+            nonces: HashSet::new(),
+        }
+    }
+}
+
+impl<I: Eq + Hash> OutstandingRequests<I> {
+    /// Track a request for a file, sent with `nonce`
+    pub fn start(&mut self, request_id: I, file_id: String, nonce: u64) {
+        self.requests.insert(request_id, (file_id, nonce));
+        self.nonces.insert(nonce);
+    }
+
+    /// Take the request a response answers, returning its file id and whether this is the first
+    /// answer for its nonce. A later answer is a late duplicate, to be ignored.
+    pub fn answered(&mut self, request_id: &I) -> Option<(String, bool)> {
+        let (file_id, nonce) = self.requests.remove(request_id)?;
+        let first = self.nonces.remove(&nonce);
+        Some((file_id, first))
+    }
+
+    /// Take a request that failed or was cancelled, returning its file id. Its nonce stays
+    /// unanswered while a retry of the request is outstanding.
+    pub fn failed(&mut self, request_id: &I) -> Option<String> {
+        let (file_id, nonce) = self.requests.remove(request_id)?;
+        if !self.requests.values().any(|(_, n)| *n == nonce) {
+            self.nonces.remove(&nonce);
+        }
+        Some(file_id)
+    }
+
+    /// Check if a file is being requested
+    pub fn contains_file(&self, file_id: &str) -> bool {
+        self.requests.values().any(|(id, _)| id == file_id)
+    }
+
+    /// The number of outstanding requests
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Check if no request is outstanding
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn peer() -> PeerId {
+        Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    #[test]
+    fn identical_inflight_requests_collapse_to_one() {
+        let mut inflight = InflightRequests::<u64>::default();
+        let peer = peer();
+        assert!(inflight.start(1, peer, 7));
+        assert!(!inflight.start(2, peer, 7));
+        assert_eq!(inflight.len(), 1);
+
+        // another nonce, or the same nonce from another peer, is another request
+        assert!(inflight.start(3, peer, 8));
+        assert!(inflight.start(4, self::peer(), 7));
+        assert_eq!(inflight.len(), 3);
+    }
+
+    #[test]
+    fn finished_inflight_request_can_start_again() {
+        let mut inflight = InflightRequests::<u64>::default();
+        let peer = peer();
+        assert!(inflight.start(1, peer, 7));
+        // finishing the collapsed duplicate doesn't finish the request being answered
+        inflight.finish(&2);
+        assert!(!inflight.start(3, peer, 7));
+
+        inflight.finish(&1);
+        assert!(!inflight.has_peer(&peer));
+        assert!(inflight.start(4, peer, 7));
+        assert!(inflight.has_peer(&peer));
+    }
+
+    #[test]
+    fn late_duplicate_response_is_ignored() {
+        let mut outstanding = OutstandingRequests::<u64>::default();
+        outstanding.start(1, "file".to_string(), 7);
+        // the request was sent again with the same nonce
+        outstanding.start(2, "file".to_string(), 7);
+        assert!(outstanding.contains_file("file"));
+
+        assert_eq!(outstanding.answered(&1), Some(("file".to_string(), true)));
+        assert_eq!(outstanding.answered(&2), Some(("file".to_string(), false)));
+        assert_eq!(outstanding.answered(&2), None);
+        assert!(outstanding.is_empty());
+        assert!(!outstanding.contains_file("file"));
+    }
+
+    #[test]
+    fn failed_request_keeps_the_nonce_of_a_pending_retry() {
+        let mut outstanding = OutstandingRequests::<u64>::default();
+        outstanding.start(1, "file".to_string(), 7);
+        outstanding.start(2, "file".to_string(), 7);
+
+        assert_eq!(outstanding.failed(&1), Some("file".to_string()));
+        // the retry's answer is still the first one
+        assert_eq!(outstanding.answered(&2), Some(("file".to_string(), true)));
+    }
+
+    #[test]
+    fn failed_request_frees_its_nonce() {
+        let mut outstanding = OutstandingRequests::<u64>::default();
+        outstanding.start(1, "file".to_string(), 7);
+        assert_eq!(outstanding.failed(&1), Some("file".to_string()));
+        assert_eq!(outstanding.failed(&1), None);
+
+        outstanding.start(2, "file".to_string(), 7);
+        assert_eq!(outstanding.answered(&2), Some(("file".to_string(), true)));
+    }
+
+    #[test]
+    fn reprovider_is_due_once_per_interval() {
+        const TTL: Duration = Duration::from_millis(50);
+        let start = Instant::now();
+        let mut reprovider = Reprovider::new(TTL, start);
+        assert!(!reprovider.due(start));
+        assert!(!reprovider.due(start + TTL - Duration::from_millis(1)));
+        assert!(reprovider.due(start + TTL));
+        // the interval restarts when it is due, not on a fixed schedule
+        assert!(!reprovider.due(start + TTL + TTL / 2));
+        assert!(reprovider.due(start + 3 * TTL));
+        assert!(!reprovider.due(start + 3 * TTL));
+        assert!(reprovider.due(start + 4 * TTL));
+    }
+
+    #[test]
+    fn evict_removes_least_recently_used_files_down_to_the_limit() {
+        let mut store = FileStore::with_max_bytes(Some(100));
+        for i in 0..5 {
+            store.insert(format!("file{i}"), vec![0; 30]);
+        }
+        // using file0 makes file1 the least recently used
+        store.touch("file0");
+        assert_eq!(store.bytes(), 150);
+
+        assert_eq!(store.evict(), ["file1", "file2"]);
+        assert_eq!(store.bytes(), 90);
+        assert_eq!(store.len(), 3);
+        assert!(store.contains("file0"));
+        assert!(store.evict().is_empty());
+    }
+
+    #[test]
+    fn evict_keeps_an_unbounded_store() {
+        let mut store = FileStore::with_max_bytes(None);
+        store.insert("file".to_string(), vec![0; 1000]);
+        assert!(store.evict().is_empty());
+        assert_eq!(store.bytes(), 1000);
+    }
+
+    #[test]
+    fn a_file_larger_than_the_limit_is_refused() {
+        let mut store = FileStore::with_max_bytes(Some(100));
+        store.insert("small".to_string(), vec![0; 10]);
+        assert!(!store.insert("large".to_string(), vec![0; 200]));
+        assert!(!store.insert_served(
+            "served".to_string(),
+            "ns".to_string(),
+            "served.txt".to_string(),
+            vec![0; 101]
+        ));
+        assert!(store.evict().is_empty());
+        // the files already held survive
+        assert!(store.contains("small"));
+        assert!(!store.contains("large"));
+        assert_eq!(store.namespace("served"), None);
+        assert_eq!(store.bytes(), 10);
+    }
+
+    #[test]
+    fn bytes_stay_accurate_across_reinsert_and_remove() {
+        let mut store = FileStore::with_max_bytes(Some(100));
+        assert!(store.insert("a".to_string(), vec![0; 40]));
+        assert!(store.insert("b".to_string(), vec![0; 20]));
+        assert_eq!(store.bytes(), 60);
+
+        // replacing a file counts its new size only
+        assert!(!store.insert("a".to_string(), vec![0; 10]));
+        assert_eq!(store.bytes(), 30);
+        assert!(!store.insert_served(
+            "b".to_string(),
+            "ns".to_string(),
+            "b.txt".to_string(),
+            vec![0; 50]
+        ));
+        assert_eq!(store.bytes(), 60);
+
+        assert_eq!(store.remove("a").map(|body| body.len()), Some(10));
+        assert_eq!(store.bytes(), 50);
+        assert_eq!(store.remove("a"), None);
+        assert_eq!(store.bytes(), 50);
+        assert_eq!(store.remove("b").map(|body| body.len()), Some(50));
+        assert_eq!(store.bytes(), 0);
+        assert!(store.evict().is_empty());
+    }
+}
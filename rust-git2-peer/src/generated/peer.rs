@@ -14,11 +14,48 @@ use quick_protobuf::{MessageInfo, MessageRead, MessageWrite, BytesReader, Writer
 use quick_protobuf::sizeofs::*;
 use super::*;
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Presence {
+    PRESENCE_UNSPECIFIED = 0,
+    JOIN = 1,
+    LEAVE = 2,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Presence::PRESENCE_UNSPECIFIED
+    }
+}
+
+impl From<i32> for Presence {
+    fn from(i: i32) -> Self {
+        match i {
+            0 => Presence::PRESENCE_UNSPECIFIED,
+            1 => Presence::JOIN,
+            2 => Presence::LEAVE,
+            _ => Self::default(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Presence {
+    fn from(s: &'a str) -> Self {
+        match s {
+            "PRESENCE_UNSPECIFIED" => Presence::PRESENCE_UNSPECIFIED,
+            "JOIN" => Presence::JOIN,
+            "LEAVE" => Presence::LEAVE,
+            _ => Self::default(),
+        }
+    }
+}
+
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct Peer<'a> {
     pub publicKey: Cow<'a, [u8]>,
     pub multiAddrs: Vec<Cow<'a, [u8]>>,
+    pub presence: peer::Presence,
+    pub timestamp: u64,
 }
 
 impl<'a> MessageRead<'a> for Peer<'a> {
@@ -28,6 +65,8 @@ impl<'a> MessageRead<'a> for Peer<'a> {
             match r.next_tag(bytes) {
                 Ok(10) => msg.publicKey = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(18) => msg.multiAddrs.push(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(24) => msg.presence = r.read_enum(bytes)?,
+                Ok(32) => msg.timestamp = r.read_uint64(bytes)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -41,11 +80,15 @@ impl<'a> MessageWrite for Peer<'a> {
         0
         + if self.publicKey == Cow::Borrowed(b"") { 0 } else { 1 + sizeof_len((&self.publicKey).len()) }
         + self.multiAddrs.iter().map(|s| 1 + sizeof_len((s).len())).sum::<usize>()
+        + if self.presence == peer::Presence::PRESENCE_UNSPECIFIED { 0 } else { 1 + sizeof_varint(*(&self.presence) as u64) }
+        + if self.timestamp == 0u64 { 0 } else { 1 + sizeof_varint(*(&self.timestamp) as u64) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
         if self.publicKey != Cow::Borrowed(b"") { w.write_with_tag(10, |w| w.write_bytes(&**&self.publicKey))?; }
         for s in &self.multiAddrs { w.write_with_tag(18, |w| w.write_bytes(&**s))?; }
+        if self.presence != peer::Presence::PRESENCE_UNSPECIFIED { w.write_with_tag(24, |w| w.write_enum(*&self.presence as i32))?; }
+        if self.timestamp != 0u64 { w.write_with_tag(32, |w| w.write_uint64(*&self.timestamp))?; }
         Ok(())
     }
 }
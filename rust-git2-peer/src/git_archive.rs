@@ -0,0 +1,471 @@
+//! Archives of a git tree, served in response to `GitRequest::Archive`.
+//!
+//! The archives are built in memory from the objects of the tree, without a checkout: tar in the
+//! POSIX ustar format, with pax headers for paths too long for it, and zip with the entries
+//! stored uncompressed. Submodules are left out since their objects aren't in the repository.
+
+use git2::{ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use std::{fmt, io::Write, str::FromStr};
+
+/// The format of a git archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A POSIX tar archive
+    Tar,
+    /// A zip archive with uncompressed entries
+    Zip,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            _ => anyhow::bail!("Unsupported archive format {s}, expected tar or zip"),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tar => write!(f, "tar"),
+            Self::Zip => write!(f, "zip"),
+        }
+    }
+}
+
+// An entry of the archive, with its path relative to the root of the tree
+struct Entry {
+    path: String,
+    kind: EntryKind,
+    mode: u32,
+    data: Vec<u8>,
+}
+
+#[derive(PartialEq, Eq)]
+enum EntryKind {
+    Dir,
+    File,
+    // the data is the link target
+    Symlink,
+}
+
+/// Build an archive of `tree`, with `mtime` (seconds since the unix epoch) as the modification
+/// time of every entry. Fails once the contents exceed `max_size` bytes.
+pub fn build_archive(
+    repository: &Repository,
+    tree: &Tree,
+    format: ArchiveFormat,
+    mtime: i64,
+    max_size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let entries = collect_entries(repository, tree, max_size)?;
+    match format {
+        ArchiveFormat::Tar => tar(&entries, mtime),
+        ArchiveFormat::Zip => zip(&entries, mtime),
+    }
+}
+
+// Walk the tree and read every blob, keeping the total size under `max_size`
+fn collect_entries(
+    repository: &Repository,
+    tree: &Tree,
+    max_size: u64,
+) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut size = 0u64;
+    let mut error = None;
+    tree.walk(TreeWalkMode::PreOrder, |root, tree_entry| {
+        let Some(name) = tree_entry.name() else {
+            error = Some(anyhow::anyhow!("Tree entry in {root} has a non-UTF-8 name"));
+            return TreeWalkResult::Abort;
+        };
+        let path = format!("{root}{name}");
+        let mode = tree_entry.filemode() as u32;
+        let entry = match tree_entry.kind() {
+            Some(ObjectType::Tree) => Entry {
+                path: format!("{path}/"),
+                kind: EntryKind::Dir,
+                mode: 0o755,
+                data: Vec::new(),
+            },
+            Some(ObjectType::Blob) => {
+                let blob = match repository.find_blob(tree_entry.id()) {
+                    Ok(blob) => blob,
+                    Err(e) => {
+                        error = Some(e.into());
+                        return TreeWalkResult::Abort;
+                    }
+                };
+                size += blob.size() as u64;
+                if size > max_size {
+                    error = Some(anyhow::anyhow!(
+                        "The archive would exceed the limit of {max_size} bytes"
+                    ));
+                    return TreeWalkResult::Abort;
+                }
+                let (kind, mode) = match mode {
+                    0o120000 => (EntryKind::Symlink, 0o777),
+                    0o100755 => (EntryKind::File, 0o755),
+                    _ => (EntryKind::File, 0o644),
+                };
+                Entry {
+                    path,
+                    kind,
+                    mode,
+                    data: blob.content().to_vec(),
+                }
+            }
+            // submodule commits and anything else have no content here
+            _ => return TreeWalkResult::Skip,
+        };
+        entries.push(entry);
+        TreeWalkResult::Ok
+    })?;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(entries),
+    }
+}
+
+// Build a ustar archive, preceding entries whose path or link target doesn't fit the ustar header
+// with a pax extended header
+fn tar(entries: &[Entry], mtime: i64) -> anyhow::Result<Vec<u8>> {
+    let mtime = mtime.max(0) as u64;
+    let mut out = Vec::new();
+    for entry in entries {
+        let link = match entry.kind {
+            EntryKind::Symlink => entry.data.as_slice(),
+            _ => &[],
+        };
+        let mut pax = Vec::new();
+        if entry.path.len() > 100 {
+            pax_record(&mut pax, "path", entry.path.as_bytes());
+        }
+        if link.len() > 100 {
+            pax_record(&mut pax, "linkpath", link);
+        }
+        if !pax.is_empty() {
+            out.extend(tar_header(
+                b"pax_header",
+                b'x',
+                0o644,
+                pax.len() as u64,
+                mtime,
+                &[],
+            ));
+            tar_data(&mut out, &pax);
+        }
+
+        let (typeflag, size, data) = match entry.kind {
+            EntryKind::Dir => (b'5', 0, &[][..]),
+            EntryKind::File => (b'0', entry.data.len() as u64, entry.data.as_slice()),
+            EntryKind::Symlink => (b'2', 0, &[][..]),
+        };
+        let name = &entry.path.as_bytes()[..entry.path.len().min(100)];
+        let link = &link[..link.len().min(100)];
+        out.extend(tar_header(name, typeflag, entry.mode, size, mtime, link));
+        tar_data(&mut out, data);
+    }
+    // the archive ends with two zero blocks
+    out.resize(out.len() + 1024, 0);
+    Ok(out)
+}
+
+fn tar_header(
+    name: &[u8],
+    typeflag: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    link: &[u8],
+) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0); // uid
+    octal(&mut header[116..124], 0); // gid
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[157..157 + link.len()].copy_from_slice(link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // the checksum is computed with the checksum field set to spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    octal(&mut header[148..155], checksum as u64);
+    header
+}
+
+// Write `value` as a NUL-terminated, zero-padded octal number filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    let digits = &digits.as_bytes()[digits.len() - (field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+// Append the data of an entry, padded to a whole number of blocks
+fn tar_data(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len().next_multiple_of(512), 0);
+}
+
+// Append a pax record, "<length> <key>=<value>\n" where the length counts the whole record
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3; // the space, '=' and newline
+    let mut len = rest + 1;
+    while len.to_string().len() + rest != len {
+        len = len.to_string().len() + rest;
+    }
+    out.extend_from_slice(format!("{len} {key}=").as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+// Build a zip archive with stored (uncompressed) entries. Zip64 isn't needed since the size of the
+// archive is bounded well below 4GiB, but the entry count is limited to what fits without it.
+fn zip(entries: &[Entry], mtime: i64) -> anyhow::Result<Vec<u8>> {
+    if entries.len() > u16::MAX as usize {
+        anyhow::bail!("Too many entries for a zip archive: {}", entries.len());
+    }
+    let (time, date) = dos_time(mtime);
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for entry in entries {
+        let offset = u32::try_from(out.len())?;
+        let crc = crc32fast::hash(&entry.data);
+        let size = u32::try_from(entry.data.len())?;
+        let name = entry.path.as_bytes();
+        let name_len = u16::try_from(name.len())?;
+        // the unix mode goes in the high half of the external attributes, with the MS-DOS
+        // directory attribute in the low half for directories
+        let attributes = match entry.kind {
+            EntryKind::Dir => ((0o040000 | entry.mode) << 16) | 0x10,
+            EntryKind::File => (0o100000 | entry.mode) << 16,
+            EntryKind::Symlink => (0o120000 | entry.mode) << 16,
+        };
+
+        // local file header: signature, version needed, flags (UTF-8 names), method (stored),
+        // time, date, crc, compressed and uncompressed sizes, name and extra field lengths
+        out.write_all(&0x04034b50u32.to_le_bytes())?;
+        out.write_all(&10u16.to_le_bytes())?;
+        out.write_all(&0x0800u16.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(&time.to_le_bytes())?;
+        out.write_all(&date.to_le_bytes())?;
+        out.write_all(&crc.to_le_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+        out.write_all(&size.to_le_bytes())?;
+        out.write_all(&name_len.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(name)?;
+        out.write_all(&entry.data)?;
+
+        // central directory header: as above, preceded by the version made by (unix, so the
+        // external attributes hold the mode) and followed by the comment length, disk number,
+        // internal and external attributes and the offset of the local header
+        central.write_all(&0x02014b50u32.to_le_bytes())?;
+        central.write_all(&((3u16 << 8) | 20).to_le_bytes())?;
+        central.write_all(&10u16.to_le_bytes())?;
+        central.write_all(&0x0800u16.to_le_bytes())?;
+        central.write_all(&0u16.to_le_bytes())?;
+        central.write_all(&time.to_le_bytes())?;
+        central.write_all(&date.to_le_bytes())?;
+        central.write_all(&crc.to_le_bytes())?;
+        central.write_all(&size.to_le_bytes())?;
+        central.write_all(&size.to_le_bytes())?;
+        central.write_all(&name_len.to_le_bytes())?;
+        central.write_all(&0u16.to_le_bytes())?;
+        central.write_all(&0u16.to_le_bytes())?;
+        central.write_all(&0u16.to_le_bytes())?;
+        central.write_all(&0u16.to_le_bytes())?;
+        central.write_all(&attributes.to_le_bytes())?;
+        central.write_all(&offset.to_le_bytes())?;
+        central.write_all(name)?;
+    }
+
+    // end of central directory record: signature, disk numbers, entry counts, the size and offset
+    // of the central directory and the comment length
+    let count = entries.len() as u16;
+    let central_offset = u32::try_from(out.len())?;
+    let central_size = u32::try_from(central.len())?;
+    out.extend_from_slice(&central);
+    out.write_all(&0x06054b50u32.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&count.to_le_bytes())?;
+    out.write_all(&count.to_le_bytes())?;
+    out.write_all(&central_size.to_le_bytes())?;
+    out.write_all(&central_offset.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    Ok(out)
+}
+
+// Convert seconds since the unix epoch to the MS-DOS time and date used by zip, in UTC and
+// clamped to the 1980 to 2107 range it can represent
+fn dos_time(secs: i64) -> (u16, u16) {
+    let days = secs.div_euclid(86_400);
+    let secs = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = year.min(2107);
+    let time = ((secs / 3600) << 11) | (((secs % 3600) / 60) << 5) | ((secs % 60) / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+// The year, month and day of a number of days since the unix epoch, in the proleptic Gregorian
+// calendar
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{FileMode, Oid};
+    use std::io::{Cursor, Read};
+
+    const MTIME: i64 = 1_700_000_000;
+    const LONG_NAME: &str =
+        "a-file-name-long-enough-that-its-path-no-longer-fits-the-hundred-bytes-of-a-ustar-header.txt";
+
+    // A repository with a tree of a plain file, an executable, a symlink and a nested file whose
+    // path is too long for a ustar header
+    fn fixture() -> (tempfile::TempDir, Repository, Oid) {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init(dir.path()).unwrap();
+        let tree = {
+            let blob = |data: &[u8]| repository.blob(data).unwrap();
+            let mut nested = repository.treebuilder(None).unwrap();
+            nested
+                .insert(LONG_NAME, blob(b"long"), FileMode::Blob.into())
+                .unwrap();
+            let nested = nested.write().unwrap();
+            let mut bin = repository.treebuilder(None).unwrap();
+            bin.insert(
+                "run.sh",
+                blob(b"#!/bin/sh\n"),
+                FileMode::BlobExecutable.into(),
+            )
+            .unwrap();
+            let bin = bin.write().unwrap();
+            let mut root = repository.treebuilder(None).unwrap();
+            root.insert("README.md", blob(b"hello\n"), FileMode::Blob.into())
+                .unwrap();
+            root.insert("link", blob(b"README.md"), FileMode::Link.into())
+                .unwrap();
+            root.insert("bin", bin, FileMode::Tree.into()).unwrap();
+            root.insert("nested", nested, FileMode::Tree.into())
+                .unwrap();
+            root.write().unwrap()
+        };
+        (dir, repository, tree)
+    }
+
+    fn archive(format: ArchiveFormat, max_size: u64) -> anyhow::Result<Vec<u8>> {
+        let (_dir, repository, tree) = fixture();
+        let tree = repository.find_tree(tree).unwrap();
+        build_archive(&repository, &tree, format, MTIME, max_size)
+    }
+
+    #[test]
+    fn tar_archives_read_back_with_their_paths_and_modes() {
+        let data = archive(ArchiveFormat::Tar, u64::MAX).unwrap();
+        let mut archive = tar::Archive::new(Cursor::new(data));
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header = entry.header();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mode = header.mode().unwrap();
+            let kind = header.entry_type();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|link| link.to_string_lossy().into_owned());
+            assert_eq!(header.mtime().unwrap(), MTIME as u64);
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            entries.push((path, mode, kind, link, contents));
+        }
+        let long_path = format!("nested/{LONG_NAME}");
+        let expected = [
+            ("README.md", 0o644, tar::EntryType::Regular, None, "hello\n"),
+            ("bin", 0o755, tar::EntryType::Directory, None, ""),
+            (
+                "bin/run.sh",
+                0o755,
+                tar::EntryType::Regular,
+                None,
+                "#!/bin/sh\n",
+            ),
+            (
+                "link",
+                0o777,
+                tar::EntryType::Symlink,
+                Some("README.md"),
+                "",
+            ),
+            ("nested", 0o755, tar::EntryType::Directory, None, ""),
+            (&long_path, 0o644, tar::EntryType::Regular, None, "long"),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for (entry, (path, mode, kind, link, contents)) in entries.iter().zip(expected) {
+            assert_eq!(entry.0.trim_end_matches('/'), path);
+            assert_eq!(entry.1, mode, "mode of {path}");
+            assert_eq!(entry.2, kind, "type of {path}");
+            assert_eq!(entry.3.as_deref(), link);
+            assert_eq!(entry.4, contents);
+        }
+    }
+
+    #[test]
+    fn zip_archives_read_back_with_their_paths_and_modes() {
+        let data = archive(ArchiveFormat::Zip, u64::MAX).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(data)).unwrap();
+        let long_path = format!("nested/{LONG_NAME}");
+        let expected = [
+            ("README.md", 0o100644, "hello\n"),
+            ("bin/", 0o040755, ""),
+            ("bin/run.sh", 0o100755, "#!/bin/sh\n"),
+            ("link", 0o120777, "README.md"),
+            ("nested/", 0o040755, ""),
+            (&long_path, 0o100644, "long"),
+        ];
+        assert_eq!(archive.len(), expected.len());
+        for (i, (path, mode, contents)) in expected.into_iter().enumerate() {
+            let mut file = archive.by_index(i).unwrap();
+            assert_eq!(file.name(), path);
+            assert_eq!(file.unix_mode(), Some(mode), "mode of {path}");
+            assert_eq!(file.is_dir(), path.ends_with('/'));
+            let mut read = String::new();
+            file.read_to_string(&mut read).unwrap();
+            assert_eq!(read, contents);
+        }
+    }
+
+    #[test]
+    fn archives_over_the_size_limit_are_refused() {
+        for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+            assert!(archive(format, 8).is_err());
+        }
+    }
+}
@@ -2,12 +2,27 @@ use async_trait::async_trait;
 use futures::{io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libp2p::{request_response, StreamProtocol};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::time::Duration;
 
 // Constants for maximum data transfer sizes
 const MAX_GIT_REQUEST_SIZE: usize = 1_000_000; // 1MB for requests (e.g., repository path, refspec)
 const MAX_GIT_RESPONSE_SIZE: usize = 500_000_000; // 500MB for responses (e.g., packfiles, ls-remote output)
 
+/// The number of packfile bytes delivered in each [`GitResponse::PackChunk`].
+pub const GIT_PACK_CHUNK_SIZE: usize = 1 << 20; // 1MiB
+
+/// The number of status lines delivered in each [`GitResponse::StatusChunk`].
+pub const GIT_STATUS_CHUNK_LINES: usize = 500;
+
+/// The number of refs delivered in each [`GitResponse::LsRemoteChunk`].
+pub const GIT_LS_REMOTE_CHUNK_REFS: usize = 1_000;
+
+/// The most status lines served for a repository, the rest are left out and marked truncated.
+pub const GIT_MAX_STATUS_LINES: usize = 10_000;
+
+/// The most content bytes served in a [`GitRequest::Archive`], larger trees are refused.
+pub const GIT_MAX_ARCHIVE_SIZE: u64 = 64 << 20; // 64MiB
+
 /// The codec for the Git exchange protocol.
 #[derive(Default, Clone)]
 pub struct Codec;
@@ -23,8 +38,117 @@ pub enum GitRequest {
     Push(String, Vec<String>),
     /// Request to list remote references (e.g., `git ls-remote`).
     LsRemote(String),
-    /// Request to get repository status (e.g., `git status`).
+    /// Request to get repository status (e.g., `git status`). Answered with the first chunk of
+    /// the status of every served repository, use `StatusChunk` to list one in full.
     Status,
+    /// Request a chunk of the packfile for a repository. Chunk 0 starts a new transfer and the
+    /// client requests each following chunk in order until one is marked `done`.
+    PackChunk {
+        /// The name of the repository being cloned.
+        repo: String,
+        /// The sequence number of the requested chunk.
+        seq: u64,
+        /// The commit ids the client already has, used on chunk 0 to leave the objects reachable
+        /// from them out of the packfile. The following chunks must repeat them, so they are
+        /// served from the same packfile.
+        #[serde(default)]
+        haves: Vec<String>,
+        /// The number of most recent commits of each ref to include, used on chunk 0 to generate a
+        /// shallow packfile. The full history is included if `None`. The following chunks must
+        /// repeat it, like the haves.
+        #[serde(default)]
+        depth: Option<u32>,
+        /// The signature of the packfile from the response to chunk 0, sent on the following
+        /// chunks so they are served from the same packfile, or fail once the server no longer
+        /// has it. Without it the server looks the packfile up by the haves and the depth.
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// Request a chunk of the status of a repository, one line per changed file. Chunk 0 starts
+    /// the listing and the client requests each following chunk in order until one is marked
+    /// `done`. All the chunks come from the status the server took at chunk 0.
+    StatusChunk {
+        /// The name of the repository.
+        repo: String,
+        /// The sequence number of the requested chunk.
+        seq: u64,
+    },
+    /// Request a chunk of the refs of a repository, in ref name order. The first chunk is requested
+    /// without a continuation token and each following one with the token of the previous chunk,
+    /// until a chunk comes without one. A listing cut short can be resumed from the last token
+    /// received.
+    LsRemoteChunk {
+        /// The name of the repository.
+        repo: String,
+        /// The continuation token of the previous chunk, `None` for the first chunk.
+        #[serde(default)]
+        after: Option<String>,
+    },
+    /// Request an archive of the tree of a commit, answered with `GitResponse::Data`. Contains
+    /// the repository name, the commit (or tree) id and the archive format, `tar` or `zip`.
+    Archive(String, String, String),
+    /// Wraps a request with a time budget. The server aborts the request and responds with
+    /// `GitResponse::Error("deadline exceeded")` if it can't finish within the budget.
+    WithDeadline {
+        /// The time budget in milliseconds, measured from when the server starts the request.
+        budget_ms: u64,
+        /// The request to run within the budget.
+        request: Box<GitRequest>,
+    },
+}
+
+impl GitRequest {
+    /// Wrap the request so the server gives up on it after `budget`.
+    pub fn with_deadline(self, budget: Duration) -> Self {
+        GitRequest::WithDeadline {
+            budget_ms: budget.as_millis().try_into().unwrap_or(u64::MAX),
+            request: Box::new(self),
+        }
+    }
+
+    /// The name of the operation requested, for the transfers command and the metrics. A request
+    /// with a deadline is named after the request it wraps.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            GitRequest::Clone(_) => "Clone",
+            GitRequest::Fetch(..) => "Fetch",
+            GitRequest::Push(..) => "Push",
+            GitRequest::LsRemote(_) => "LsRemote",
+            GitRequest::Status => "Status",
+            GitRequest::PackChunk { .. } => "PackChunk",
+            GitRequest::StatusChunk { .. } => "StatusChunk",
+            GitRequest::LsRemoteChunk { .. } => "LsRemoteChunk",
+            GitRequest::Archive(..) => "Archive",
+            GitRequest::WithDeadline { request, .. } => request.operation(),
+        }
+    }
+
+    /// A one line summary of the request and its parameters, for the request log.
+    pub fn summary(&self) -> String {
+        match self {
+            GitRequest::Clone(repo) => format!("Clone {repo}"),
+            GitRequest::Fetch(remote, Some(refspecs)) => format!("Fetch {remote} {}", refspecs.join(" ")),
+            GitRequest::Fetch(remote, None) => format!("Fetch {remote}"),
+            GitRequest::Push(remote, refspecs) => format!("Push {remote} {}", refspecs.join(" ")),
+            GitRequest::LsRemote(repo) => format!("LsRemote {repo}"),
+            GitRequest::Status => "Status".to_string(),
+            GitRequest::PackChunk { repo, seq, haves, depth: Some(depth), .. } => {
+                format!("PackChunk {repo} seq={seq} haves={} depth={depth}", haves.len())
+            }
+            GitRequest::PackChunk { repo, seq, haves, depth: None, .. } => {
+                format!("PackChunk {repo} seq={seq} haves={}", haves.len())
+            }
+            GitRequest::StatusChunk { repo, seq } => format!("StatusChunk {repo} seq={seq}"),
+            GitRequest::LsRemoteChunk { repo, after: Some(after) } => {
+                format!("LsRemoteChunk {repo} after={after}")
+            }
+            GitRequest::LsRemoteChunk { repo, after: None } => format!("LsRemoteChunk {repo}"),
+            GitRequest::Archive(repo, oid, format) => format!("Archive {repo} {oid} {format}"),
+            GitRequest::WithDeadline { budget_ms, request } => {
+                format!("{} deadline={budget_ms}ms", request.summary())
+            }
+        }
+    }
 }
 
 /// Represents possible Git responses that can be sent between peers.
@@ -40,6 +164,47 @@ pub enum GitResponse {
     Status(String),
     /// Bytes data, useful for packfiles during fetch/push.
     Data(Vec<u8>),
+    /// One ordered chunk of a packfile, in response to `GitRequest::PackChunk`.
+    PackChunk {
+        /// The sequence number of this chunk.
+        seq: u64,
+        /// The total size of the packfile in bytes.
+        total_size: u64,
+        /// Set on the last chunk of the packfile.
+        done: bool,
+        /// The packfile bytes in this chunk.
+        data: Vec<u8>,
+        /// The CRC32 of `data`, see [`pack_chunk_checksum`].
+        checksum: u32,
+        /// The shallow boundary of a shallow packfile, set on the last chunk: the commits whose
+        /// parents were left out, which the client lists in `.git/shallow`. Empty for a packfile
+        /// with the full history.
+        #[serde(default)]
+        shallow: Vec<String>,
+        /// The signature of the packfile, for the client to send with the following chunks.
+        #[serde(default)]
+        signature: String,
+    },
+    /// One ordered chunk of the status of a repository, in response to `GitRequest::StatusChunk`.
+    StatusChunk {
+        /// The sequence number of this chunk.
+        seq: u64,
+        /// The status lines in this chunk, in the `git status --porcelain` format.
+        lines: Vec<String>,
+        /// The total number of changed files, including any that were left out.
+        total: u64,
+        /// Set on the last chunk of the status.
+        done: bool,
+        /// Set on the last chunk when the status was cut off at [`GIT_MAX_STATUS_LINES`].
+        truncated: bool,
+    },
+    /// One chunk of the refs of a repository, in response to `GitRequest::LsRemoteChunk`.
+    LsRemoteChunk {
+        /// The refs in this chunk as (ref, oid), sorted by ref name.
+        refs: Vec<(String, String)>,
+        /// The continuation token to request the next chunk with, `None` on the last chunk.
+        next: Option<String>,
+    },
 }
 
 impl GitResponse {
@@ -47,6 +212,42 @@ impl GitResponse {
     pub fn is_error(&self) -> bool {
         matches!(self, GitResponse::Error(_))
     }
+
+    /// The number of payload bytes in the response, the packfile or archive bytes it carries.
+    pub fn payload_len(&self) -> u64 {
+        match self {
+            GitResponse::Data(data) | GitResponse::PackChunk { data, .. } => data.len() as u64,
+            _ => 0,
+        }
+    }
+
+    /// A one line summary of the response and its size, for the request log. Payloads are
+    /// summarized by their size rather than shown.
+    pub fn summary(&self) -> String {
+        match self {
+            GitResponse::Success(message) => format!("Success: {message}"),
+            GitResponse::Error(message) => format!("Error: {message}"),
+            GitResponse::LsRemote(refs) => format!("LsRemote: {} refs", refs.len()),
+            GitResponse::Status(status) => format!("Status: {} lines", status.lines().count()),
+            GitResponse::Data(data) => format!("Data: {} bytes", data.len()),
+            GitResponse::PackChunk { seq, total_size, done, data, .. } => format!(
+                "PackChunk seq={seq}: {} of {total_size} bytes{}",
+                data.len(),
+                if *done { ", done" } else { "" }
+            ),
+            GitResponse::StatusChunk { seq, lines, total, done, truncated } => format!(
+                "StatusChunk seq={seq}: {} of {total} lines{}{}",
+                lines.len(),
+                if *done { ", done" } else { "" },
+                if *truncated { ", truncated" } else { "" }
+            ),
+            GitResponse::LsRemoteChunk { refs, next } => format!(
+                "LsRemoteChunk: {} refs{}",
+                refs.len(),
+                if next.is_none() { ", done" } else { "" }
+            ),
+        }
+    }
 }
 
 #[async_trait]
@@ -94,6 +295,218 @@ impl request_response::Codec for Codec {
     }
 }
 
+/// The checksum of a packfile chunk, verified by the receiver on arrival so a corrupt chunk is
+/// caught before the rest of the transfer instead of when the pack is indexed.
+pub fn pack_chunk_checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// What to do with a received packfile chunk, see [`verify_pack_chunk`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkVerdict {
+    /// The chunk is intact, write it.
+    Accept,
+    /// The chunk is corrupt, request it again.
+    Retry,
+    /// The chunk was corrupt too many times, abort the transfer.
+    Abort,
+}
+
+/// Verify a received packfile chunk against its checksum. `retries` counts the re-requests of the
+/// current chunk: a corrupt chunk is re-requested up to `max_retries` times, and the count is reset
+/// once the chunk arrives intact.
+pub fn verify_pack_chunk(
+    data: &[u8],
+    checksum: u32,
+    retries: &mut u32,
+    max_retries: u32,
+) -> ChunkVerdict {
+    if pack_chunk_checksum(data) == checksum {
+        *retries = 0;
+        return ChunkVerdict::Accept;
+    }
+    *retries += 1;
+    if *retries > max_retries {
+        ChunkVerdict::Abort
+    } else {
+        ChunkVerdict::Retry
+    }
+}
+
+/// Reassembles a packfile delivered as a sequence of [`GitResponse::PackChunk`]s, writing each
+/// chunk to `writer` as it arrives. Chunks must arrive in order; a missing, repeated or
+/// out-of-order chunk aborts the transfer with an error.
+///
+/// Every chunk either moves the transfer forward or fails it: the bytes written are always a
+/// prefix of the packfile, and a transfer only completes with exactly `total_size` bytes. An empty
+/// chunk that isn't the last one is refused, so a peer can't keep a transfer open forever without
+/// sending any data.
+pub struct PackReassembler<W> {
+    writer: W,
+    next_seq: u64,
+    received: u64,
+    total_size: Option<u64>,
+    done: bool,
+}
+
+impl<W: std::io::Write> PackReassembler<W> {
+    /// Create a new reassembler writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            next_seq: 0,
+            received: 0,
+            total_size: None,
+            done: false,
+        }
+    }
+
+    /// Write the next chunk of the packfile.
+    pub fn push(&mut self, seq: u64, total_size: u64, done: bool, data: &[u8]) -> io::Result<()> {
+        if self.done {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Received chunk {seq} after the final chunk"),
+            ));
+        }
+        if seq != self.next_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected chunk {} but received chunk {seq}", self.next_seq),
+            ));
+        }
+        if self.total_size.is_some_and(|size| size != total_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packfile size changed to {total_size} bytes mid-transfer"),
+            ));
+        }
+        if data.is_empty() && !done {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Received empty chunk {seq} before the final chunk"),
+            ));
+        }
+        let received = self.received + data.len() as u64;
+        if received > total_size || (done && received != total_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Received {received} bytes of a {total_size} byte packfile"),
+            ));
+        }
+
+        self.writer.write_all(data)?;
+        self.total_size = Some(total_size);
+        self.received = received;
+        self.next_seq += 1;
+        if done {
+            self.writer.flush()?;
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    /// The sequence number of the next expected chunk.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// The number of packfile bytes received so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// The total size of the packfile, once the first chunk has arrived.
+    pub fn total_size(&self) -> Option<u64> {
+        self.total_size
+    }
+
+    /// Whether the final chunk has been received.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Consume the reassembler, returning the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reassembles a ref listing delivered as a sequence of [`GitResponse::LsRemoteChunk`]s. The refs
+/// received so far stay usable when the listing is cut short, and [`RefListing::continuation`]
+/// gives the token to resume it with.
+///
+/// Refs must arrive in strictly increasing name order across chunks, so a resumed listing never
+/// repeats a ref even if the repository changed in between. Refs created or deleted behind the
+/// continuation point while the listing was interrupted are missed, as with any paginated listing.
+#[derive(Debug, Default)]
+pub struct RefListing {
+    refs: Vec<(String, String)>,
+    next: Option<String>,
+    done: bool,
+}
+
+impl RefListing {
+    /// Create a new, empty listing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next chunk of the listing.
+    pub fn push(&mut self, refs: Vec<(String, String)>, next: Option<String>) -> io::Result<()> {
+        if self.done {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Received refs after the final chunk",
+            ));
+        }
+        if refs.is_empty() && next.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Received an empty chunk before the final chunk",
+            ));
+        }
+        let mut last = self.refs.last().map(|(name, _)| name.as_str());
+        for (name, _) in &refs {
+            if last.is_some_and(|last| name.as_str() <= last) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Received ref {name} out of order"),
+                ));
+            }
+            last = Some(name);
+        }
+
+        self.refs.extend(refs);
+        self.done = next.is_none();
+        self.next = next;
+        Ok(())
+    }
+
+    /// The token to request the next chunk with, `None` before the first chunk and once done.
+    pub fn continuation(&self) -> Option<&str> {
+        self.next.as_deref()
+    }
+
+    /// The refs received so far, as (ref, oid).
+    pub fn refs(&self) -> &[(String, String)] {
+        &self.refs
+    }
+
+    /// The refs received so far in the `git ls-remote` format, an "oid\tref" line each.
+    pub fn lines(&self) -> Vec<String> {
+        self.refs
+            .iter()
+            .map(|(name, oid)| format!("{oid}\t{name}"))
+            .collect()
+    }
+
+    /// Whether the final chunk has been received.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
 // --- BEGIN Utility functions (copied and adapted from file_exchange.rs) ---
 
 /// Writes a message to the given socket with a length prefix appended to it. Also flushes the socket.
@@ -174,3 +587,260 @@ where
 }
 
 // --- END Utility functions ---
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // A packfile chunk as the server sends it
+    #[derive(Clone, Debug)]
+    struct Chunk {
+        seq: u64,
+        total_size: u64,
+        done: bool,
+        data: Vec<u8>,
+        checksum: u32,
+    }
+
+    // How a chunk is delivered, relative to the chunk the reassembler expects next
+    #[derive(Clone, Debug)]
+    enum Delivery {
+        // the expected chunk
+        Next,
+        // the chunk before it again
+        Repeat,
+        // the chunk after it, as if the expected one was dropped
+        Skip,
+        // the expected chunk with a byte flipped
+        Corrupt(usize),
+        // the expected chunk claiming a different packfile size
+        WrongSize,
+        // the expected chunk marked as the last one
+        EarlyDone,
+    }
+
+    fn delivery() -> impl Strategy<Value = Delivery> {
+        prop_oneof![
+            4 => Just(Delivery::Next),
+            1 => Just(Delivery::Repeat),
+            1 => Just(Delivery::Skip),
+            1 => any::<usize>().prop_map(Delivery::Corrupt),
+            1 => Just(Delivery::WrongSize),
+            1 => Just(Delivery::EarlyDone),
+        ]
+    }
+
+    // Split a packfile into the chunks the server sends
+    fn chunks(pack: &[u8], chunk_size: usize) -> Vec<Chunk> {
+        pack.chunks(chunk_size)
+            .enumerate()
+            .map(|(seq, data)| Chunk {
+                seq: seq as u64,
+                total_size: pack.len() as u64,
+                done: (seq + 1) * chunk_size >= pack.len(),
+                data: data.to_vec(),
+                checksum: pack_chunk_checksum(data),
+            })
+            .collect()
+    }
+
+    // Receive a chunk like the peer does, dropping it if it fails its checksum
+    fn receive(reassembler: &mut PackReassembler<Vec<u8>>, chunk: &Chunk) -> io::Result<()> {
+        if pack_chunk_checksum(&chunk.data) != chunk.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch",
+            ));
+        }
+        reassembler.push(chunk.seq, chunk.total_size, chunk.done, &chunk.data)
+    }
+
+    // The chunk to deliver for `delivery`, and whether it must be accepted if that is certain
+    fn deliver(
+        chunks: &[Chunk],
+        reassembler: &PackReassembler<Vec<u8>>,
+        delivery: &Delivery,
+    ) -> Option<(Chunk, Option<bool>)> {
+        let next = reassembler.next_seq() as usize;
+        let total_size = chunks[0].total_size;
+        // once a wrong size was accepted with chunk 0, no honest chunk is accepted anymore
+        let honest = reassembler
+            .total_size()
+            .is_none_or(|size| size == total_size);
+        match delivery {
+            Delivery::Next => chunks.get(next).map(|chunk| (chunk.clone(), Some(honest))),
+            Delivery::Repeat => next
+                .checked_sub(1)
+                .map(|previous| (chunks[previous].clone(), Some(false))),
+            Delivery::Skip => chunks
+                .get(next + 1)
+                .map(|chunk| (chunk.clone(), Some(false))),
+            Delivery::Corrupt(byte) => chunks.get(next).map(|chunk| {
+                let mut chunk = chunk.clone();
+                let byte = byte % chunk.data.len();
+                chunk.data[byte] ^= 0x01;
+                (chunk, Some(false))
+            }),
+            Delivery::WrongSize => chunks.get(next).map(|chunk| {
+                let chunk = Chunk {
+                    total_size: chunk.total_size + 1,
+                    ..chunk.clone()
+                };
+                (chunk, None)
+            }),
+            Delivery::EarlyDone => chunks.get(next).map(|chunk| {
+                let expected = honest && chunk.done;
+                let chunk = Chunk {
+                    done: true,
+                    ..chunk.clone()
+                };
+                (chunk, Some(expected))
+            }),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn reassembled_pack_is_a_prefix_of_the_source(
+            pack in prop::collection::vec(any::<u8>(), 1..4096),
+            chunk_size in 1usize..512,
+            deliveries in prop::collection::vec(delivery(), 0..64),
+        ) {
+            let chunks = chunks(&pack, chunk_size);
+            let mut reassembler = PackReassembler::new(Vec::new());
+            for delivery in &deliveries {
+                let Some((chunk, expected)) = deliver(&chunks, &reassembler, delivery) else {
+                    continue;
+                };
+                let result = receive(&mut reassembler, &chunk);
+                if let Some(expected) = expected {
+                    prop_assert_eq!(
+                        result.is_ok(),
+                        expected,
+                        "{:?} of chunk {} gave {:?}",
+                        delivery,
+                        chunk.seq,
+                        result
+                    );
+                }
+                prop_assert!(reassembler.received() <= pack.len() as u64);
+                prop_assert!(
+                    !reassembler.is_done() || reassembler.received() == pack.len() as u64
+                );
+            }
+
+            let done = reassembler.is_done();
+            let received = reassembler.received();
+            let output = reassembler.into_inner();
+            prop_assert_eq!(output.len() as u64, received);
+            prop_assert!(pack.starts_with(&output));
+            prop_assert_eq!(done, output == pack);
+        }
+
+        #[test]
+        fn rejected_chunks_leave_the_transfer_intact(
+            pack in prop::collection::vec(any::<u8>(), 1..4096),
+            chunk_size in 1usize..512,
+            deliveries in prop::collection::vec(delivery(), 0..64),
+        ) {
+            let chunks = chunks(&pack, chunk_size);
+            let mut reassembler = PackReassembler::new(Vec::new());
+            // a wrong size on chunk 0 is indistinguishable from the real size, leave it out
+            for delivery in deliveries
+                .iter()
+                .filter(|delivery| !matches!(delivery, Delivery::WrongSize))
+            {
+                if let Some((chunk, _)) = deliver(&chunks, &reassembler, delivery) {
+                    let _ = receive(&mut reassembler, &chunk);
+                }
+            }
+            while let Some((chunk, _)) = deliver(&chunks, &reassembler, &Delivery::Next) {
+                receive(&mut reassembler, &chunk).unwrap();
+            }
+
+            prop_assert!(reassembler.is_done());
+            prop_assert_eq!(reassembler.into_inner(), pack);
+        }
+    }
+
+    // A packfile chunk as it arrives over the wire
+    fn pack_chunk_response(data: Vec<u8>) -> GitResponse {
+        let response = GitResponse::PackChunk {
+            seq: 0,
+            total_size: data.len() as u64,
+            done: true,
+            checksum: pack_chunk_checksum(&data),
+            data,
+            shallow: Vec::new(),
+            signature: String::new(),
+        };
+        serde_json::from_slice(&serde_json::to_vec(&response).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn flipped_byte_is_detected_and_the_chunk_re_requested() {
+        let GitResponse::PackChunk {
+            mut data, checksum, ..
+        } = pack_chunk_response(b"PACK the packfile bytes".to_vec())
+        else {
+            panic!("expected a packfile chunk");
+        };
+        let intact = data.clone();
+        data[5] ^= 0x01;
+
+        let mut retries = 0;
+        assert_eq!(
+            verify_pack_chunk(&data, checksum, &mut retries, 3),
+            ChunkVerdict::Retry
+        );
+        assert_eq!(retries, 1);
+        // the re-requested chunk arrives intact
+        assert_eq!(
+            verify_pack_chunk(&intact, checksum, &mut retries, 3),
+            ChunkVerdict::Accept
+        );
+        assert_eq!(retries, 0);
+    }
+
+    #[test]
+    fn chunk_corrupt_too_often_aborts_the_transfer() {
+        let data = b"PACK the packfile bytes".to_vec();
+        let checksum = pack_chunk_checksum(&data) ^ 1;
+        let mut retries = 0;
+        for _ in 0..3 {
+            assert_eq!(
+                verify_pack_chunk(&data, checksum, &mut retries, 3),
+                ChunkVerdict::Retry
+            );
+        }
+        assert_eq!(
+            verify_pack_chunk(&data, checksum, &mut retries, 3),
+            ChunkVerdict::Abort
+        );
+    }
+
+    #[test]
+    fn empty_chunk_before_the_final_one_is_rejected() {
+        let mut reassembler = PackReassembler::new(Vec::new());
+        assert!(reassembler.push(0, 10, false, &[]).is_err());
+        assert_eq!(reassembler.next_seq(), 0);
+        assert_eq!(reassembler.total_size(), None);
+
+        reassembler.push(0, 10, false, &[1; 6]).unwrap();
+        assert!(reassembler.push(1, 10, false, &[]).is_err());
+        assert_eq!(reassembler.next_seq(), 1);
+        assert_eq!(reassembler.received(), 6);
+
+        reassembler.push(1, 10, true, &[2; 4]).unwrap();
+        assert!(reassembler.is_done());
+    }
+
+    #[test]
+    fn chunk_after_the_final_one_is_rejected() {
+        let mut reassembler = PackReassembler::new(Vec::new());
+        reassembler.push(0, 3, true, &[1, 2, 3]).unwrap();
+        assert!(reassembler.push(1, 3, true, &[]).is_err());
+        assert_eq!(reassembler.into_inner(), vec![1, 2, 3]);
+    }
+}
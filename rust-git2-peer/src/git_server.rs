@@ -0,0 +1,1774 @@
+use crate::{
+    git_archive::{build_archive, ArchiveFormat},
+    git_exchange::{
+        pack_chunk_checksum, GitRequest, GitResponse, GIT_LS_REMOTE_CHUNK_REFS,
+        GIT_MAX_ARCHIVE_SIZE, GIT_MAX_STATUS_LINES, GIT_PACK_CHUNK_SIZE, GIT_STATUS_CHUNK_LINES,
+    },
+};
+use clap::ValueEnum;
+use git2::{
+    build::RepoBuilder, FetchOptions, Oid, RemoteCallbacks, Repository, StatusEntry,
+    StatusOptions,
+};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{error, info, warn};
+
+/// The directory that repositories are cloned into and served from
+pub const GIT_REPOS_DIR: &str = "./cloned_repos";
+
+/// How the objects in a served packfile are chosen.
+///
+/// Neither strategy produces deltas against objects outside of the pack, so the client's indexer
+/// only needs to support self-contained packs (no `--fix-thin`). A thin pack however leaves out
+/// every object reachable from the client's haves, so it only completes the history when it is
+/// indexed into the client's existing object database rather than used as a standalone repository.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PackStrategy {
+    /// Only the objects the client doesn't have, which is every object when it sends no haves
+    #[default]
+    Auto,
+    /// Every object reachable from the refs, ignoring the haves
+    Full,
+}
+
+/// The settings for serving git requests
+#[derive(Clone, Copy, Debug)]
+pub struct ServerConfig {
+    /// How served packfiles are generated
+    pub pack_strategy: PackStrategy,
+    /// The largest repository, by the estimated size of its packfile, that will be served
+    pub max_repo_size: u64,
+    /// The longest a clone may run before it is aborted
+    pub clone_timeout: Duration,
+    /// The longest a fetch may run before it is aborted
+    pub fetch_timeout: Duration,
+    /// The longest generating a packfile may run before it is aborted
+    pub pack_timeout: Duration,
+}
+
+/// The error message returned when a request runs past its deadline
+const DEADLINE_EXCEEDED: &str = "deadline exceeded";
+
+/// The error message returned when an operation runs past the server's timeout for it
+const TIMED_OUT: &str = "git operation timed out";
+
+/// How long a packfile generated for a transfer is kept once it is no longer read
+const PACK_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How long the status snapshot of a listing is kept once no chunk of it is requested
+const STATUS_SNAPSHOT_RETENTION: Duration = Duration::from_secs(60);
+
+// The point in time a request must finish by: the earlier of the client's budget, if it gave one,
+// and the server's timeout for the operation. git2 runs in-process, so an operation is stopped by
+// returning false from its progress callbacks rather than by killing a subprocess.
+#[derive(Clone, Copy, Debug)]
+struct Deadline {
+    at: Instant,
+    // whether `at` is the server's timeout rather than the client's budget
+    timeout: bool,
+}
+
+impl Deadline {
+    fn new(start: Instant, budget: Option<Duration>, timeout: Duration) -> Self {
+        match budget {
+            Some(budget) if budget < timeout => Self {
+                at: start + budget,
+                timeout: false,
+            },
+            _ => Self {
+                at: start + timeout,
+                timeout: true,
+            },
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    fn message(&self) -> &'static str {
+        if self.timeout {
+            TIMED_OUT
+        } else {
+            DEADLINE_EXCEEDED
+        }
+    }
+}
+
+/// The status of the repositories each peer is listing, taken when a listing starts so that all of
+/// its chunks come from one consistent snapshot, and the status is only computed once per listing
+/// rather than once per chunk.
+///
+/// A snapshot is dropped once its last chunk is served, once no chunk of it was requested for a
+/// minute, or when the peer disconnects. Clones share the snapshots, so requests can be handled
+/// off the event loop.
+#[derive(Clone, Debug, Default)]
+pub struct StatusSnapshots {
+    snapshots: Arc<Mutex<HashMap<(PeerId, String), StatusSnapshot>>>,
+}
+
+// The status lines of a repository, up to GIT_MAX_STATUS_LINES of them
+#[derive(Debug)]
+struct StatusSnapshot {
+    lines: Vec<String>,
+    // the number of status entries, including those that were cut off
+    total: usize,
+    // when a chunk was last served from the snapshot
+    used: Instant,
+}
+
+impl StatusSnapshots {
+    /// Drop the snapshots of a peer, such as when it disconnects
+    pub fn forget(&self, peer: &PeerId) {
+        self.lock().retain(|(owner, _), _| owner != peer);
+    }
+
+    /// The number of listings in progress
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Check if no listing is in progress
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    // Nothing that can panic runs while the lock is held, so a poisoned lock still holds
+    // consistent snapshots
+    fn lock(&self) -> MutexGuard<'_, HashMap<(PeerId, String), StatusSnapshot>> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Handle an inbound git request from `peer` like [`handle_request`], turning a panic in a handler
+/// into an error response. The response channel is then always answered, instead of being dropped
+/// and leaving the requester to time out.
+pub fn respond(
+    statuses: &StatusSnapshots,
+    peer: &PeerId,
+    request: GitRequest,
+    config: &ServerConfig,
+) -> GitResponse {
+    answer_panics(|| handle_request(statuses, peer, request, config))
+}
+
+// Run a request handler, turning a panic in it into an error response
+fn answer_panics(handler: impl FnOnce() -> GitResponse) -> GitResponse {
+    match panic::catch_unwind(AssertUnwindSafe(handler)) {
+        Ok(response) => response,
+        Err(panic) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!("Git request handler panicked: {reason}");
+            GitResponse::Error("Internal error while handling the request".to_string())
+        }
+    }
+}
+
+/// Handle an inbound git request from `peer`, always producing a response
+pub fn handle_request(
+    statuses: &StatusSnapshots,
+    peer: &PeerId,
+    request: GitRequest,
+    config: &ServerConfig,
+) -> GitResponse {
+    handle_request_in(Path::new(GIT_REPOS_DIR), statuses, peer, request, config)
+}
+
+/// Handle an inbound git request from `peer` for the repositories in `repos_dir`, always producing
+/// a response
+pub fn handle_request_in(
+    repos_dir: &Path,
+    statuses: &StatusSnapshots,
+    peer: &PeerId,
+    request: GitRequest,
+    config: &ServerConfig,
+) -> GitResponse {
+    // the budget is relative so it doesn't depend on the peers' clocks agreeing
+    let start = Instant::now();
+    let (request, budget) = match request {
+        GitRequest::WithDeadline { budget_ms, request } => {
+            (*request, Some(Duration::from_millis(budget_ms)))
+        }
+        request => (request, None),
+    };
+
+    match request {
+        GitRequest::Clone(repo_url) => clone(
+            repos_dir,
+            &repo_url,
+            Deadline::new(start, budget, config.clone_timeout),
+        ),
+        GitRequest::Fetch(remote_name, refspecs) => fetch(
+            repos_dir,
+            &remote_name,
+            refspecs,
+            Deadline::new(start, budget, config.fetch_timeout),
+        ),
+        GitRequest::Push(remote, refspecs) => GitResponse::Error(format!(
+            "Push not yet implemented for remote: {}, refspecs: {:?}",
+            remote, refspecs
+        )),
+        GitRequest::LsRemote(remote) => GitResponse::Error(format!(
+            "LsRemote not yet implemented for remote: {}",
+            remote
+        )),
+        GitRequest::Status => status(repos_dir),
+        GitRequest::PackChunk {
+            repo,
+            seq,
+            haves,
+            depth,
+            signature,
+        } => {
+            // thin packs leave out whatever the client already has
+            let haves = match config.pack_strategy {
+                PackStrategy::Auto => haves,
+                PackStrategy::Full => Vec::new(),
+            };
+            pack_chunk(
+                repos_dir,
+                PackChunkRequest {
+                    repo: &repo,
+                    seq,
+                    haves: &haves,
+                    depth,
+                    signature: signature.as_deref(),
+                },
+                config.max_repo_size,
+                Deadline::new(start, budget, config.pack_timeout),
+            )
+        }
+        GitRequest::StatusChunk { repo, seq } => {
+            status_chunk(repos_dir, statuses, peer, &repo, seq)
+        }
+        GitRequest::LsRemoteChunk { repo, after } => {
+            ls_remote_chunk(repos_dir, &repo, after.as_deref())
+        }
+        GitRequest::Archive(repo, oid, format) => archive(repos_dir, &repo, &oid, &format),
+        GitRequest::WithDeadline { .. } => {
+            GitResponse::Error("Nested deadlines are not supported".to_string())
+        }
+    }
+}
+
+// Fetch options that abort the transfer once the deadline is exceeded
+fn fetch_options(deadline: Deadline) -> FetchOptions<'static> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(move |_| !deadline.exceeded());
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(callbacks);
+    fo
+}
+
+/// Get the local path of a repository from its url or name, using only the last path component
+pub fn repo_path(repos_dir: &Path, repo: &str) -> Option<PathBuf> {
+    let name = repo.trim_end_matches('/').rsplit('/').next()?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(repos_dir.join(name))
+}
+
+/// Get the commits at the tips of the refs of the local copy of `repo` in `repos_dir`, to send as
+/// the haves of a packfile request. There are none when there is no local copy.
+pub fn local_haves(repos_dir: &Path, repo: &str) -> Vec<String> {
+    let Some(repo) = repo_path(repos_dir, repo).and_then(|path| Repository::open(path).ok()) else {
+        return Vec::new();
+    };
+    let Ok(references) = repo.references_glob("refs/*") else {
+        return Vec::new();
+    };
+    let mut haves: Vec<String> = references
+        .flatten()
+        .filter_map(|reference| reference.peel_to_commit().ok())
+        .map(|commit| commit.id().to_string())
+        .collect();
+    haves.sort();
+    haves.dedup();
+    haves
+}
+
+/// Get the contents of a `.git/shallow` file listing the shallow boundary of a packfile, checking
+/// that each commit of the boundary is a full hex object id
+pub fn shallow_file(shallow: &[String]) -> anyhow::Result<String> {
+    let mut lines = String::new();
+    for oid in shallow {
+        if oid.len() != 40 || !oid.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid commit {oid:?} in the shallow boundary");
+        }
+        lines.push_str(&oid.to_ascii_lowercase());
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+// Clone the repository at `repo_url` into the repos directory
+fn clone(repos_dir: &Path, repo_url: &str, deadline: Deadline) -> GitResponse {
+    if !repos_dir.exists() {
+        if let Err(e) = fs::create_dir_all(repos_dir) {
+            error!("Failed to create clone directory {:?}: {}", repos_dir, e);
+            return GitResponse::Error(format!("Failed to create clone directory: {}", e));
+        }
+        info!("Clone directory created: {:?}", repos_dir);
+    } else {
+        info!("Clone directory already exists: {:?}", repos_dir);
+    }
+
+    let Some(repo_path) = repo_path(repos_dir, repo_url) else {
+        return GitResponse::Error(format!("Invalid repository url {}", repo_url));
+    };
+    let mut builder = RepoBuilder::new();
+    builder.fetch_options(fetch_options(deadline));
+    match builder.clone(repo_url, &repo_path) {
+        Ok(_) => {
+            info!(
+                "Successfully cloned repository {} to {:?}",
+                repo_url, repo_path
+            );
+            GitResponse::Success(format!("Successfully cloned repository {}", repo_url))
+        }
+        Err(_) if deadline.exceeded() => {
+            error!("{} cloning repository {}", deadline.message(), repo_url);
+            GitResponse::Error(deadline.message().to_string())
+        }
+        Err(e) => {
+            error!("Failed to clone repository {}: {}", repo_url, e);
+            GitResponse::Error(format!("Failed to clone repository {}: {}", repo_url, e))
+        }
+    }
+}
+
+// Fetch from `remote_name` into the matching repository in the repos directory
+fn fetch(
+    repos_dir: &Path,
+    remote_name: &str,
+    refspecs: Option<Vec<String>>,
+    deadline: Deadline,
+) -> GitResponse {
+    // Assuming remote_name is part of the URL or a known name
+    let Some(repo_path) = repo_path(repos_dir, remote_name) else {
+        return GitResponse::Error(format!("Invalid remote name {}", remote_name));
+    };
+
+    let repo = match Repository::open(&repo_path) {
+        Ok(repo) => repo,
+        Err(e) => {
+            return GitResponse::Error(format!(
+                "Failed to open repository at {:?}: {}",
+                repo_path, e
+            ))
+        }
+    };
+    let mut remote = match repo.find_remote(remote_name) {
+        Ok(remote) => remote,
+        Err(e) => {
+            return GitResponse::Error(format!(
+                "Failed to find remote '{}' in repo at {:?}: {}",
+                remote_name, repo_path, e
+            ))
+        }
+    };
+
+    let mut fo = fetch_options(deadline);
+    let refspecs: Vec<&str> = refspecs
+        .as_ref()
+        .map(|v| v.iter().map(|s| s.as_str()).collect())
+        .unwrap_or_default();
+    match remote.fetch(&refspecs, Some(&mut fo), None) {
+        Ok(_) => {
+            info!("Fetched from {} for repo at {:?}", remote_name, repo_path);
+            GitResponse::Success(format!("Fetched from {}", remote_name))
+        }
+        Err(_) if deadline.exceeded() => GitResponse::Error(deadline.message().to_string()),
+        Err(e) => GitResponse::Error(format!(
+            "Failed to fetch from remote {}: {}",
+            remote_name, e
+        )),
+    }
+}
+
+// The fields of a packfile chunk request
+#[derive(Clone, Copy)]
+struct PackChunkRequest<'a> {
+    repo: &'a str,
+    seq: u64,
+    haves: &'a [String],
+    depth: Option<u32>,
+    signature: Option<&'a str>,
+}
+
+// Serve one chunk of the packfile for `repo`, generating the packfile on the first chunk. Each
+// request gets the packfile generated for its signature, kept next to the repository along with
+// its shallow boundary, so transfers of different packfiles never share files.
+fn pack_chunk(
+    repos_dir: &Path,
+    request: PackChunkRequest<'_>,
+    max_repo_size: u64,
+    deadline: Deadline,
+) -> GitResponse {
+    let PackChunkRequest {
+        repo,
+        seq,
+        haves,
+        depth,
+        signature,
+    } = request;
+    let Some(repo_path) = repo_path(repos_dir, repo) else {
+        return GitResponse::Error(format!("Invalid repository name {}", repo));
+    };
+
+    if seq == 0 {
+        if let Some(depth) = depth {
+            if let Err(e) = check_shallow_support(&repo_path, depth) {
+                return GitResponse::Error(format!(
+                    "Can't serve a shallow packfile of {}: {}",
+                    repo, e
+                ));
+            }
+        }
+
+        // refuse before spending the effort of generating a pack that is too large
+        match estimate_pack_size(&repo_path) {
+            Ok(size) if size > max_repo_size => {
+                warn!(
+                    "Refusing to serve {:?}: estimated packfile size {size} bytes exceeds the maximum of {max_repo_size} bytes",
+                    repo_path
+                );
+                return GitResponse::Error("repository too large".to_string());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return GitResponse::Error(format!("Failed to open repository {}: {}", repo, e))
+            }
+        }
+    }
+
+    let signature = match signature {
+        // the signature is part of a path, so it must be one we could have generated
+        Some(signature) if seq > 0 && !is_pack_signature(signature) => {
+            return GitResponse::Error(format!("Invalid packfile signature {}", signature))
+        }
+        Some(signature) if seq > 0 => signature.to_string(),
+        _ => match pack_signature(&repo_path, haves, depth) {
+            Ok(signature) => signature,
+            Err(e) => {
+                return GitResponse::Error(format!(
+                    "Failed to generate packfile for {}: {}",
+                    repo, e
+                ))
+            }
+        },
+    };
+    let (pack_path, shallow_path) = pack_paths(&repo_path, &signature);
+
+    // the following chunks come from the packfile chunk 0 was served from. Once it is gone, because
+    // it was pruned or, for a client that doesn't send the signature, the refs changed, the
+    // transfer fails rather than mixing two packfiles.
+    if seq > 0 {
+        if !pack_path.is_file() {
+            return GitResponse::Error(format!(
+                "The packfile {} of {} no longer exists, restart the transfer",
+                signature, repo
+            ));
+        }
+        keep_pack(&pack_path);
+        return read_pack_response(repo, &pack_path, &shallow_path, &signature, seq);
+    }
+
+    // chunk 0 starts a new transfer, the packfile is only generated if no identical request
+    // generated it already
+    if pack_path.is_file() {
+        info!(
+            "Reusing the packfile generated for an identical request for {:?}",
+            repo_path
+        );
+        keep_pack(&pack_path);
+        return read_pack_response(repo, &pack_path, &shallow_path, &signature, seq);
+    }
+
+    match generate_pack(
+        &repo_path,
+        &pack_path,
+        &shallow_path,
+        haves,
+        depth,
+        deadline,
+    ) {
+        Ok(size) => info!("Generated {size} byte packfile for {:?}", repo_path),
+        Err(_) if deadline.exceeded() => {
+            error!(
+                "{} generating packfile for {:?}",
+                deadline.message(),
+                repo_path
+            );
+            return GitResponse::Error(deadline.message().to_string());
+        }
+        Err(e) => {
+            error!("Failed to generate packfile for {:?}: {}", repo_path, e);
+            return GitResponse::Error(format!("Failed to generate packfile for {}: {}", repo, e));
+        }
+    }
+    prune_packs(&repo_path, &signature);
+
+    read_pack_response(repo, &pack_path, &shallow_path, &signature, seq)
+}
+
+// The paths of the packfile and the shallow boundary generated for requests with `signature`
+fn pack_paths(repo_path: &Path, signature: &str) -> (PathBuf, PathBuf) {
+    (
+        repo_path.with_extension(format!("{signature}.pack")),
+        repo_path.with_extension(format!("{signature}.shallow")),
+    )
+}
+
+// Generate the packfile and the shallow boundary for a request and return the packfile size. Both
+// are written to temporary files and linked into place, the packfile last, so a concurrent request
+// with the same signature either generates its own or reads a complete one. Linking never
+// replaces a published file, so when identical requests race all of them are served the one
+// published first.
+fn generate_pack(
+    repo_path: &Path,
+    pack_path: &Path,
+    shallow_path: &Path,
+    haves: &[String],
+    depth: Option<u32>,
+    deadline: Deadline,
+) -> anyhow::Result<u64> {
+    let nonce: u64 = rand::random();
+    let tmp_pack_path = pack_path.with_extension(format!("pack.{nonce:016x}.tmp"));
+    let tmp_shallow_path = shallow_path.with_extension(format!("shallow.{nonce:016x}.tmp"));
+
+    let result = write_pack(repo_path, &tmp_pack_path, haves, depth, deadline).and_then(
+        |(size, shallow)| {
+            // no boundary is written for a packfile with the full history
+            if !shallow.is_empty() {
+                let lines: String = shallow.iter().map(|oid| format!("{oid}\n")).collect();
+                fs::write(&tmp_shallow_path, lines)?;
+                publish(&tmp_shallow_path, shallow_path)?;
+            }
+            publish(&tmp_pack_path, pack_path)?;
+            Ok(size)
+        },
+    );
+    // don't leave a truncated pack behind, nor a copy of a published one
+    let _ = fs::remove_file(&tmp_pack_path);
+    let _ = fs::remove_file(&tmp_shallow_path);
+    result
+}
+
+// Link a generated file into place, unless an identical request already published it
+fn publish(tmp_path: &Path, path: &Path) -> io::Result<()> {
+    match fs::hard_link(tmp_path, path) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+// Remove the packfiles of the repository generated for other signatures, and their shallow
+// boundaries, once no chunk of them was read for PACK_RETENTION. A transfer resuming after that
+// fails with an error.
+fn prune_packs(repo_path: &Path, signature: &str) {
+    let (Some(dir), Some(name)) = (
+        repo_path.parent(),
+        repo_path.file_stem().and_then(|name| name.to_str()),
+    ) else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(other) = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(".pack"))
+        else {
+            continue;
+        };
+        if other == signature || !is_pack_signature(other) {
+            continue;
+        }
+        let unused = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| {
+                modified
+                    .elapsed()
+                    .is_ok_and(|unused| unused >= PACK_RETENTION)
+            });
+        if unused {
+            info!("Removing unused packfile {:?}", entry.path());
+            let (pack_path, shallow_path) = pack_paths(repo_path, other);
+            let _ = fs::remove_file(pack_path);
+            let _ = fs::remove_file(shallow_path);
+        }
+    }
+}
+
+// Mark a packfile as read, so it isn't pruned while a transfer is reading it
+fn keep_pack(pack_path: &Path) {
+    let _ = fs::File::options()
+        .append(true)
+        .open(pack_path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+// Check if `signature` looks like the signature of a packfile, a hex sha256
+fn is_pack_signature(signature: &str) -> bool {
+    signature.len() == 64 && signature.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// Answer with chunk `seq` of the generated packfile, with the shallow boundary on the last chunk
+fn read_pack_response(
+    repo: &str,
+    pack_path: &Path,
+    shallow_path: &Path,
+    signature: &str,
+    seq: u64,
+) -> GitResponse {
+    match read_pack_chunk(pack_path, seq) {
+        Ok((data, total_size)) => {
+            let done = seq * GIT_PACK_CHUNK_SIZE as u64 + data.len() as u64 >= total_size;
+            let shallow = if done {
+                // no boundary was written for a packfile with the full history
+                fs::read_to_string(shallow_path)
+                    .map(|lines| lines.lines().map(str::to_string).collect())
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            GitResponse::PackChunk {
+                seq,
+                total_size,
+                done,
+                checksum: pack_chunk_checksum(&data),
+                data,
+                shallow,
+                signature: signature.to_string(),
+            }
+        }
+        Err(e) => GitResponse::Error(format!(
+            "Failed to read chunk {} of packfile for {}: {}",
+            seq, repo, e
+        )),
+    }
+}
+
+// Serve one chunk of the status of `repo` to `peer`. Chunk 0 takes a snapshot of the status that
+// the following chunks are served from, so a listing is consistent even when the worktree changes
+// while it is in progress.
+fn status_chunk(
+    repos_dir: &Path,
+    statuses: &StatusSnapshots,
+    peer: &PeerId,
+    repo: &str,
+    seq: u64,
+) -> GitResponse {
+    let Some(repo_path) = repo_path(repos_dir, repo) else {
+        return GitResponse::Error(format!("Invalid repository name {}", repo));
+    };
+
+    let key = (*peer, repo.to_string());
+    // the status is computed without holding the lock, so other listings go on meanwhile
+    let snapshot = (seq == 0).then(|| status_snapshot(&repo_path));
+
+    let mut snapshots = statuses.lock();
+    // listings that were abandoned don't hold on to their snapshot
+    snapshots.retain(|_, snapshot| snapshot.used.elapsed() < STATUS_SNAPSHOT_RETENTION);
+    match snapshot {
+        Some(Ok(snapshot)) => {
+            snapshots.insert(key.clone(), snapshot);
+        }
+        Some(Err(e)) => {
+            snapshots.remove(&key);
+            return GitResponse::Error(format!("Failed to get the status of {}: {}", repo, e));
+        }
+        None => {}
+    }
+    let Some(snapshot) = snapshots.get_mut(&key) else {
+        return GitResponse::Error(format!(
+            "The status of {} is no longer held, restart the listing",
+            repo
+        ));
+    };
+
+    let served = snapshot.lines.len();
+    let start = (seq as usize)
+        .saturating_mul(GIT_STATUS_CHUNK_LINES)
+        .min(served);
+    let end = start.saturating_add(GIT_STATUS_CHUNK_LINES).min(served);
+    let done = end >= served;
+    let response = GitResponse::StatusChunk {
+        seq,
+        lines: snapshot.lines[start..end].to_vec(),
+        total: snapshot.total as u64,
+        done,
+        truncated: done && snapshot.total > served,
+    };
+    if done {
+        snapshots.remove(&key);
+    } else {
+        snapshot.used = Instant::now();
+    }
+    response
+}
+
+// Answer a legacy status request, which names no repository, with the first chunk of the status of
+// every served repository. Each line is prefixed with its repository, and the listing ends with the
+// number of lines that were left out when it doesn't fit in a chunk.
+fn status(repos_dir: &Path) -> GitResponse {
+    let mut repos: Vec<PathBuf> = match fs::read_dir(repos_dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(e) => return GitResponse::Error(format!("Failed to list the repositories: {}", e)),
+    };
+    repos.sort();
+
+    let mut lines = Vec::new();
+    let mut total = 0;
+    for repo_path in repos {
+        // anything in the directory that isn't a repository has no status
+        let Ok(snapshot) = status_snapshot(&repo_path) else {
+            continue;
+        };
+        let name = repo_path.file_name().unwrap_or_default().to_string_lossy();
+        let room = GIT_STATUS_CHUNK_LINES.saturating_sub(lines.len());
+        lines.extend(snapshot.lines.iter().take(room).map(|line| format!("{name}: {line}")));
+        total += snapshot.total;
+    }
+    if total > lines.len() {
+        lines.push(format!(
+            "... {} more, request the status of a repository in chunks to see them",
+            total - lines.len()
+        ));
+    }
+    GitResponse::Status(lines.join("\n"))
+}
+
+// Take a snapshot of the status of the repository at `repo_path`
+fn status_snapshot(repo_path: &Path) -> Result<StatusSnapshot, git2::Error> {
+    let repository = Repository::open(repo_path)?;
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let statuses = repository.statuses(Some(&mut opts))?;
+    Ok(StatusSnapshot {
+        lines: statuses
+            .iter()
+            .take(GIT_MAX_STATUS_LINES)
+            .map(|entry| status_line(&entry))
+            .collect(),
+        total: statuses.len(),
+        used: Instant::now(),
+    })
+}
+
+// Serve the chunk of the refs of `repo` following the ref named `after`. The continuation token is
+// the name of the last ref served, so nothing is held between requests and a listing can be
+// resumed at any time, even after the refs changed.
+fn ls_remote_chunk(repos_dir: &Path, repo: &str, after: Option<&str>) -> GitResponse {
+    let Some(repo_path) = repo_path(repos_dir, repo) else {
+        return GitResponse::Error(format!("Invalid repository name {}", repo));
+    };
+    let repository = match Repository::open(&repo_path) {
+        Ok(repository) => repository,
+        Err(e) => return GitResponse::Error(format!("Failed to open repository {}: {}", repo, e)),
+    };
+    let mut refs = match list_refs(&repository) {
+        Ok(refs) => refs,
+        Err(e) => return GitResponse::Error(format!("Failed to list the refs of {}: {}", repo, e)),
+    };
+
+    let start = after.map_or(0, |after| {
+        refs.partition_point(|(name, _)| name.as_str() <= after)
+    });
+    let end = start
+        .saturating_add(GIT_LS_REMOTE_CHUNK_REFS)
+        .min(refs.len());
+    let more = end < refs.len();
+    refs.truncate(end);
+    let refs = refs.split_off(start);
+    let next = if more {
+        refs.last().map(|(name, _)| name.clone())
+    } else {
+        None
+    };
+    GitResponse::LsRemoteChunk { refs, next }
+}
+
+// List HEAD and the refs of a repository as (ref, oid) sorted by ref name, resolving symbolic refs
+// to the commit they point at like `git ls-remote`
+fn list_refs(repository: &Repository) -> Result<Vec<(String, String)>, git2::Error> {
+    let mut refs = Vec::new();
+    if let Ok(head) = repository.head() {
+        if let Some(oid) = head.target() {
+            refs.push(("HEAD".to_string(), oid.to_string()));
+        }
+    }
+    for reference in repository.references()? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        let name = name.to_string();
+        if let Some(oid) = reference.resolve()?.target() {
+            refs.push((name, oid.to_string()));
+        }
+    }
+    refs.sort();
+    refs.dedup_by(|a, b| a.0 == b.0);
+    Ok(refs)
+}
+
+// Build an archive of the tree of commit `oid` in `repo`
+fn archive(repos_dir: &Path, repo: &str, oid: &str, format: &str) -> GitResponse {
+    let format: ArchiveFormat = match format.parse() {
+        Ok(format) => format,
+        Err(e) => return GitResponse::Error(e.to_string()),
+    };
+    let Some(repo_path) = repo_path(repos_dir, repo) else {
+        return GitResponse::Error(format!("Invalid repository name {}", repo));
+    };
+    let repository = match Repository::open(&repo_path) {
+        Ok(repository) => repository,
+        Err(e) => return GitResponse::Error(format!("Failed to open repository {}: {}", repo, e)),
+    };
+    let Ok(oid) = Oid::from_str(oid) else {
+        return GitResponse::Error(format!("Invalid object id {}", oid));
+    };
+
+    // a commit or a tag is archived as its tree, dated by its commit
+    let object = match repository.find_object(oid, None) {
+        Ok(object) => object,
+        Err(_) => return GitResponse::Error(format!("Unknown object {} in {}", oid, repo)),
+    };
+    let mtime = object
+        .peel_to_commit()
+        .map_or(0, |commit| commit.time().seconds());
+    let tree = match object.peel_to_tree() {
+        Ok(tree) => tree,
+        Err(_) => return GitResponse::Error(format!("{} in {} has no tree", oid, repo)),
+    };
+
+    match build_archive(&repository, &tree, format, mtime, GIT_MAX_ARCHIVE_SIZE) {
+        Ok(data) => {
+            info!("Serving a {} byte {} archive of {} in {}", data.len(), format, oid, repo);
+            GitResponse::Data(data)
+        }
+        Err(e) => {
+            warn!("Failed to archive {} in {}: {}", oid, repo, e);
+            GitResponse::Error(format!("Failed to archive {} in {}: {}", oid, repo, e))
+        }
+    }
+}
+
+// Format a status entry like a line of `git status --porcelain`
+fn status_line(entry: &StatusEntry) -> String {
+    let status = entry.status();
+    let path = entry.path().unwrap_or("<non UTF-8 path>");
+    if status.is_conflicted() {
+        return format!("UU {path}");
+    }
+    if status.is_wt_new() && !status.is_index_new() {
+        return format!("?? {path}");
+    }
+
+    let index = if status.is_index_new() {
+        'A'
+    } else if status.is_index_modified() {
+        'M'
+    } else if status.is_index_deleted() {
+        'D'
+    } else if status.is_index_renamed() {
+        'R'
+    } else if status.is_index_typechange() {
+        'T'
+    } else {
+        ' '
+    };
+    let worktree = if status.is_wt_modified() {
+        'M'
+    } else if status.is_wt_deleted() {
+        'D'
+    } else if status.is_wt_renamed() {
+        'R'
+    } else if status.is_wt_typechange() {
+        'T'
+    } else {
+        ' '
+    };
+    format!("{index}{worktree} {path}")
+}
+
+// Estimate the size of a full packfile for the repository from the size of its object database.
+// Objects are stored compressed, so this is close to the size of a full pack and an upper bound for
+// a thin one.
+fn estimate_pack_size(repo_path: &Path) -> anyhow::Result<u64> {
+    let repo = Repository::open(repo_path)?;
+    dir_size(&repo.path().join("objects"))
+}
+
+// The total size of the files in a directory and its subdirectories
+fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+// Check that a shallow packfile of `depth` commits can be generated for the repository. A
+// repository that is itself shallow is refused, since its missing history would make the boundary
+// of the packfile wrong.
+fn check_shallow_support(repo_path: &Path, depth: u32) -> anyhow::Result<()> {
+    if depth == 0 {
+        anyhow::bail!("the depth must be at least 1");
+    }
+    let repo = Repository::open(repo_path)?;
+    if repo.is_shallow() {
+        anyhow::bail!("the repository is shallow itself");
+    }
+    Ok(())
+}
+
+// Find the commits within `depth` commits of the tips of the refs, and the shallow boundary: the
+// commits among them with parents that were left out
+fn shallow_commits(
+    repo: &Repository,
+    depth: u32,
+    deadline: Deadline,
+) -> anyhow::Result<(HashSet<Oid>, Vec<Oid>)> {
+    // breadth first, so each commit is first reached at its shortest distance from a tip
+    let mut queue = VecDeque::new();
+    let mut commits = HashSet::new();
+    for reference in repo.references()? {
+        if let Ok(commit) = reference?.peel_to_commit() {
+            if commits.insert(commit.id()) {
+                queue.push_back((commit.id(), 1));
+            }
+        }
+    }
+    while let Some((oid, distance)) = queue.pop_front() {
+        if deadline.exceeded() {
+            anyhow::bail!(deadline.message());
+        }
+        if distance >= depth {
+            continue;
+        }
+        for parent in repo.find_commit(oid)?.parent_ids() {
+            if commits.insert(parent) {
+                queue.push_back((parent, distance + 1));
+            }
+        }
+    }
+
+    let mut shallow = Vec::new();
+    for oid in commits.iter() {
+        let commit = repo.find_commit(*oid)?;
+        if commit.parent_ids().any(|parent| !commits.contains(&parent)) {
+            shallow.push(*oid);
+        }
+    }
+    shallow.sort();
+    Ok((commits, shallow))
+}
+
+// Write a packfile containing the objects reachable from the refs of the repository, leaving out
+// those reachable from the haves, and return its size. With a depth only the commits within
+// `depth` commits of the tips of the refs are included, and the shallow boundary is returned too.
+fn write_pack(
+    repo_path: &Path,
+    pack_path: &Path,
+    haves: &[String],
+    depth: Option<u32>,
+    deadline: Deadline,
+) -> anyhow::Result<(u64, Vec<Oid>)> {
+    let repo = Repository::open(repo_path)?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_glob("refs/*")?;
+    let known_haves = known_haves(&repo, haves);
+    let hidden = !known_haves.is_empty();
+    for have in known_haves {
+        revwalk.hide(have)?;
+    }
+
+    let mut builder = repo.packbuilder()?;
+    let shallow = match depth {
+        None => {
+            builder.insert_walk(&mut revwalk)?;
+            Vec::new()
+        }
+        Some(depth) => {
+            let (commits, shallow) = shallow_commits(&repo, depth, deadline)?;
+            // each commit brings its tree along, the walk is only needed to leave out the commits
+            // the client has
+            if hidden {
+                for oid in revwalk {
+                    let oid = oid?;
+                    if commits.contains(&oid) {
+                        builder.insert_commit(oid)?;
+                    }
+                }
+            } else {
+                for oid in commits {
+                    builder.insert_commit(oid)?;
+                }
+            }
+            shallow
+        }
+    };
+    if deadline.exceeded() {
+        anyhow::bail!(deadline.message());
+    }
+    // returning false from the progress callback aborts the pack generation
+    builder.set_progress_callback(move |_, _, _| !deadline.exceeded())?;
+
+    // stream the pack to disk instead of buffering it so large repos don't exhaust memory
+    let mut file = fs::File::create(pack_path)?;
+    let mut write_result = Ok(());
+    let pack_result = builder.foreach(|buf| match file.write_all(buf) {
+        Ok(()) => !deadline.exceeded(),
+        Err(e) => {
+            write_result = Err(e);
+            false
+        }
+    });
+    write_result?;
+    pack_result?;
+    file.flush()?;
+
+    Ok((file.metadata()?.len(), shallow))
+}
+
+// The signature of the packfile a request would generate: a hash of the tips of the refs, the
+// haves the repository knows about and the depth. Requests with the same signature get the same
+// packfile, so it is generated once and served to all of them, and a change to the refs gives new
+// requests a new packfile without touching the one being served.
+fn pack_signature(
+    repo_path: &Path,
+    haves: &[String],
+    depth: Option<u32>,
+) -> anyhow::Result<String> {
+    let repo = Repository::open(repo_path)?;
+    let mut refs = Vec::new();
+    for reference in repo.references_glob("refs/*")? {
+        let reference = reference?;
+        if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+            refs.push(format!("{name} {target}"));
+        }
+    }
+    refs.sort();
+    // write_pack ignores the haves we don't have, so they don't change the packfile
+    let known_haves = known_haves(&repo, haves);
+
+    let mut hasher = Sha256::new();
+    for line in refs {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    for have in known_haves {
+        hasher.update(format!("have {have}\n").as_bytes());
+    }
+    if let Some(depth) = depth {
+        hasher.update(format!("depth {depth}\n").as_bytes());
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// The haves of a request that are commits of the repository, sorted. The client may have commits we
+// don't, and a malformed have only costs the client a bigger pack, so both are skipped.
+fn known_haves(repo: &Repository, haves: &[String]) -> Vec<Oid> {
+    let mut known_haves: Vec<Oid> = haves
+        .iter()
+        .filter_map(|have| Oid::from_str(have).ok())
+        .filter(|oid| repo.find_commit(*oid).is_ok())
+        .collect();
+    known_haves.sort();
+    known_haves.dedup();
+    known_haves
+}
+
+// Read chunk `seq` of the packfile, returning the chunk and the total packfile size
+fn read_pack_chunk(pack_path: &Path, seq: u64) -> anyhow::Result<(Vec<u8>, u64)> {
+    let mut file = fs::File::open(pack_path)?;
+    let total_size = file.metadata()?.len();
+    let offset = seq * GIT_PACK_CHUNK_SIZE as u64;
+    if offset >= total_size {
+        anyhow::bail!("chunk starts at {offset} but the packfile is {total_size} bytes");
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(GIT_PACK_CHUNK_SIZE);
+    file.take(GIT_PACK_CHUNK_SIZE as u64)
+        .read_to_end(&mut data)?;
+
+    Ok((data, total_size))
+}
+
+// The git repository fixture, shared with the integration tests
+#[cfg(test)]
+#[path = "../tests/fixture/mod.rs"]
+mod fixture;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_exchange::PackReassembler;
+    use libp2p::identity::Keypair;
+    use rand::RngCore;
+    use std::thread;
+    use tempfile::TempDir;
+    use super::fixture::{Fixture, REPO};
+
+    // Commit a file on main large enough to span several packfile chunks
+    fn commit_large_file(fixture: &mut Fixture) -> Oid {
+        // random bytes don't compress, so the packfile is as large as the file
+        let mut contents = vec![0; 3 * GIT_PACK_CHUNK_SIZE];
+        rand::thread_rng().fill_bytes(&mut contents);
+        fixture.commit_on_main("large", &contents)
+    }
+
+    fn peer() -> PeerId {
+        Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    // Handle a request from a peer with no listing in progress
+    fn handle(repos_dir: &Path, request: GitRequest, config: &ServerConfig) -> GitResponse {
+        handle_request_in(
+            repos_dir,
+            &StatusSnapshots::default(),
+            &peer(),
+            request,
+            config,
+        )
+    }
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            pack_strategy: PackStrategy::Auto,
+            max_repo_size: u64::MAX,
+            clone_timeout: Duration::from_secs(60),
+            fetch_timeout: Duration::from_secs(60),
+            pack_timeout: Duration::from_secs(60),
+        }
+    }
+
+    // A client fetching a packfile one chunk at a time
+    struct PackClient {
+        haves: Vec<String>,
+        depth: Option<u32>,
+        reassembler: PackReassembler<Vec<u8>>,
+        shallow: Vec<String>,
+        // the signature from chunk 0, sent with the following chunks
+        signature: Option<String>,
+        // whether the client predates the signature and doesn't send it
+        legacy: bool,
+    }
+
+    impl PackClient {
+        fn new(haves: Vec<String>, depth: Option<u32>) -> Self {
+            Self {
+                haves,
+                depth,
+                reassembler: PackReassembler::new(Vec::new()),
+                shallow: Vec::new(),
+                signature: None,
+                legacy: false,
+            }
+        }
+
+        // A client that doesn't send the signature
+        fn legacy(haves: Vec<String>, depth: Option<u32>) -> Self {
+            Self {
+                legacy: true,
+                ..Self::new(haves, depth)
+            }
+        }
+
+        // Request the next chunk, returning whether the packfile is complete or the error the
+        // server answered with
+        fn next(&mut self, repos_dir: &Path) -> Result<bool, String> {
+            let request = GitRequest::PackChunk {
+                repo: REPO.to_string(),
+                seq: self.reassembler.next_seq(),
+                haves: self.haves.clone(),
+                depth: self.depth,
+                signature: self.signature.clone().filter(|_| !self.legacy),
+            };
+            match handle(repos_dir, request, &config()) {
+                GitResponse::PackChunk {
+                    seq,
+                    total_size,
+                    done,
+                    data,
+                    checksum,
+                    shallow,
+                    signature,
+                } => {
+                    assert_eq!(checksum, pack_chunk_checksum(&data));
+                    if let Some(expected) = &self.signature {
+                        assert_eq!(&signature, expected);
+                    }
+                    self.signature = Some(signature);
+                    self.reassembler
+                        .push(seq, total_size, done, &data)
+                        .map_err(|e| e.to_string())?;
+                    self.shallow = shallow;
+                    Ok(done)
+                }
+                GitResponse::Error(e) => Err(e),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+
+        // The packfile and its shallow boundary
+        fn finish(self) -> (Vec<u8>, Vec<String>) {
+            assert!(self.reassembler.is_done());
+            (self.reassembler.into_inner(), self.shallow)
+        }
+    }
+
+    // Fetch every chunk of a packfile, returning the packfile and its shallow boundary
+    fn fetch_pack(
+        repos_dir: &Path,
+        haves: Vec<String>,
+        depth: Option<u32>,
+    ) -> (Vec<u8>, Vec<String>) {
+        let mut client = PackClient::new(haves, depth);
+        while !client.next(repos_dir).unwrap() {}
+        client.finish()
+    }
+
+    // Index a packfile into a new, empty repository
+    fn index_pack(dir: &Path, pack: &[u8]) -> Repository {
+        let repo = Repository::init_bare(dir).unwrap();
+        {
+            let odb = repo.odb().unwrap();
+            let mut writer = odb.packwriter().unwrap();
+            writer.write_all(pack).unwrap();
+            writer.commit().unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn pack_chunk_serves_the_full_history() {
+        let fixture = Fixture::new();
+        let (pack, shallow) = fetch_pack(fixture.repos_dir(), Vec::new(), None);
+        assert!(shallow.is_empty());
+
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &pack);
+        for oid in fixture.main.iter().chain([&fixture.feature]) {
+            assert!(client.find_commit(*oid).is_ok(), "{oid} is missing");
+        }
+    }
+
+    #[test]
+    fn pack_chunk_leaves_out_the_haves() {
+        let fixture = Fixture::new();
+        let (pack, _) = fetch_pack(fixture.repos_dir(), vec![fixture.main[1].to_string()], None);
+
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &pack);
+        assert!(client.find_commit(fixture.main[0]).is_err());
+        assert!(client.find_commit(fixture.main[1]).is_err());
+        assert!(client.find_commit(fixture.main[2]).is_ok());
+        assert!(client.find_commit(fixture.feature).is_ok());
+    }
+
+    #[test]
+    fn pack_chunk_skips_malformed_haves() {
+        let fixture = Fixture::new();
+        let haves = vec!["not a commit".to_string(), fixture.main[1].to_string()];
+        let (pack, _) = fetch_pack(fixture.repos_dir(), haves, None);
+
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &pack);
+        assert!(client.find_commit(fixture.main[1]).is_err());
+        assert!(client.find_commit(fixture.main[2]).is_ok());
+    }
+
+    #[test]
+    fn local_haves_are_the_tips_of_the_local_copy() {
+        let fixture = Fixture::new();
+        // v1 tags the first commit of main
+        let mut haves: Vec<String> = [fixture.main[0], fixture.main[2], fixture.feature]
+            .iter()
+            .map(Oid::to_string)
+            .collect();
+        haves.sort();
+        assert_eq!(local_haves(fixture.repos_dir(), REPO), haves);
+        assert!(local_haves(fixture.repos_dir(), "missing").is_empty());
+    }
+
+    #[test]
+    fn pack_chunk_serves_a_shallow_history() {
+        let fixture = Fixture::new();
+        let (pack, shallow) = fetch_pack(fixture.repos_dir(), Vec::new(), Some(1));
+
+        // a depth of one keeps the commits the refs point at, and only those with a parent left
+        // out are on the boundary: feature's parent is the tip of main
+        assert_eq!(shallow, vec![fixture.main[2].to_string()]);
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &pack);
+        assert!(client.find_commit(fixture.main[1]).is_err());
+        assert!(client.find_commit(fixture.main[2]).is_ok());
+        assert!(client.find_commit(fixture.feature).is_ok());
+    }
+
+    #[test]
+    fn shallow_file_lists_only_full_object_ids() {
+        let oid = "0123456789ABCDEF0123456789abcdef01234567".to_string();
+        assert_eq!(
+            shallow_file(std::slice::from_ref(&oid)).unwrap(),
+            "0123456789abcdef0123456789abcdef01234567\n"
+        );
+        assert_eq!(shallow_file(&[]).unwrap(), "");
+        assert!(shallow_file(&[oid.clone(), oid[..39].to_string()]).is_err());
+        assert!(shallow_file(&[format!("{}\n{}", &oid[..19], &oid[..20])]).is_err());
+        assert!(shallow_file(&[oid.replace('0', "g")]).is_err());
+    }
+
+    #[test]
+    fn pack_chunk_refuses_an_unknown_repository() {
+        let fixture = Fixture::new();
+        let request = GitRequest::PackChunk {
+            repo: "missing".to_string(),
+            seq: 0,
+            haves: Vec::new(),
+            depth: None,
+            signature: None,
+        };
+        assert!(matches!(
+            handle(fixture.repos_dir(), request, &config()),
+            GitResponse::Error(_)
+        ));
+    }
+
+    #[test]
+    fn pack_chunk_keeps_concurrent_transfers_apart() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let mut full = PackClient::new(Vec::new(), None);
+        let mut shallow = PackClient::new(Vec::new(), Some(1));
+
+        // the shallow transfer starts while the full one is in flight, and generates its own
+        // packfile instead of replacing the one the full transfer reads
+        assert!(!full.next(fixture.repos_dir()).unwrap());
+        let (mut full_done, mut shallow_done) = (false, false);
+        while !full_done || !shallow_done {
+            if !shallow_done {
+                shallow_done = shallow.next(fixture.repos_dir()).unwrap();
+            }
+            if !full_done {
+                full_done = full.next(fixture.repos_dir()).unwrap();
+            }
+        }
+
+        let (full_pack, full_shallow) = full.finish();
+        let (shallow_pack, shallow_shallow) = shallow.finish();
+        assert!(full_shallow.is_empty());
+        assert_eq!(shallow_shallow, vec![fixture.main[2].to_string()]);
+        let full_client = TempDir::new().unwrap();
+        let full_client = index_pack(full_client.path(), &full_pack);
+        let shallow_client = TempDir::new().unwrap();
+        let shallow_client = index_pack(shallow_client.path(), &shallow_pack);
+        for oid in &fixture.main {
+            assert!(full_client.find_commit(*oid).is_ok(), "{oid} is missing");
+        }
+        assert!(shallow_client.find_commit(fixture.main[1]).is_err());
+        assert!(shallow_client.find_commit(fixture.main[3]).is_ok());
+    }
+
+    #[test]
+    fn pack_chunk_serves_concurrent_identical_clones() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let repos_dir = fixture.repos_dir();
+
+        // each clone may generate the packfile itself, but all of them are served the same one
+        let packs: Vec<(Vec<u8>, Vec<String>)> = thread::scope(|scope| {
+            let clones: Vec<_> = (0..4)
+                .map(|_| scope.spawn(move || fetch_pack(repos_dir, Vec::new(), Some(1))))
+                .collect();
+            clones
+                .into_iter()
+                .map(|clone| clone.join().unwrap())
+                .collect()
+        });
+        for (pack, shallow) in &packs {
+            assert!(pack == &packs[0].0, "the clones got different packfiles");
+            assert_eq!(shallow, &vec![fixture.main[2].to_string()]);
+        }
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &packs[0].0);
+        assert!(client.find_commit(fixture.main[1]).is_err());
+        assert!(client.find_commit(fixture.main[2]).is_ok());
+        assert!(client.find_commit(fixture.main[3]).is_ok());
+    }
+
+    #[test]
+    fn pack_chunk_finishes_a_transfer_from_its_packfile_when_the_refs_change() {
+        let mut fixture = Fixture::new();
+        let large = commit_large_file(&mut fixture);
+        let mut client = PackClient::new(Vec::new(), None);
+        assert!(!client.next(fixture.repos_dir()).unwrap());
+
+        let late = fixture.commit_on_main("late", b"late");
+        while !client.next(fixture.repos_dir()).unwrap() {}
+        let (pack, _) = client.finish();
+        let client = TempDir::new().unwrap();
+        let client = index_pack(client.path(), &pack);
+        assert!(client.find_commit(large).is_ok());
+        assert!(client.find_commit(late).is_err());
+    }
+
+    #[test]
+    fn pack_chunk_fails_a_legacy_transfer_when_the_refs_change() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let mut client = PackClient::legacy(Vec::new(), None);
+        assert!(!client.next(fixture.repos_dir()).unwrap());
+
+        fixture.commit_on_main("late", b"late");
+        assert!(client.next(fixture.repos_dir()).is_err());
+    }
+
+    #[test]
+    fn pack_chunk_fails_once_the_packfile_is_gone() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let mut client = PackClient::new(Vec::new(), None);
+        assert!(!client.next(fixture.repos_dir()).unwrap());
+
+        let repo_path = fixture.repos_dir().join(REPO);
+        let (pack_path, _) = pack_paths(&repo_path, client.signature.as_deref().unwrap());
+        fs::remove_file(pack_path).unwrap();
+        assert!(client.next(fixture.repos_dir()).is_err());
+    }
+
+    #[test]
+    fn pack_chunk_refuses_an_invalid_signature() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let request = GitRequest::PackChunk {
+            repo: REPO.to_string(),
+            seq: 1,
+            haves: Vec::new(),
+            depth: None,
+            signature: Some("../../etc/passwd".to_string()),
+        };
+        assert!(matches!(
+            handle(fixture.repos_dir(), request, &config()),
+            GitResponse::Error(_)
+        ));
+    }
+
+    #[test]
+    fn pack_chunk_times_out_past_the_pack_timeout() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let request = GitRequest::PackChunk {
+            repo: REPO.to_string(),
+            seq: 0,
+            haves: Vec::new(),
+            depth: None,
+            signature: None,
+        };
+        let config = ServerConfig {
+            pack_timeout: Duration::ZERO,
+            ..config()
+        };
+        let GitResponse::Error(e) = handle(fixture.repos_dir(), request, &config) else {
+            panic!("expected the pack generation to time out");
+        };
+        assert_eq!(e, TIMED_OUT);
+
+        // nothing is published or left behind, so the next transfer generates a complete packfile
+        let packs: Vec<_> = fs::read_dir(fixture.repos_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != REPO)
+            .collect();
+        assert!(packs.is_empty(), "left behind {packs:?}");
+        let (pack, _) = fetch_pack(fixture.repos_dir(), Vec::new(), None);
+        let dir = TempDir::new().unwrap();
+        let clone = index_pack(dir.path(), &pack);
+        assert!(clone.find_commit(*fixture.main.last().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn pack_chunk_exceeds_a_budget_shorter_than_the_pack_timeout() {
+        let mut fixture = Fixture::new();
+        commit_large_file(&mut fixture);
+        let request = GitRequest::WithDeadline {
+            budget_ms: 0,
+            request: Box::new(GitRequest::PackChunk {
+                repo: REPO.to_string(),
+                seq: 0,
+                haves: Vec::new(),
+                depth: None,
+                signature: None,
+            }),
+        };
+        let GitResponse::Error(e) = handle(fixture.repos_dir(), request, &config()) else {
+            panic!("expected the pack generation to run out of budget");
+        };
+        assert_eq!(e, DEADLINE_EXCEEDED);
+    }
+
+    #[test]
+    fn answer_panics_turns_a_panicking_handler_into_an_error() {
+        let responses = [
+            answer_panics(|| panic!("static message")),
+            answer_panics(|| panic!("formatted {}", "message")),
+            answer_panics(|| panic::panic_any(42)),
+        ];
+        for response in responses {
+            match response {
+                GitResponse::Error(e) => assert_eq!(e, "Internal error while handling the request"),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn answer_panics_passes_a_response_through() {
+        let fixture = Fixture::new();
+        let request = GitRequest::LsRemoteChunk {
+            repo: REPO.to_string(),
+            after: None,
+        };
+        let response = answer_panics(|| handle(fixture.repos_dir(), request, &config()));
+        assert!(matches!(response, GitResponse::LsRemoteChunk { .. }));
+    }
+
+    #[test]
+    fn respond_passes_an_error_response_through() {
+        // a request that fails is answered with its error, not a generic internal error
+        let request = GitRequest::Push("origin".to_string(), Vec::new());
+        let GitResponse::Error(e) =
+            respond(&StatusSnapshots::default(), &peer(), request, &config())
+        else {
+            panic!("expected an error response");
+        };
+        assert!(e.starts_with("Push not yet implemented"), "{e}");
+    }
+
+    // Leave `count` untracked files in the directory `dir` of the worktree of the fixture
+    fn dirty(fixture: &Fixture, dir: &str, count: usize) {
+        let dir = fixture.repos_dir().join(REPO).join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..count {
+            fs::write(dir.join(format!("{i:05}")), b"dirty").unwrap();
+        }
+    }
+
+    // Request chunk `seq` of the status of the fixture, returning its lines, total and whether it
+    // was the last one
+    fn status_chunk_of(
+        fixture: &Fixture,
+        statuses: &StatusSnapshots,
+        peer: &PeerId,
+        seq: u64,
+    ) -> Result<(Vec<String>, u64, bool), String> {
+        let request = GitRequest::StatusChunk {
+            repo: REPO.to_string(),
+            seq,
+        };
+        match handle_request_in(fixture.repos_dir(), statuses, peer, request, &config()) {
+            GitResponse::StatusChunk {
+                seq: served,
+                lines,
+                total,
+                done,
+                truncated,
+            } => {
+                assert_eq!(served, seq);
+                assert!(!truncated);
+                Ok((lines, total, done))
+            }
+            GitResponse::Error(e) => Err(e),
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    #[test]
+    fn status_chunk_serves_a_consistent_snapshot_of_many_dirty_files() {
+        let fixture = Fixture::new();
+        dirty(&fixture, "dirty", 2 * GIT_STATUS_CHUNK_LINES + 100);
+        let expected = status_snapshot(&fixture.repos_dir().join(REPO)).unwrap();
+        assert!(expected.lines.len() > 2 * GIT_STATUS_CHUNK_LINES);
+
+        let statuses = StatusSnapshots::default();
+        let peer = peer();
+        let mut lines = Vec::new();
+        let mut seq = 0;
+        loop {
+            let (chunk, total, done) = status_chunk_of(&fixture, &statuses, &peer, seq).unwrap();
+            assert_eq!(total, expected.total as u64);
+            assert!(chunk.len() <= GIT_STATUS_CHUNK_LINES);
+            lines.extend(chunk);
+            if seq == 0 {
+                // the worktree changes while the listing is in progress
+                fs::remove_file(fixture.repos_dir().join(REPO).join("dirty/00000")).unwrap();
+                dirty(&fixture, "late", 50);
+            }
+            if done {
+                break;
+            }
+            seq += 1;
+        }
+        assert_eq!(lines, expected.lines);
+        assert!(statuses.is_empty());
+
+        // the next listing sees the changes
+        let (_, total, _) = status_chunk_of(&fixture, &statuses, &peer, 0).unwrap();
+        assert_eq!(total, expected.total as u64 + 49);
+    }
+
+    #[test]
+    fn status_serves_the_first_chunk_of_every_repository() {
+        let fixture = Fixture::new();
+        dirty(&fixture, "dirty", GIT_STATUS_CHUNK_LINES + 10);
+        let expected = status_snapshot(&fixture.repos_dir().join(REPO)).unwrap();
+        let statuses = StatusSnapshots::default();
+        let request = GitRequest::Status;
+        let GitResponse::Status(status) =
+            handle_request_in(fixture.repos_dir(), &statuses, &peer(), request, &config())
+        else {
+            panic!("expected a status response");
+        };
+        let lines: Vec<&str> = status.lines().collect();
+        assert_eq!(lines.len(), GIT_STATUS_CHUNK_LINES + 1);
+        assert!(lines[0].starts_with(&format!("{REPO}: ")), "{}", lines[0]);
+        let left_out = expected.total - GIT_STATUS_CHUNK_LINES;
+        assert!(lines[GIT_STATUS_CHUNK_LINES].starts_with(&format!("... {left_out} more")));
+        // the legacy request doesn't hold a snapshot
+        assert!(statuses.is_empty());
+    }
+
+    #[test]
+    fn status_chunk_requires_a_listing_in_progress() {
+        let fixture = Fixture::new();
+        dirty(&fixture, "dirty", 2 * GIT_STATUS_CHUNK_LINES);
+        let statuses = StatusSnapshots::default();
+        assert!(status_chunk_of(&fixture, &statuses, &peer(), 1).is_err());
+    }
+
+    #[test]
+    fn status_chunk_keeps_the_listings_of_peers_apart() {
+        let fixture = Fixture::new();
+        dirty(&fixture, "dirty", 2 * GIT_STATUS_CHUNK_LINES);
+        let statuses = StatusSnapshots::default();
+        let (first, second) = (peer(), peer());
+
+        let (_, first_total, _) = status_chunk_of(&fixture, &statuses, &first, 0).unwrap();
+        dirty(&fixture, "late", 10);
+        let (_, second_total, _) = status_chunk_of(&fixture, &statuses, &second, 0).unwrap();
+        assert_eq!(second_total, first_total + 10);
+        assert_eq!(statuses.len(), 2);
+
+        // each peer goes on with its own snapshot, until it disconnects
+        let (_, total, _) = status_chunk_of(&fixture, &statuses, &first, 1).unwrap();
+        assert_eq!(total, first_total);
+        statuses.forget(&first);
+        assert!(status_chunk_of(&fixture, &statuses, &first, 2).is_err());
+        let (_, total, _) = status_chunk_of(&fixture, &statuses, &second, 1).unwrap();
+        assert_eq!(total, second_total);
+    }
+
+    #[test]
+    fn status_listing_goes_on_across_threads_of_the_blocking_pool() {
+        let fixture = Fixture::new();
+        dirty(&fixture, "dirty", 2 * GIT_STATUS_CHUNK_LINES);
+        let statuses = StatusSnapshots::default();
+        let peer = peer();
+
+        // each chunk is handled on its own thread with a clone of the snapshots, like the peer does
+        let chunk = |seq| {
+            let statuses = statuses.clone();
+            thread::scope(|scope| {
+                scope
+                    .spawn(|| status_chunk_of(&fixture, &statuses, &peer, seq))
+                    .join()
+                    .unwrap()
+            })
+        };
+        let (_, total, done) = chunk(0).unwrap();
+        assert!(!done);
+        assert_eq!(statuses.len(), 1);
+        let (_, served_total, _) = chunk(1).unwrap();
+        assert_eq!(served_total, total);
+    }
+
+    #[test]
+    fn ls_remote_chunk_lists_head_branches_and_tags() {
+        let fixture = Fixture::new();
+        let request = GitRequest::LsRemoteChunk {
+            repo: REPO.to_string(),
+            after: None,
+        };
+        let GitResponse::LsRemoteChunk { refs, next } =
+            handle(fixture.repos_dir(), request, &config())
+        else {
+            panic!("expected a ref listing");
+        };
+
+        assert_eq!(next, None);
+        assert_eq!(
+            refs,
+            vec![
+                ("HEAD".to_string(), fixture.main[2].to_string()),
+                (
+                    "refs/heads/feature".to_string(),
+                    fixture.feature.to_string()
+                ),
+                ("refs/heads/main".to_string(), fixture.main[2].to_string()),
+                ("refs/tags/v1".to_string(), fixture.main[0].to_string()),
+                ("refs/tags/v2".to_string(), fixture.v2.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ls_remote_chunk_resumes_after_the_token() {
+        let fixture = Fixture::new();
+        let request = GitRequest::LsRemoteChunk {
+            repo: REPO.to_string(),
+            after: Some("refs/heads/main".to_string()),
+        };
+        let GitResponse::LsRemoteChunk { refs, next } =
+            handle(fixture.repos_dir(), request, &config())
+        else {
+            panic!("expected a ref listing");
+        };
+
+        assert_eq!(next, None);
+        let names: Vec<&str> = refs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["refs/tags/v1", "refs/tags/v2"]);
+    }
+}
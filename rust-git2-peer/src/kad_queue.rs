@@ -0,0 +1,69 @@
+use libp2p::{kad::RecordKey, PeerId};
+use std::collections::VecDeque;
+
+/// A Kademlia query that can be queued until there is room for it to run
+#[derive(Clone, Debug)]
+pub enum KadQuery {
+    /// Look up the closest peers to a peer
+    GetClosestPeers(PeerId),
+    /// Announce that we provide a record
+    StartProviding(RecordKey),
+    /// Look up the providers of a record
+    GetProviders(RecordKey),
+    /// Refresh the routing table, started by the bootstrap command when `manual`
+    Bootstrap {
+        /// Whether the bootstrap command started it
+        manual: bool,
+    },
+}
+
+/// Caps the number of Kademlia queries in progress, queueing the excess so a burst of lookups
+/// can't flood the network
+#[derive(Debug)]
+pub struct KadQueryQueue {
+    max_active: usize,
+    queued: VecDeque<KadQuery>,
+}
+
+impl KadQueryQueue {
+    /// Create a queue allowing at most `max_active` queries in progress
+    pub fn new(max_active: usize) -> Self {
+        Self {
+            max_active,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Queue a query, returning it back if it can start now with `active` queries in progress
+    pub fn push(&mut self, query: KadQuery, active: usize) -> Option<KadQuery> {
+        if active < self.max_active && self.queued.is_empty() {
+            return Some(query);
+        }
+        self.queued.push_back(query);
+        None
+    }
+
+    /// Take the next queued query if there is room for it with `active` queries in progress
+    pub fn pop(&mut self, active: usize) -> Option<KadQuery> {
+        if active < self.max_active {
+            self.queued.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// The maximum number of queries in progress
+    pub fn max_active(&self) -> usize {
+        self.max_active
+    }
+
+    /// The number of queued queries
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Check if no queries are queued
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
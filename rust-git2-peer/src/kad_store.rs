@@ -0,0 +1,209 @@
+use libp2p::{
+    kad::{
+        store::{Error, MemoryStore, MemoryStoreConfig, RecordStore, Result},
+        ProviderRecord, Record, RecordKey,
+    },
+    PeerId,
+};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+};
+use tracing::warn;
+
+/// A Kademlia [`MemoryStore`] that evicts the least recently used entries when full.
+///
+/// A plain `MemoryStore` rejects new records and providers once it reaches its limits, so a busy
+/// DHT node stops accepting provider records for good. This store instead evicts the record, or
+/// the providers of the key, that was least recently stored or looked up. Keys we provide
+/// ourselves are never evicted.
+pub struct LruMemoryStore {
+    inner: MemoryStore,
+    local_id: PeerId,
+    max_records: usize,
+    max_provider_keys: usize,
+    // the tick each record and provider key was last used at, updated by lookups too
+    records: RefCell<HashMap<RecordKey, u64>>,
+    provider_keys: RefCell<HashMap<RecordKey, u64>>,
+    tick: Cell<u64>,
+    evictions: u64,
+}
+
+// MemoryStore isn't Debug, the records it holds are summarized by their count
+impl fmt::Debug for LruMemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LruMemoryStore")
+            .field("local_id", &self.local_id)
+            .field("max_records", &self.max_records)
+            .field("max_provider_keys", &self.max_provider_keys)
+            .field("records", &self.records.borrow().len())
+            .field("provider_keys", &self.provider_keys.borrow().len())
+            .field("evictions", &self.evictions)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How full a [`LruMemoryStore`] is
+#[derive(Clone, Copy, Debug)]
+pub struct StoreUtilization {
+    /// The number of records held
+    pub records: usize,
+    /// The maximum number of records
+    pub max_records: usize,
+    /// The number of keys with provider records
+    pub provider_keys: usize,
+    /// The maximum number of keys with provider records
+    pub max_provider_keys: usize,
+    /// The number of records and provider keys evicted since startup
+    pub evictions: u64,
+}
+
+impl LruMemoryStore {
+    /// Create a store holding at most `max_records` records and the providers of at most
+    /// `max_provider_keys` keys
+    pub fn new(local_id: PeerId, max_records: usize, max_provider_keys: usize) -> Self {
+        let config = MemoryStoreConfig {
+            max_records,
+            max_provided_keys: max_provider_keys,
+            ..Default::default()
+        };
+        Self {
+            inner: MemoryStore::with_config(local_id, config),
+            local_id,
+            max_records,
+            max_provider_keys,
+            records: RefCell::default(),
+            provider_keys: RefCell::default(),
+            tick: Cell::new(0),
+            evictions: 0,
+        }
+    }
+
+    /// Get how full the store is
+    pub fn utilization(&self) -> StoreUtilization {
+        StoreUtilization {
+            records: self.records.borrow().len(),
+            max_records: self.max_records,
+            provider_keys: self.provider_keys.borrow().len(),
+            max_provider_keys: self.max_provider_keys,
+            evictions: self.evictions,
+        }
+    }
+
+    fn touch(&self, keys: &RefCell<HashMap<RecordKey, u64>>, key: &RecordKey) {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        if let Some(used) = keys.borrow_mut().get_mut(key) {
+            *used = tick;
+        }
+    }
+
+    fn evict_record(&mut self) -> bool {
+        let lru = self
+            .records
+            .borrow()
+            .iter()
+            .min_by_key(|(_, used)| **used)
+            .map(|(key, _)| key.clone());
+        let Some(key) = lru else {
+            return false;
+        };
+        warn!("Kademlia store is full, evicting the least recently used record {key:?}");
+        self.remove(&key);
+        self.evictions += 1;
+        true
+    }
+
+    fn evict_provider_key(&mut self) -> bool {
+        let lru = self
+            .provider_keys
+            .borrow()
+            .iter()
+            .filter(|(key, _)| {
+                !self
+                    .inner
+                    .providers(key)
+                    .iter()
+                    .any(|p| p.provider == self.local_id)
+            })
+            .min_by_key(|(_, used)| **used)
+            .map(|(key, _)| key.clone());
+        let Some(key) = lru else {
+            return false;
+        };
+        warn!(
+            "Kademlia store is full, evicting the providers of the least recently used key {key:?}"
+        );
+        for record in self.inner.providers(&key) {
+            self.inner.remove_provider(&key, &record.provider);
+        }
+        self.provider_keys.borrow_mut().remove(&key);
+        self.evictions += 1;
+        true
+    }
+}
+
+impl RecordStore for LruMemoryStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.touch(&self.records, k);
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        let key = r.key.clone();
+        let result = match self.inner.put(r.clone()) {
+            Err(Error::MaxRecords) if self.evict_record() => self.inner.put(r),
+            result => result,
+        };
+        if result.is_ok() {
+            self.records.borrow_mut().insert(key.clone(), 0);
+            self.touch(&self.records, &key);
+        }
+        result
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.records.borrow_mut().remove(k);
+        self.inner.remove(k)
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        let key = record.key.clone();
+        let result = match self.inner.add_provider(record.clone()) {
+            Err(Error::MaxProvidedKeys) if self.evict_provider_key() => {
+                self.inner.add_provider(record)
+            }
+            result => result,
+        };
+        if result.is_ok() {
+            self.provider_keys.borrow_mut().insert(key.clone(), 0);
+            self.touch(&self.provider_keys, &key);
+        }
+        result
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.touch(&self.provider_keys, key);
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.borrow_mut().remove(k);
+        }
+    }
+}
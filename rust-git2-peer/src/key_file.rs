@@ -0,0 +1,246 @@
+//! Loading and creating the identity of the peer.
+//!
+//! The identity is stored as two files next to each other: `<path>.key` holds the protobuf encoded
+//! key pair and `<path>.peerid` the peer id derived from it, for reference. Older versions of the
+//! rust-peer stored the key pair alone in a single file without an extension, `./local_key` by
+//! default. That legacy layout is still understood: when there is no `.key` file, a legacy key is
+//! used instead of generating a new identity, and it can be migrated to the `.key`/`.peerid`
+//! layout keeping its peer id.
+
+use anyhow::{bail, Result};
+use libp2p::{identity, PeerId};
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// The delay before the first retry of a transient I/O error, doubled for each further retry
+const IO_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Read the identity at `path`, falling back to the legacy key file at `legacy_path` if there is
+/// no `.key` file, or create a new identity if neither exists
+pub async fn read_or_create_identity(
+    path: &Path,
+    legacy_path: Option<&Path>,
+    regen_corrupt: bool,
+    retries: u32,
+) -> Result<identity::Keypair> {
+    let (key_path, peer_id_path) = identity_paths(path);
+
+    if retry_io(retries, || fs::try_exists(&key_path)).await? {
+        match read_identity(&key_path, retries).await {
+            Ok(identity) => {
+                info!("Using existing identity from {}", key_path.display());
+                return Ok(identity);
+            }
+            Err(e) if regen_corrupt => {
+                warn!(
+                    "Identity {} is corrupt ({e}), generating a new one with a new peer id",
+                    key_path.display()
+                );
+                fs::remove_file(&key_path).await?;
+                let _ = fs::remove_file(&peer_id_path).await;
+            }
+            Err(e) => bail!(
+                "Identity {} is corrupt: {e}. Delete it or run with --regen-corrupt-cert to generate a new one",
+                key_path.display()
+            ),
+        }
+    } else if let Some(legacy_path) = legacy_path {
+        if retry_io(retries, || fs::try_exists(legacy_path)).await? {
+            let identity = read_identity(legacy_path, retries).await?;
+            warn!(
+                "Using legacy identity from {}, run with --migrate-legacy-key to move it to {}",
+                legacy_path.display(),
+                key_path.display()
+            );
+            return Ok(identity);
+        }
+    }
+
+    let identity = identity::Keypair::generate_ed25519();
+    if !create_new(&key_path, &identity.to_protobuf_encoding()?, retries).await? {
+        // another instance started at the same time and won the race, use its identity
+        info!(
+            "Identity {} was created by another instance, using it",
+            key_path.display()
+        );
+        return read_identity(&key_path, retries).await;
+    }
+    // a .peerid left behind by a deleted key names another identity, so it is replaced
+    let peer_id: PeerId = identity.public().into();
+    replace(&peer_id_path, peer_id.to_string().as_bytes(), retries).await?;
+
+    info!(
+        "Generated new identity and wrote it to {}",
+        key_path.display()
+    );
+
+    Ok(identity)
+}
+
+/// Migrate the legacy key file at `legacy_path` to the `.key`/`.peerid` layout at `path`, keeping
+/// the peer id. An existing identity at `path` is never overwritten and the legacy file is left in
+/// place. Returns true if the identity was migrated.
+pub async fn migrate_legacy_identity(
+    path: &Path,
+    legacy_path: &Path,
+    retries: u32,
+) -> Result<bool> {
+    let (key_path, peer_id_path) = identity_paths(path);
+
+    if !retry_io(retries, || fs::try_exists(legacy_path)).await? {
+        info!(
+            "No legacy identity at {}, nothing to migrate",
+            legacy_path.display()
+        );
+        return Ok(false);
+    }
+    let identity = read_identity(legacy_path, retries).await?;
+    let peer_id: PeerId = identity.public().into();
+
+    if !create_new(&key_path, &identity.to_protobuf_encoding()?, retries).await? {
+        let existing: PeerId = read_identity(&key_path, retries).await?.public().into();
+        if existing == peer_id {
+            info!(
+                "Legacy identity {} was already migrated to {}",
+                legacy_path.display(),
+                key_path.display()
+            );
+        } else {
+            warn!(
+                "Not migrating legacy identity {} ({peer_id}): {} already holds identity {existing}",
+                legacy_path.display(),
+                key_path.display()
+            );
+        }
+        return Ok(false);
+    }
+    if !create_new(&peer_id_path, peer_id.to_string().as_bytes(), retries).await? {
+        warn!(
+            "Not overwriting {}, it should contain {peer_id}",
+            peer_id_path.display()
+        );
+    }
+
+    info!(
+        "Migrated legacy identity {} to {} keeping peer id {peer_id}, the legacy file can be removed",
+        legacy_path.display(),
+        key_path.display()
+    );
+    Ok(true)
+}
+
+/// The paths of the key file and the peer id file for the identity at `path`
+pub fn identity_paths(path: &Path) -> (PathBuf, PathBuf) {
+    let mut key_path = PathBuf::from(path);
+    let is_key = key_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext == "key")
+        .unwrap_or(false);
+    if !is_key {
+        key_path.set_extension("key");
+    }
+
+    let mut peer_id_path = PathBuf::from(path);
+    let is_peer_id = peer_id_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext == "peerid")
+        .unwrap_or(false);
+    if !is_peer_id {
+        peer_id_path.set_extension("peerid");
+    }
+
+    (key_path, peer_id_path)
+}
+
+async fn read_identity(path: &Path, retries: u32) -> Result<identity::Keypair> {
+    let bytes = retry_io(retries, || fs::read(path)).await?;
+    Ok(identity::Keypair::from_protobuf_encoding(&bytes)?)
+}
+
+/// Atomically create `path` with `contents` if it doesn't exist yet, returning false if it already
+/// does. The contents are written to a temporary file first and then hard linked into place, which
+/// fails if the path exists, so a concurrent reader never sees a partially written file and a
+/// concurrent writer never overwrites the file. The temporary file is unique to the call, so
+/// writers in the same process don't clobber each other's temporary file either.
+pub async fn create_new(path: &Path, contents: &[u8], retries: u32) -> Result<bool> {
+    let tmp_path = tmp_path(path);
+    retry_io(retries, || fs::write(&tmp_path, contents)).await?;
+    let linked = retry_io(retries, || fs::hard_link(&tmp_path, path)).await;
+    retry_io(retries, || fs::remove_file(&tmp_path)).await?;
+
+    match linked {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Atomically write `path` with `contents`, replacing it if it exists. The contents are written
+/// to a temporary file first and then renamed into place, so a concurrent reader sees either the
+/// old or the new file.
+pub async fn replace(path: &Path, contents: &[u8], retries: u32) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    retry_io(retries, || fs::write(&tmp_path, contents)).await?;
+    if let Err(e) = retry_io(retries, || fs::rename(&tmp_path, path)).await {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// A temporary file next to `path`, unique to the call, so writers in the same process don't
+// clobber each other's temporary file
+fn tmp_path(path: &Path) -> PathBuf {
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(tmp_path)
+}
+
+/// Run a filesystem operation, retrying transient I/O errors up to `retries` times with an
+/// exponential backoff. A missing or already existing file is an answer rather than a failure, so
+/// those errors, like the other permanent ones, are returned straight away.
+pub async fn retry_io<T, F, Fut>(retries: u32, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let backoff = IO_RETRY_BACKOFF * 2u32.saturating_pow(attempt);
+                warn!("Transient I/O error ({e}), retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Check if an I/O error may go away when the operation is retried
+fn is_transient(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::AlreadyExists
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::Unsupported
+    )
+}
@@ -7,20 +7,110 @@
     unused_qualifications
 )]
 
+/// The connection address change module
+pub mod address_change;
+pub use address_change::{AddressChangeTracker, AddressChanged};
+
+/// The WebRTC certificate rotation module
+pub mod cert_rotation;
+
+/// The chat message envelope module
+pub mod chat_envelope;
+pub use chat_envelope::ChatEnvelope;
+
 /// The chat peer module
 pub mod chatpeer;
 pub use chatpeer::ChatPeer;
 
+/// The content addressing module
+pub mod content_hash;
+pub use content_hash::ContentHash;
+
+/// The clock skew estimation module
+pub mod clock_skew;
+pub use clock_skew::ClockSkew;
+
+/// The status dashboard module
+pub mod dashboard;
+pub use dashboard::DASHBOARD_UPDATE_INTERVAL;
+
+/// The dial coalescing module
+pub mod dial_coalescer;
+pub use dial_coalescer::DialCoalescer;
+
+/// The peer echo diagnostics protocol
+pub mod echo;
+pub use echo::{EchoCodec, EchoRequest, EchoResponse};
+
+/// The file fetch throttling module
+pub mod fetch_queue;
+pub use fetch_queue::{FetchDecision, FetchQueue, FileFetch};
+
+/// The file payload encryption module
+pub mod file_crypto;
+pub use file_crypto::FileDecryptor;
+
 /// The peer file transfer protocol
 pub mod file_exchange;
 pub use file_exchange::{Codec, Request, Response};
 
+/// The peer file manifest protocol
+pub mod file_manifest;
+pub use file_manifest::{ManifestCodec, ManifestEntry, ManifestRequest, ManifestResponse};
+
+/// The file offer module
+pub mod file_offer;
+pub use file_offer::FileOffer;
+
+/// The SQLite file index module
+#[cfg(feature = "sqlite-index")]
+pub mod file_index;
+#[cfg(feature = "sqlite-index")]
+pub use file_index::{FileIndex, IndexedFile};
+
+/// The file store module
+pub mod file_store;
+pub use file_store::{FileStore, InflightRequests, OutstandingRequests, Reprovider};
+
+/// The git archive module
+pub mod git_archive;
+pub use git_archive::ArchiveFormat;
+
 /// The peer git transfer protocol
 pub mod git_exchange;
 
+/// The git request handlers
+pub mod git_server;
+
+/// The Kademlia query queue module
+pub mod kad_queue;
+pub use kad_queue::{KadQuery, KadQueryQueue};
+
+/// The Kademlia record store module
+pub mod kad_store;
+pub use kad_store::{LruMemoryStore, StoreUtilization};
+
+/// The identity key file module
+pub mod key_file;
+pub use key_file::{
+    create_new, identity_paths, migrate_legacy_identity, read_or_create_identity, retry_io,
+};
+
+/// The listen interface module
+pub mod listen_interface;
+pub use listen_interface::ListenInterface;
+
 /// The peer logging module
 pub mod log;
-pub use log::Log;
+pub use log::{Log, LogBuffer, LogControl, LogHandle};
+
+/// The unsent message buffer module
+pub mod message_buffer;
+pub use message_buffer::MessageBuffer;
+
+/// The peer metrics module
+pub mod metrics;
+pub use metrics::Metrics;
 
 /// The peer message module
 pub mod message;
@@ -28,28 +118,104 @@ pub use message::Message;
 
 /// The command line options module
 pub mod options;
-pub use options::Options;
+pub use options::{Command, Options};
+
+/// The resumable file fetch module
+pub mod partial_file;
+pub use partial_file::PartialFile;
 
 /// The peer module
 pub mod peer;
 pub use peer::Peer;
 
+/// The relay loop detection and circuit limits module
+pub mod relay_guard;
+pub use relay_guard::{LimitedRelayServer, RelayCircuitLimits, RelayLoopGuard};
+
+/// The peer reputation module
+pub mod reputation;
+pub use reputation::ReputationStore;
+
+/// The self-test module
+pub mod self_test;
+pub use self_test::SelfTestResult;
+
+/// The peer exchange protocol
+pub mod pex;
+pub use pex::{PexCodec, PexPeer, PexRequest, PexResponse};
+
+/// The versioned persisted file module
+pub mod persisted;
+pub use persisted::PersistedFile;
+
+/// The peer seeding module
+pub mod peer_seeds;
+pub use peer_seeds::{PeerSeed, PeerSeeds};
+
+/// The file provider index module
+pub mod provider_index;
+pub use provider_index::{ProviderAdvertisement, ProviderIndex};
+
+/// The protocol ids and topics module
+pub mod protocol_names;
+pub use protocol_names::ProtocolNames;
+
+/// The proxied TCP transport module
+pub mod proxy;
+pub use proxy::{Proxy, ProxyKind};
+
 /// The protobuf generated module
 mod proto {
-    #![allow(unreachable_pub)]
+    // generated/peer.rs is written by pb-rs 0.10.0 from generated/peer.proto and isn't edited by
+    // hand, it qualifies the enums it references with the protobuf package and casts every varint
+    // to u64
+    #![allow(unreachable_pub, unused_qualifications, trivial_numeric_casts)]
     include!("generated/mod.rs");
-    pub(crate) use self::peer::Peer;
+    pub(crate) use self::peer::{Peer, Presence};
 }
 
 /// The peer ui module
 pub mod ui;
 pub use ui::{Headless, Tui, Ui};
 
+/// The per-topic message authentication module
+pub mod topic_auth;
+pub use topic_auth::{SignedAuthorTransform, TopicAuth, TopicPolicies};
+
+/// The connection upgrade timeout module
+pub mod upgrade_timeout;
+pub use upgrade_timeout::{is_upgrade_timeout, UpgradeTimeout, UpgradeTimeoutError};
+
+/// The served directories module
+pub mod serve_dir;
+pub use serve_dir::{ServeDir, ServedFile};
+
+/// The local server address module
+pub mod serve_addr;
+pub use serve_addr::{ServeAddr, ServeListener, ServeStream};
+
+/// The control socket module
+pub mod control;
+pub use control::{ControlCommand, ControlCommands};
+
+/// The per-topic activity module
+pub mod topic_stats;
+pub use topic_stats::{TopicActivity, TopicStats};
+
+/// The in-flight transfers module
+pub mod transfers;
+pub use transfers::{Transfer, TransferId, TransferProtocol, Transfers};
+
+/// The unsupported request protocols module
+pub mod unsupported_protocols;
+pub use unsupported_protocols::UnsupportedProtocols;
+
 /// The misc util module
 pub mod util;
 pub use util::{
     decode_unknown_protobuf, extract_ip_multiaddr, ipaddr_to_multiaddr, is_private_ip,
-    pretty_print_fields, split_peer_id, WireType,
+    address_family, listen_error, order_dial_addresses, pretty_print_fields, read_peer_list, split_peer_id, transport_rank, verbose_error,
+    AddressFamilyPreference, PreferredTransport, WireType,
 };
 
 /// Prelude module
@@ -0,0 +1,87 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// How often the addresses of the interface are resolved again
+pub const INTERFACE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A network interface listened on by name, such as `eth0` or `tailscale0`, whose addresses are
+/// resolved at startup and again periodically so listeners follow a dynamic address
+#[derive(Clone, Debug)]
+pub struct ListenInterface {
+    name: String,
+    addresses: HashSet<IpAddr>,
+    next_check: Instant,
+}
+
+impl ListenInterface {
+    /// Resolve the addresses of an interface, failing if it doesn't exist or has none
+    pub fn resolve(name: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            addresses: interface_addresses(name)?,
+            next_check: Instant::now() + INTERFACE_CHECK_INTERVAL,
+        })
+    }
+
+    /// The name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The addresses of the interface when it was last resolved
+    pub fn addresses(&self) -> impl Iterator<Item = &IpAddr> {
+        self.addresses.iter()
+    }
+
+    /// Check if it is time to resolve the addresses again
+    pub fn due(&self, now: Instant) -> bool {
+        now >= self.next_check
+    }
+
+    /// Resolve the addresses again, returning the ones that were added and removed since the last
+    /// time. If the interface is gone or has no addresses the previous ones are kept, so a
+    /// briefly down VPN doesn't close every listener.
+    pub fn refresh(&mut self, now: Instant) -> anyhow::Result<(Vec<IpAddr>, Vec<IpAddr>)> {
+        self.next_check = now + INTERFACE_CHECK_INTERVAL;
+        let addresses = interface_addresses(&self.name)?;
+        let added = addresses.difference(&self.addresses).copied().collect();
+        let removed = self.addresses.difference(&addresses).copied().collect();
+        self.addresses = addresses;
+        Ok((added, removed))
+    }
+}
+
+/// The addresses of the interface `name` that can be listened on. IPv6 link-local addresses are
+/// left out, a multiaddr can't carry the zone they need.
+pub fn interface_addresses(name: &str) -> anyhow::Result<HashSet<IpAddr>> {
+    let interfaces = if_addrs::get_if_addrs()?;
+    if !interfaces.iter().any(|interface| interface.name == name) {
+        let names: BTreeSet<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        anyhow::bail!(
+            "Network interface {name} not found, the interfaces are: {}",
+            names.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let addresses: HashSet<IpAddr> = interfaces
+        .iter()
+        .filter(|interface| interface.name == name)
+        .map(|interface| interface.ip())
+        .filter(|ip| !is_ipv6_link_local(ip))
+        .collect();
+    if addresses.is_empty() {
+        anyhow::bail!("Network interface {name} has no addresses to listen on");
+    }
+    Ok(addresses)
+}
+
+// Check if an address is an IPv6 link-local address, fe80::/10
+fn is_ipv6_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
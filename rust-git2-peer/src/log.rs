@@ -1,16 +1,56 @@
-use std::fmt;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, Receiver, Sender},
+    watch,
+};
 use tracing::{
     field::{Field, Visit},
+    subscriber::DefaultGuard,
     Event, Level, Subscriber,
 };
 use tracing_subscriber::{
-    filter::EnvFilter, layer::Context, prelude::*, registry::LookupSpan, Layer,
+    filter::{EnvFilter, LevelFilter},
+    layer::Context,
+    prelude::*,
+    registry::LookupSpan,
+    Layer,
 };
 
+// The number of log messages buffered for the UI
+const LOG_CHANNEL_CAPACITY: usize = 16;
+
+// The number of buffered log messages at which the UI is considered busy
+const LOG_BUSY_BACKLOG: usize = LOG_CHANNEL_CAPACITY * 3 / 4;
+
 // Custom tracing layer to send log events over mpsc
 struct MpscLayer {
     sender: Sender<Message>,
+    // the most verbose level sent while throttled, everything is sent if None
+    throttle: watch::Receiver<Option<Level>>,
+    // the number of messages dropped since the UI last asked
+    suppressed: Arc<AtomicU64>,
+    // where the messages go once the UI has stopped
+    stderr: Stderr,
+}
+
+// Writes the log messages the UI doesn't receive to stderr, or to the buffer of a capturing
+// logger so tests can check them
+#[derive(Clone, Debug, Default)]
+struct Stderr(Option<LogBuffer>);
+
+impl Stderr {
+    fn write(&self, line: impl fmt::Display) {
+        match &self.0 {
+            Some(buffer) => buffer.stderr.lock().unwrap().push(line.to_string()),
+            None => eprintln!("{line}"),
+        }
+    }
 }
 
 /// Custom tracing event that is send and sync
@@ -22,6 +62,12 @@ pub struct Message {
     pub message: String,
 }
 
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5} {}", self.level, self.message)
+    }
+}
+
 // Implement a visitor to extract fields from the event
 struct FieldVisitor {
     message: Option<String>,
@@ -35,20 +81,166 @@ impl Visit for FieldVisitor {
     }
 }
 
+impl Message {
+    fn from_event(event: &Event<'_>) -> Self {
+        let mut visitor = FieldVisitor { message: None };
+        event.record(&mut visitor);
+
+        Message {
+            level: *event.metadata().level(),
+            message: visitor.message.unwrap_or_default(),
+        }
+    }
+}
+
 impl<S> Layer<S> for MpscLayer
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
     fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
-        let mut visitor = FieldVisitor { message: None };
-        event.record(&mut visitor);
+        // once the UI has stopped, or failed, messages go to stderr instead of being lost
+        if self.sender.is_closed() {
+            self.stderr.write(Message::from_event(event));
+            return;
+        }
+        let throttled = self
+            .throttle
+            .borrow()
+            .is_some_and(|max| *event.metadata().level() > max);
+        if throttled {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // messages the UI has no room for are counted instead of queued
+        match self.sender.try_send(Message::from_event(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(message)) => self.stderr.write(message),
+        }
+    }
+}
 
-        let event_data = Message {
-            level: *event.metadata().level(),
-            message: visitor.message.unwrap_or_default(),
-        };
+/// Lets the UI throttle the log messages sent to it by [`Log::init`] at runtime. While the UI
+/// falls behind, debug and trace messages are dropped so that a burst of them, such as during a
+/// dial storm, doesn't keep it busy, and messages it has no room for are dropped too. The dropped
+/// messages are counted so the UI can show how many it missed.
+#[derive(Debug)]
+pub struct LogControl {
+    throttle: watch::Sender<Option<Level>>,
+    suppressed: Arc<AtomicU64>,
+    stderr: Stderr,
+}
+
+impl LogControl {
+    /// Only send messages at `max` level and below, such as warnings and errors for
+    /// [`Level::WARN`], or every message if `None`
+    pub fn set_throttle(&self, max: Option<Level>) {
+        self.throttle.send_if_modified(|throttle| {
+            let modified = *throttle != max;
+            *throttle = max;
+            modified
+        });
+    }
+
+    /// The most verbose level sent, every message is sent if `None`
+    pub fn throttle(&self) -> Option<Level> {
+        *self.throttle.borrow()
+    }
+
+    /// Throttle debug and trace messages while `backlog` log messages wait for the UI, and stop
+    /// once it has caught up with all of them
+    pub fn adjust(&self, backlog: usize) {
+        if backlog >= LOG_BUSY_BACKLOG {
+            self.set_throttle(Some(Level::INFO));
+        } else if backlog == 0 {
+            self.set_throttle(None);
+        }
+    }
+
+    /// The number of messages dropped since the last call, coalescing them into one count
+    pub fn take_suppressed(&self) -> u64 {
+        self.suppressed.swap(0, Ordering::Relaxed)
+    }
 
-        let _ = self.sender.try_send(event_data);
+    /// Stop sending log messages to the UI and write the ones it hasn't received yet to stderr.
+    /// The UI calls this as it stops, after the peer has shut down and once the terminal is
+    /// restored, so the tail end of the session's log isn't lost. Later messages go straight to
+    /// stderr.
+    pub fn flush(&self, from_log: &mut Receiver<Message>) {
+        from_log.close();
+        while let Ok(message) = from_log.try_recv() {
+            self.stderr.write(message);
+        }
+        let suppressed = self.take_suppressed();
+        if suppressed > 0 {
+            self.stderr
+                .write(format_args!("{suppressed} log messages suppressed"));
+        }
+    }
+}
+
+/// The log messages captured by [`Log::init_capturing`], shared with the capturing layer
+#[derive(Clone, Debug, Default)]
+pub struct LogBuffer {
+    messages: Arc<Mutex<Vec<Message>>>,
+    // the lines the logger would have written to stderr
+    stderr: Arc<Mutex<Vec<String>>>,
+}
+
+impl LogBuffer {
+    /// Get a copy of the messages captured so far, oldest first
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Get a copy of the lines written to stderr instead of the UI so far, oldest first: the
+    /// messages logged once the UI has stopped and those [`LogControl::flush`] wrote out
+    pub fn stderr(&self) -> Vec<String> {
+        self.stderr.lock().unwrap().clone()
+    }
+
+    /// Check if a message at `level` containing `text` was captured
+    pub fn contains(&self, level: Level, text: &str) -> bool {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.level == level && m.message.contains(text))
+    }
+
+    /// Forget the messages captured so far
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+        self.stderr.lock().unwrap().clear();
+    }
+}
+
+impl<S> Layer<S> for LogBuffer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        self.messages
+            .lock()
+            .unwrap()
+            .push(Message::from_event(event));
+    }
+}
+
+/// Keeps [`Log::init_capturing`] capturing until it is dropped
+#[derive(Debug)]
+pub struct LogHandle {
+    _guard: DefaultGuard,
+    ui: Option<(Receiver<Message>, LogControl)>,
+}
+
+impl LogHandle {
+    /// Take the receiver for the log messages and their control, as [`Log::init`] returns them,
+    /// to stand in for the UI. Returns None once they were taken.
+    pub fn take_ui(&mut self) -> Option<(Receiver<Message>, LogControl)> {
+        self.ui.take()
     }
 }
 
@@ -57,15 +249,150 @@ where
 pub struct Log;
 
 impl Log {
-    /// Starts the logger and returns the task handle and receiver for the log messages.
-    pub fn init() -> Receiver<Message> {
-        let (sender, receiver) = mpsc::channel(16);
-
+    /// Starts the logger and returns the receiver for the log messages and the control to
+    /// throttle them with.
+    pub fn init() -> (Receiver<Message>, LogControl) {
+        let (layer, receiver, control) = Self::ui_layer(Stderr::default());
         let filter = EnvFilter::from_default_env();
-        let layer = MpscLayer { sender }.with_filter(filter);
 
-        tracing_subscriber::registry().with(layer).init();
+        tracing_subscriber::registry()
+            .with(layer.with_filter(filter))
+            .init();
+
+        (receiver, control)
+    }
+
+    /// Starts capturing log messages into a buffer instead of sending them to a UI, for tests.
+    /// Messages at debug level and above are captured unless RUST_LOG says otherwise.
+    ///
+    /// Unlike [`Log::init`] this doesn't install a global logger: it captures the messages logged
+    /// on the current thread until the returned handle is dropped, so use it with a single
+    /// threaded runtime. It can be called again by each test.
+    ///
+    /// The messages are also sent to a UI channel like the one [`Log::init`] returns, available
+    /// from [`LogHandle::take_ui`], with the lines that would go to stderr captured as well.
+    pub fn init_capturing() -> (LogHandle, LogBuffer) {
+        let buffer = LogBuffer::default();
+        let (ui_layer, receiver, control) = Self::ui_layer(Stderr(Some(buffer.clone())));
+
+        let filter = || {
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::DEBUG.into())
+                .from_env_lossy()
+        };
+        let guard = tracing_subscriber::registry()
+            .with(buffer.clone().with_filter(filter()))
+            .with(ui_layer.with_filter(filter()))
+            .set_default();
+
+        let handle = LogHandle {
+            _guard: guard,
+            ui: Some((receiver, control)),
+        };
+        (handle, buffer)
+    }
+
+    // The layer sending log messages to the UI, with the receiver for them and their control
+    fn ui_layer(stderr: Stderr) -> (MpscLayer, Receiver<Message>, LogControl) {
+        let (sender, receiver) = mpsc::channel(LOG_CHANNEL_CAPACITY);
+        let (throttle, throttle_receiver) = watch::channel(None);
+        let suppressed = Arc::new(AtomicU64::new(0));
+
+        let layer = MpscLayer {
+            sender,
+            throttle: throttle_receiver,
+            suppressed: suppressed.clone(),
+            stderr: stderr.clone(),
+        };
+        let control = LogControl {
+            throttle,
+            suppressed,
+            stderr,
+        };
+        (layer, receiver, control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, warn};
+
+    // A message as it is written to stderr
+    fn line(level: Level, message: &str) -> String {
+        Message {
+            level,
+            message: message.to_string(),
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn flush_writes_out_the_messages_logged_just_before_shutdown() {
+        let (mut handle, buffer) = Log::init_capturing();
+        let (mut from_log, control) = handle.take_ui().unwrap();
+
+        info!("first");
+        // the UI received this one before it stopped
+        assert_eq!(from_log.try_recv().unwrap().message, "first");
+        info!("shutting down");
+        warn!("transfer aborted");
+        assert!(buffer.stderr().is_empty());
+
+        control.flush(&mut from_log);
+        assert_eq!(
+            buffer.stderr(),
+            [
+                line(Level::INFO, "shutting down"),
+                line(Level::WARN, "transfer aborted")
+            ]
+        );
+
+        // once flushed, messages go straight to stderr
+        info!("stopped");
+        assert_eq!(
+            buffer.stderr().last().unwrap(),
+            &line(Level::INFO, "stopped")
+        );
+        assert!(from_log.try_recv().is_err());
+
+        // every message was captured as well
+        assert!(buffer.contains(Level::INFO, "first"));
+        assert!(buffer.contains(Level::INFO, "stopped"));
+    }
+
+    #[test]
+    fn flush_reports_the_suppressed_messages() {
+        let (mut handle, buffer) = Log::init_capturing();
+        let (mut from_log, control) = handle.take_ui().unwrap();
+
+        for i in 0..LOG_CHANNEL_CAPACITY + 3 {
+            info!("message {i}");
+        }
+        control.flush(&mut from_log);
+
+        let stderr = buffer.stderr();
+        assert_eq!(stderr.len(), LOG_CHANNEL_CAPACITY + 1);
+        assert_eq!(stderr[0], line(Level::INFO, "message 0"));
+        assert_eq!(
+            stderr[LOG_CHANNEL_CAPACITY - 1],
+            line(
+                Level::INFO,
+                &format!("message {}", LOG_CHANNEL_CAPACITY - 1)
+            )
+        );
+        assert_eq!(stderr[LOG_CHANNEL_CAPACITY], "3 log messages suppressed");
+    }
+
+    #[test]
+    fn messages_go_to_stderr_once_the_ui_is_gone() {
+        let (mut handle, buffer) = Log::init_capturing();
+        let (from_log, _control) = handle.take_ui().unwrap();
+        assert!(handle.take_ui().is_none());
 
-        receiver
+        // a UI that panicked drops its receiver without flushing
+        drop(from_log);
+        warn!("peer stopped");
+        assert_eq!(buffer.stderr(), [line(Level::WARN, "peer stopped")]);
     }
 }
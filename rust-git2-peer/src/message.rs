@@ -1,5 +1,6 @@
-use crate::ChatPeer;
-use libp2p::core::PeerId;
+use crate::{ChatEnvelope, ChatPeer};
+use libp2p::{core::PeerId, request_response::OutboundRequestId};
+use std::time::Duration;
 
 /// The different types of messages sent between the UI and the Peer
 #[derive(Debug)]
@@ -8,8 +9,8 @@ pub enum Message {
     Chat {
         /// The peer sending the message
         from: Option<ChatPeer>,
-        /// The message sent
-        data: Vec<u8>,
+        /// The message sent, with its content type
+        envelope: ChatEnvelope,
     },
     /// All gossipsub peers and their topics
     AllPeers {
@@ -22,4 +23,28 @@ pub enum Message {
     RemovePeer(ChatPeer),
     /// Add an event message
     Event(String),
+    /// Run a command entered by the user
+    Command(String),
+    /// A git request was sent to a peer
+    GitRequestSent {
+        /// The id the response is correlated by
+        id: OutboundRequestId,
+        /// The peer the request was sent to
+        peer: PeerId,
+        /// A summary of the request, see `GitRequest::summary`
+        summary: String,
+    },
+    /// A git request was answered or failed
+    GitResponseReceived {
+        /// The id of the request
+        id: OutboundRequestId,
+        /// The peer the request was sent to
+        peer: PeerId,
+        /// A summary of the response or the failure, see `GitResponse::summary`
+        summary: String,
+        /// How long the request took
+        elapsed: Duration,
+        /// Set when the peer answered with an error or the request failed
+        error: bool,
+    },
 }
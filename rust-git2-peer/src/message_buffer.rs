@@ -0,0 +1,51 @@
+use libp2p::gossipsub::TopicHash;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Holds gossipsub messages that couldn't be published because no peers were subscribed to the
+/// topic, so they can be retried once peers join
+#[derive(Debug)]
+pub struct MessageBuffer {
+    max_age: Duration,
+    messages: VecDeque<(TopicHash, Vec<u8>, Instant)>,
+}
+
+impl MessageBuffer {
+    /// Create an empty buffer that keeps messages for at most `max_age`
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Buffer a message for the topic, keeping the time it was first sent
+    pub fn push(&mut self, topic: TopicHash, data: Vec<u8>, sent: Instant) {
+        self.messages.push_back((topic, data, sent));
+    }
+
+    /// Drop the messages older than the maximum age at `now`, returning how many were dropped
+    pub fn drop_expired(&mut self, now: Instant) -> usize {
+        let before = self.messages.len();
+        self.messages
+            .retain(|(_, _, sent)| now.duration_since(*sent) < self.max_age);
+        before - self.messages.len()
+    }
+
+    /// Take all of the buffered messages, oldest first
+    pub fn take(&mut self) -> VecDeque<(TopicHash, Vec<u8>, Instant)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// The number of buffered messages
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Check if the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,531 @@
+use crate::{ServeListener, Transfer};
+use libp2p::{
+    core::ConnectedPoint,
+    identify::Event as IdentifyEvent,
+    kad::QueryResult,
+    multiaddr::{Multiaddr, Protocol},
+    request_response::{Event as RequestResponseEvent, Message as RequestResponseMessage},
+    swarm::{ConnectionError, ConnectionId},
+    PeerId,
+};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// The labels for metrics about a type of Kademlia query
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KadQueryLabels {
+    query_type: String,
+}
+
+/// The labels for metrics about finished Kademlia queries
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KadQueryOutcomeLabels {
+    query_type: String,
+    outcome: String,
+}
+
+/// The labels for metrics about connections
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ConnectionLabels {
+    transport: String,
+    direction: String,
+}
+
+/// The labels for metrics about closed connections
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ConnectionClosedLabels {
+    transport: String,
+    direction: String,
+    cause: String,
+}
+
+/// The labels for metrics about substreams
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SubstreamLabels {
+    protocol: String,
+    transport: String,
+    direction: String,
+    outcome: String,
+}
+
+/// The labels for metrics about a gossipsub topic
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TopicLabels {
+    topic: String,
+}
+
+/// The labels for metrics about transfers in flight
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransferLabels {
+    protocol: String,
+    direction: String,
+    operation: String,
+}
+
+impl TransferLabels {
+    fn of(transfer: &Transfer) -> Self {
+        Self {
+            protocol: transfer.protocol.as_str().to_string(),
+            direction: transfer.id.direction().to_string(),
+            operation: transfer.operation.to_string(),
+        }
+    }
+}
+
+/// The labels for metrics about peer presence notifications
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PresenceLabels {
+    event: String,
+}
+
+/// What is known about an open connection
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// The peer the connection is to
+    pub peer_id: PeerId,
+    /// When the connection was established
+    pub established: Instant,
+    /// The address of the remote end of the connection
+    pub remote_addr: Multiaddr,
+    /// The transport of the connection, see [`connection_transport`]
+    pub transport: &'static str,
+    /// Whether this peer dialed the connection
+    pub dialer: bool,
+    /// The number of substreams that were opened and used successfully
+    pub substreams_ok: u64,
+    /// The number of substreams that failed to open or failed while in use
+    pub substreams_failed: u64,
+}
+
+impl ConnectionStats {
+    /// Start tracking a newly established connection
+    pub fn new(peer_id: PeerId, endpoint: &ConnectedPoint) -> Self {
+        Self {
+            peer_id,
+            established: Instant::now(),
+            remote_addr: endpoint.get_remote_address().clone(),
+            transport: connection_transport(endpoint.get_remote_address()),
+            dialer: endpoint.is_dialer(),
+            substreams_ok: 0,
+            substreams_failed: 0,
+        }
+    }
+}
+
+/// The peer metrics, exported in the Prometheus text format
+#[derive(Clone)]
+pub struct Metrics {
+    connections_established: Family<ConnectionLabels, Counter>,
+    connections_closed: Family<ConnectionClosedLabels, Counter>,
+    connection_duration: Family<ConnectionClosedLabels, Histogram, fn() -> Histogram>,
+    substreams: Family<SubstreamLabels, Counter>,
+    kad_queries_started: Family<KadQueryLabels, Counter>,
+    kad_queries_finished: Family<KadQueryOutcomeLabels, Counter>,
+    kad_queries_active: Gauge,
+    kad_query_duration: Family<KadQueryLabels, Histogram, fn() -> Histogram>,
+    kad_query_requests: Family<KadQueryLabels, Histogram, fn() -> Histogram>,
+    presence_events: Family<PresenceLabels, Counter>,
+    gossipsub_messages: Family<TopicLabels, Counter>,
+    gossipsub_oversized_forwards: Family<TopicLabels, Counter>,
+    webrtc_certificate_mismatches: Counter,
+    transfers_in_flight: Family<TransferLabels, Gauge>,
+    transfer_bytes_in_flight: Family<TransferLabels, Gauge>,
+}
+
+impl Metrics {
+    /// Create the metrics, registering them with the registry
+    pub fn register(registry: &mut Registry) -> Self {
+        let metrics = Self {
+            connections_established: Family::default(),
+            connections_closed: Family::default(),
+            connection_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1.0, 2.0, 16))
+            }),
+            substreams: Family::default(),
+            kad_queries_started: Family::default(),
+            kad_queries_finished: Family::default(),
+            kad_queries_active: Gauge::default(),
+            kad_query_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.01, 2.0, 14))
+            }),
+            kad_query_requests: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1.0, 2.0, 10))
+            }),
+            presence_events: Family::default(),
+            gossipsub_messages: Family::default(),
+            gossipsub_oversized_forwards: Family::default(),
+            webrtc_certificate_mismatches: Counter::default(),
+            transfers_in_flight: Family::default(),
+            transfer_bytes_in_flight: Family::default(),
+        };
+
+        registry.register(
+            "connections_established",
+            "Connections established, by transport and direction",
+            metrics.connections_established.clone(),
+        );
+        registry.register(
+            "connections_closed",
+            "Connections closed, by transport, direction and cause",
+            metrics.connections_closed.clone(),
+        );
+        registry.register(
+            "connection_duration_seconds",
+            "Duration of closed connections",
+            metrics.connection_duration.clone(),
+        );
+        registry.register(
+            "substreams",
+            "Substreams by protocol, transport, direction and outcome",
+            metrics.substreams.clone(),
+        );
+        registry.register(
+            "kad_queries_started",
+            "Kademlia queries started",
+            metrics.kad_queries_started.clone(),
+        );
+        registry.register(
+            "kad_queries_finished",
+            "Kademlia queries finished, by outcome",
+            metrics.kad_queries_finished.clone(),
+        );
+        registry.register(
+            "kad_queries_active",
+            "Kademlia queries in progress",
+            metrics.kad_queries_active.clone(),
+        );
+        registry.register(
+            "kad_query_duration_seconds",
+            "Duration of finished Kademlia queries",
+            metrics.kad_query_duration.clone(),
+        );
+        registry.register(
+            "kad_query_requests",
+            "Number of peers queried by finished Kademlia queries",
+            metrics.kad_query_requests.clone(),
+        );
+        registry.register(
+            "peer_presence_events",
+            "Join and leave notifications received from other peers",
+            metrics.presence_events.clone(),
+        );
+        registry.register(
+            "gossipsub_messages",
+            "Gossipsub messages received, by topic",
+            metrics.gossipsub_messages.clone(),
+        );
+        registry.register(
+            "gossipsub_oversized_forwards",
+            "Received gossipsub messages too large for peers with the default maximum transmit size, by topic",
+            metrics.gossipsub_oversized_forwards.clone(),
+        );
+        registry.register(
+            "webrtc_certificate_mismatches",
+            "Inbound WebRTC handshakes that failed on the certificate, likely from a stale certhash",
+            metrics.webrtc_certificate_mismatches.clone(),
+        );
+        registry.register(
+            "transfers_in_flight",
+            "File and git exchanges in flight, by protocol, direction and operation",
+            metrics.transfers_in_flight.clone(),
+        );
+        registry.register(
+            "transfer_bytes_in_flight",
+            "Payload bytes of the file and git exchanges in flight, by protocol, direction and operation",
+            metrics.transfer_bytes_in_flight.clone(),
+        );
+
+        metrics
+    }
+
+    /// Record a newly established connection
+    pub fn connection_established(&self, endpoint: &ConnectedPoint) {
+        self.connections_established
+            .get_or_create(&ConnectionLabels {
+                transport: connection_transport(endpoint.get_remote_address()).to_string(),
+                direction: connection_direction(endpoint).to_string(),
+            })
+            .inc();
+    }
+
+    /// Record a closed connection and how long it was open
+    pub fn connection_closed(
+        &self,
+        endpoint: &ConnectedPoint,
+        cause: Option<&ConnectionError>,
+        duration: Option<Duration>,
+    ) {
+        let labels = ConnectionClosedLabels {
+            transport: connection_transport(endpoint.get_remote_address()).to_string(),
+            direction: connection_direction(endpoint).to_string(),
+            cause: connection_close_cause(cause).to_string(),
+        };
+        self.connections_closed.get_or_create(&labels).inc();
+        if let Some(duration) = duration {
+            self.connection_duration
+                .get_or_create(&labels)
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Record the outcome of a substream
+    pub fn substream(&self, protocol: &str, transport: &str, direction: &str, ok: bool) {
+        self.substreams
+            .get_or_create(&SubstreamLabels {
+                protocol: protocol.to_string(),
+                transport: transport.to_string(),
+                direction: direction.to_string(),
+                outcome: if ok { "ok" } else { "error" }.to_string(),
+            })
+            .inc();
+    }
+
+    /// Record the start of a Kademlia query
+    pub fn kad_query_started(&self, query_type: &str) {
+        self.kad_queries_started
+            .get_or_create(&KadQueryLabels {
+                query_type: query_type.to_string(),
+            })
+            .inc();
+        self.kad_queries_active.inc();
+    }
+
+    /// Record the end of a Kademlia query
+    pub fn kad_query_finished(
+        &self,
+        query_type: &str,
+        failed: bool,
+        duration: Duration,
+        requests: u32,
+    ) {
+        let labels = KadQueryLabels {
+            query_type: query_type.to_string(),
+        };
+        self.kad_queries_finished
+            .get_or_create(&KadQueryOutcomeLabels {
+                query_type: query_type.to_string(),
+                outcome: if failed { "error" } else { "ok" }.to_string(),
+            })
+            .inc();
+        self.kad_queries_active.dec();
+        self.kad_query_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+        self.kad_query_requests
+            .get_or_create(&labels)
+            .observe(requests as f64);
+    }
+
+    /// Record a gossipsub message received on a topic
+    pub fn gossipsub_message(&self, topic: &str) {
+        self.gossipsub_messages
+            .get_or_create(&TopicLabels {
+                topic: topic.to_string(),
+            })
+            .inc();
+    }
+
+    /// Record a received gossipsub message that peers with the default maximum transmit size
+    /// won't accept when it is forwarded to them
+    pub fn gossipsub_oversized_forward(&self, topic: &str) {
+        self.gossipsub_oversized_forwards
+            .get_or_create(&TopicLabels {
+                topic: topic.to_string(),
+            })
+            .inc();
+    }
+
+    /// Record an inbound WebRTC handshake that failed on the certificate
+    pub fn webrtc_certificate_mismatch(&self) {
+        self.webrtc_certificate_mismatches.inc();
+    }
+
+    /// Record a join or leave notification from another peer
+    pub fn presence_event(&self, event: &str) {
+        self.presence_events
+            .get_or_create(&PresenceLabels {
+                event: event.to_string(),
+            })
+            .inc();
+    }
+
+    /// Record the start of a transfer
+    pub fn transfer_started(&self, transfer: &Transfer) {
+        self.transfers_in_flight
+            .get_or_create(&TransferLabels::of(transfer))
+            .inc();
+    }
+
+    /// Record the bytes a transfer moved forward by
+    pub fn transfer_progressed(&self, transfer: &Transfer, delta: i64) {
+        self.transfer_bytes_in_flight
+            .get_or_create(&TransferLabels::of(transfer))
+            .inc_by(delta);
+    }
+
+    /// Record the end of a transfer, successful or not
+    pub fn transfer_finished(&self, transfer: &Transfer) {
+        let labels = TransferLabels::of(transfer);
+        self.transfers_in_flight.get_or_create(&labels).dec();
+        self.transfer_bytes_in_flight
+            .get_or_create(&labels)
+            .dec_by(transfer.bytes as i64);
+    }
+}
+
+/// Get the transport of a connection from its remote address
+pub fn connection_transport(addr: &Multiaddr) -> &'static str {
+    // a relayed address also contains the transport used to reach the relay so check it first
+    if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        return "relay";
+    }
+    let mut transport = "other";
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::WebRTCDirect => return "webrtc",
+            Protocol::QuicV1 => return "quic",
+            Protocol::Tcp(_) => transport = "tcp",
+            _ => {}
+        }
+    }
+    transport
+}
+
+/// Get the direction of a connection
+pub fn connection_direction(endpoint: &ConnectedPoint) -> &'static str {
+    if endpoint.is_dialer() {
+        "outbound"
+    } else {
+        "inbound"
+    }
+}
+
+/// Get the cause of a connection closing, where no error means it was closed by us
+pub fn connection_close_cause(cause: Option<&ConnectionError>) -> &'static str {
+    match cause {
+        None => "closed",
+        Some(ConnectionError::IO(_)) => "io",
+        Some(ConnectionError::KeepAliveTimeout) => "keep_alive_timeout",
+    }
+}
+
+/// Get the connection, direction and outcome of the substream a request_response event reports on
+pub fn request_response_substream<Req, Resp>(
+    event: &RequestResponseEvent<Req, Resp>,
+) -> Option<(ConnectionId, &'static str, Result<(), String>)> {
+    match event {
+        RequestResponseEvent::Message {
+            connection_id,
+            message: RequestResponseMessage::Request { .. },
+            ..
+        } => Some((*connection_id, "inbound", Ok(()))),
+        RequestResponseEvent::Message {
+            connection_id,
+            message: RequestResponseMessage::Response { .. },
+            ..
+        } => Some((*connection_id, "outbound", Ok(()))),
+        RequestResponseEvent::OutboundFailure {
+            connection_id,
+            error,
+            ..
+        } => Some((*connection_id, "outbound", Err(error.to_string()))),
+        RequestResponseEvent::InboundFailure {
+            connection_id,
+            error,
+            ..
+        } => Some((*connection_id, "inbound", Err(error.to_string()))),
+        RequestResponseEvent::ResponseSent { .. } => None,
+    }
+}
+
+/// Get the connection, direction and outcome of the substream an identify event reports on
+pub fn identify_substream(
+    event: &IdentifyEvent,
+) -> Option<(ConnectionId, &'static str, Result<(), String>)> {
+    match event {
+        IdentifyEvent::Received { connection_id, .. } => {
+            Some((*connection_id, "outbound", Ok(())))
+        }
+        IdentifyEvent::Sent { connection_id, .. } => Some((*connection_id, "inbound", Ok(()))),
+        IdentifyEvent::Pushed { connection_id, .. } => Some((*connection_id, "outbound", Ok(()))),
+        IdentifyEvent::Error {
+            connection_id,
+            error,
+            ..
+        } => Some((*connection_id, "unknown", Err(error.to_string()))),
+    }
+}
+
+/// Get the type of a Kademlia query from its result
+pub fn kad_query_type(result: &QueryResult) -> &'static str {
+    match result {
+        QueryResult::Bootstrap(_) => "bootstrap",
+        QueryResult::GetClosestPeers(_) => "get_closest_peers",
+        QueryResult::GetProviders(_) => "get_providers",
+        QueryResult::StartProviding(_) | QueryResult::RepublishProvider(_) => "start_providing",
+        QueryResult::GetRecord(_) => "get_record",
+        QueryResult::PutRecord(_) | QueryResult::RepublishRecord(_) => "put_record",
+    }
+}
+
+/// Check if a Kademlia query result is an error
+pub fn kad_query_failed(result: &QueryResult) -> bool {
+    match result {
+        QueryResult::Bootstrap(result) => result.is_err(),
+        QueryResult::GetClosestPeers(result) => result.is_err(),
+        QueryResult::GetProviders(result) => result.is_err(),
+        QueryResult::StartProviding(result) | QueryResult::RepublishProvider(result) => {
+            result.is_err()
+        }
+        QueryResult::GetRecord(result) => result.is_err(),
+        QueryResult::PutRecord(result) | QueryResult::RepublishRecord(result) => result.is_err(),
+    }
+}
+
+/// Serve the metrics registry in the Prometheus text format over HTTP. Every request gets the
+/// metrics regardless of its path.
+pub async fn serve(listener: ServeListener, registry: Registry) {
+    let registry = Arc::new(registry);
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {e}");
+                continue;
+            }
+        };
+        debug!("Serving metrics to {addr}");
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            // the request itself is ignored, read it so the client sees a clean response
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+
+            let mut body = String::new();
+            if let Err(e) = encode(&mut body, &registry) {
+                warn!("Failed to encode metrics: {e}");
+                return;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -1,18 +1,33 @@
-use clap::Parser;
+use crate::{
+    content_hash::ContentHash, git_server::PackStrategy, proxy::Proxy, serve_addr::ServeAddr, serve_dir::ServeDir,
+    util::{AddressFamilyPreference, PreferredTransport},
+};
+use clap::{Parser, Subcommand};
 use std::{net::IpAddr, path::PathBuf};
 
 const LISTEN_ADDR: [&str; 1] = ["0.0.0.0"];
 const LOCAL_KEY_PATH: &str = "./local";
+const LEGACY_KEY_PATH: &str = "./local_key";
 const LOCAL_CERT_PATH: &str = "./cert.pem";
 
 /// The rust peer command line options
 #[derive(Debug, Parser)]
 #[clap(name = "universal connectivity rust peer")]
 pub struct Options {
+    /// Run a command instead of the peer
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Address to listen on.
     #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',', default_values = LISTEN_ADDR)]
     pub listen_addresses: Vec<IpAddr>,
 
+    /// The network interface to listen on by name, such as eth0 or tailscale0, instead of the
+    /// --listen-addresses. Its addresses are resolved at startup, failing if it doesn't exist,
+    /// and the listeners follow them when they change.
+    #[clap(long, env, conflicts_with = "listen_addresses")]
+    pub listen_interface: Option<String>,
+
     /// If known, the external address of this node. Will be used to correctly advertise our external address across all transports.
     #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',')]
     pub external_addresses: Vec<IpAddr>,
@@ -21,18 +36,200 @@ pub struct Options {
     #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',')]
     pub connect: Vec<String>,
 
+    /// If set, serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9100, or on a
+    /// Unix domain socket only the current user can access, e.g. unix:/run/peer/metrics.sock.
+    #[clap(long, env)]
+    pub metrics_addr: Option<ServeAddr>,
+
+    /// If set, serve a status dashboard over HTTP on this address, e.g. 127.0.0.1:9101, or on a
+    /// Unix domain socket. The page shows the connections, topics, external addresses with their
+    /// WebRTC dial strings, relay reservations and transfers in flight, and polls the same status
+    /// as JSON on /status.json. It has no authentication, so keep it on localhost. Requests have
+    /// to address it by IP address or as localhost, other host names are refused.
+    #[clap(long, env)]
+    pub dashboard_addr: Option<ServeAddr>,
+
+    /// If set, serve a control socket on this address that runs the commands the UI accepts, one
+    /// per line, and writes back each reply followed by an empty line. It has no authentication,
+    /// so prefer a Unix domain socket only the current user can access, e.g.
+    /// unix:/run/peer/control.sock, or keep it on localhost.
+    #[clap(long, env)]
+    pub control_addr: Option<ServeAddr>,
+
+    /// The transport to dial first when a discovered peer is reachable over both QUIC and WebRTC.
+    /// The peer's addresses are then tried one at a time, the other transport only if the
+    /// preferred one fails, and no further connection is opened once one is established.
+    #[clap(long, env, value_enum, default_value_t = PreferredTransport::Quic)]
+    pub prefer_transport: PreferredTransport,
+
+    /// The IP version to dial first when a peer has both IPv4 and IPv6 addresses. By default the
+    /// addresses are dialed in the order they are known. With auto they alternate between IPv6
+    /// and IPv4, and the first address of the other version is dialed 250ms after the first one
+    /// if the peer hasn't connected by then, so a broken IPv6 route doesn't hold up the
+    /// connection.
+    #[clap(long, env, value_enum, default_value_t = AddressFamilyPreference::Any)]
+    pub address_family_preference: AddressFamilyPreference,
+
+    /// The minimum time in seconds before a peer is dialed again after dialing it failed. Dials to
+    /// a peer are also not repeated while one is pending.
+    #[clap(long, env, default_value = "10")]
+    pub min_redial_interval: u64,
+
+    /// The number of consecutive failed dials after which a peer isn't dialed again for
+    /// --dial-breaker-cooldown seconds, or until it connects to us.
+    #[clap(long, env, default_value = "5", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_dial_attempts_per_peer: u32,
+
+    /// The time in seconds a peer isn't dialed after --max-dial-attempts-per-peer consecutive
+    /// failures.
+    #[clap(long, env, default_value = "600")]
+    pub dial_breaker_cooldown: u64,
+
+    /// If set, connections older than this many seconds are closed to force a fresh handshake.
+    /// The --connect peers are dialed again once their last connection is closed this way.
+    /// Relayed connections are exempt.
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connection_lifetime: Option<u64>,
+
+    /// If set, a --connect peer whose identify request times out has the connection closed and
+    /// is dialed again, with the usual redial backoff, instead of being dropped like other peers
+    /// (default: true)
+    #[clap(long, env, default_value = "true")]
+    pub redial_on_identify_timeout: bool,
+
+    /// If set, only one connection is kept to each peer. When another connection to a peer is
+    /// established, the best one by --prefer-transport is kept, the oldest of equally good ones,
+    /// and the others are closed. Peers with a transfer in flight are left alone until it's done.
+    #[clap(long, env)]
+    pub dedup_connections: bool,
+
+    /// If set, peers whose identify agent version doesn't contain this are disconnected once
+    /// identified, e.g. to only talk to one implementation in interop tests. This is a testing aid,
+    /// not a security control: the agent version is whatever the peer claims it is.
+    #[clap(long, env)]
+    pub require_agent_substring: Option<String>,
+
+    /// If set, peers whose identify agent version contains this are disconnected once identified.
+    /// Like --require-agent-substring, this is a testing aid and not a security control.
+    #[clap(long, env)]
+    pub exclude_agent_substring: Option<String>,
+
+    /// The longest in seconds a drain, started with the drain command or SIGUSR1, waits for the
+    /// transfers in flight to finish before the peer exits anyway.
+    #[clap(long, env, default_value = "300")]
+    pub drain_timeout: u64,
+
+    /// The clock skew in seconds beyond which a peer is warned about. Skew is estimated from the
+    /// publish time in the join announcements of peers, and shown by the status command.
+    #[clap(long, env, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub clock_skew_threshold: u64,
+
+    /// A file listing nodes to connect to on startup, one Multiaddr or PeerId per line. Blank lines
+    /// and lines starting with `#` are ignored. Merged with the --connect nodes.
+    #[clap(long, env)]
+    pub connect_file: Option<PathBuf>,
+
+    /// A file of peers exported with the export-peers command to seed the Kademlia routing table
+    /// with on startup. Exports older than a week and peers without a usable address are skipped. A
+    /// corrupt file is moved aside to the same path with `.corrupt` appended.
+    #[clap(long, env)]
+    pub import_peers: Option<PathBuf>,
+
+    /// If set, the peers imported with --import-peers are also dialed on startup.
+    #[clap(long, env)]
+    pub dial_imported_peers: bool,
+
+    /// The number of peers asked for, and sent to other peers, in a peer exchange. A peer asks
+    /// each newly identified peer supporting the exchange for a sample of the peers it knows. 0
+    /// disables asking, the peers we know are still sent to others.
+    #[clap(long, env, default_value = "16", value_parser = clap::value_parser!(u32).range(0..=64))]
+    pub pex_sample_size: u32,
+
+    /// The most peers from each peer exchange sample that are dialed.
+    #[clap(long, env, default_value = "4")]
+    pub pex_dial: usize,
+
+    /// A file listing additional Kademlia bootstrap nodes, one Multiaddr with a /p2p/ PeerId per
+    /// line. Blank lines and lines starting with `#` are ignored.
+    #[clap(long, env)]
+    pub bootstrap_file: Option<PathBuf>,
+
+    /// If set, private and loopback addresses learned through identify, peer discovery and
+    /// Kademlia are kept and dialed, and our own private listen addresses are advertised. Meant for
+    /// running several peers on one host or LAN; leave it off in production.
+    #[clap(long, env)]
+    pub allow_private_addresses: bool,
+
+    /// If set, the reputation of misbehaving peers is saved to this file and reloaded at startup
+    /// so known bad actors stay penalized across restarts. It is saved at most every 10 seconds
+    /// while peers misbehave, and at shutdown. A corrupt file is moved aside to the
+    /// same path with `.corrupt` appended, and the node starts with no reputation.
+    #[clap(long, env)]
+    pub reputation_path: Option<PathBuf>,
+
     /// If set, the path to the local certificate file.
     #[clap(long, env, default_value = LOCAL_CERT_PATH)]
     pub local_cert_path: PathBuf,
 
+    /// Load the WebRTC certificate from this PEM file instead of --local-cert-path. The
+    /// certificate is never generated or written, startup fails if it can't be read.
+    #[clap(long, env, conflicts_with = "cert_pem_env")]
+    pub cert_pem: Option<PathBuf>,
+
+    /// Load the WebRTC certificate from the base64 encoded PEM in this environment variable
+    /// instead of --local-cert-path. The certificate is never generated or written.
+    #[clap(long, env)]
+    pub cert_pem_env: Option<String>,
+
+    /// Additional WebRTC certificates to serve alongside --local-cert-path, each on its own port
+    /// starting at UDP 9093, so the certificate can be rotated without breaking clients that know
+    /// the old certhash. Missing certificates are generated. Can be specified several times.
+    #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',')]
+    pub extra_cert_paths: Vec<PathBuf>,
+
     /// If set, the path to the local key file.
     #[clap(long, env, default_value = LOCAL_KEY_PATH)]
     pub local_key_path: PathBuf,
 
+    /// The key file written by older versions of the rust-peer, a single protobuf encoded key pair
+    /// without an extension. It is used if there is no identity at --local-key-path.
+    #[clap(long, env, default_value = LEGACY_KEY_PATH)]
+    pub legacy_key_path: PathBuf,
+
+    /// If set, the key file at --legacy-key-path is copied to the .key/.peerid files at
+    /// --local-key-path, keeping its peer id. An existing identity is never overwritten.
+    #[clap(long, env)]
+    pub migrate_legacy_key: bool,
+
+    /// If set, a corrupt certificate or key file is replaced with a newly generated one instead of
+    /// aborting startup. Note that a new key changes the peer id.
+    #[clap(long, env)]
+    pub regen_corrupt_cert: bool,
+
+    /// The number of times a transient I/O error reading or writing the certificate or key files
+    /// is retried, with a short backoff, before startup fails. Useful on flaky storage like NFS.
+    #[clap(long, env, default_value = "3")]
+    pub key_io_retries: u32,
+
+    /// If set, the peer dials each of its advertised addresses from a temporary in-process swarm
+    /// once its listeners are up and reports which ones are reachable.
+    #[clap(long, env)]
+    pub self_test: bool,
+
+    /// If set, the peer exits with an error if any address fails the --self-test.
+    #[clap(long, env, requires = "self_test")]
+    pub self_test_strict: bool,
+
     /// If set, the peer will make autonat client requests (default: true)
     #[clap(long, env, default_value = "true")]
     pub autonat_client: bool,
 
+    /// If set, the peer keeps its addresses out of identify and the peer discovery topic until
+    /// AutoNAT or the --self-test confirms each of them is reachable, and stays a Kademlia client
+    /// until the first one is. The self-test doesn't confirm private or loopback addresses.
+    #[clap(long, env)]
+    pub announce_only_confirmed: bool,
+
     /// If set, the peer will act as an autonat server
     #[clap(long, env)]
     pub autonat_server: bool,
@@ -41,14 +238,207 @@ pub struct Options {
     #[clap(long, env, default_value = "true")]
     pub dcutr: bool,
 
+    /// If set, dial, connection and request failures are logged with their full chain of error
+    /// sources, plus a backtrace when RUST_BACKTRACE=1 is set. Noisy, meant for debugging.
+    #[clap(long, env)]
+    pub verbose_errors: bool,
+
     /// If set, the peer will not initialize the TUI and will run headless.
     #[clap(long, env)]
     pub headless: bool,
 
+    /// The maximum number of concurrent inbound request_response requests across all peers.
+    /// Requests beyond this are refused with an error response.
+    #[clap(long, env, default_value = "100")]
+    pub max_inbound_streams: usize,
+
+    /// The maximum number of concurrent inbound request_response requests from a single peer.
+    /// Requests beyond this are refused with an error response.
+    #[clap(long, env, default_value = "10")]
+    pub max_inbound_streams_per_peer: usize,
+
+    /// The interval in milliseconds between gossipsub heartbeats, which maintain the mesh. Faster
+    /// heartbeats lower propagation latency at the cost of more control traffic.
+    #[clap(long, env, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub gossip_heartbeat_interval: u64,
+
+    /// The time in seconds that gossipsub keeps fanout peers for a topic we publish to but aren't
+    /// subscribed to.
+    #[clap(long, env, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    pub gossip_fanout_ttl: u64,
+
+    /// The largest gossipsub message in bytes that this peer publishes or accepts. Raising it above
+    /// the 65536 byte default of other peers lets larger messages through here, but the peers that
+    /// kept the default drop them when they are forwarded. Received messages over the default are
+    /// therefore logged and counted in the gossipsub_oversized_forwards metric.
+    #[clap(long, env, default_value = "65536", value_parser = clap::value_parser!(u64).range(1024..))]
+    pub gossip_max_transmit_size: u64,
+
+    /// If set, gossipsub v1.2 IDONTWANT messages are sent to mesh peers when a message is
+    /// received, so they don't forward us copies we already have. Only messages of at least
+    /// --gossip-idontwant-threshold bytes are announced, since for small ones the IDONTWANT costs
+    /// about as much as the duplicate (default: true)
+    #[clap(long, env, default_value = "true")]
+    pub gossip_idontwant: bool,
+
+    /// The smallest gossipsub message in bytes that IDONTWANT is sent for.
+    ///
+    /// Expected effect, not measured on a live mesh: a message id is the 64 hex characters of its
+    /// content hash, so an IDONTWANT costs about 70 bytes to each of the other 5 peers of the
+    /// default 6 peer mesh, 350 bytes per received message. A peer receives up to 5 duplicates of
+    /// each message, and every duplicate an IDONTWANT stops saves the whole message. At the
+    /// 1000 byte default IDONTWANT pays off once it stops 1 in 14 of the duplicates, and for a
+    /// message of the 65536 byte transmit limit a single stopped duplicate saves more than 180
+    /// times its cost. Chat messages are mostly below the default and are unaffected.
+    #[clap(long, env, default_value = "1000")]
+    pub gossip_idontwant_threshold: usize,
+
+    /// If set, a JSON snapshot of the peer's state, the same as the dump-state command writes, is
+    /// written to --state-dump-path every this many seconds for post-mortem analysis.
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub state_dump_interval: Option<u64>,
+
+    /// The file the periodic --state-dump-interval snapshot is written to, replaced each time.
+    #[clap(long, env, default_value = "./state_dump.json")]
+    pub state_dump_path: PathBuf,
+
+    /// A prefix for the protocol ids, the Kademlia protocol and the gossipsub topics, such as
+    /// `acme`, to isolate a private deployment from the public network. Only peers with the same
+    /// prefix can be talked to, so the public bootstrap nodes are skipped and the JS and Go peers
+    /// need the same prefix. Identify is not prefixed.
+    #[clap(long, env)]
+    pub protocol_prefix: Option<String>,
+
     /// If set, the peer will use kademlia (default: true)
     #[clap(long, env, default_value = "true")]
     pub kademlia: bool,
 
+    /// If set, the peer runs without Kademlia, for pure gossip deployments. This overrides
+    /// --kademlia and skips bootstrapping. Files are then only discovered through the gossipsub
+    /// file announcements since there is no DHT to look up or announce providers in.
+    #[clap(long, env)]
+    pub no_kademlia: bool,
+
+    /// The maximum number of Kademlia queries in progress at once. Further provider announcements
+    /// and peer lookups are queued until a query finishes.
+    #[clap(long, env, default_value = "16", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_kad_queries: u64,
+
+    /// The maximum number of records in the Kademlia store. Once it is full, the least recently
+    /// used record is evicted to make room for a new one.
+    #[clap(long, env, default_value = "1024", value_parser = clap::value_parser!(u64).range(1..))]
+    pub kad_max_records: u64,
+
+    /// The maximum number of keys the Kademlia store holds provider records for. Once it is full,
+    /// the providers of the least recently used key are evicted, except for keys we provide.
+    #[clap(long, env, default_value = "1024", value_parser = clap::value_parser!(u64).range(1..))]
+    pub kad_max_providers: u64,
+
+    /// The interval in seconds between re-announcements of the provider records for held files.
+    /// Must be shorter than the 24 hour provider record TTL so the records never expire.
+    #[clap(long, env, default_value = "82800")]
+    pub reprovide_interval: u64,
+
+    /// A directory to serve the files of, as `name=path`. Each file is announced as
+    /// `name/relative/path`, so several directories can be served without their file ids
+    /// colliding. The names must be unique. Can be specified several times.
+    #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',')]
+    pub serve_dir: Vec<ServeDir>,
+
+    /// The SQLite database to index the files we hold and the provider records we announced in,
+    /// so they are reloaded and re-provided after a restart. Received files are stored in the
+    /// directory next to it with a `files` extension. Requires the `sqlite-index` feature.
+    #[clap(long, env)]
+    pub index_db: Option<PathBuf>,
+
+    /// The most bytes the files held in memory may take, unbounded if not set. Once the files
+    /// take more, the least recently received or served ones are dropped and no longer provided.
+    /// This is separate from the memory connection limits.
+    #[clap(long, env)]
+    pub file_cache_max_bytes: Option<u64>,
+
+    /// The maximum number of offered files fetched at once. Further offers are queued and fetched
+    /// as the fetches in flight finish.
+    #[clap(long, env, default_value = "8", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_file_fetches: u64,
+
+    /// The maximum number of offered files waiting to be fetched. Offers beyond this are skipped,
+    /// so a storm of offers can't pile up in memory; 0 skips every offer that can't be fetched
+    /// right away.
+    #[clap(long, env, default_value = "256")]
+    pub max_queued_file_fetches: usize,
+
+    /// If set, offered files are queued rather than fetched while the file cache takes at least
+    /// this many bytes, until evictions bring it below. Set it under --file-cache-max-bytes.
+    #[clap(long, env)]
+    pub file_fetch_cache_threshold: Option<u64>,
+
+    /// The hash content is addressed with: the ids of files added with the add-file command, and
+    /// the gossipsub message ids. sha256 matches the CIDs of IPFS and the JS and Go libraries.
+    /// Received files whose id is a content id of either hash are checked against it.
+    #[clap(long, env, value_enum, default_value_t = ContentHash::Sha256)]
+    pub content_hash: ContentHash,
+
+    /// If set, the files we request are asked to be encrypted end to end to our identity, and
+    /// files sent unencrypted are discarded. Requires an ed25519 identity, and only peers with
+    /// ed25519 identities can be answered encrypted.
+    #[clap(long, env)]
+    pub encrypt_files: bool,
+
+    /// The topics whose offered files are fetched. File offers can be scoped to the topic the file
+    /// belongs to: when this is set, only the files offered for these topics are fetched and
+    /// unscoped offers are ignored. Every offered file is fetched if it isn't set.
+    #[clap(long, env, action = clap::ArgAction::Append, value_delimiter = ',')]
+    pub file_topics_of_interest: Vec<String>,
+
+    /// If set, messages published to a topic with no subscribed peers are buffered and retried
+    /// until a peer joins or they exceed --unsent-message-max-age.
+    #[clap(long, env)]
+    pub buffer_unsent_messages: bool,
+
+    /// The maximum age in seconds of a buffered unsent message before it is dropped.
+    #[clap(long, env, default_value = "60")]
+    pub unsent_message_max_age: u64,
+
+    /// How served packfiles are generated: auto leaves out the objects reachable from the commits
+    /// the client says it has, full packs are self-contained.
+    #[clap(long, env, value_enum, default_value_t = PackStrategy::Auto)]
+    pub pack_strategy: PackStrategy,
+
+    /// The largest repository, in bytes, that will be served to other peers. The packfile size is
+    /// estimated from the size of the repository's object database before generating it.
+    #[clap(long, env, default_value = "500000000")]
+    pub max_repo_size: u64,
+
+    /// The longest, in seconds, that cloning a repository for another peer may run before it is
+    /// aborted with a timeout error
+    #[clap(long, env, default_value = "600", value_parser = clap::value_parser!(u64).range(1..))]
+    pub git_clone_timeout: u64,
+
+    /// The longest, in seconds, that fetching into a repository for another peer may run before
+    /// it is aborted with a timeout error
+    #[clap(long, env, default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    pub git_fetch_timeout: u64,
+
+    /// The longest, in seconds, that generating a packfile for another peer may run before it is
+    /// aborted with a timeout error
+    #[clap(long, env, default_value = "120", value_parser = clap::value_parser!(u64).range(1..))]
+    pub git_pack_timeout: u64,
+
+    /// A proxy to dial TCP connections through, `socks5://[user:password@]host:port` or
+    /// `http://[user:password@]host:port` for an HTTP CONNECT proxy. QUIC and WebRTC run over UDP
+    /// and can't be proxied, so they keep dialing directly. Proxied TCP connections use Noise only.
+    /// Host names are resolved by the proxy, never locally, so only TCP `/dns` addresses can be
+    /// dialed.
+    #[clap(long, env)]
+    pub proxy: Option<Proxy>,
+
+    /// The longest, in seconds, a connection may take to complete its security and muxer upgrade
+    /// (Noise or TLS handshake, QUIC handshake, WebRTC DTLS) before it is aborted, on every
+    /// transport. Catches peers that accept a connection but never finish the handshake.
+    #[clap(long, env, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub upgrade_timeout: u64,
+
     /// If set, the peer will support relay client connections (default: true)
     #[clap(long, env, default_value = "true")]
     pub relay_client: bool,
@@ -56,4 +446,28 @@ pub struct Options {
     /// If set the peer will act as a relay server
     #[clap(long, env)]
     pub relay_server: bool,
+
+    /// The maximum number of relay circuits a peer can open through this relay server as the
+    /// source. Further circuits from it are refused.
+    #[clap(long, env, default_value = "100")]
+    pub max_circuits_per_source: usize,
+
+    /// The maximum number of relay circuits to a peer through this relay server. A circuit request
+    /// past the limit is refused before the destination is contacted, by closing the connection
+    /// of the source it came on.
+    #[clap(long, env, default_value = "100")]
+    pub max_circuits_per_destination: usize,
+}
+
+/// The commands that can be run instead of the peer
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create the identity and certificate files if they don't exist, print the peer id and
+    /// certhash and exit without starting the peer
+    Init {
+        /// Replace existing identity and certificate files with newly generated ones. Note that a
+        /// new identity changes the peer id.
+        #[clap(long)]
+        force: bool,
+    },
 }
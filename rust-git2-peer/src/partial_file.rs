@@ -0,0 +1,378 @@
+use crate::{
+    file_exchange::{Range, FILE_RANGE_SIZE},
+    persisted::PersistedFile,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// The format of the state file of a partial file
+const PARTIAL_FILE: PersistedFile<PartialState> = PersistedFile::new("partial-file", 1);
+
+/// A file being fetched in ranges over version 3 of the file exchange protocol.
+///
+/// The file is fetched front to back: the bytes received so far are written to a data file, and
+/// their count is recorded with the session id of the fetch in a state file next to it, so a fetch
+/// interrupted by a failed request, a lost connection or a restart resumes where it stopped. The
+/// data is written before the state records it, so a crash in between only loses the range.
+///
+/// The size of the file and the offset of each range come from the responder, so a range is only
+/// taken if it is the one requested and the file fits in `max_size`.
+#[derive(Debug)]
+pub struct PartialFile {
+    data_path: PathBuf,
+    state_path: PathBuf,
+    max_size: u64,
+    state: PartialState,
+}
+
+/// The state of a partial file, as stored on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct PartialState {
+    file_id: String,
+    session: u64,
+    // the size of the file, once a range of it was received
+    total_size: Option<u64>,
+    // the number of bytes received, from the start of the file
+    received: u64,
+}
+
+impl PartialFile {
+    /// Open the partial file of `file_id` in `dir`, resuming the fetch recorded there, or starting
+    /// a new one with a new session id if there is none or it can't be read. Files larger than
+    /// `max_size` are refused.
+    pub fn open(dir: &Path, file_id: &str, max_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        // file ids aren't necessarily valid file names
+        let name = hex::encode(Sha256::digest(file_id.as_bytes()));
+        let data_path = dir.join(format!("{name}.part"));
+        let state_path = dir.join(format!("{name}.state"));
+
+        let resumed = match PARTIAL_FILE.load(&state_path) {
+            Ok(state) if state.file_id == file_id && data_path.is_file() => Some(state),
+            Ok(_) => None,
+            Err(_) if !state_path.exists() => None,
+            Err(e) => {
+                warn!("Restarting the fetch of {file_id}: {e:#}");
+                None
+            }
+        };
+        let mut partial = Self {
+            data_path,
+            state_path,
+            max_size,
+            state: PartialState {
+                file_id: file_id.to_string(),
+                session: rand::random(),
+                total_size: None,
+                received: 0,
+            },
+        };
+        match resumed {
+            Some(state) if state.total_size.unwrap_or(0) <= max_size => partial.state = state,
+            _ => {
+                fs::File::create(&partial.data_path)?;
+                partial.save()?;
+            }
+        }
+        Ok(partial)
+    }
+
+    /// The session id of the fetch, sent with each range request
+    pub fn session(&self) -> u64 {
+        self.state.session
+    }
+
+    /// The number of bytes received so far
+    pub fn received_bytes(&self) -> u64 {
+        self.state.received
+    }
+
+    /// The next range to request, None once the file is complete. The size of the file is only
+    /// known once a range of it was received, until then the first range is requested.
+    pub fn next_range(&self) -> Option<Range> {
+        let offset = self.state.received;
+        let length = match self.state.total_size {
+            None => FILE_RANGE_SIZE,
+            Some(total_size) if offset < total_size => (total_size - offset).min(FILE_RANGE_SIZE),
+            Some(_) => return None,
+        };
+        Some(Range {
+            session: self.state.session,
+            offset,
+            length,
+        })
+    }
+
+    /// Record the range of the file received for `session`, writing it to the data file. A range
+    /// of another session, at another offset than the one requested, or of a file larger than
+    /// the maximum or of another size than before is refused. A file that changed size is fetched
+    /// from the start again.
+    pub fn receive(
+        &mut self,
+        session: u64,
+        offset: u64,
+        total_size: u64,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        if session != self.state.session {
+            anyhow::bail!(
+                "Range of session {session:016x}, the fetch is session {:016x}",
+                self.state.session
+            );
+        }
+        if total_size > self.max_size {
+            anyhow::bail!(
+                "The file is {total_size} bytes, more than the maximum of {} bytes",
+                self.max_size
+            );
+        }
+        if let Some(known) = self.state.total_size.filter(|known| *known != total_size) {
+            self.restart()?;
+            anyhow::bail!("The size of the file changed from {known} to {total_size} bytes");
+        }
+        if offset != self.state.received {
+            anyhow::bail!(
+                "Range at {offset}, the range at {} was requested",
+                self.state.received
+            );
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| !data.is_empty() && *end <= total_size)
+            .with_context(|| {
+                format!(
+                    "Range of {} bytes at {offset} doesn't fit in a file of {total_size} bytes",
+                    data.len()
+                )
+            })?;
+
+        let mut file = fs::OpenOptions::new().write(true).open(&self.data_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.sync_data()?;
+
+        self.state.total_size = Some(total_size);
+        self.state.received = end;
+        self.save()
+    }
+
+    /// Check if the whole file was received
+    pub fn is_complete(&self) -> bool {
+        self.state.total_size == Some(self.state.received)
+    }
+
+    /// Read the complete file and remove the partial file. Only the bytes received are read, so
+    /// the file takes at most the maximum size in memory.
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        let Some(total_size) = self.state.total_size.filter(|_| self.is_complete()) else {
+            anyhow::bail!(
+                "Only {} bytes of {} were received",
+                self.state.received,
+                self.state.file_id
+            );
+        };
+        let mut body = Vec::with_capacity(total_size as usize);
+        fs::File::open(&self.data_path)?
+            .take(total_size)
+            .read_to_end(&mut body)?;
+        if body.len() as u64 != total_size {
+            anyhow::bail!("The partial file of {} is truncated", self.state.file_id);
+        }
+        self.discard();
+        Ok(body)
+    }
+
+    /// Remove the partial file, dropping the ranges received so far
+    pub fn discard(self) {
+        let _ = fs::remove_file(&self.state_path);
+        let _ = fs::remove_file(&self.data_path);
+    }
+
+    // Drop the ranges received so far, keeping the session
+    fn restart(&mut self) -> anyhow::Result<()> {
+        self.state.total_size = None;
+        self.state.received = 0;
+        fs::File::create(&self.data_path)?;
+        self.save()
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        PARTIAL_FILE.save(&self.state_path, &self.state)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file_exchange::{self, Codec, Request, Response},
+        FileStore,
+    };
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::{identity::Keypair, request_response::Codec as _, PeerId, StreamProtocol};
+    use rand::RngCore;
+    use tempfile::TempDir;
+
+    const V3: StreamProtocol = StreamProtocol::new("/universal-connectivity-file/3");
+    const MAX: u64 = 1 << 30;
+
+    // A file of three and a half ranges
+    fn file() -> Vec<u8> {
+        let mut body = vec![0; (7 * FILE_RANGE_SIZE / 2) as usize];
+        rand::thread_rng().fill_bytes(&mut body);
+        body
+    }
+
+    // Request the next range of `partial` from `peer` serving `files` over version 3 and record
+    // it, the way the peer does
+    fn fetch_range(files: &mut FileStore, peer: &PeerId, partial: &mut PartialFile, file_id: &str) {
+        let request = Request {
+            file_id: file_id.to_string(),
+            nonce: Some(rand::random()),
+            encrypt: false,
+            range: partial.next_range(),
+        };
+        let mut codec = Codec;
+        let response = block_on(async {
+            let mut buf = Vec::new();
+            codec.write_request(&V3, &mut buf, request).await?;
+            let request = codec.read_request(&V3, &mut Cursor::new(buf)).await?;
+            let response = file_exchange::respond(files, peer, &request);
+            let mut buf = Vec::new();
+            codec.write_response(&V3, &mut buf, response).await?;
+            codec.read_response(&V3, &mut Cursor::new(buf)).await
+        })
+        .unwrap();
+        let Response::Range {
+            session,
+            offset,
+            total_size,
+            data,
+            encrypted: false,
+        } = response
+        else {
+            panic!("expected a range, got {response:?}");
+        };
+        partial.receive(session, offset, total_size, &data).unwrap();
+    }
+
+    #[test]
+    fn interrupted_fetch_resumes_to_completion() {
+        let dir = TempDir::new().unwrap();
+        let body = file();
+        let mut files = FileStore::default();
+        files.insert("file".to_string(), body.clone());
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        let session = partial.session();
+        fetch_range(&mut files, &peer, &mut partial, "file");
+        fetch_range(&mut files, &peer, &mut partial, "file");
+        assert!(!partial.is_complete());
+        // the fetch is interrupted, such as by a restart
+        drop(partial);
+
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        assert_eq!(partial.session(), session);
+        assert_eq!(partial.received_bytes(), 2 * FILE_RANGE_SIZE);
+        assert_eq!(partial.next_range().unwrap().offset, 2 * FILE_RANGE_SIZE);
+        let mut requests = 0;
+        while !partial.is_complete() {
+            fetch_range(&mut files, &peer, &mut partial, "file");
+            requests += 1;
+        }
+        // only the missing ranges were requested
+        assert_eq!(requests, 2);
+        assert_eq!(partial.next_range(), None);
+        assert_eq!(partial.finish().unwrap(), body);
+
+        // nothing is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn ranges_of_another_session_or_outside_of_the_file_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        let session = partial.session();
+
+        assert!(partial.receive(session ^ 1, 0, 10, b"data").is_err());
+        assert!(partial.receive(session, 8, 10, b"data").is_err());
+        assert!(partial.receive(session, 0, 10, b"").is_err());
+        assert!(partial
+            .receive(session, u64::MAX, u64::MAX, b"data")
+            .is_err());
+        assert_eq!(partial.received_bytes(), 0);
+    }
+
+    #[test]
+    fn only_the_requested_range_is_taken() {
+        let dir = TempDir::new().unwrap();
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        let session = partial.session();
+
+        assert!(partial.receive(session, 4, 12, b"efgh").is_err());
+        assert_eq!(partial.next_range().unwrap().offset, 0);
+        partial.receive(session, 0, 12, b"abcd").unwrap();
+        assert_eq!(partial.next_range().unwrap().offset, 4);
+        assert_eq!(partial.next_range().unwrap().length, 8);
+        // a range overlapping the bytes received is refused too
+        assert!(partial.receive(session, 2, 12, b"cdef").is_err());
+        partial.receive(session, 4, 12, b"efghijkl").unwrap();
+        assert!(partial.is_complete());
+        assert_eq!(partial.finish().unwrap(), b"abcdefghijkl");
+    }
+
+    #[test]
+    fn files_larger_than_the_maximum_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let mut partial = PartialFile::open(dir.path(), "file", 8).unwrap();
+        let session = partial.session();
+
+        assert!(partial.receive(session, 0, 12, b"abcd").is_err());
+        assert!(partial.receive(session, 0, u64::MAX, b"abcd").is_err());
+        assert_eq!(partial.received_bytes(), 0);
+        partial.receive(session, 0, 8, b"abcd").unwrap();
+        drop(partial);
+
+        // a fetch recorded with a larger maximum is started over
+        let partial = PartialFile::open(dir.path(), "file", 6).unwrap();
+        assert_eq!(partial.received_bytes(), 0);
+    }
+
+    #[test]
+    fn fetch_starts_over_when_the_file_size_changes() {
+        let dir = TempDir::new().unwrap();
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        let session = partial.session();
+
+        partial.receive(session, 0, 12, b"abcd").unwrap();
+        assert!(partial.receive(session, 4, 6, b"ef").is_err());
+        assert_eq!(partial.received_bytes(), 0);
+        partial.receive(session, 0, 6, b"abcdef").unwrap();
+        assert_eq!(partial.finish().unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn unreadable_state_starts_a_new_fetch() {
+        let dir = TempDir::new().unwrap();
+        let mut partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        let session = partial.session();
+        partial.receive(session, 0, 12, b"abcd").unwrap();
+        fs::write(&partial.state_path, b"garbage").unwrap();
+
+        let partial = PartialFile::open(dir.path(), "file", MAX).unwrap();
+        assert_eq!(partial.received_bytes(), 0);
+        assert_ne!(partial.session(), session);
+
+        // another file id has its own partial file
+        let other = PartialFile::open(dir.path(), "other", MAX).unwrap();
+        assert_ne!(other.state_path, partial.state_path);
+    }
+}
@@ -1,9 +1,30 @@
 use crate::{
-    decode_unknown_protobuf, ipaddr_to_multiaddr, is_private_ip, pretty_print_fields,
-    proto::Peer as DiscoveredPeer, split_peer_id, ChatPeer, Codec as FileExchangeCodec, Message,
-    Options,
+    decode_unknown_protobuf, ipaddr_to_multiaddr, is_private_ip, listen_error, pretty_print_fields,
+    address_family, order_dial_addresses, proto::{Peer as DiscoveredPeer, Presence}, read_peer_list, split_peer_id, transport_rank, verbose_error, ArchiveFormat, ChatEnvelope, ChatPeer, ClockSkew, FetchDecision, FetchQueue, FileFetch, ContentHash, DialCoalescer, Codec as FileExchangeCodec, FileDecryptor, EchoCodec, EchoRequest, EchoResponse, FileStore, InflightRequests, OutstandingRequests, ListenInterface, KadQuery, KadQueryQueue, LruMemoryStore, FileOffer, ManifestCodec, ManifestRequest, PartialFile, PexCodec, PexRequest, PexResponse,
+    Message, MessageBuffer, Options, PeerSeeds, AddressFamilyPreference, ProtocolNames, PreferredTransport, ProviderAdvertisement, ProviderIndex, LimitedRelayServer, RelayCircuitLimits, RelayLoopGuard, ReputationStore, Request as FileRequest, Reprovider, Response as FileResponse, ServeDir, SignedAuthorTransform, AddressChangeTracker, AddressChanged, TopicAuth, TransferId, TransferProtocol, Transfers, UnsupportedProtocols,
+    TopicPolicies, TopicStats, ControlCommand, ControlCommands,
 };
-use crate::git_exchange::{Codec as GitExchangeCodec, GitRequest, GitResponse};
+use crate::git_exchange::{
+    verify_pack_chunk, ChunkVerdict, Codec as GitExchangeCodec, GitRequest, GitResponse,
+    PackReassembler, RefListing, GIT_MAX_STATUS_LINES,
+};
+use crate::{
+    cert_rotation::{self, PORT_WEBRTC_EXTRA},
+    clock_skew,
+    dashboard::{self, DASHBOARD_UPDATE_INTERVAL},
+    echo::MAX_ECHO_SIZE,
+    file_exchange,
+    git_server::{self, ServerConfig, StatusSnapshots},
+    metrics::{self, identify_substream, request_response_substream, ConnectionStats},
+    protocol_names,
+    proxy,
+    self_test,
+    upgrade_timeout::{is_upgrade_timeout, UpgradeTimeout},
+    Metrics, SelfTestResult,
+};
+#[cfg(feature = "sqlite-index")]
+use crate::FileIndex;
+use anyhow::Context;
 use clap::Parser;
 use futures::StreamExt;
 use libp2p::{
@@ -15,75 +36,116 @@ use libp2p::{
         v2::server::{Behaviour as AutonatServer, Event as AutonatServerEvent},
     },
     connection_limits::{self, Behaviour as ConnectionLimits},
+    core::transport::{timeout::TransportTimeout, ListenerId},
     dcutr::{Behaviour as Dcutr, Event as DcutrEvent},
     gossipsub::{
         self, Behaviour as Gossipsub, Event as GossipsubEvent, IdentTopic as GossipsubIdentTopic,
-        Message as GossipsubMessage, MessageId as GossipsubMessageId, TopicHash,
+        Message as GossipsubMessage, MessageId as GossipsubMessageId, PublishError, TopicHash,
     },
     identify::{Behaviour as Identify, Config as IdentifyConfig, Event as IdentifyEvent},
     identity::{self, PublicKey},
     kad::{
-        store::MemoryStore, AddProviderOk, Behaviour as Kademlia, Config as KademliaConfig,
-        Event as KademliaEvent, GetClosestPeersOk, GetProvidersOk, QueryId, QueryResult, RecordKey,
+        AddProviderOk, Behaviour as Kademlia, Config as KademliaConfig,
+        Event as KademliaEvent, GetClosestPeersOk, GetProvidersOk, Mode as KademliaMode,
+        ProgressStep, QueryId, QueryResult, QueryStats, RecordKey,
     },
     memory_connection_limits::Behaviour as MemoryConnectionLimits,
     multiaddr::{Multiaddr, Protocol},
     noise::Config as NoiseConfig,
     relay::{
         client::{Behaviour as RelayClient, Event as RelayClientEvent},
-        Behaviour as RelayServer, Config as RelayServerConfig, Event as RelayServerEvent,
+        Config as RelayServerConfig, Event as RelayServerEvent,
     },
     request_response::{
         Behaviour as RequestResponse, Config as RequestResponseConfig,
-        Event as RequestResponseEvent, Message as RequestResponseMessage, ProtocolSupport,
+        Event as RequestResponseEvent, InboundRequestId, Message as RequestResponseMessage,
+        OutboundRequestId, ProtocolSupport, ResponseChannel,
+    },
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, DialError, NetworkBehaviour, Swarm, SwarmEvent,
     },
-    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, Swarm, SwarmEvent},
     tcp::Config as TcpConfig,
     tls::Config as TlsConfig,
     yamux::Config as YamuxConfig,
-    PeerId, StreamProtocol, SwarmBuilder,
+    PeerId, StreamProtocol, SwarmBuilder, Transport,
 };
-use libp2p_webrtc as webrtc;
 use libp2p_webrtc::tokio::Certificate;
-use quick_protobuf::{BytesReader, MessageRead};
-use rand::rngs::OsRng;
+use prometheus_client::registry::Registry;
+use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+use rand::{rngs::OsRng, RngCore};
 use std::{
-    collections::{hash_map::DefaultHasher, HashSet},
+    collections::{HashMap, HashSet},
     fmt::{self, Write},
-    hash::{Hash, Hasher},
-    time::Duration,
-    path::PathBuf,
     fs,
+    io,
+    net::IpAddr,
+    num::NonZeroU8,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch,
+    },
+    task::{JoinHandle, JoinSet},
 };
-use tokio::sync::mpsc::{Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
-use git2::build::RepoBuilder;
-use git2::{Repository, Remote};
+
+// How long after startup the self-test runs, giving the listeners and external addresses time to
+// come up
+const SELF_TEST_DELAY: Duration = Duration::from_secs(10);
+// How long the self-test waits for each dial
+const SELF_TEST_DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the swarm keeps running at shutdown to send the leave notification
+const LEAVE_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+// How long the swarm keeps running at shutdown to finish the transfers in flight
+const SHUTDOWN_TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+// How long no transfer may be in flight before a drain completes. Chunked transfers are a series
+// of requests, this bridges the gaps between them.
+const DRAIN_QUIET_PERIOD: Duration = Duration::from_secs(5);
+// The head start of the first address family with the auto address family preference, the
+// connection attempt delay recommended by RFC 8305
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
 
 // Universal connectivity agent string
 const UNIVERSAL_CONNECTIVITY_AGENT: &str = "universal-connectivity/0.1.0";
 
 // Protocol Names
-const IPFS_KADEMLIA_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ipfs/kad/1.0.0");
+// The other protocol names and the gossipsub topics can be prefixed, see ProtocolNames
 const IPFS_IDENTIFY_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/ipfs/id/1.0.0");
-const FILE_EXCHANGE_PROTOCOL_NAME: StreamProtocol = 
-    StreamProtocol::new("/universal-connectivity-file/1");
-
-const GIT_EXCHANGE_PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/universal-connectivity-git/1");
+// The default payload size of a ping-peer command
+const ECHO_DEFAULT_SIZE: usize = 32;
 
-// Gossipsub Topics
-const GOSSIPSUB_CHAT_TOPIC: &str = "universal-connectivity";
-const GOSSIPSUB_CHAT_FILE_TOPIC: &str = "universal-connectivity-file";
-const GOSSIPSUB_PEER_DISCOVERY: &str = "universal-connectivity-browser-peer-discovery";
+// The gossipsub maximum transmit size of peers that don't configure it, libp2p's default
+const GOSSIPSUB_DEFAULT_MAX_TRANSMIT_SIZE: usize = 65536;
 
 // Listen Ports
 const PORT_WEBRTC: u16 = 9090; // UDP
 const PORT_QUIC: u16 = 9091; // UDP
 const PORT_TCP: u16 = 9092; // TCP
 
+// The directory that packfiles cloned from other peers are written to
+const RECEIVED_PACKS_DIR: &str = "./received_packs";
+// The directory that files fetched in ranges are kept in until they are complete
+const PARTIAL_FILES_DIR: &str = "./partial_files";
+// How many times a packfile chunk that fails its checksum is re-requested before the clone aborts
+const PACK_CHUNK_RETRIES: u32 = 3;
+// How long the reputation file is left unsaved after a violation, so a burst of violations is
+// written once
+const REPUTATION_SAVE_DELAY: Duration = Duration::from_secs(10);
+
 // Kademlia bootstrap interval
 const KADEMLIA_BOOTSTRAP_INTERVAL: u64 = 300;
+// The minimum time between bootstraps started with the bootstrap command
+const MANUAL_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(10);
+// How long provider records live in the DHT before they must be re-announced
+const PROVIDER_RECORD_TTL: u64 = 24 * 60 * 60;
 const IPFS_BOOTSTRAP_NODES: [&str; 4] = [
     "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
     "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
@@ -98,13 +160,18 @@ struct Behaviour {
     autonat_server: Toggle<AutonatServer>,
     connection_limits: ConnectionLimits,
     dcutr: Toggle<Dcutr>,
-    gossipsub: Gossipsub,
+    gossipsub: Gossipsub<SignedAuthorTransform>,
     identify: Identify,
-    kademlia: Toggle<Kademlia<MemoryStore>>,
+    kademlia: Toggle<Kademlia<LruMemoryStore>>,
     memory_connection_limits: MemoryConnectionLimits,
     relay_client: Toggle<RelayClient>,
-    relay_server: Toggle<RelayServer>,
+    relay_server: Toggle<LimitedRelayServer>,
     request_response: RequestResponse<GitExchangeCodec>,
+    file_exchange: RequestResponse<FileExchangeCodec>,
+    echo: RequestResponse<EchoCodec>,
+    file_manifest: RequestResponse<ManifestCodec>,
+    pex: RequestResponse<PexCodec>,
+    address_change: AddressChangeTracker,
 }
 
 
@@ -117,31 +184,227 @@ struct Behaviour {
 // - QUIC + TLS on UDP port 9091
 // - TCP + Noise on TCP port 9092
 // - TCP + TLS on TCP port 9092
+//
+// WebTransport is not supported: rust-libp2p only ships a browser (wasm) WebTransport client,
+// libp2p-webtransport-websys, and has no WebTransport listener that a native peer could add to the
+// SwarmBuilder. Browsers reach this peer over WebRTC-direct instead, which also uses a self-signed
+// certificate advertised by its /certhash in the multiaddr.
 
 /// The Peer state
 pub struct Peer {
     /// The addresses we're listening on
     listen_addresses: HashSet<Multiaddr>,
+    /// The address each listener was started on, removed when the listener closes
+    listeners: HashMap<ListenerId, Multiaddr>,
+    /// The interface listened on by name, if given, whose addresses the listeners follow
+    listen_interface: Option<ListenInterface>,
+    /// The number of extra WebRTC certificates, each listened on with its own port
+    extra_webrtc_listeners: usize,
     /// The external addresses that others see, given on command line
     external_addresses: HashSet<Multiaddr>,
+    /// If set, private and loopback addresses are used like public ones
+    allow_private_addresses: bool,
+    /// The transport dialed first when a peer has both QUIC and WebRTC addresses
+    prefer_transport: PreferredTransport,
+    /// The IP version dialed first when a peer has both IPv4 and IPv6 addresses
+    address_family_preference: AddressFamilyPreference,
+    /// The first addresses of the other IP version to dial once the happy eyeballs head start of
+    /// a dial is over, unless the peer connected by then
+    raced_dials: Vec<(Instant, PeerId, Multiaddr)>,
+    /// If set, failures are logged with their full error chain
+    verbose_errors: bool,
     /// The multiaddrs to dial, given on command line
     to_dial: Vec<String>,
+    /// The peers imported to seed the routing table, and if they should be dialed
+    seed_peers: Vec<(PeerId, Vec<Multiaddr>)>,
+    dial_seed_peers: bool,
+    /// The extra kademlia bootstrap nodes, given on command line
+    bootstrap_nodes: Vec<Multiaddr>,
     /// The sender to the ui
     to_ui: Sender<Message>,
     /// The receiver from the ui
     from_ui: Receiver<Message>,
     /// The shutdown token
     shutdown: CancellationToken,
+    /// Our public key, announced in the presence notifications
+    public_key: PublicKey,
+    /// Whether our join notification has been published
+    joined: bool,
+    /// If set, our addresses are only announced once we are confirmed reachable
+    announce_only_confirmed: bool,
+    /// Whether AutoNAT or the self-test confirmed we are reachable on any address, always set when
+    /// addresses are announced without confirmation
+    reachable: bool,
+    /// The external addresses held back until they are confirmed reachable, each on its own
+    unannounced_addresses: HashSet<Multiaddr>,
     /// The swarm itself
     swarm: Swarm<Behaviour>,
     /// The query id for the kademlia bootstrap
     bootstrap_query_id: Option<QueryId>,
+    /// The peers found by each bootstrap started with the bootstrap command
+    manual_bootstraps: HashMap<QueryId, HashSet<PeerId>>,
+    /// When the bootstrap command last started a bootstrap
+    last_manual_bootstrap: Option<Instant>,
     /// The query id for providing the universal connectivity agent string
     start_providing_query_id: Option<QueryId>,
     /// The query id for getting the providers of the universal connectivity agent string
     get_providers_query_id: Option<QueryId>,
     /// The query id for getting the closest peers to the universal connectivity agent string
     get_closest_peers_query_id: HashSet<QueryId>,
+    /// The repository each outstanding packfile chunk request is for
+    pack_requests: HashMap<OutboundRequestId, String>,
+    /// The repository each outstanding status chunk request is for
+    status_requests: HashMap<OutboundRequestId, String>,
+    /// The peer and repository each outstanding ls-remote chunk request is for
+    ls_remote_requests: HashMap<OutboundRequestId, (PeerId, String)>,
+    /// The ref listings being received, kept when cut short so they can be resumed
+    ls_remote_listings: HashMap<(PeerId, String), RefListing>,
+    /// The file each outstanding archive request is saved to
+    archive_requests: HashMap<OutboundRequestId, PathBuf>,
+    /// When each outstanding git request was sent, for the request log
+    git_requests_sent: HashMap<OutboundRequestId, Instant>,
+    /// The type of each Kademlia query in progress
+    kad_queries: HashMap<QueryId, &'static str>,
+    /// The Kademlia queries waiting for a free slot
+    kad_queue: KadQueryQueue,
+    /// The Kademlia queries cancelled with the cancel command, until their final event arrives
+    cancelled_queries: HashSet<QueryId>,
+    /// The peers being dialed or recently failed to dial
+    dial_coalescer: DialCoalescer,
+    /// How far the clocks of other peers are off from ours
+    clock_skew: ClockSkew,
+    /// The protocols each connected peer listed in its most recent identify exchange, with when it
+    /// was received
+    peer_protocols: HashMap<PeerId, (Vec<StreamProtocol>, Instant)>,
+    /// The protocol families each connected peer failed to negotiate any version of
+    unsupported_protocols: UnsupportedProtocols,
+    /// The peer metrics
+    metrics: Metrics,
+    /// What is known about each open connection
+    connections: HashMap<ConnectionId, ConnectionStats>,
+    /// The age at which connections are closed, if any
+    max_connection_lifetime: Option<Duration>,
+    /// The connections being closed for exceeding the maximum lifetime or timing out identify,
+    /// after which persistent peers are dialed again
+    expired_connections: HashSet<ConnectionId>,
+    /// If set, persistent peers whose identify times out are reconnected instead of dropped
+    redial_on_identify_timeout: bool,
+    /// Whether only one connection is kept to each peer
+    dedup_connections: bool,
+    /// The substring the agent versions of peers must contain, a testing aid
+    require_agent_substring: Option<String>,
+    /// The substring the agent versions of peers must not contain, a testing aid
+    exclude_agent_substring: Option<String>,
+    /// The redundant connections being closed
+    redundant_connections: HashSet<ConnectionId>,
+    /// The peers with redundant connections left open while a transfer is in flight
+    dedup_deferred: HashSet<PeerId>,
+    /// The longest a drain waits for the transfers in flight to finish
+    drain_timeout: Duration,
+    /// When draining started, if it did
+    drain_started: Option<Instant>,
+    /// Since when no transfer has been in flight while draining
+    drain_quiet_since: Option<Instant>,
+    /// When the shutdown started, while the transfers in flight are being finished
+    stopping_since: Option<Instant>,
+    /// The peers given on the command line, with their addresses if known, dialed again after
+    /// their connections expire
+    persistent_peers: HashMap<PeerId, Vec<Multiaddr>>,
+    /// When the self-test should start, if it is enabled and hasn't started yet
+    self_test_at: Option<Instant>,
+    /// The running self-test
+    self_test: Option<JoinHandle<anyhow::Result<Vec<SelfTestResult>>>>,
+    /// If set, a failed self-test stops the peer with an error
+    self_test_strict: bool,
+    /// The options the peer was started with, included in the state snapshots
+    config: String,
+    /// How often a state snapshot is written to the state dump path, if periodically
+    state_dump_interval: Option<Duration>,
+    state_dump_path: PathBuf,
+    /// When the next periodic state snapshot is due
+    next_state_dump: Option<Instant>,
+    /// The status shown on the dashboard, if it is served
+    dashboard: Option<watch::Sender<serde_json::Value>>,
+    /// The commands received on the control socket
+    control: ControlCommands,
+    /// When the dashboard status is next updated
+    next_dashboard_update: Instant,
+    /// The messages waiting for subscribed peers, if buffering unsent messages is enabled
+    unsent_messages: Option<MessageBuffer>,
+    /// The files this peer holds and provides
+    file_store: FileStore,
+    /// The hash the files added with the add-file command are addressed with
+    content_hash: ContentHash,
+    /// The persistent index of the files in the store and the provider records we announced
+    #[cfg(feature = "sqlite-index")]
+    file_index: Option<FileIndex>,
+    /// The providers of files advertised by other peers
+    provider_index: ProviderIndex,
+    /// The protocol ids and gossipsub topics, prefixed for a private deployment
+    protocols: ProtocolNames,
+    /// The relays we hold reservations on, shared with the relay server's loop detection
+    relay_loop_guard: RelayLoopGuard,
+    /// The file requests that haven't been answered yet, with their idempotency nonce
+    file_requests: OutstandingRequests,
+    /// The files being fetched in ranges, by file id, while a request for one of their ranges is
+    /// outstanding
+    partial_files: HashMap<String, PartialFile>,
+    /// The offered files waiting for capacity to be fetched
+    fetch_queue: FetchQueue,
+    /// The inbound file requests being answered, to collapse duplicates
+    inflight_file_requests: InflightRequests,
+    /// Decrypts the files we request encrypted, set with --encrypt-files
+    file_decryptor: Option<FileDecryptor>,
+    /// The topics whose offered files are fetched, every offered file is fetched if empty
+    file_topics_of_interest: Vec<String>,
+    /// The outstanding file manifest requests
+    manifest_requests: HashSet<OutboundRequestId>,
+    /// The number of peers asked for in a peer exchange, 0 to not ask
+    pex_sample_size: u32,
+    /// The most peers dialed from each peer exchange sample
+    pex_dial: usize,
+    /// The peers we asked for a peer exchange sample, each is only asked once
+    pex_asked: HashSet<PeerId>,
+    /// The payload and send time of each outstanding echo request
+    echo_requests: HashMap<OutboundRequestId, (Vec<u8>, Instant)>,
+    /// Schedules re-announcing the provider records for the held files
+    reprovider: Reprovider,
+    /// The peer each inbound request still being answered came from, by the protocol family it
+    /// came over since each request-response behaviour numbers its requests on its own
+    inbound_requests: HashMap<(&'static str, InboundRequestId), PeerId>,
+    /// The maximum number of inbound requests being answered at once
+    max_inbound_streams: usize,
+    /// The maximum number of inbound requests from a single peer being answered at once
+    max_inbound_streams_per_peer: usize,
+    /// The authentication policy of each subscribed topic
+    topic_policies: TopicPolicies,
+    /// The activity on each subscribed topic, shown by the status command
+    topic_stats: TopicStats,
+    /// The reputation of the peers that have misbehaved
+    reputation: ReputationStore,
+    /// When the reputation is next saved, set by the first violation since it was last saved
+    reputation_save_at: Option<Instant>,
+    /// The settings for serving git requests from other peers
+    git_server_config: ServerConfig,
+    /// The status snapshots of the repositories other peers are listing
+    status_snapshots: StatusSnapshots,
+    /// The inbound git requests being handled on the blocking thread pool, each finishing with
+    /// its response and the channel to send it on
+    git_handlers: JoinSet<(InboundRequestId, ResponseChannel<GitResponse>, GitResponse)>,
+    /// The packfiles being cloned from other peers, by peer and repository
+    pack_transfers: HashMap<(PeerId, String), PackReassembler<fs::File>>,
+    /// The number of times the current chunk of each packfile transfer has been re-requested
+    pack_chunk_retries: HashMap<(PeerId, String), u32>,
+    /// The depth of each shallow packfile transfer, by peer and repository
+    pack_depths: HashMap<(PeerId, String), u32>,
+    /// The tips of the local copy of the repository of each packfile transfer, sent as its haves,
+    /// by peer and repository
+    pack_haves: HashMap<(PeerId, String), Vec<String>>,
+    /// The signature of the packfile of each transfer, from its first chunk, by peer and
+    /// repository
+    pack_signatures: HashMap<(PeerId, String), String>,
+    /// The file and git exchanges in flight, shown by the transfers command
+    transfers: Transfers,
 }
 
 impl Peer {
@@ -149,6 +412,7 @@ impl Peer {
     pub async fn new(
         keypair: identity::Keypair,
         tls_cert: Certificate,
+        extra_tls_certs: Vec<Certificate>,
         to_ui: Sender<Message>,
         from_ui: Receiver<Message>,
         shutdown: CancellationToken,
@@ -161,22 +425,23 @@ impl Peer {
         // const PORT_QUIC: u16 = 9091; // UDP
         // const PORT_TCP: u16 = 9092; // TCP
 
+        // listen on the addresses of the interface instead of the listen addresses if one is given
+        let listen_interface = opt
+            .listen_interface
+            .as_deref()
+            .map(ListenInterface::resolve)
+            .transpose()?;
+        let listen_ips: Vec<IpAddr> = match listen_interface.as_ref() {
+            Some(interface) => {
+                let ips: Vec<IpAddr> = interface.addresses().copied().collect();
+                info!("Listening on interface {} with addresses {ips:?}", interface.name());
+                ips
+            }
+            None => opt.listen_addresses.clone(),
+        };
         let mut listen_addresses = HashSet::new();
-        for addr in opt.listen_addresses.iter() {
-            // add the WebRTC address
-            listen_addresses.insert(
-                ipaddr_to_multiaddr(addr)
-                    .with(Protocol::Udp(PORT_WEBRTC))
-                    .with(Protocol::WebRTCDirect),
-            );
-            // add the QUIC address
-            listen_addresses.insert(
-                ipaddr_to_multiaddr(addr)
-                    .with(Protocol::Udp(PORT_QUIC))
-                    .with(Protocol::QuicV1),
-            );
-            // add the TCP address
-            listen_addresses.insert(ipaddr_to_multiaddr(addr).with(Protocol::Tcp(PORT_TCP)));
+        for addr in listen_ips.iter() {
+            listen_addresses.extend(Self::listen_multiaddrs(addr, extra_tls_certs.len()));
         }
 
         let mut external_addresses = HashSet::new();
@@ -184,8 +449,211 @@ impl Peer {
             external_addresses.insert(ipaddr_to_multiaddr(addr));
         }
 
+        // register the metrics and serve them if asked to
+        let mut registry = Registry::with_prefix("universal_connectivity");
+        let metrics = Metrics::register(&mut registry);
+        if let Some(addr) = opt.metrics_addr.as_ref() {
+            let listener = addr.bind().await?;
+            info!("Serving metrics on {addr}");
+            tokio::spawn(metrics::serve(listener, registry));
+        }
+
+        // serve the dashboard if asked to, the status it shows is updated from the event loop
+        let dashboard = match opt.dashboard_addr.as_ref() {
+            Some(addr) => {
+                let listener = addr.bind().await?;
+                info!("Serving the dashboard on {addr}");
+                let (status, status_receiver) = watch::channel(serde_json::Value::Null);
+                tokio::spawn(dashboard::serve(listener, status_receiver));
+                Some(status)
+            }
+            None => None,
+        };
+
+        // serve the control socket if asked to, its commands are run by the event loop
+        let control = match opt.control_addr.as_ref() {
+            Some(addr) => {
+                let listener = addr.bind().await?;
+                info!("Serving the control socket on {addr}");
+                ControlCommands::serve(listener)
+            }
+            None => ControlCommands::default(),
+        };
+
+        // re-announce provider records before they expire
+        let reprovide_interval = Duration::from_secs(opt.reprovide_interval);
+        if reprovide_interval >= Duration::from_secs(PROVIDER_RECORD_TTL) {
+            warn!(
+                "Reprovide interval {reprovide_interval:?} is not shorter than the provider record TTL, provider records will expire"
+            );
+        }
+
+        // reload the reputation of the peers that misbehaved before the restart
+        let reputation = match opt.reputation_path.as_ref() {
+            Some(path) => {
+                let reputation = ReputationStore::load(path).with_context(|| {
+                    format!("Failed to load reputation file {}", path.display())
+                })?;
+                info!("Loaded the reputation of {} peers", reputation.len());
+                reputation
+            }
+            None => ReputationStore::default(),
+        };
+
+        // load the peers exported by another node to skip the cold bootstrap
+        let seed_peers = match opt.import_peers.as_ref() {
+            Some(path) => {
+                let seeds = PeerSeeds::load(path).with_context(|| {
+                    format!("Failed to load peer seeds file {}", path.display())
+                })?;
+                if seeds.peers.is_empty() {
+                    info!("No peers to import from {}", path.display());
+                    Vec::new()
+                } else if seeds.is_expired() {
+                    warn!(
+                        "Not importing peers from {}: exported {}s ago",
+                        path.display(),
+                        seeds.age().as_secs()
+                    );
+                    Vec::new()
+                } else {
+                    let peers = seeds.entries();
+                    info!("Imported {} peers from {}", peers.len(), path.display());
+                    peers
+                }
+            }
+            None => Vec::new(),
+        };
+
         // keep them as Strings because they can be PeerId's or Multiaddr's
-        let to_dial = opt.connect;
+        let mut to_dial = opt.connect.clone();
+        if let Some(path) = opt.connect_file.as_ref() {
+            let peers = read_peer_list(path, true)
+                .with_context(|| format!("Failed to read connect file {}", path.display()))?;
+            to_dial.extend(peers);
+        }
+
+        // the peers to keep connected to, even when their connections are recycled
+        let mut persistent_peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+        for peer in to_dial.iter() {
+            if let Ok(peer_id) = peer.parse::<PeerId>() {
+                persistent_peers.entry(peer_id).or_default();
+            } else if let Some((addr, peer_id)) =
+                peer.parse::<Multiaddr>().ok().and_then(split_peer_id)
+            {
+                persistent_peers.entry(peer_id).or_default().push(addr);
+            }
+        }
+
+        #[cfg(feature = "sqlite-index")]
+        let file_index = opt.index_db.as_deref().map(FileIndex::open).transpose()?;
+        #[cfg(not(feature = "sqlite-index"))]
+        if opt.index_db.is_some() {
+            anyhow::bail!("--index-db requires building with the sqlite-index feature");
+        }
+
+        // load the files of the served directories
+        ServeDir::validate(&opt.serve_dir)?;
+        let mut file_store = FileStore::with_max_bytes(opt.file_cache_max_bytes);
+        for dir in opt.serve_dir.iter() {
+            let files = dir.list_files()?;
+            info!(
+                "Serving {} files from {} under {}",
+                files.len(),
+                dir.path.display(),
+                dir.namespace
+            );
+            for file in files {
+                // files are read one at a time, and only once they are known to fit
+                if file.size > file_exchange::MAX_FILE_SIZE || !file_store.fits(file.size) {
+                    warn!("Not serving {}: it is larger than --file-cache-max-bytes or the largest file that can be exchanged", file.path.display());
+                    continue;
+                }
+                let body = match file.read() {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Not serving {}: {e}", file.path.display());
+                        continue;
+                    }
+                };
+                let name = file.name;
+                let file_id = dir.file_id(&name);
+                #[cfg(feature = "sqlite-index")]
+                if let Some(index) = file_index.as_ref() {
+                    let path = dir.path.join(&name);
+                    index.insert_served(&file_id, &path, body.len() as u64, &dir.namespace, &name)?;
+                }
+                file_store.insert_served(file_id, dir.namespace.clone(), name, body);
+            }
+        }
+
+        // reload the other indexed files, dropping the ones that went missing or changed
+        #[cfg(feature = "sqlite-index")]
+        if let Some(index) = file_index.as_ref() {
+            // the files of directories that are no longer served are neither held nor provided
+            let namespaces: Vec<&str> = opt.serve_dir.iter().map(|dir| dir.namespace.as_str()).collect();
+            for file_id in index.remove_namespaces_except(&namespaces)? {
+                info!("Dropped {file_id} from the file index: its namespace is no longer served");
+            }
+            let mut reloaded = 0;
+            for file in index.files()? {
+                if file_store.contains(&file.file_id) {
+                    continue;
+                }
+                let body = match file.read() {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Dropping {} from the file index: {e}", file.file_id);
+                        index.remove(&file.file_id)?;
+                        continue;
+                    }
+                };
+                match (file.namespace, file.name) {
+                    (Some(namespace), Some(name)) => {
+                        file_store.insert_served(file.file_id, namespace, name, body)
+                    }
+                    _ => file_store.insert(file.file_id, body),
+                };
+                reloaded += 1;
+            }
+            info!("Reloaded {reloaded} files from the file index");
+        }
+
+        // the files beyond the cache limit are never provided
+        for file_id in file_store.evict() {
+            warn!("Not holding {file_id}: the files take more than --file-cache-max-bytes");
+            #[cfg(feature = "sqlite-index")]
+            if let Some(index) = file_index.as_ref() {
+                index.remove(&file_id)?;
+            }
+        }
+
+        let file_decryptor = opt
+            .encrypt_files
+            .then(|| FileDecryptor::new(&keypair))
+            .transpose()?;
+
+        // the bootstrap nodes must be Multiaddr's so their address can be added to kademlia
+        let bootstrap_nodes = match opt.bootstrap_file.as_ref() {
+            Some(path) => read_peer_list(path, false)
+                .with_context(|| format!("Failed to read bootstrap file {}", path.display()))?
+                .iter()
+                .filter_map(|s| s.parse().ok())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // the protocol ids and topics, prefixed for a private deployment
+        let protocols = ProtocolNames::new(opt.protocol_prefix.as_deref())?;
+        if let Some(prefix) = opt.protocol_prefix.as_ref() {
+            info!("Using the protocol prefix {prefix}, only peers with the same prefix can be reached");
+        }
+
+        // shared with the relay server to refuse circuits that would loop back through our relays
+        let relay_loop_guard = RelayLoopGuard::default();
+        // shared with the relay server to cap the circuits of each source and destination
+        let relay_circuit_limits =
+            RelayCircuitLimits::new(opt.max_circuits_per_source, opt.max_circuits_per_destination);
 
         // initialize the swarm
         let swarm = {
@@ -230,28 +698,53 @@ impl Peer {
             // Create a gossipsub behaviour
             let gossipsub = {
                 // This closure creates a unique message id for each message by hashing its contents
-                let message_id_fn = |message: &GossipsubMessage| {
-                    let mut s = DefaultHasher::new();
-                    message.data.hash(&mut s);
-                    GossipsubMessageId::from(s.finish().to_string())
+                let content_hash = opt.content_hash;
+                let message_id_fn = move |message: &GossipsubMessage| {
+                    GossipsubMessageId::from(content_hash.message_id(&message.data))
                 };
 
                 // Set a custom gossipsub configuration
+                let heartbeat_interval = Duration::from_millis(opt.gossip_heartbeat_interval);
+                let fanout_ttl = Duration::from_secs(opt.gossip_fanout_ttl);
+                info!("Gossipsub heartbeat interval: {heartbeat_interval:?}, fanout TTL: {fanout_ttl:?}");
+
+                // IDONTWANT can't be switched off, it is only sent for messages at least the
+                // threshold in size, so a threshold no message reaches disables it
+                let idontwant_threshold = match opt.gossip_idontwant {
+                    true => {
+                        info!("Gossipsub IDONTWANT enabled for messages of at least {} bytes", opt.gossip_idontwant_threshold);
+                        opt.gossip_idontwant_threshold
+                    }
+                    false => {
+                        info!("Gossipsub IDONTWANT disabled");
+                        usize::MAX
+                    }
+                };
+
                 let gossipsub_config = gossipsub::ConfigBuilder::default()
+                    .heartbeat_interval(heartbeat_interval)
+                    .fanout_ttl(fanout_ttl)
+                    .max_transmit_size(opt.gossip_max_transmit_size as usize)
+                    .idontwant_message_size_threshold(idontwant_threshold)
                     // This sets the kind of message validation. The default is Strict (enforce message signing)
                     .validation_mode(gossipsub::ValidationMode::Permissive)
                     // This ensures no two messages of the same content will be propagated.
                     .message_id_fn(message_id_fn)
+                    // Messages are only forwarded once they pass their topic's TopicAuth policy
+                    .validate_messages()
                     .mesh_outbound_min(1)
                     .mesh_n_low(1)
                     .flood_publish(true)
                     .build()
                     .expect("Valid config");
 
-                // build a gossipsub network behaviour
-                Gossipsub::new(
+                // build a gossipsub network behaviour. Everything we publish is signed, on every
+                // topic: a TopicAuth policy only decides which received messages are accepted.
+                Gossipsub::new_with_transform(
                     gossipsub::MessageAuthenticity::Signed(keypair.clone()),
                     gossipsub_config,
+                    None,
+                    SignedAuthorTransform,
                 )
                 .expect("Correct configuration")
             };
@@ -262,18 +755,31 @@ impl Peer {
                     IPFS_IDENTIFY_PROTOCOL_NAME.to_string(), // bug: https://github.com/libp2p/rust-libp2p/issues/5940
                     keypair.public(),
                 )
-                .with_agent_version(UNIVERSAL_CONNECTIVITY_AGENT.to_string());
+                .with_agent_version(UNIVERSAL_CONNECTIVITY_AGENT.to_string())
+                // push our info to connected peers as soon as our listen addresses change, e.g.
+                // after a relay reservation, instead of waiting for their next identify request.
+                // Confirmed external addresses are pushed on ExternalAddrConfirmed.
+                .with_push_listen_addr_updates(true)
+                // our listen addresses are unconfirmed, so only share the external ones
+                .with_hide_listen_addrs(opt.announce_only_confirmed);
                 Identify::new(cfg)
             };
 
             // Create a Kademlia behaviour
-            let kademlia: Toggle<Kademlia<MemoryStore>> = if opt.kademlia {
-                let mut cfg = KademliaConfig::new(IPFS_KADEMLIA_PROTOCOL_NAME);
+            let kademlia: Toggle<Kademlia<LruMemoryStore>> = if opt.kademlia && !opt.no_kademlia {
+                let mut cfg = KademliaConfig::new(protocols.kademlia.clone());
                 cfg.set_query_timeout(Duration::from_secs(60));
                 cfg.set_periodic_bootstrap_interval(Some(Duration::from_secs(
                     KADEMLIA_BOOTSTRAP_INTERVAL,
                 )));
-                let store = MemoryStore::new(local_peer_id);
+                // provider records are re-announced by the Reprovider instead of by Kademlia
+                cfg.set_provider_record_ttl(Some(Duration::from_secs(PROVIDER_RECORD_TTL)));
+                cfg.set_provider_publication_interval(None);
+                let store = LruMemoryStore::new(
+                    local_peer_id,
+                    opt.kad_max_records as usize,
+                    opt.kad_max_providers as usize,
+                );
                 Some(Kademlia::with_config(local_peer_id, store, cfg))
             } else {
                 None
@@ -289,12 +795,20 @@ impl Peer {
                     max_reservations: usize::MAX,
                     max_reservations_per_peer: 100,
                     reservation_rate_limiters: Vec::default(),
-                    circuit_src_rate_limiters: Vec::default(),
+                    circuit_src_rate_limiters: vec![
+                        Box::new(relay_loop_guard.clone()),
+                        Box::new(relay_circuit_limits.clone()),
+                    ],
                     max_circuits: usize::MAX,
-                    max_circuits_per_peer: 100,
+                    // the relay server counts circuits a peer is the source and destination of
+                    // together, the separate limits are applied by relay_circuit_limits and the
+                    // LimitedRelayServer
+                    max_circuits_per_peer: opt
+                        .max_circuits_per_source
+                        .saturating_add(opt.max_circuits_per_destination),
                     ..Default::default()
                 };
-                Some(RelayServer::new(local_peer_id, cfg))
+                Some(LimitedRelayServer::new(local_peer_id, cfg, relay_circuit_limits.clone()))
             } else {
                 None
             }
@@ -302,8 +816,34 @@ impl Peer {
 
             // Create the RequestResponse behaviour
             let request_response = {
+                // bound the streams a single connection can have open at the transport level
+                let cfg = RequestResponseConfig::default()
+                    .with_max_concurrent_streams(opt.max_inbound_streams_per_peer);
+                RequestResponse::new(protocols.git_exchange.clone().map(|p| (p, ProtocolSupport::Full)), cfg)
+            };
+
+            // Create the file exchange RequestResponse behaviour
+            let file_exchange = {
+                let cfg = RequestResponseConfig::default();
+                RequestResponse::new(protocols.file_exchange.clone().map(|p| (p, ProtocolSupport::Full)), cfg)
+            };
+
+            // Create the echo RequestResponse behaviour
+            let echo = {
                 let cfg = RequestResponseConfig::default();
-                RequestResponse::new([(GIT_EXCHANGE_PROTOCOL_NAME, ProtocolSupport::Full)], cfg)
+                RequestResponse::new([(protocols.echo.clone(), ProtocolSupport::Full)], cfg)
+            };
+
+            // Create the file manifest RequestResponse behaviour
+            let file_manifest = {
+                let cfg = RequestResponseConfig::default();
+                RequestResponse::new([(protocols.file_manifest.clone(), ProtocolSupport::Full)], cfg)
+            };
+
+            // Create the peer exchange RequestResponse behaviour
+            let pex = {
+                let cfg = RequestResponseConfig::default();
+                RequestResponse::new([(protocols.pex.clone(), ProtocolSupport::Full)], cfg)
             };
 
             // Initialize the overall peer behaviour
@@ -319,62 +859,509 @@ impl Peer {
                 relay_client: None.into(),
                 relay_server,
                 request_response,
+                file_exchange,
+                echo,
+                file_manifest,
+                pex,
+                address_change: AddressChangeTracker::default(),
             };
 
-            // Build the swarm
-            let sb = SwarmBuilder::with_existing_identity(keypair.clone())
-                .with_tokio()
-                .with_tcp(
-                    TcpConfig::new().nodelay(true),
-                    (TlsConfig::new, NoiseConfig::new), // passes the keypair to the constructors
-                    YamuxConfig::default,
-                )?
-                .with_quic()
-                .with_other_transport(|id_keys| {
-                    Ok(webrtc::tokio::Transport::new(
-                        id_keys.clone(),
-                        tls_cert.clone(),
-                    ))
-                })?
-                .with_dns()?;
-
-            // if we are to be a relay client, add the relay client behaviour
-            if opt.relay_client {
-                sb.with_relay_client((TlsConfig::new, NoiseConfig::new), YamuxConfig::default)?
-                    .with_behaviour(|_key, relay_client| {
-                        behaviour.relay_client = Some(relay_client).into();
-                        behaviour
+            // Every transport aborts connections whose security and muxer upgrade stalls. The
+            // builder's TCP and relay upgrades get it through their security handshake, QUIC
+            // through its handshake timeout and the hand built transports through a transport
+            // timeout.
+            let upgrade_timeout = Duration::from_secs(opt.upgrade_timeout);
+            let tls = move |keypair: &identity::Keypair| {
+                TlsConfig::new(keypair).map(|tls| UpgradeTimeout::new(tls, upgrade_timeout))
+            };
+            let noise = move |keypair: &identity::Keypair| {
+                NoiseConfig::new(keypair).map(|noise| UpgradeTimeout::new(noise, upgrade_timeout))
+            };
+
+            // Add the WebRTC transport after the TCP and QUIC transports, which are set up
+            // differently when dialing through a proxy
+            macro_rules! with_webrtc {
+                ($sb:expr) => {
+                    $sb.with_other_transport(|id_keys| {
+                        TransportTimeout::new(
+                            cert_rotation::webrtc_transport(
+                                id_keys,
+                                tls_cert.clone(),
+                                PORT_WEBRTC,
+                                &extra_tls_certs,
+                            ),
+                            upgrade_timeout,
+                        )
+                        .boxed()
                     })?
-                    .build()
-            } else {
-                sb.with_behaviour(|_key| behaviour)?.build()
+                };
+            }
+
+            // Add the behaviour, after the transports
+            macro_rules! build_swarm {
+                ($sb:expr) => {{
+                    let sb = $sb;
+                    // if we are to be a relay client, add the relay client behaviour
+                    if opt.relay_client {
+                        sb.with_relay_client((tls, noise), YamuxConfig::default)?
+                            .with_behaviour(|_key, relay_client| {
+                                behaviour.relay_client = Some(relay_client).into();
+                                behaviour
+                            })?
+                            .build()
+                    } else {
+                        sb.with_behaviour(|_key| behaviour)?.build()
+                    }
+                }};
+            }
+
+            // Build the swarm
+            let sb = SwarmBuilder::with_existing_identity(keypair.clone()).with_tokio();
+            match opt.proxy.clone() {
+                // without the DNS transport, which would resolve host names here and leak the
+                // lookups: the proxy resolves the host names of TCP addresses, and the other
+                // transports refuse addresses with a host name
+                Some(proxy) => {
+                    info!("Dialing TCP connections through {proxy:?}, /dns addresses are only dialed over TCP");
+                    build_swarm!(with_webrtc!(sb
+                        .with_quic_config(|mut cfg| {
+                            cfg.handshake_timeout = upgrade_timeout;
+                            cfg
+                        })
+                        .with_other_transport(|id_keys| {
+                            proxy::tcp_transport(id_keys, proxy, upgrade_timeout)
+                        })?))
+                }
+                None => build_swarm!(with_webrtc!(sb
+                    .with_tcp(
+                        TcpConfig::new().nodelay(true),
+                        (tls, noise), // passes the keypair to the constructors
+                        YamuxConfig::default,
+                    )?
+                    .with_quic_config(|mut cfg| {
+                        cfg.handshake_timeout = upgrade_timeout;
+                        cfg
+                    }))
+                .with_dns()?),
             }
         };
 
         Ok(Self {
             listen_addresses,
+            listen_interface,
+            extra_webrtc_listeners: extra_tls_certs.len(),
+            listeners: HashMap::new(),
             external_addresses,
+            allow_private_addresses: opt.allow_private_addresses,
+            prefer_transport: opt.prefer_transport,
+            address_family_preference: opt.address_family_preference,
+            raced_dials: Vec::new(),
+            verbose_errors: opt.verbose_errors,
             to_dial,
+            seed_peers,
+            dial_seed_peers: opt.dial_imported_peers,
+            bootstrap_nodes,
             to_ui,
             from_ui,
             shutdown,
+            public_key: keypair.public(),
+            joined: false,
+            announce_only_confirmed: opt.announce_only_confirmed,
+            reachable: !opt.announce_only_confirmed,
+            unannounced_addresses: HashSet::new(),
             swarm,
             bootstrap_query_id: None,
+            manual_bootstraps: HashMap::new(),
+            last_manual_bootstrap: None,
             start_providing_query_id: None,
             get_providers_query_id: None,
             get_closest_peers_query_id: HashSet::new(),
+            kad_queries: HashMap::new(),
+            kad_queue: KadQueryQueue::new(opt.max_kad_queries as usize),
+            cancelled_queries: HashSet::new(),
+            dial_coalescer: DialCoalescer::new(
+                Duration::from_secs(opt.min_redial_interval),
+                opt.max_dial_attempts_per_peer,
+                Duration::from_secs(opt.dial_breaker_cooldown),
+            ),
+            clock_skew: ClockSkew::new(Duration::from_secs(opt.clock_skew_threshold)),
+            peer_protocols: HashMap::new(),
+            unsupported_protocols: UnsupportedProtocols::default(),
+            connections: HashMap::new(),
+            max_connection_lifetime: opt.max_connection_lifetime.map(Duration::from_secs),
+            expired_connections: HashSet::new(),
+            redial_on_identify_timeout: opt.redial_on_identify_timeout,
+            dedup_connections: opt.dedup_connections,
+            require_agent_substring: opt.require_agent_substring.clone(),
+            exclude_agent_substring: opt.exclude_agent_substring.clone(),
+            redundant_connections: HashSet::new(),
+            dedup_deferred: HashSet::new(),
+            drain_timeout: Duration::from_secs(opt.drain_timeout),
+            drain_started: None,
+            drain_quiet_since: None,
+            stopping_since: None,
+            persistent_peers,
+            self_test_at: opt.self_test.then(|| Instant::now() + SELF_TEST_DELAY),
+            self_test: None,
+            self_test_strict: opt.self_test_strict,
+            config: format!("{opt:?}"),
+            state_dump_interval: opt.state_dump_interval.map(Duration::from_secs),
+            state_dump_path: opt.state_dump_path.clone(),
+            next_state_dump: opt
+                .state_dump_interval
+                .map(|interval| Instant::now() + Duration::from_secs(interval)),
+            dashboard,
+            control,
+            next_dashboard_update: Instant::now(),
+            unsent_messages: opt
+                .buffer_unsent_messages
+                .then(|| MessageBuffer::new(Duration::from_secs(opt.unsent_message_max_age))),
+            metrics,
+            file_store,
+            content_hash: opt.content_hash,
+            #[cfg(feature = "sqlite-index")]
+            file_index,
+            provider_index: ProviderIndex::default(),
+            protocols,
+            relay_loop_guard,
+            file_requests: OutstandingRequests::default(),
+            partial_files: HashMap::new(),
+            fetch_queue: FetchQueue::new(
+                opt.max_file_fetches as usize,
+                opt.max_queued_file_fetches,
+                opt.file_fetch_cache_threshold,
+            ),
+            file_decryptor,
+            file_topics_of_interest: opt.file_topics_of_interest.clone(),
+            inflight_file_requests: InflightRequests::default(),
+            echo_requests: HashMap::new(),
+            manifest_requests: HashSet::new(),
+            pex_sample_size: opt.pex_sample_size,
+            pex_dial: opt.pex_dial,
+            pex_asked: HashSet::new(),
+            reprovider: Reprovider::new(reprovide_interval, Instant::now()),
+            inbound_requests: HashMap::new(),
+            max_inbound_streams: opt.max_inbound_streams,
+            max_inbound_streams_per_peer: opt.max_inbound_streams_per_peer,
+            topic_policies: TopicPolicies::default(),
+            topic_stats: TopicStats::default(),
+            reputation,
+            reputation_save_at: None,
+            pack_requests: HashMap::new(),
+            status_requests: HashMap::new(),
+            ls_remote_requests: HashMap::new(),
+            ls_remote_listings: HashMap::new(),
+            archive_requests: HashMap::new(),
+            git_requests_sent: HashMap::new(),
+            git_server_config: ServerConfig {
+                pack_strategy: opt.pack_strategy,
+                max_repo_size: opt.max_repo_size,
+                clone_timeout: Duration::from_secs(opt.git_clone_timeout),
+                fetch_timeout: Duration::from_secs(opt.git_fetch_timeout),
+                pack_timeout: Duration::from_secs(opt.git_pack_timeout),
+            },
+            status_snapshots: StatusSnapshots::default(),
+            git_handlers: JoinSet::new(),
+            pack_transfers: HashMap::new(),
+            pack_chunk_retries: HashMap::new(),
+            pack_depths: HashMap::new(),
+            pack_haves: HashMap::new(),
+            pack_signatures: HashMap::new(),
+            transfers: Transfers::default(),
         })
     }
 
+    /// Publish a gossipsub message. If buffering is enabled and no peers are subscribed to the
+    /// topic, the message is buffered to be retried and counts as sent.
+    fn publish(
+        &mut self,
+        topic: TopicHash,
+        data: Vec<u8>,
+        sent: Instant,
+    ) -> Result<(), PublishError> {
+        match self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic.clone(), data.clone())
+        {
+            Ok(_) => Ok(()),
+            Err(PublishError::InsufficientPeers) if self.unsent_messages.is_some() => {
+                debug!("No peers subscribed to {topic}, buffering the message");
+                if let Some(unsent) = self.unsent_messages.as_mut() {
+                    unsent.push(topic, data, sent);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Retry publishing the buffered messages, dropping those that are too old
+    fn publish_unsent_messages(&mut self) {
+        let Some(unsent) = self.unsent_messages.as_mut() else {
+            return;
+        };
+        if unsent.is_empty() {
+            return;
+        }
+
+        let dropped = unsent.drop_expired(Instant::now());
+        if dropped > 0 {
+            warn!("Dropped {dropped} unsent messages that exceeded the maximum age");
+        }
+
+        // anything still without peers goes back into the buffer
+        for (topic, data, sent) in unsent.take() {
+            if let Err(e) = self.publish(topic.clone(), data, sent) {
+                debug!("Failed to publish buffered message to {topic}: {e}");
+            }
+        }
+    }
+
+    /// Publish a chat message on the chat topic
+    fn send_chat(&mut self, envelope: &ChatEnvelope) -> anyhow::Result<()> {
+        let topic = GossipsubIdentTopic::new(&self.protocols.chat_topic).hash();
+        self.topic_policies.check_publish(&topic)?;
+        self.publish(topic, envelope.encode()?, Instant::now())?;
+        Ok(())
+    }
+
+    /// Start dialing our own advertised addresses in the background
+    async fn start_self_test(&mut self) -> anyhow::Result<()> {
+        let mut addresses: Vec<Multiaddr> = self.swarm.external_addresses().cloned().collect();
+        for addr in self.unannounced_addresses.iter().chain(self.swarm.listeners()) {
+            if !addresses.contains(addr) {
+                addresses.push(addr.clone());
+            }
+        }
+
+        self.msg(format!("Self-test dialing {} addresses", addresses.len()))
+            .await?;
+        let peer_id = *self.swarm.local_peer_id();
+        self.self_test = Some(tokio::spawn(self_test::run(
+            peer_id,
+            addresses,
+            SELF_TEST_DIAL_TIMEOUT,
+        )));
+        Ok(())
+    }
+
+    /// Report the results of the self-test, failing if it is strict and an address is unreachable
+    async fn self_test_finished(&mut self) -> anyhow::Result<()> {
+        let Some(task) = self.self_test.take() else {
+            return Ok(());
+        };
+        let results = task.await??;
+
+        self.msg("Self-test results:").await?;
+        for result in results.iter() {
+            self.msg(result.to_string()).await?;
+        }
+
+        let failed = results.iter().filter(|r| !r.passed()).count();
+        self.msg(format!(
+            "Self-test: {} passed, {failed} failed",
+            results.len() - failed
+        ))
+        .await?;
+
+        if failed > 0 && self.self_test_strict {
+            self.shutdown.cancel();
+            anyhow::bail!("Self-test failed for {failed} addresses");
+        }
+
+        // a private or loopback address is reached without leaving the host, which says nothing
+        // about whether other peers can reach it
+        for result in results.iter().filter(|r| r.passed() && !is_private_ip(&r.address)) {
+            self.confirm_reachable(&result.address, "the self-test").await?;
+        }
+        Ok(())
+    }
+
     /// Send a message to the UI
     pub async fn msg(&mut self, msg: impl ToString) -> anyhow::Result<()> {
-        self.to_ui.send(Message::Event(msg.to_string())).await?;
+        let msg = msg.to_string();
+        if let Err(e) = self.to_ui.send(Message::Event(msg)).await {
+            // a UI that failed stops first, that isn't an error of the peer's own
+            if !self.shutdown.is_cancelled() {
+                return Err(e.into());
+            }
+            debug!("Dropping message for the stopped UI: {:?}", e.0);
+        }
+        Ok(())
+    }
+
+    /// Format an error for the log, with its full source chain in verbose mode
+    fn error_message(&self, error: &(dyn std::error::Error + 'static)) -> String {
+        if self.verbose_errors {
+            verbose_error(error)
+        } else {
+            error.to_string()
+        }
+    }
+
+    /// Check if an address may be dialed, advertised or added to the routing table. Private
+    /// addresses are only allowed with --allow-private-addresses.
+    fn address_allowed(&self, address: &Multiaddr) -> bool {
+        self.allow_private_addresses || !is_private_ip(address)
+    }
+
+    /// Dial a peer on its addresses one at a time, ordered by the transport and address family
+    /// preferences, so the other transports are only tried if the preferred one fails. With the
+    /// auto address family the first address of the other IP version is left out and dialed on
+    /// its own if the peer hasn't connected after [`HAPPY_EYEBALLS_DELAY`]. Nothing is dialed if
+    /// we are already connected to or dialing the peer, failed to dial it recently or its dial
+    /// breaker is open. Returns the addresses in the order they will be tried.
+    fn dial_peer(
+        &mut self,
+        peer: PeerId,
+        mut addrs: Vec<Multiaddr>,
+    ) -> Result<Vec<Multiaddr>, DialError> {
+        if !self.dial_coalescer.try_dial(peer, Instant::now()) {
+            debug!("Not dialing {peer}: a dial is pending, failed recently or its breaker is open");
+            return Ok(Vec::new());
+        }
+        order_dial_addresses(&mut addrs, self.prefer_transport, self.address_family_preference);
+        // With auto the first address of the other IP version gets its own dial after a head
+        // start, happy eyeballs style
+        let raced = match (self.address_family_preference, addrs.first().and_then(address_family)) {
+            (AddressFamilyPreference::Auto, Some(first)) => addrs
+                .iter()
+                .position(|addr| address_family(addr).is_some_and(|family| family != first))
+                .map(|i| addrs.remove(i)),
+            _ => None,
+        };
+        let opts = DialOpts::peer_id(peer)
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .addresses(addrs.clone())
+            .override_dial_concurrency_factor(NonZeroU8::MIN)
+            .build();
+        match self.swarm.dial(opts) {
+            Ok(()) => {
+                if let Some(raced) = raced {
+                    self.raced_dials.push((Instant::now() + HAPPY_EYEBALLS_DELAY, peer, raced.clone()));
+                    addrs.push(raced);
+                }
+                Ok(addrs)
+            }
+            Err(DialError::DialPeerConditionFalse(_)) => {
+                debug!("Not dialing {peer}: already connected or dialing");
+                self.dial_coalescer.cancel(&peer);
+                Ok(Vec::new())
+            }
+            Err(e) => {
+                self.dial_coalescer.failed(peer, Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    /// Start the raced dials whose head start is over at `now`, unless their peer connected
+    fn start_raced_dials(&mut self, now: Instant) {
+        let (due, waiting) = std::mem::take(&mut self.raced_dials)
+            .into_iter()
+            .partition(|(at, _, _)| *at <= now);
+        self.raced_dials = waiting;
+        for (_, peer, address) in due {
+            if self.swarm.is_connected(&peer) {
+                continue;
+            }
+            debug!("Racing a dial to {peer} on {address}");
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::Disconnected)
+                .addresses(vec![address])
+                .build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("Failed to race a dial to {peer}: {e}");
+            }
+        }
+    }
+
+    /// The addresses to listen on for an IP: WebRTC, QUIC and TCP, plus a WebRTC address for each
+    /// extra certificate
+    fn listen_multiaddrs(addr: &IpAddr, extra_webrtc_listeners: usize) -> Vec<Multiaddr> {
+        let mut addrs = vec![
+            ipaddr_to_multiaddr(addr)
+                .with(Protocol::Udp(PORT_WEBRTC))
+                .with(Protocol::WebRTCDirect),
+            ipaddr_to_multiaddr(addr)
+                .with(Protocol::Udp(PORT_QUIC))
+                .with(Protocol::QuicV1),
+            ipaddr_to_multiaddr(addr).with(Protocol::Tcp(PORT_TCP)),
+        ];
+        for port in (PORT_WEBRTC_EXTRA..).take(extra_webrtc_listeners) {
+            addrs.push(
+                ipaddr_to_multiaddr(addr)
+                    .with(Protocol::Udp(port))
+                    .with(Protocol::WebRTCDirect),
+            );
+        }
+        addrs
+    }
+
+    /// Follow the addresses of the listen interface: listen on the new ones, then stop listening
+    /// on the ones that are gone
+    async fn refresh_listen_interface(&mut self, now: Instant) -> anyhow::Result<()> {
+        let Some(interface) = self.listen_interface.as_mut() else {
+            return Ok(());
+        };
+        if !interface.due(now) {
+            return Ok(());
+        }
+        let name = interface.name().to_string();
+        let (added, removed) = match interface.refresh(now) {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("Keeping the listeners on interface {name}: {e}");
+                return Ok(());
+            }
+        };
+
+        for ip in added.iter() {
+            self.msg(format!("Interface {name} gained address {ip}")).await?;
+            for addr in Self::listen_multiaddrs(ip, self.extra_webrtc_listeners) {
+                match self.swarm.listen_on(addr.clone()) {
+                    Ok(listener_id) => {
+                        self.listeners.insert(listener_id, addr.clone());
+                        self.listen_addresses.insert(addr);
+                    }
+                    Err(e) => {
+                        self.msg(format!("Failed to listen on {addr}: {}", listen_error(&e)))
+                            .await?;
+                    }
+                }
+            }
+        }
+        for ip in removed.iter() {
+            self.msg(format!("Interface {name} lost address {ip}")).await?;
+            let gone = ipaddr_to_multiaddr(ip);
+            let listeners: Vec<ListenerId> = self
+                .listeners
+                .iter()
+                .filter(|(_, addr)| addr.iter().next() == gone.iter().next())
+                .map(|(id, _)| *id)
+                .collect();
+            // the listeners are forgotten once they report being closed
+            for listener_id in listeners {
+                self.swarm.remove_listener(listener_id);
+            }
+            self.listen_addresses
+                .retain(|addr| addr.iter().next() != gone.iter().next());
+        }
         Ok(())
     }
 
-    /// Update our external address if needed
+    /// Update our external address if needed. If only confirmed addresses are announced, the
+    /// address is held back until it is confirmed reachable.
     pub async fn update_external_address(&mut self, address: &Multiaddr) -> anyhow::Result<bool> {
-        if !is_private_ip(address) && self.external_addresses.insert(address.clone()) {
+        if self.address_allowed(address) && self.external_addresses.insert(address.clone()) {
+            if self.announce_only_confirmed {
+                self.msg(format!("Holding back external address until reachable: {address}"))
+                    .await?;
+                self.unannounced_addresses.insert(address.clone());
+                return Ok(true);
+            }
             self.msg(format!("Adding external address: {address}"))
                 .await?;
             self.swarm.add_external_address(address.clone());
@@ -383,14 +1370,1581 @@ impl Peer {
         Ok(false)
     }
 
-    /// Run the Peer
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        // Listen on the given addresses
-        let addrs: Vec<Multiaddr> = self.listen_addresses.iter().cloned().collect();
-        for addr in addrs.iter() {
-            if let Err(e) = self.swarm.listen_on(addr.clone()) {
-                self.msg(format!("Failed to listen on {addr}: {e}")).await?;
+    /// Announce a held back external address once AutoNAT or the self-test confirmed it is
+    /// reachable, and switch Kademlia to server mode on the first confirmed address. The other
+    /// held back addresses wait for their own confirmation.
+    async fn confirm_reachable(&mut self, address: &Multiaddr, confirmed_by: &str) -> anyhow::Result<()> {
+        if self.unannounced_addresses.remove(address) {
+            self.msg(format!("Confirmed {address} reachable by {confirmed_by}, adding external address"))
+                .await?;
+            self.swarm.add_external_address(address.clone());
+        }
+        if self.reachable {
+            return Ok(());
+        }
+        self.reachable = true;
+        self.msg(format!("Confirmed reachable by {confirmed_by}")).await?;
+        if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+            kad.set_mode(Some(KademliaMode::Server));
+        }
+
+        // announce that we joined now, or once someone subscribes to the discovery topic
+        let peer_discovery = GossipsubIdentTopic::new(&self.protocols.peer_discovery_topic).hash();
+        match self
+            .presence_message(Presence::JOIN)
+            .and_then(|data| Ok(self.publish(peer_discovery, data, Instant::now())?))
+        {
+            Ok(()) => self.joined = true,
+            Err(e) => debug!("Deferring the join notification: {e}"),
+        }
+        Ok(())
+    }
+
+    /// Handle a command entered in the UI, returning the text to show the user
+    async fn handle_command(&mut self, command: &str) -> anyhow::Result<String> {
+        let mut args = command.split_whitespace();
+        match args.next() {
+            Some("clone") => {
+                let (Some(peer), Some(repo)) = (args.next(), args.next()) else {
+                    anyhow::bail!("Usage: clone <peer_id> <repo> [depth]");
+                };
+                let peer: PeerId = peer.parse()?;
+                let depth = match args.next() {
+                    Some(depth) => match depth.parse::<u32>() {
+                        Ok(depth) if depth > 0 => Some(depth),
+                        _ => anyhow::bail!("Invalid depth {depth}, give a number of commits of at least 1"),
+                    },
+                    None => None,
+                };
+                self.check_git_support(&peer)?;
+                self.start_pack_transfer(peer, repo.to_string(), depth).await?;
+                match depth {
+                    Some(depth) => Ok(format!("Cloning the last {depth} commits of {repo} from {peer}")),
+                    None => Ok(format!("Cloning {repo} from {peer}")),
+                }
+            }
+            Some("git-status") => {
+                let (Some(peer), Some(repo)) = (args.next(), args.next()) else {
+                    anyhow::bail!("Usage: git-status <peer_id> <repo>");
+                };
+                let peer: PeerId = peer.parse()?;
+                self.check_git_support(&peer)?;
+                self.request_status_chunk(peer, repo.to_string(), 0).await?;
+                Ok(format!("Getting the status of {repo} from {peer}"))
+            }
+            Some("ls-remote") => {
+                let (Some(peer), Some(repo)) = (args.next(), args.next()) else {
+                    anyhow::bail!("Usage: ls-remote <peer_id> <repo>");
+                };
+                let peer: PeerId = peer.parse()?;
+                self.check_git_support(&peer)?;
+                let key = (peer, repo.to_string());
+                if self.ls_remote_requests.values().any(|k| *k == key) {
+                    anyhow::bail!("Already listing the refs of {repo} on {peer}");
+                }
+                // an interrupted listing is resumed where it was cut short
+                let listing = self.ls_remote_listings.entry(key).or_default();
+                let received = listing.refs().len();
+                let after = listing.continuation().map(str::to_string);
+                let resumed = after.is_some();
+                self.request_ls_remote_chunk(peer, repo.to_string(), after).await?;
+                if resumed {
+                    Ok(format!("Resuming the refs of {repo} on {peer} after {received} refs"))
+                } else {
+                    Ok(format!("Listing the refs of {repo} on {peer}"))
+                }
+            }
+            Some("archive") => {
+                let (Some(peer), Some(repo), Some(oid)) = (args.next(), args.next(), args.next()) else {
+                    anyhow::bail!("Usage: archive <peer_id> <repo> <commit> [tar|zip]");
+                };
+                let peer: PeerId = peer.parse()?;
+                self.check_git_support(&peer)?;
+                let format: ArchiveFormat = args.next().unwrap_or("tar").parse()?;
+                // name the file after the last component of the repository url
+                let name = repo.trim_end_matches('/').rsplit('/').next().unwrap_or(repo);
+                let path = PathBuf::from(format!("{name}-{oid}.{format}"));
+                let request = GitRequest::Archive(repo.to_string(), oid.to_string(), format.to_string());
+                let request_id = self.send_git_request(peer, request).await?;
+                self.archive_requests.insert(request_id, path.clone());
+                Ok(format!("Requesting a {format} archive of {oid} in {repo} from {peer}, saving it to {}", path.display()))
+            }
+            Some("status") => {
+                let mut status = format!(
+                    "Connections: {}, Kademlia queries: {} active, {} queued (max {})",
+                    self.connections.len(),
+                    self.kad_queries.len(),
+                    self.kad_queue.len(),
+                    self.kad_queue.max_active(),
+                );
+                if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                    let store = kad.store_mut().utilization();
+                    write!(
+                        status,
+                        "\nKademlia store: {}/{} records, {}/{} provider keys, {} evictions",
+                        store.records,
+                        store.max_records,
+                        store.provider_keys,
+                        store.max_provider_keys,
+                        store.evictions
+                    )?;
+                }
+                write!(status, "\nClock skew: {}", self.clock_skew.report()?)?;
+                write!(status, "\nDial breaker: {}", self.dial_coalescer.report(Instant::now())?)?;
+                write!(status, "\nTopics:{}", self.topic_stats.report(Instant::now())?)?;
+                write!(status, "\nTransfers: {} in flight", self.transfers.len())?;
+                write!(
+                    status,
+                    "\nFile fetches: {} in flight, {} queued (max {})",
+                    self.file_requests.len(),
+                    self.fetch_queue.len(),
+                    self.fetch_queue.max_active()
+                )?;
+                write!(status, "\nFile cache: {} files, {} bytes", self.file_store.len(), self.file_store.bytes())?;
+                if let Some(max_bytes) = self.file_store.max_bytes() {
+                    write!(status, " (max {max_bytes})")?;
+                }
+                if self.announce_only_confirmed {
+                    match self.reachable {
+                        true => write!(status, "\nReachability: confirmed")?,
+                        false => write!(status, "\nReachability: unconfirmed")?,
+                    }
+                    write!(status, ", holding back {} addresses", self.unannounced_addresses.len())?;
+                }
+                if let Some(started) = self.drain_started {
+                    write!(
+                        status,
+                        "\nDraining: started {}s ago, {} transfers in flight, exiting within {}s",
+                        started.elapsed().as_secs(),
+                        self.transfers_in_flight(),
+                        self.drain_timeout.saturating_sub(started.elapsed()).as_secs()
+                    )?;
+                }
+                Ok(status)
+            }
+            Some("drain") => self.start_drain(),
+            Some("transfers") => {
+                if self.transfers.is_empty() {
+                    return Ok("No transfers in flight".to_string());
+                }
+                let mut report = format!("Transfers in flight: {}", self.transfers.len());
+                for transfer in self.transfers.snapshot() {
+                    write!(
+                        report,
+                        "\n\t{} {} {} #{} {}: {} bytes, started {}s ago",
+                        transfer.protocol.as_str(),
+                        transfer.id.direction(),
+                        transfer.operation,
+                        transfer.id,
+                        transfer.peer,
+                        transfer.bytes,
+                        transfer.started.elapsed().as_secs()
+                    )?;
+                }
+                Ok(report)
             }
+            Some("send-as") => {
+                let Some(content_type) = args.next() else {
+                    anyhow::bail!("Usage: send-as <content_type> <message>");
+                };
+                let payload = args.collect::<Vec<_>>().join(" ");
+                if payload.is_empty() {
+                    anyhow::bail!("Usage: send-as <content_type> <message>");
+                }
+                let envelope = ChatEnvelope::new(content_type, payload)?;
+                self.send_chat(&envelope)?;
+                Ok(format!("Sent {} chat message from you", envelope.content_type))
+            }
+            Some("queries") => {
+                if self.kad_queries.is_empty() {
+                    return Ok(format!(
+                        "No Kademlia queries in progress, {} queued",
+                        self.kad_queue.len()
+                    ));
+                }
+                let mut report = format!(
+                    "Kademlia queries in progress: {}, {} queued",
+                    self.kad_queries.len(),
+                    self.kad_queue.len()
+                );
+                let mut queries: Vec<_> = self.kad_queries.iter().collect();
+                queries.sort_by_key(|(_, query_type)| **query_type);
+                for (id, query_type) in queries {
+                    write!(report, "\n\t{query_type} #{id}")?;
+                }
+                Ok(report)
+            }
+            Some("cancel") => {
+                let Some(id) = args.next().map(|id| id.trim_start_matches('#')) else {
+                    anyhow::bail!("Usage: cancel <id> [query|file|git]");
+                };
+                let kind = args.next();
+                if kind.is_some_and(|kind| !["query", "file", "git"].contains(&kind)) {
+                    anyhow::bail!("Usage: cancel <id> [query|file|git]");
+                }
+
+                // the ids of queries and of each protocol's requests are counted separately
+                let query = self
+                    .kad_queries
+                    .keys()
+                    .find(|query| query.to_string() == id)
+                    .filter(|_| kind.is_none_or(|kind| kind == "query"))
+                    .copied();
+                let transfers: Vec<_> = self
+                    .transfers
+                    .snapshot()
+                    .into_iter()
+                    .filter(|transfer| transfer.id.to_string() == id)
+                    .filter(|transfer| kind.is_none_or(|kind| kind == transfer.protocol.as_str()))
+                    .map(|transfer| (transfer.protocol, transfer.id, transfer.peer))
+                    .collect();
+
+                match (query, transfers.as_slice()) {
+                    (None, []) => Ok(format!("Nothing to cancel with id {id}")),
+                    (Some(query), []) => self.cancel_query(query),
+                    (None, [(protocol, transfer_id, peer)]) => {
+                        self.cancel_transfer(*protocol, *transfer_id, *peer).await
+                    }
+                    _ => anyhow::bail!(
+                        "Several queries or transfers have id {id}, add query, file or git to pick one"
+                    ),
+                }
+            }
+            Some("dump-state") => {
+                let Some(path) = args.next() else {
+                    anyhow::bail!("Usage: dump-state <path>");
+                };
+                self.dump_state(PathBuf::from(path))?;
+                Ok(format!("Writing a state snapshot to {path}"))
+            }
+            Some("export-peers") => {
+                let Some(path) = args.next() else {
+                    anyhow::bail!("Usage: export-peers <path>");
+                };
+                let Some(peers) = self.routing_table_peers() else {
+                    anyhow::bail!("Kademlia is disabled, there is no routing table to export");
+                };
+                let seeds = PeerSeeds::new(peers);
+                seeds.save(Path::new(path))?;
+                Ok(format!("Exported {} peers to {path}", seeds.peers.len()))
+            }
+            Some("bootstrap") => {
+                let now = Instant::now();
+                if let Some(last) = self.last_manual_bootstrap {
+                    let wait = MANUAL_BOOTSTRAP_INTERVAL.saturating_sub(now.duration_since(last));
+                    if !wait.is_zero() {
+                        anyhow::bail!("A bootstrap was started recently, try again in {}s", wait.as_secs() + 1);
+                    }
+                }
+                if self.swarm.behaviour().kademlia.as_ref().is_none() {
+                    anyhow::bail!("Kademlia is disabled");
+                }
+                self.last_manual_bootstrap = Some(now);
+                if self.kad_query(KadQuery::Bootstrap { manual: true }) {
+                    Ok("Started a Kademlia bootstrap".to_string())
+                } else {
+                    Ok(format!(
+                        "Queued a Kademlia bootstrap behind {} other queries",
+                        self.kad_queue.len() - 1
+                    ))
+                }
+            }
+            Some("reset-stats") => {
+                self.topic_stats.reset();
+                Ok("Reset the topic statistics".to_string())
+            }
+            Some("disconnect") => {
+                let Some(peer) = args.next() else {
+                    anyhow::bail!("Usage: disconnect <peer_id>");
+                };
+                let peer: PeerId = peer.parse()?;
+                let connections = self
+                    .connections
+                    .values()
+                    .filter(|stats| stats.peer_id == peer)
+                    .count();
+                if self.swarm.disconnect_peer_id(peer).is_err() {
+                    anyhow::bail!("Not connected to {peer}");
+                }
+
+                if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                    kad.remove_peer(&peer);
+                }
+                Ok(format!("Closed {connections} connections to {peer}"))
+            }
+            Some("find-file") => {
+                let Some(file_id) = args.next() else {
+                    anyhow::bail!("Usage: find-file <file_id>");
+                };
+                let mut reply = if self.file_store.contains(file_id) {
+                    format!("{file_id} is stored locally")
+                } else {
+                    format!("{file_id} is not stored locally")
+                };
+                let providers = self.provider_index.providers(file_id, Instant::now());
+                if providers.is_empty() {
+                    reply.push_str(", no providers are known");
+                }
+                for (peer, age) in providers {
+                    write!(reply, "\n\tprovided by {peer}, advertised {}s ago", age.as_secs()).unwrap();
+                }
+                Ok(reply)
+            }
+            Some("add-file") => {
+                let Some(path) = args.next() else {
+                    anyhow::bail!("Usage: add-file <path>");
+                };
+                let body = fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+                if body.is_empty() {
+                    anyhow::bail!("{path} is empty, it can't be exchanged");
+                }
+                let file_id = self.content_hash.content_id(&body);
+                let new = self.file_store.insert(file_id.clone(), body);
+                self.evict_files().await?;
+                if !self.file_store.contains(&file_id) {
+                    anyhow::bail!("{path} is too large for the file cache");
+                }
+                if new {
+                    self.provide_file(&file_id)?;
+                }
+                Ok(format!("Added {path} as {file_id}, offer it with offer-file {file_id}"))
+            }
+            Some("offer-file") => {
+                let Some(file_id) = args.next() else {
+                    anyhow::bail!("Usage: offer-file <file_id> [topic]");
+                };
+                if !self.file_store.contains(file_id) {
+                    anyhow::bail!("{file_id} is not stored locally");
+                }
+                let offer = FileOffer {
+                    file_id: file_id.to_string(),
+                    topic: args.next().map(str::to_string),
+                };
+                let topic = GossipsubIdentTopic::new(&self.protocols.file_topic).hash();
+                self.publish(topic, offer.encode()?, Instant::now())?;
+                match offer.topic {
+                    Some(topic) => Ok(format!("Offered {file_id} for topic {topic}")),
+                    None => Ok(format!("Offered {file_id}")),
+                }
+            }
+            Some("list-files") => {
+                let Some(peer) = args.next() else {
+                    anyhow::bail!("Usage: list-files <peer_id>");
+                };
+                let peer: PeerId = peer.parse()?;
+                self.unsupported_protocols.check(&peer, protocol_names::FILE_MANIFEST)?;
+                self.request_manifest_page(peer, 0);
+                Ok(format!("Listing the files of {peer}"))
+            }
+            Some("peer-protocols") => {
+                let Some(peer) = args.next() else {
+                    anyhow::bail!("Usage: peer-protocols <peer_id> [protocol]");
+                };
+                let peer: PeerId = peer.parse()?;
+                let Some((protocols, identified)) = self.peer_protocols.get(&peer) else {
+                    anyhow::bail!("No identify data for {peer} yet");
+                };
+                let age = identified.elapsed().as_secs();
+                if let Some(protocol) = args.next() {
+                    let supported = protocols.iter().any(|p| p.as_ref() == protocol);
+                    return Ok(format!(
+                        "{peer} {} {protocol}, identified {age}s ago",
+                        if supported { "supports" } else { "doesn't support" }
+                    ));
+                }
+                let mut reply = format!("{peer} supports {} protocols, identified {age}s ago:", protocols.len());
+                for protocol in protocols {
+                    write!(reply, "\n\t{protocol}").unwrap();
+                }
+                Ok(reply)
+            }
+            Some("ping-peer") => {
+                let Some(peer) = args.next() else {
+                    anyhow::bail!("Usage: ping-peer <peer_id> [size]");
+                };
+                let peer: PeerId = peer.parse()?;
+                let size = match args.next() {
+                    Some(size) => size.parse()?,
+                    None => ECHO_DEFAULT_SIZE,
+                };
+                if size > MAX_ECHO_SIZE {
+                    anyhow::bail!("Size {size} exceeds the maximum of {MAX_ECHO_SIZE} bytes");
+                }
+
+                self.unsupported_protocols.check(&peer, protocol_names::ECHO)?;
+
+                let mut payload = vec![0; size];
+                OsRng.fill_bytes(&mut payload);
+                let request_id = self.swarm.behaviour_mut().echo.send_request(
+                    &peer,
+                    EchoRequest {
+                        payload: payload.clone(),
+                    },
+                );
+                self.echo_requests
+                    .insert(request_id, (payload, Instant::now()));
+                Ok(format!("Pinging {peer} with {size} bytes"))
+            }
+            Some(command) => anyhow::bail!("Unknown command: {command}"),
+            None => anyhow::bail!("Empty command"),
+        }
+    }
+
+    /// Encode a presence notification about ourselves for the peer discovery topic
+    fn presence_message(&self, presence: Presence) -> anyhow::Result<Vec<u8>> {
+        let peer = DiscoveredPeer {
+            publicKey: self.public_key.encode_protobuf().into(),
+            multiAddrs: self
+                .swarm
+                .external_addresses()
+                .map(|addr| addr.to_vec().into())
+                .collect(),
+            presence,
+            timestamp: clock_skew::unix_millis(SystemTime::now()),
+        };
+        let mut data = Vec::new();
+        peer.write_message(&mut Writer::new(&mut data))?;
+        Ok(data)
+    }
+
+    /// A JSON snapshot of the peer's state for post-mortem analysis: the connections, routing
+    /// table, topics, transfers and queries in flight, addresses, relay reservations and options
+    fn state_snapshot(&mut self) -> serde_json::Value {
+        let now = Instant::now();
+        let connections: Vec<serde_json::Value> = self
+            .connections
+            .iter()
+            .map(|(id, stats)| {
+                serde_json::json!({
+                    "connection_id": format!("{id:?}"),
+                    "peer_id": stats.peer_id.to_string(),
+                    "transport": stats.transport,
+                    "remote_addr": stats.remote_addr.to_string(),
+                    "age_secs": now.saturating_duration_since(stats.established).as_secs(),
+                    "substreams_ok": stats.substreams_ok,
+                    "substreams_failed": stats.substreams_failed,
+                })
+            })
+            .collect();
+
+        let routing_table = self.routing_table_peers().map(|peers| {
+            serde_json::json!({
+                "peers": peers.len(),
+                "addresses": peers.iter().map(|(_, addrs)| addrs.len()).sum::<usize>(),
+            })
+        });
+
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let topics: Vec<serde_json::Value> = gossipsub
+            .topics()
+            .map(|topic| {
+                let activity = self.topic_stats.get(topic).cloned().unwrap_or_default();
+                serde_json::json!({
+                    "topic": topic.as_str(),
+                    "mesh_peers": gossipsub.mesh_peers(topic).count(),
+                    "messages": activity.messages,
+                    "sources": activity.sources.len(),
+                    "last_message_secs_ago": activity
+                        .last_message
+                        .map(|last| now.saturating_duration_since(last).as_secs()),
+                })
+            })
+            .collect();
+
+        let transfers: Vec<serde_json::Value> = self
+            .transfers
+            .snapshot()
+            .into_iter()
+            .map(|transfer| {
+                serde_json::json!({
+                    "protocol": transfer.protocol.as_str(),
+                    "direction": transfer.id.direction(),
+                    "id": transfer.id.to_string(),
+                    "peer_id": transfer.peer.to_string(),
+                    "operation": transfer.operation,
+                    "bytes": transfer.bytes,
+                    "age_secs": now.saturating_duration_since(transfer.started).as_secs(),
+                })
+            })
+            .collect();
+        let queries: Vec<serde_json::Value> = self
+            .kad_queries
+            .iter()
+            .map(|(id, query_type)| serde_json::json!({ "id": id.to_string(), "type": query_type }))
+            .collect();
+
+        let listeners: Vec<&Multiaddr> = self.swarm.listeners().collect();
+        // a relay reservation shows up as a listener on a circuit address
+        let relay_reservations: Vec<String> = listeners
+            .iter()
+            .filter(|addr| addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)))
+            .map(|addr| addr.to_string())
+            .collect();
+
+        serde_json::json!({
+            "peer_id": self.swarm.local_peer_id().to_string(),
+            "unix_millis": clock_skew::unix_millis(SystemTime::now()),
+            "connections": connections,
+            "routing_table": routing_table,
+            "topics": topics,
+            "transfers": transfers,
+            "kad_queries": queries,
+            "kad_queries_queued": self.kad_queue.len(),
+            "file_fetches_queued": self.fetch_queue.len(),
+            "listen_addresses": listeners.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+            "external_addresses": self
+                .swarm
+                .external_addresses()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+            "relay_reservations": relay_reservations,
+            "config": self.config,
+        })
+    }
+
+    /// Write a state snapshot to `path`. The snapshot is taken in the event loop, the file is
+    /// written in the background so a slow disk doesn't stall it.
+    fn dump_state(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.state_snapshot())?;
+        tokio::spawn(async move {
+            match tokio::fs::write(&path, data).await {
+                Ok(()) => debug!("Wrote a state snapshot to {}", path.display()),
+                Err(e) => warn!("Failed to write a state snapshot to {}: {e}", path.display()),
+            }
+        });
+        Ok(())
+    }
+
+    /// Publish a new status snapshot to the dashboard, if it is served and an update is due. The
+    /// options are left out since the dashboard has no authentication and they can hold secrets,
+    /// such as proxy credentials.
+    fn update_dashboard(&mut self, now: Instant) {
+        if self.dashboard.is_none() || now < self.next_dashboard_update {
+            return;
+        }
+        self.next_dashboard_update = now + DASHBOARD_UPDATE_INTERVAL;
+        let mut status = self.state_snapshot();
+        if let Some(status) = status.as_object_mut() {
+            status.remove("config");
+        }
+        if let Some(dashboard) = self.dashboard.as_ref() {
+            dashboard.send_replace(status);
+        }
+    }
+
+    /// The peers in the Kademlia routing table with their addresses, if Kademlia is enabled
+    fn routing_table_peers(&mut self) -> Option<Vec<(PeerId, Vec<Multiaddr>)>> {
+        let kad = self.swarm.behaviour_mut().kademlia.as_mut()?;
+        let mut peers = Vec::new();
+        for bucket in kad.kbuckets() {
+            for entry in bucket.iter() {
+                peers.push((
+                    *entry.node.key.preimage(),
+                    entry.node.value.iter().cloned().collect(),
+                ));
+            }
+        }
+        Some(peers)
+    }
+
+    /// Dial some of the peers from a peer exchange sample. The sample comes unverified from a
+    /// single peer, so it is only used to dial: a dialed peer proves its identity in the handshake.
+    async fn pex_received(&mut self, from: PeerId, response: PexResponse) -> anyhow::Result<()> {
+        let mut dialed = 0;
+        for (peer, addrs) in response.entries() {
+            if dialed == self.pex_dial {
+                break;
+            }
+            if peer == *self.swarm.local_peer_id()
+                || self.swarm.is_connected(&peer)
+                || self.reputation.is_banned(&peer)
+            {
+                continue;
+            }
+            let addrs: Vec<Multiaddr> = addrs
+                .into_iter()
+                .filter(|addr| self.address_allowed(addr))
+                .collect();
+            if addrs.is_empty() {
+                continue;
+            }
+            match self.dial_peer(peer, addrs) {
+                Ok(addrs) if !addrs.is_empty() => dialed += 1,
+                Ok(_) => {}
+                Err(e) => debug!("Failed to dial {peer} from the peer exchange with {from}: {e}"),
+            }
+        }
+        self.msg(format!(
+            "Peer exchange with {from}: {} peers received, {dialed} dialed",
+            response.peers.len()
+        ))
+        .await
+    }
+
+    /// Request a page of the file manifest of a peer
+    fn request_manifest_page(&mut self, peer: PeerId, page: u32) {
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .file_manifest
+            .send_request(&peer, ManifestRequest { page });
+        self.manifest_requests.insert(request_id);
+    }
+
+    /// Start cloning the packfile for `repo` from `peer`, one chunk at a time
+    async fn start_pack_transfer(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        depth: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let key = (peer, repo.clone());
+        if self.pack_transfers.contains_key(&key) {
+            anyhow::bail!("Already cloning {repo} from {peer}");
+        }
+        let Some(pack_path) = git_server::repo_path(&PathBuf::from(RECEIVED_PACKS_DIR), &repo)
+        else {
+            anyhow::bail!("Invalid repository name {repo}");
+        };
+
+        fs::create_dir_all(RECEIVED_PACKS_DIR)?;
+        let file = fs::File::create(pack_path.with_extension("pack"))?;
+        // don't leave the boundary of an earlier shallow clone next to a full one
+        if let Err(e) = fs::remove_file(pack_path.with_extension("shallow")) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        match depth {
+            Some(depth) => self.pack_depths.insert(key.clone(), depth),
+            None => self.pack_depths.remove(&key),
+        };
+        // the haves are fixed for the transfer, so every chunk names the same packfile
+        let haves = git_server::local_haves(Path::new(git_server::GIT_REPOS_DIR), &repo);
+        self.pack_haves.insert(key.clone(), haves);
+        self.pack_signatures.remove(&key);
+        self.pack_transfers.insert(key, PackReassembler::new(file));
+        self.request_pack_chunk(peer, repo, 0).await
+    }
+
+    /// Check that a peer didn't leave the git protocol out of its identify exchange, before
+    /// starting a git command with it. Peers that haven't been identified yet are given the benefit
+    /// of the doubt.
+    fn check_git_support(&self, peer: &PeerId) -> anyhow::Result<()> {
+        self.unsupported_protocols.check(peer, protocol_names::GIT_EXCHANGE)?;
+        match self.peer_protocols.get(peer) {
+            Some((protocols, _))
+                if !self
+                    .protocols
+                    .git_exchange
+                    .iter()
+                    .any(|protocol| protocols.contains(protocol)) =>
+            {
+                anyhow::bail!("{peer} doesn't support the git protocol")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Send a git request to a peer, adding it to the request log in the UI
+    async fn send_git_request(
+        &mut self,
+        peer: PeerId,
+        request: GitRequest,
+    ) -> anyhow::Result<OutboundRequestId> {
+        let summary = request.summary();
+        let operation = request.operation();
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_request(&peer, request);
+        self.git_requests_sent.insert(id, Instant::now());
+        self.transfer_started(TransferProtocol::Git, TransferId::Outbound(id), peer, operation);
+        self.to_ui
+            .send(Message::GitRequestSent { id, peer, summary })
+            .await?;
+        Ok(id)
+    }
+
+    /// Add the response to a git request, or its failure, to the request log in the UI
+    async fn git_response_received(
+        &mut self,
+        id: OutboundRequestId,
+        peer: PeerId,
+        summary: String,
+        error: bool,
+    ) -> anyhow::Result<()> {
+        self.transfer_finished(TransferProtocol::Git, TransferId::Outbound(id));
+        // a cancelled request was already logged when it was cancelled
+        let Some(sent) = self.git_requests_sent.remove(&id) else {
+            return Ok(());
+        };
+        let elapsed = sent.elapsed();
+        self.to_ui
+            .send(Message::GitResponseReceived {
+                id,
+                peer,
+                summary,
+                elapsed,
+                error,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Request a chunk of the status of a repository
+    async fn request_status_chunk(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        seq: u64,
+    ) -> anyhow::Result<()> {
+        let request = GitRequest::StatusChunk {
+            repo: repo.clone(),
+            seq,
+        };
+        let request_id = self.send_git_request(peer, request).await?;
+        self.status_requests.insert(request_id, repo);
+        Ok(())
+    }
+
+    /// Request the chunk of the refs of a repository following the continuation token `after`
+    async fn request_ls_remote_chunk(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        after: Option<String>,
+    ) -> anyhow::Result<()> {
+        let request = GitRequest::LsRemoteChunk {
+            repo: repo.clone(),
+            after,
+        };
+        let request_id = self.send_git_request(peer, request).await?;
+        self.ls_remote_requests.insert(request_id, (peer, repo));
+        Ok(())
+    }
+
+    /// Add a received chunk to its ref listing and request the next one, showing the refs once the
+    /// listing is complete
+    async fn ls_remote_chunk_received(
+        &mut self,
+        key: (PeerId, String),
+        refs: Vec<(String, String)>,
+        next: Option<String>,
+    ) -> anyhow::Result<()> {
+        let Some(listing) = self.ls_remote_listings.get_mut(&key) else {
+            return Ok(());
+        };
+        let (peer, repo) = key;
+        if let Err(e) = listing.push(refs, next) {
+            self.ls_remote_listings.remove(&(peer, repo.clone()));
+            self.msg(format!("Listing the refs of {repo} on {peer} aborted: {e}"))
+                .await?;
+            return Ok(());
+        }
+        if let Some(after) = listing.continuation().map(str::to_string) {
+            return self.request_ls_remote_chunk(peer, repo, Some(after)).await;
+        }
+        let lines = listing.lines();
+        self.ls_remote_listings.remove(&(peer, repo.clone()));
+        self.msg(format!("Refs of {repo} on {peer}:\n\t{}", lines.join("\n\t")))
+            .await
+    }
+
+    /// Show the refs received before a ref listing was cut short. The listing is kept so running
+    /// ls-remote again resumes it.
+    async fn ls_remote_failed(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        reason: String,
+    ) -> anyhow::Result<()> {
+        let lines = self
+            .ls_remote_listings
+            .get(&(peer, repo.clone()))
+            .map(RefListing::lines)
+            .unwrap_or_default();
+        self.msg(format!(
+            "Refs of {repo} on {peer}, incomplete: {} refs received before {reason}, run ls-remote again to resume:\n\t{}",
+            lines.len(),
+            lines.join("\n\t")
+        ))
+        .await
+    }
+
+    /// Request the next chunk of a packfile
+    async fn request_pack_chunk(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        seq: u64,
+    ) -> anyhow::Result<()> {
+        // chunk 0 generates the packfile, also when it is re-requested, and the following chunks
+        // name the packfile it was served from
+        let key = (peer, repo.clone());
+        let depth = self.pack_depths.get(&key).copied();
+        let signature = match seq {
+            0 => None,
+            _ => self.pack_signatures.get(&key).cloned(),
+        };
+        let request = GitRequest::PackChunk {
+            repo: repo.clone(),
+            seq,
+            haves: self.pack_haves.get(&key).cloned().unwrap_or_default(),
+            depth,
+            signature,
+        };
+        let request_id = self.send_git_request(peer, request).await?;
+        // the chunk arrives whole, so the transfer shows the progress of the packfile
+        let received = self
+            .pack_transfers
+            .get(&(peer, repo.clone()))
+            .map_or(0, PackReassembler::received);
+        self.transfer_progressed(TransferProtocol::Git, TransferId::Outbound(request_id), received);
+        self.pack_requests.insert(request_id, repo);
+        Ok(())
+    }
+
+    /// Write a received packfile chunk to disk and request the next one
+    async fn pack_chunk_received(
+        &mut self,
+        peer: PeerId,
+        repo: String,
+        chunk: GitResponse,
+    ) -> anyhow::Result<()> {
+        let GitResponse::PackChunk {
+            seq,
+            total_size,
+            done,
+            data,
+            checksum,
+            shallow,
+            signature,
+        } = chunk
+        else {
+            return Ok(());
+        };
+        let key = (peer, repo);
+        let Some(transfer) = self.pack_transfers.get_mut(&key) else {
+            return Ok(());
+        };
+
+        // a corrupt chunk is re-requested a few times before giving up on the clone
+        let retries = self.pack_chunk_retries.entry(key.clone()).or_default();
+        match verify_pack_chunk(&data, checksum, retries, PACK_CHUNK_RETRIES) {
+            ChunkVerdict::Accept => {
+                self.pack_chunk_retries.remove(&key);
+            }
+            ChunkVerdict::Retry => {
+                warn!("Chunk {seq} of {} from {peer} failed its checksum, re-requesting", key.1);
+                self.request_pack_chunk(peer, key.1, seq).await?;
+                return Ok(());
+            }
+            ChunkVerdict::Abort => {
+                self.pack_transfers.remove(&key);
+                self.pack_chunk_retries.remove(&key);
+                self.msg(format!(
+                    "Clone of {} from {peer} aborted: chunk {seq} failed its checksum {PACK_CHUNK_RETRIES} times",
+                    key.1
+                ))
+                .await?;
+                return Ok(());
+            }
+        }
+
+        // the server serves a transfer from one packfile, a chunk of another one would corrupt it
+        if seq == 0 {
+            self.pack_signatures.insert(key.clone(), signature);
+        } else if self.pack_signatures.get(&key).is_some_and(|expected| *expected != signature) {
+            self.pack_transfers.remove(&key);
+            self.pack_signatures.remove(&key);
+            self.msg(format!(
+                "Clone of {} from {peer} aborted: chunk {seq} came from another packfile",
+                key.1
+            ))
+            .await?;
+            return Ok(());
+        }
+
+        // a missing or out of order chunk means the pack on disk is corrupt so abort the clone
+        if let Err(e) = transfer.push(seq, total_size, done, &data) {
+            self.pack_transfers.remove(&key);
+            self.msg(format!("Clone of {} from {peer} aborted: {e}", key.1))
+                .await?;
+            return Ok(());
+        }
+
+        let progress = transfer.received() * 100 / total_size.max(1);
+        if transfer.is_done() {
+            self.pack_transfers.remove(&key);
+            self.pack_signatures.remove(&key);
+            let shallow_clone = self.pack_depths.remove(&key).is_some();
+            let thin = self.pack_haves.remove(&key).is_some_and(|haves| !haves.is_empty());
+            if shallow.is_empty() {
+                if shallow_clone {
+                    warn!("Shallow clone of {} from {peer} came without a shallow boundary", key.1);
+                }
+                if thin {
+                    self.msg(format!(
+                        "Fetched {} from {peer}: {total_size} bytes written to {RECEIVED_PACKS_DIR}, the pack leaves out the commits of the local copy in {} so index it there",
+                        key.1,
+                        git_server::GIT_REPOS_DIR
+                    ))
+                    .await?;
+                } else {
+                    self.msg(format!(
+                        "Cloned {} from {peer}: {total_size} bytes written to {RECEIVED_PACKS_DIR}",
+                        key.1
+                    ))
+                    .await?;
+                }
+            } else {
+                // the boundary is listed like .git/shallow, for the repository the pack is indexed into
+                let shallow_path = git_server::repo_path(&PathBuf::from(RECEIVED_PACKS_DIR), &key.1)
+                    .map(|path| path.with_extension("shallow"))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid repository name {}", key.1))?;
+                let written = git_server::shallow_file(&shallow)
+                    .and_then(|lines| fs::write(&shallow_path, lines).map_err(anyhow::Error::from));
+                if let Err(e) = written {
+                    // the pack is useless without its boundary
+                    let _ = fs::remove_file(&shallow_path);
+                    let _ = fs::remove_file(shallow_path.with_extension("pack"));
+                    self.msg(format!("Clone of {} from {peer} aborted: {e:#}", key.1)).await?;
+                    return Ok(());
+                }
+                self.msg(format!(
+                    "Cloned {} from {peer} shallow: {total_size} bytes written to {RECEIVED_PACKS_DIR}, copy {} to .git/shallow of the repository the pack is indexed into",
+                    key.1,
+                    shallow_path.display()
+                ))
+                .await?;
+            }
+        } else {
+            let next_seq = transfer.next_seq();
+            self.msg(format!(
+                "Cloning {} from {peer}: {progress}% of {total_size} bytes",
+                key.1
+            ))
+            .await?;
+            self.request_pack_chunk(peer, key.1, next_seq).await?;
+        }
+        Ok(())
+    }
+
+    /// Track a Kademlia query, recording its start if it is new
+    fn kad_query_started(&mut self, id: QueryId, query_type: &'static str) {
+        if self.kad_queries.insert(id, query_type).is_none() {
+            self.metrics.kad_query_started(query_type);
+            debug!("Kademlia {query_type} query {id:?} started");
+        }
+    }
+
+    /// Record the progress of a Kademlia query, and its result once it is finished
+    fn kad_query_progressed(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        step: &ProgressStep,
+        stats: &QueryStats,
+    ) {
+        // queries started by Kademlia itself, like the periodic bootstrap, are first seen here
+        let query_type = metrics::kad_query_type(result);
+        self.kad_query_started(id, query_type);
+        debug!(
+            "Kademlia {query_type} query {id:?} step {}: {} peers queried, {} pending",
+            step.count,
+            stats.num_requests(),
+            stats.num_pending()
+        );
+
+        if step.last {
+            self.kad_queries.remove(&id);
+            self.start_queued_kad_queries();
+            let failed = metrics::kad_query_failed(result);
+            let duration = stats.duration().unwrap_or_default();
+            self.metrics
+                .kad_query_finished(query_type, failed, duration, stats.num_requests());
+            debug!(
+                "Kademlia {query_type} query {id:?} finished after {duration:?}: {}",
+                if failed { "error" } else { "ok" }
+            );
+        }
+    }
+
+    /// Count the substream outcome a swarm event reports on, see [`Self::count_substream`], and
+    /// pass the event on to be handled
+    fn record_substream(&mut self, event: SwarmEvent<BehaviourEvent>) -> SwarmEvent<BehaviourEvent> {
+        self.count_substream(&event);
+        event
+    }
+
+    /// Count the outcome of the substream a swarm event reports on against its connection, so that
+    /// connections that are established but can't exchange anything show up
+    fn count_substream(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+        let (protocol, substream) = match event {
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(event)) => {
+                (self.protocols.git_exchange[0].clone(), request_response_substream(event))
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::FileExchange(event)) => {
+                (self.protocols.file_exchange[0].clone(), request_response_substream(event))
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Echo(event)) => {
+                (self.protocols.echo.clone(), request_response_substream(event))
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
+                (IPFS_IDENTIFY_PROTOCOL_NAME, identify_substream(event))
+            }
+            _ => return,
+        };
+        let Some((connection_id, direction, result)) = substream else {
+            return;
+        };
+        let Some(stats) = self.connections.get_mut(&connection_id) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => stats.substreams_ok += 1,
+            Err(ref e) => {
+                stats.substreams_failed += 1;
+                warn!(
+                    "{direction} {protocol} substream on {} connection {connection_id:?} failed: {e}",
+                    stats.transport
+                );
+            }
+        }
+        self.metrics
+            .substream(protocol.as_ref(), stats.transport, direction, result.is_ok());
+    }
+
+    /// Start tracking a file or git exchange in flight
+    fn transfer_started(
+        &mut self,
+        protocol: TransferProtocol,
+        id: TransferId,
+        peer: PeerId,
+        operation: &'static str,
+    ) {
+        let transfer = self.transfers.start(protocol, id, peer, operation);
+        self.metrics.transfer_started(transfer);
+    }
+
+    /// Record the payload bytes a file or git exchange in flight has transferred so far
+    fn transfer_progressed(&mut self, protocol: TransferProtocol, id: TransferId, bytes: u64) {
+        if let Some((transfer, delta)) = self.transfers.progress(protocol, id, bytes) {
+            self.metrics.transfer_progressed(transfer, delta);
+        }
+    }
+
+    /// Stop tracking a file or git exchange that completed or failed
+    fn transfer_finished(&mut self, protocol: TransferProtocol, id: TransferId) {
+        if let Some(transfer) = self.transfers.finish(protocol, id) {
+            self.metrics.transfer_finished(&transfer);
+        }
+    }
+
+    /// Cancel a Kademlia query in progress. Kademlia still reports it as finished, which ends its
+    /// tracking, but its result is ignored.
+    fn cancel_query(&mut self, id: QueryId) -> anyhow::Result<String> {
+        let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            anyhow::bail!("Kademlia is disabled");
+        };
+        let Some(mut query) = kad.query_mut(&id) else {
+            return Ok(format!("Kademlia query #{id} already finished"));
+        };
+        query.finish();
+
+        self.manual_bootstraps.remove(&id);
+        self.get_closest_peers_query_id.remove(&id);
+        for query_id in [
+            &mut self.bootstrap_query_id,
+            &mut self.start_providing_query_id,
+            &mut self.get_providers_query_id,
+        ] {
+            if *query_id == Some(id) {
+                *query_id = None;
+            }
+        }
+        self.cancelled_queries.insert(id);
+
+        let query_type = self.kad_queries.get(&id).copied().unwrap_or("unknown");
+        Ok(format!("Cancelled Kademlia {query_type} query #{id}"))
+    }
+
+    /// Cancel an outbound file or git request, dropping the state kept for it. The response is
+    /// ignored if it still arrives. Inbound requests are answered as soon as they arrive, so there
+    /// is nothing left to cancel.
+    async fn cancel_transfer(
+        &mut self,
+        protocol: TransferProtocol,
+        id: TransferId,
+        peer: PeerId,
+    ) -> anyhow::Result<String> {
+        let TransferId::Outbound(request_id) = id else {
+            return Ok(format!(
+                "Inbound {} request #{id} from {peer} is already answered, it can't be cancelled",
+                protocol.as_str()
+            ));
+        };
+
+        match protocol {
+            TransferProtocol::File => {
+                self.transfer_finished(protocol, id);
+                // the ranges received so far stay on disk for the next fetch of the file
+                if let Some(file_id) = self.file_requests.failed(&request_id) {
+                    self.partial_files.remove(&file_id);
+                }
+            }
+            TransferProtocol::Git => {
+                self.git_response_received(request_id, peer, "Cancelled".to_string(), true)
+                    .await?;
+                if let Some(repo) = self.pack_requests.remove(&request_id) {
+                    let key = (peer, repo);
+                    // dropping the reassembler closes the partly written packfile
+                    self.pack_transfers.remove(&key);
+                    self.pack_depths.remove(&key);
+                    self.pack_haves.remove(&key);
+                    self.pack_signatures.remove(&key);
+                }
+                self.status_requests.remove(&request_id);
+                self.ls_remote_requests.remove(&request_id);
+                self.archive_requests.remove(&request_id);
+            }
+        }
+        Ok(format!("Cancelled {} request #{id} to {peer}", protocol.as_str()))
+    }
+
+    /// The number of transfers in flight in either direction
+    fn transfers_in_flight(&self) -> usize {
+        self.pack_transfers.len()
+            + self.ls_remote_requests.len()
+            + self.status_requests.len()
+            + self.archive_requests.len()
+            + self.file_requests.len()
+            + self.inbound_requests.len()
+    }
+
+    /// Start answering an inbound request over the protocol `family`, unless too many inbound
+    /// requests are being answered already, in total or from the peer. Returns the reason to give
+    /// the peer if the request is refused.
+    fn admit_inbound_request(&mut self, family: &'static str, request_id: InboundRequestId, peer: PeerId) -> Result<(), &'static str> {
+        let from_peer = self.inbound_requests.values().filter(|p| **p == peer).count();
+        if self.inbound_requests.len() >= self.max_inbound_streams {
+            warn!("Refusing {family} request from {peer}: {} inbound requests in flight", self.inbound_requests.len());
+            return Err("Too many concurrent requests");
+        }
+        if from_peer >= self.max_inbound_streams_per_peer {
+            warn!("Refusing {family} request from {peer}: {from_peer} inbound requests in flight from this peer");
+            return Err("Too many concurrent requests from this peer");
+        }
+        self.inbound_requests.insert((family, request_id), peer);
+        Ok(())
+    }
+
+    /// Start draining for a redeploy: new inbound connections are closed once they are established
+    /// and peers making a new relay reservation are disconnected, the existing connections keep
+    /// being served, and the peer exits once no transfer has been in flight for a moment or the
+    /// drain timeout passes. The listeners stay open, since closing a QUIC or WebRTC listener would
+    /// also close the connections it accepted.
+    fn start_drain(&mut self) -> anyhow::Result<String> {
+        if self.drain_started.is_some() {
+            anyhow::bail!("Already draining");
+        }
+        self.drain_started = Some(Instant::now());
+        self.drain_quiet_since = None;
+        Ok(format!(
+            "Draining: closing new inbound connections and reservations once established, exiting once the {} transfers in flight finish or within {}s",
+            self.transfers_in_flight(),
+            self.drain_timeout.as_secs()
+        ))
+    }
+
+    /// Exit once a drain is complete or timed out
+    async fn check_drain(&mut self, now: Instant) -> anyhow::Result<()> {
+        let Some(started) = self.drain_started else {
+            return Ok(());
+        };
+        if self.transfers_in_flight() > 0 {
+            self.drain_quiet_since = None;
+        } else if self.drain_quiet_since.is_none() {
+            self.drain_quiet_since = Some(now);
+        }
+        let drained = self
+            .drain_quiet_since
+            .is_some_and(|quiet| now.saturating_duration_since(quiet) >= DRAIN_QUIET_PERIOD);
+        let timed_out = now.saturating_duration_since(started) >= self.drain_timeout;
+        if !drained && !timed_out {
+            return Ok(());
+        }
+        if drained {
+            self.msg("Drained: no transfers in flight, shutting down".to_string())
+                .await?;
+        } else {
+            self.msg(format!(
+                "Drain timed out after {}s with {} transfers in flight, shutting down",
+                self.drain_timeout.as_secs(),
+                self.transfers_in_flight()
+            ))
+            .await?;
+        }
+        // stop checking, the shutdown takes over
+        self.drain_started = None;
+        self.shutdown.cancel();
+        Ok(())
+    }
+
+    /// Check an agent version against --require-agent-substring and --exclude-agent-substring,
+    /// returning why a peer with it is filtered out, if it is
+    fn agent_filtered(&self, agent_version: &str) -> Option<String> {
+        if let Some(required) = self.require_agent_substring.as_deref() {
+            if !agent_version.contains(required) {
+                return Some(format!("its agent doesn't contain {required:?}"));
+            }
+        }
+        if let Some(excluded) = self.exclude_agent_substring.as_deref() {
+            if agent_version.contains(excluded) {
+                return Some(format!("its agent contains {excluded:?}"));
+            }
+        }
+        None
+    }
+
+    /// Check if a transfer with a peer is in flight, which closing one of its connections might cut
+    fn has_transfer_in_flight(&self, peer: &PeerId) -> bool {
+        self.pack_transfers.keys().any(|(p, _)| p == peer)
+            || self.ls_remote_requests.values().any(|(p, _)| p == peer)
+            || self.inbound_requests.values().any(|p| p == peer)
+            || self.inflight_file_requests.has_peer(peer)
+    }
+
+    /// Close all but the best connection to a peer by the transport preference. Of equally good
+    /// ones the connection dialed by the lower peer id is kept, so that both peers keep the same
+    /// one when they dialed each other at once, then the oldest. Deferred while a transfer with the
+    /// peer is in flight.
+    fn dedup_connections(&mut self, peer: PeerId) {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let lower_peer_id = local_peer_id.min(peer);
+        let mut connections: Vec<(ConnectionId, (u8, bool, Instant), &'static str)> = self
+            .connections
+            .iter()
+            .filter(|(id, stats)| {
+                stats.peer_id == peer
+                    && !self.redundant_connections.contains(id)
+                    && !self.expired_connections.contains(id)
+            })
+            .map(|(id, stats)| {
+                let dialer = if stats.dialer { local_peer_id } else { peer };
+                let preference = (
+                    transport_rank(&stats.remote_addr, self.prefer_transport),
+                    dialer != lower_peer_id,
+                    stats.established,
+                );
+                (*id, preference, stats.transport)
+            })
+            .collect();
+        if connections.len() < 2 {
+            return;
+        }
+        if self.has_transfer_in_flight(&peer) {
+            debug!("Not deduplicating the {} connections to {peer}: a transfer is in flight", connections.len());
+            self.dedup_deferred.insert(peer);
+            return;
+        }
+
+        connections.sort_by_key(|(_, preference, _)| *preference);
+        let (kept, _, kept_transport) = connections[0];
+        for (connection_id, _, transport) in connections.into_iter().skip(1) {
+            info!("Closing redundant {transport} connection {connection_id:?} to {peer}, keeping {kept_transport} connection {kept:?}");
+            if self.swarm.close_connection(connection_id) {
+                self.redundant_connections.insert(connection_id);
+            }
+        }
+    }
+
+    /// Close the connections that exceeded the maximum lifetime, except relayed ones
+    fn close_expired_connections(&mut self, now: Instant) {
+        let Some(max_lifetime) = self.max_connection_lifetime else {
+            return;
+        };
+        let expired: Vec<(ConnectionId, PeerId, Duration)> = self
+            .connections
+            .iter()
+            .filter(|(id, stats)| stats.transport != "relay" && !self.expired_connections.contains(id))
+            .map(|(id, stats)| (*id, stats.peer_id, now.saturating_duration_since(stats.established)))
+            .filter(|(_, _, age)| *age >= max_lifetime)
+            .collect();
+        for (connection_id, peer_id, age) in expired {
+            info!(
+                "Closing connection {connection_id:?} to {peer_id}: it is {}s old, the maximum lifetime is {}s",
+                age.as_secs(),
+                max_lifetime.as_secs()
+            );
+            if self.swarm.close_connection(connection_id) {
+                self.expired_connections.insert(connection_id);
+            }
+        }
+    }
+
+    /// Lower the reputation of a peer that misbehaved, disconnecting it once it is banned
+    fn peer_misbehaved(&mut self, peer: PeerId) {
+        let score = self.reputation.violation(&peer);
+        debug!("{peer} misbehaved, reputation is now {score:.2}");
+        self.reputation_save_at
+            .get_or_insert_with(|| Instant::now() + REPUTATION_SAVE_DELAY);
+        if self.reputation.is_banned(&peer) {
+            info!("Disconnecting from {peer}: banned for misbehaving");
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    /// Fetch an offered file we don't hold, or queue the fetch if too many are in flight or the
+    /// file cache is too full. The offer is skipped if the queue is full too.
+    async fn fetch_offered_file(&mut self, fetch: FileFetch) -> anyhow::Result<()> {
+        if self.file_store.contains(&fetch.file_id)
+            || self.file_requests.contains_file(&fetch.file_id)
+        {
+            return Ok(());
+        }
+        if let Err(e) = self.unsupported_protocols.check(&fetch.peer, protocol_names::FILE_EXCHANGE) {
+            debug!("Not fetching {}: {e}", fetch.file_id);
+            return Ok(());
+        }
+        let active = self.file_requests.len();
+        let file_id = fetch.file_id.clone();
+        match self.fetch_queue.push(fetch, active, self.file_store.bytes()) {
+            FetchDecision::Start(fetch) => self.start_file_fetch(fetch).await?,
+            FetchDecision::Queued => debug!("Queued fetching {file_id}, {} fetches queued", self.fetch_queue.len()),
+            FetchDecision::Skipped => warn!("Not fetching {file_id}: {} fetches are already queued", self.fetch_queue.len()),
+        }
+        Ok(())
+    }
+
+    /// Start the queued file fetches that there is now capacity for
+    async fn start_queued_file_fetches(&mut self) -> anyhow::Result<()> {
+        while let Some(fetch) = self.fetch_queue.pop(self.file_requests.len(), self.file_store.bytes()) {
+            let supported = self.unsupported_protocols.check(&fetch.peer, protocol_names::FILE_EXCHANGE).is_ok();
+            if supported && !self.file_store.contains(&fetch.file_id) {
+                self.start_file_fetch(fetch).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Request a file from the peer that offered it, resuming with the ranges still missing if an
+    /// earlier fetch of it was interrupted
+    async fn start_file_fetch(&mut self, fetch: FileFetch) -> anyhow::Result<()> {
+        let FileFetch { peer, file_id } = fetch;
+        // a file larger than the cache is dropped once received, so it isn't fetched at all
+        let max_size = self.file_store.max_bytes().map_or(file_exchange::MAX_FILE_SIZE, |max_bytes| max_bytes.min(file_exchange::MAX_FILE_SIZE));
+        let partial = match PartialFile::open(Path::new(PARTIAL_FILES_DIR), &file_id, max_size) {
+            Ok(partial) => partial,
+            Err(e) => return self.msg(format!("Not fetching {file_id} from {peer}: {e:#}")).await,
+        };
+        let range = partial.next_range();
+        let received = partial.received_bytes();
+        self.partial_files.insert(file_id.clone(), partial);
+        self.send_file_request(peer, file_id.clone(), range);
+        if received > 0 {
+            self.msg(format!("Resumed file request to {peer} for {file_id} after {received} bytes")).await
+        } else {
+            self.msg(format!("Sent file request to {peer} for {file_id}")).await
+        }
+    }
+
+    /// Request a range of a file, or the whole file from a peer that only supports the older
+    /// versions of the file exchange protocol
+    fn send_file_request(&mut self, peer: PeerId, file_id: String, range: Option<file_exchange::Range>) {
+        let nonce = OsRng.next_u64();
+        let request_id = self.swarm.behaviour_mut().file_exchange.send_request(
+            &peer,
+            FileRequest {
+                file_id: file_id.clone(),
+                nonce: Some(nonce),
+                encrypt: self.file_decryptor.is_some(),
+                range,
+            },
+        );
+        self.file_requests.start(request_id, file_id, nonce);
+        self.transfer_started(TransferProtocol::File, TransferId::Outbound(request_id), peer, "Get");
+    }
+
+    /// Send the response to an inbound git request
+    fn send_git_response(&mut self, request_id: InboundRequestId, channel: ResponseChannel<GitResponse>, response: GitResponse) {
+        self.transfer_progressed(TransferProtocol::Git, TransferId::Inbound(request_id), response.payload_len());
+        if let Err(e) = self.swarm.behaviour_mut().request_response.send_response(channel, response) {
+            error!("Failed to send GitResponse: {:?}", e);
+            self.transfer_finished(TransferProtocol::Git, TransferId::Inbound(request_id));
+        }
+    }
+
+    /// Decrypt a file, or a range of one, we received, refusing it if it isn't encrypted the way we
+    /// asked for
+    fn decrypt_file(&self, body: Vec<u8>, encrypted: bool) -> Result<Vec<u8>, String> {
+        match (&self.file_decryptor, encrypted) {
+            (None, false) => Ok(body),
+            (Some(decryptor), true) => decryptor.decrypt(&body).map_err(|e| e.to_string()),
+            (Some(_), false) => Err("it was sent unencrypted".to_string()),
+            (None, true) => Err("it was encrypted unasked".to_string()),
+        }
+    }
+
+    /// Run a Kademlia query, or queue it if too many are already in progress. Returns whether the
+    /// query started now.
+    fn kad_query(&mut self, query: KadQuery) -> bool {
+        match self.kad_queue.push(query, self.kad_queries.len()) {
+            Some(query) => {
+                self.start_kad_query(query);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start the queued Kademlia queries that there is now room for
+    fn start_queued_kad_queries(&mut self) {
+        while let Some(query) = self.kad_queue.pop(self.kad_queries.len()) {
+            self.start_kad_query(query);
+        }
+    }
+
+    /// Start a Kademlia query now
+    fn start_kad_query(&mut self, query: KadQuery) {
+        let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            return;
+        };
+        match query {
+            KadQuery::GetClosestPeers(peer) => {
+                let qid = kad.get_closest_peers(peer);
+                self.get_closest_peers_query_id.insert(qid);
+                self.kad_query_started(qid, "get_closest_peers");
+            }
+            KadQuery::StartProviding(key) => {
+                // the agent string is announced once bootstrapped, and its providers looked up once
+                // it is announced
+                let agent = key == RecordKey::new(&UNIVERSAL_CONNECTIVITY_AGENT);
+                match kad.start_providing(key) {
+                    Ok(qid) => {
+                        if agent {
+                            self.start_providing_query_id = Some(qid);
+                        }
+                        self.kad_query_started(qid, "start_providing");
+                    }
+                    Err(e) => warn!("Failed to start providing: {e}"),
+                }
+            }
+            KadQuery::GetProviders(key) => {
+                let qid = kad.get_providers(key);
+                self.get_providers_query_id = Some(qid);
+                self.kad_query_started(qid, "get_providers");
+            }
+            KadQuery::Bootstrap { manual } => match kad.bootstrap() {
+                Ok(qid) => {
+                    if manual {
+                        self.manual_bootstraps.insert(qid, HashSet::new());
+                    } else {
+                        self.bootstrap_query_id = Some(qid);
+                    }
+                    self.kad_query_started(qid, "bootstrap");
+                }
+                Err(e) => warn!(
+                    "Failed to bootstrap Kademlia: {e}, it will try again in {KADEMLIA_BOOTSTRAP_INTERVAL} seconds"
+                ),
+            },
+        }
+    }
+
+    /// Start providing a file via Kademlia and advertise it on the file provider topic
+    fn provide_file(&mut self, file_id: &str) -> anyhow::Result<()> {
+        self.kad_query(KadQuery::StartProviding(RecordKey::new(&file_id)));
+
+        let advertisement = ProviderAdvertisement {
+            file_id: file_id.to_string(),
+            provider: self.swarm.local_peer_id().to_base58(),
+            namespace: self.file_store.namespace(file_id).map(str::to_string),
+        };
+        #[cfg(feature = "sqlite-index")]
+        if let Some(index) = self.file_index.as_ref() {
+            if let Err(e) = index.record_provided(file_id) {
+                warn!("Failed to record the provider record of {file_id} in the file index: {e}");
+            }
+        }
+
+        let topic = GossipsubIdentTopic::new(&self.protocols.file_providers_topic).hash();
+        // the advertisement is best effort, the provider record is still announced without it
+        if let Err(e) = self.publish(topic, serde_json::to_vec(&advertisement)?, Instant::now()) {
+            debug!("Failed to advertise {file_id}: {e}");
+        }
+        Ok(())
+    }
+
+    /// Drop the least recently used files once the files held take more than
+    /// --file-cache-max-bytes, and stop providing them
+    async fn evict_files(&mut self) -> anyhow::Result<()> {
+        for file_id in self.file_store.evict() {
+            if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                kad.stop_providing(&RecordKey::new(&file_id));
+            }
+            #[cfg(feature = "sqlite-index")]
+            if let Some(index) = self.file_index.as_ref() {
+                if let Err(e) = index.remove(&file_id) {
+                    warn!("Failed to remove {file_id} from the file index: {e}");
+                }
+            }
+            self.msg(format!("Dropped file {file_id} to keep the file cache within its limit"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Re-announce the provider records and advertisements of the files that are still in the
+    /// store
+    async fn reprovide_files(&mut self) -> anyhow::Result<()> {
+        let file_ids: Vec<String> = self.file_store.file_ids().cloned().collect();
+        for file_id in file_ids.iter() {
+            if let Err(e) = self.provide_file(file_id) {
+                warn!("Failed to re-provide {file_id}: {e}");
+            }
+        }
+        if !file_ids.is_empty() {
+            self.msg(format!(
+                "Re-provided {} files, next in {:?}",
+                file_ids.len(),
+                self.reprovider.interval()
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Run the Peer
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        // Listen on the given addresses
+        let addrs: Vec<Multiaddr> = self.listen_addresses.iter().cloned().collect();
+        for addr in addrs.iter() {
+            match self.swarm.listen_on(addr.clone()) {
+                Ok(listener_id) => {
+                    self.listeners.insert(listener_id, addr.clone());
+                }
+                Err(e) => {
+                    self.msg(format!("Failed to listen on {addr}: {}", listen_error(&e)))
+                        .await?;
+                }
+            }
+        }
+        // carry on with the transports that could listen, but not with none of them
+        if !addrs.is_empty() && self.listeners.is_empty() {
+            self.shutdown.cancel();
+            anyhow::bail!("Failed to listen on any of the listen addresses");
+        }
+
+        // stay a Kademlia client until we are confirmed reachable
+        if !self.reachable {
+            if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                kad.set_mode(Some(KademliaMode::Client));
+            }
+            self.msg("Not announcing our addresses until we are confirmed reachable")
+                .await?;
         }
 
         // Set the external address if passed in
@@ -427,12 +2981,42 @@ impl Peer {
             }
         }
 
+        // seed the routing table with the imported peers, skipping the ones we can't reach
+        for (peer, addrs) in std::mem::take(&mut self.seed_peers) {
+            let addrs: Vec<Multiaddr> = addrs
+                .into_iter()
+                .filter(|addr| self.address_allowed(addr))
+                .collect();
+            if peer == *self.swarm.local_peer_id()
+                || addrs.is_empty()
+                || self.reputation.is_banned(&peer)
+            {
+                continue;
+            }
+            if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                for addr in addrs.iter() {
+                    kad.add_address(&peer, addr.clone());
+                }
+            }
+            if self.dial_seed_peers {
+                if let Err(e) = self.dial_peer(peer, addrs) {
+                    self.msg(format!("Failed to dial imported peer {peer}: {e}")).await?;
+                }
+            }
+        }
+
         // initiate a bootstrap of kademlia if it is enabled
         if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
             // parse the bootstrap multiaddrs
-            let bootstrappers: Vec<Multiaddr> = IPFS_BOOTSTRAP_NODES
+            // the public bootstrap nodes don't speak a prefixed Kademlia protocol
+            let public_bootstrappers = match self.protocols.prefix {
+                Some(_) => &[][..],
+                None => &IPFS_BOOTSTRAP_NODES[..],
+            };
+            let bootstrappers: Vec<Multiaddr> = public_bootstrappers
                 .iter()
                 .filter_map(|s| s.parse().ok())
+                .chain(self.bootstrap_nodes.iter().cloned())
                 .collect();
             for addr in bootstrappers.iter() {
                 if let Some((multiaddr, peerid)) = split_peer_id(addr.clone()) {
@@ -441,39 +3025,64 @@ impl Peer {
             }
 
             // start the bootstrap process
-            match kad.bootstrap() {
-                Ok(query_id) => {
-                    self.bootstrap_query_id = Some(query_id);
-                    self.msg("Bootstrapping Kademlia").await?;
-                }
-                Err(e) => {
-                    self.msg(format!("Failed to bootstrap Kademlia: {e}"))
-                        .await?;
-                    self.msg(format!(
-                        "Don't worry, it will try again in {KADEMLIA_BOOTSTRAP_INTERVAL} seconds"
-                    ))
-                    .await?;
-                }
-            }
+            self.kad_query(KadQuery::Bootstrap { manual: false });
+            self.msg("Bootstrapping Kademlia").await?;
         }
 
         // Initialize the gossipsub topics, the hashes are the same as the topic names
-        let chat_topic = GossipsubIdentTopic::new(GOSSIPSUB_CHAT_TOPIC);
-        let file_topic = GossipsubIdentTopic::new(GOSSIPSUB_CHAT_FILE_TOPIC);
-        let peer_discovery = GossipsubIdentTopic::new(GOSSIPSUB_PEER_DISCOVERY);
+        let chat_topic = GossipsubIdentTopic::new(&self.protocols.chat_topic);
+        let file_topic = GossipsubIdentTopic::new(&self.protocols.file_topic);
+        let peer_discovery = GossipsubIdentTopic::new(&self.protocols.peer_discovery_topic);
+        let file_providers = GossipsubIdentTopic::new(&self.protocols.file_providers_topic);
 
-        // Subscribe to the gossipsub topics
+        // Subscribe to the gossipsub topics, declaring the authentication each one requires. Chat
+        // accepts unsigned messages from other peers but file and discovery messages must be
+        // signed to attribute them. What we publish is signed either way.
         info!("Subscribing to topics");
-        for topic in [
-            chat_topic.clone(),
-            file_topic.clone(),
-            peer_discovery.clone(),
+        for (topic, auth) in [
+            (chat_topic.clone(), TopicAuth::AcceptUnsigned),
+            (file_topic.clone(), TopicAuth::Signed),
+            (peer_discovery.clone(), TopicAuth::Signed),
+            (file_providers.clone(), TopicAuth::Signed),
         ] {
+            self.topic_policies.insert(&topic, auth);
+            self.topic_stats.track(topic.hash());
             if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
                 debug!("Failed to subscribe to topic {topic}: {e}");
             }
         }
 
+        // announce the files served from directories, the reprovider keeps them announced
+        let served: Vec<String> = self
+            .file_store
+            .file_ids()
+            .filter(|file_id| self.file_store.namespace(file_id).is_some())
+            .cloned()
+            .collect();
+        // and the indexed files we provided before the restart
+        #[cfg(feature = "sqlite-index")]
+        let served = {
+            let mut served = served;
+            if let Some(index) = self.file_index.as_ref() {
+                for (file_id, _) in index.provided()? {
+                    if self.file_store.contains(&file_id) && !served.contains(&file_id) {
+                        served.push(file_id);
+                    }
+                }
+            }
+            served
+        };
+        for file_id in served.iter() {
+            self.provide_file(file_id)?;
+        }
+        if !served.is_empty() {
+            self.msg(format!("Providing {} files", served.len()))
+                .await?;
+        }
+
+        // SIGUSR1 starts a drain
+        let mut drain_signal = DrainSignal::new();
+
         // Create our loop ticker
         let mut tick = tokio::time::interval(Duration::from_millis(18));
 
@@ -482,18 +3091,20 @@ impl Peer {
             // process messages from the UI
             if let Ok(message) = self.from_ui.try_recv() {
                 match message {
-                    Message::Chat { data, .. } => {
+                    Message::Chat { envelope, .. } => {
                         error!("chat received");
-                        match self
-                            .swarm
-                            .behaviour_mut()
-                            .gossipsub
-                            .publish(chat_topic.hash(), data)
-                        {
+                        match self.send_chat(&envelope) {
+                            Ok(()) => self.msg("Sent chat message from you".to_string()).await?,
                             Err(e) => debug!("Failed to publish chat message: {e}"),
-                            _ => self.msg("Sent chat message from you".to_string()).await?,
                         }
                     }
+                    Message::Command(command) => {
+                        let reply = self
+                            .handle_command(&command)
+                            .await
+                            .unwrap_or_else(|e| format!("Command failed: {e}"));
+                        self.msg(reply).await?;
+                    }
                     Message::AllPeers { .. } => {
                         error!("all peers received");
                         let peers = self
@@ -515,22 +3126,95 @@ impl Peer {
             }
 
             tokio::select! {
-                _ = self.shutdown.cancelled() => {
-                    info!("Unsubscribing from topics");
-                    // Subscribe to the gossipsub topics
-                    for topic in &[chat_topic, file_topic, peer_discovery] {
-                        if !self.swarm.behaviour_mut().gossipsub.unsubscribe(topic) {
-                            debug!("Failed to unsubscribe from topic {topic}");
+                // keep running until the transfers in flight finish, the tick stops the loop
+                _ = self.shutdown.cancelled(), if self.stopping_since.is_none() => {
+                    self.stopping_since = Some(Instant::now());
+                    let in_flight = self.transfers_in_flight();
+                    if in_flight > 0 {
+                        self.msg(format!(
+                            "Shutting down: finishing {in_flight} transfers in flight, for at most {}s",
+                            SHUTDOWN_TRANSFER_TIMEOUT.as_secs()
+                        ))
+                        .await?;
+                    }
+                }
+
+                _ = tokio::time::sleep_until(
+                    self.raced_dials.iter().map(|(at, _, _)| *at).min().unwrap_or_else(Instant::now).into()
+                ), if !self.raced_dials.is_empty() => {
+                    self.start_raced_dials(Instant::now());
+                }
+
+                _ = tick.tick() => {
+                    if let Some(since) = self.stopping_since {
+                        let in_flight = self.transfers_in_flight();
+                        if in_flight == 0 {
+                            break;
+                        }
+                        if since.elapsed() >= SHUTDOWN_TRANSFER_TIMEOUT {
+                            warn!("Shutting down with {in_flight} transfers still in flight");
+                            break;
+                        }
+                    }
+                    if self.reprovider.due(Instant::now()) {
+                        self.reprovide_files().await?;
+                    }
+                    self.publish_unsent_messages();
+                    self.start_queued_file_fetches().await?;
+                    self.refresh_listen_interface(Instant::now()).await?;
+                    if let (Some(at), Some(interval)) = (self.next_state_dump, self.state_dump_interval) {
+                        if Instant::now() >= at {
+                            self.next_state_dump = Some(Instant::now() + interval);
+                            if let Err(e) = self.dump_state(self.state_dump_path.clone()) {
+                                warn!("Failed to take a state snapshot: {e}");
+                            }
                         }
                     }
+                    if self.reputation_save_at.is_some_and(|at| Instant::now() >= at) {
+                        self.reputation_save_at = None;
+                        if let Err(e) = self.reputation.save() {
+                            warn!("Failed to save the peer reputation: {e}");
+                        }
+                    }
+                    self.update_dashboard(Instant::now());
+                    self.close_expired_connections(Instant::now());
+                    for peer in std::mem::take(&mut self.dedup_deferred) {
+                        self.dedup_connections(peer);
+                    }
+                    self.check_drain(Instant::now()).await?;
+                    if self.self_test_at.is_some_and(|at| Instant::now() >= at) {
+                        self.self_test_at = None;
+                        self.start_self_test().await?;
+                    }
+                    if self.self_test.as_ref().is_some_and(|task| task.is_finished()) {
+                        self.self_test_finished().await?;
+                    }
+                }
 
-                    info!("Shutting down the peer");
-                    break;
+                Some(handled) = self.git_handlers.join_next(), if !self.git_handlers.is_empty() => match handled {
+                    Ok((request_id, channel, response)) => self.send_git_response(request_id, channel, response),
+                    // the handlers turn panics into responses, so the task was cancelled and
+                    // dropping its channel fails the request
+                    Err(e) => error!("Git request handler failed: {e}"),
+                },
+
+                Some(ControlCommand { command, reply }) = self.control.recv() => {
+                    let response = self
+                        .handle_command(&command)
+                        .await
+                        .unwrap_or_else(|e| format!("Command failed: {e}"));
+                    // the client may have gone away meanwhile
+                    let _ = reply.send(response);
                 }
 
-                _ = tick.tick() => {} // tick.tick() returns immediately
+                Some(()) = drain_signal.recv() => {
+                    let reply = self
+                        .start_drain()
+                        .unwrap_or_else(|e| format!("Not draining on SIGUSR1: {e}"));
+                    self.msg(reply).await?;
+                }
 
-                Some(event) = self.swarm.next() => match event {
+                Some(event) = self.swarm.next() => match self.record_substream(event) {
 
                     // When the swarm in initiates a dial
                     SwarmEvent::Dialing { peer_id, .. } => {
@@ -544,35 +3228,129 @@ impl Peer {
                             .clone()
                             .with(Protocol::P2p(*self.swarm.local_peer_id()));
                         self.msg(format!("Confirmed external address: {p2p_address}")).await?;
+                        // identify only pushes listen address changes by itself
+                        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+                        self.swarm.behaviour_mut().identify.push(peers);
                     }
 
                     // When we successfully listen on an address
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                        if let Some(addr) = self.listeners.remove(&listener_id) {
+                            match reason {
+                                Ok(()) => self.msg(format!("Stopped listening on {addr}")).await?,
+                                Err(e) => self.msg(format!("Stopped listening on {addr}: {}", listen_error(&e))).await?,
+                            }
+                            if self.listeners.is_empty() {
+                                self.shutdown.cancel();
+                                anyhow::bail!("No listener is left");
+                            }
+                        }
+                    }
                     SwarmEvent::NewListenAddr { address, .. } => {
                         let p2p_address = address
                             .clone()
                             .with(Protocol::P2p(*self.swarm.local_peer_id()));
                         self.msg(format!("Listening on {p2p_address}"))
                             .await?;
+                        // public listen addresses are advertised once confirmed, private ones
+                        // never get confirmed so advertise them directly when allowed
+                        if self.allow_private_addresses && is_private_ip(&address) {
+                            self.update_external_address(&address).await?;
+                        }
                     }
 
                     // When we successfully connect to a peer
-                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
                         debug!("Connected to {peer_id}");
+                        if endpoint.is_dialer() {
+                            let address = endpoint.get_remote_address();
+                            let family = address_family(address).unwrap_or("unknown family");
+                            info!("Dialed {peer_id} over {family} on {address}");
+                        }
+                        self.dial_coalescer.connected(&peer_id);
+                        self.raced_dials.retain(|(_, peer, _)| *peer != peer_id);
+                        if self.reputation.is_banned(&peer_id) {
+                            info!("Disconnecting from {peer_id}: banned for misbehaving");
+                            let _ = self.swarm.disconnect_peer_id(peer_id);
+                        }
+                        self.connections.insert(connection_id, ConnectionStats::new(peer_id, &endpoint));
+                        self.metrics.connection_established(&endpoint);
+                        if self.drain_started.is_some() && endpoint.is_listener() {
+                            info!("Closing inbound connection {connection_id:?} from {peer_id}: draining");
+                            self.swarm.close_connection(connection_id);
+                        } else if self.dedup_connections {
+                            self.dedup_connections(peer_id);
+                        }
                     }
 
                     // When we fail to connect to a peer
                     SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                        warn!("Failed to dial {peer_id:?}: {error}");
+                        if let Some(peer_id) = peer_id {
+                            self.dial_coalescer.failed(peer_id, Instant::now());
+                        }
+                        if is_upgrade_timeout(&error) {
+                            warn!("Connection upgrade to {peer_id:?} timed out: {}", self.error_message(&error));
+                        } else {
+                            warn!("Failed to dial {peer_id:?}: {}", self.error_message(&error));
+                        }
                     }
 
                     // When we fail to accept a connection from a peer
-                    SwarmEvent::IncomingConnectionError { error, .. } => {
-                        warn!("{:#}", anyhow::Error::from(error))
+                    SwarmEvent::IncomingConnectionError { error, local_addr, send_back_addr, .. } => {
+                        if cert_rotation::is_certificate_mismatch(&local_addr, &error) {
+                            self.metrics.webrtc_certificate_mismatch();
+                            let current: Vec<String> = self.swarm.external_addresses()
+                                .chain(self.swarm.listeners())
+                                .filter(|addr| addr.iter().any(|p| matches!(p, Protocol::Certhash(_))))
+                                .map(Multiaddr::to_string)
+                                .collect();
+                            warn!(
+                                "WebRTC handshake from {send_back_addr} on {local_addr} failed on the certificate, the peer is likely dialing a stale certhash. \
+                                 Ask it to re-fetch our current address: {}. Error: {}",
+                                if current.is_empty() { "unknown".to_string() } else { current.join(", ") },
+                                self.error_message(&error)
+                            );
+                        } else if is_upgrade_timeout(&error) {
+                            warn!("Connection upgrade from {send_back_addr} timed out: {}", self.error_message(&error));
+                        } else if self.verbose_errors {
+                            warn!("{}", verbose_error(&error));
+                        } else {
+                            warn!("{:#}", anyhow::Error::from(error));
+                        }
                     }
 
                     // When a connection to a peer is closed
-                    SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                    SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint, num_established, cause } => {
                         warn!("Connection to {peer_id} closed: {cause:?}");
+                        // a reservation doesn't outlive the connections to the relay
+                        if num_established == 0 {
+                            self.relay_loop_guard.remove_relay(&peer_id);
+                            self.peer_protocols.remove(&peer_id);
+                            self.unsupported_protocols.forget(&peer_id);
+                            self.status_snapshots.forget(&peer_id);
+                        }
+                        let stats = self.connections.remove(&connection_id);
+                        if let Some(stats) = stats.as_ref() {
+                            debug!(
+                                "{} connection {connection_id:?} to {peer_id} had {} ok and {} failed substreams",
+                                stats.transport, stats.substreams_ok, stats.substreams_failed
+                            );
+                        }
+                        let duration = stats.map(|s| s.established.elapsed());
+                        self.metrics.connection_closed(&endpoint, cause.as_ref(), duration);
+                        if self.expired_connections.remove(&connection_id) && num_established == 0 {
+                            if let Some(addrs) = self.persistent_peers.get(&peer_id).cloned() {
+                                info!("Dialing {peer_id} again after closing its connection");
+                                if let Err(e) = self.dial_peer(peer_id, addrs) {
+                                    warn!("Failed to dial {peer_id} again: {}", self.error_message(&e));
+                                }
+                            }
+                        }
+                        self.redundant_connections.remove(&connection_id);
+                        // the peer is still connected over another connection
+                        if num_established > 0 {
+                            continue;
+                        }
                         self.to_ui.send(Message::RemovePeer(peer_id.into())).await?;
 
                         if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
@@ -583,8 +3361,12 @@ impl Peer {
 
                     // When we receive an autonat client event
                     SwarmEvent::Behaviour(BehaviourEvent::AutonatClient(AutonatClientEvent { tested_addr, server, result, .. })) => {
+                        let reachable = result.is_ok();
                         let result = result.map(|_| "Ok".to_string()).unwrap_or_else(|e| e.to_string());
                         debug!("NAT test to {tested_addr} with {server}: {result}");
+                        if reachable {
+                            self.confirm_reachable(&tested_addr, &format!("AutoNAT with {server}")).await?;
+                        }
                     }
                     // When we receive an autonat server event
                     SwarmEvent::Behaviour(BehaviourEvent::AutonatServer(AutonatServerEvent { tested_addr, client, result, .. })) => {
@@ -600,58 +3382,146 @@ impl Peer {
 
                     // When we receive a gossipsub event
                     SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(event)) => match event {
-                        GossipsubEvent::Message { .. } => {
-                            let msg = UniversalConnectivityMessage::try_from(event)?;
+                        GossipsubEvent::Message { ref propagation_source, ref message_id, ref message } => {
+                            // enforce the topic's authentication policy before acting on the message
+                            let acceptance = self.topic_policies.validate(message);
+                            let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                            if !accepted {
+                                warn!("Dropping message from {propagation_source} on {}: {acceptance:?}", message.topic);
+                            }
+                            if matches!(acceptance, gossipsub::MessageAcceptance::Reject) {
+                                self.peer_misbehaved(*propagation_source);
+                            }
+                            let _ = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                message_id,
+                                propagation_source,
+                                acceptance,
+                            );
+                            if !accepted {
+                                continue;
+                            }
+                            self.topic_stats.record(&message.topic, message.source, Instant::now());
+                            self.metrics.gossipsub_message(message.topic.as_str());
+                            // we accepted it under a raised --gossip-max-transmit-size, but the mesh
+                            // peers that kept the default drop it when we forward it
+                            if message.data.len() > GOSSIPSUB_DEFAULT_MAX_TRANSMIT_SIZE {
+                                self.metrics.gossipsub_oversized_forward(message.topic.as_str());
+                                warn!(
+                                    "Message {message_id} from {:?} on {} is {} bytes, peers with the default maximum transmit size of {GOSSIPSUB_DEFAULT_MAX_TRANSMIT_SIZE} bytes will drop it when it is forwarded",
+                                    message.source,
+                                    message.topic,
+                                    message.data.len()
+                                );
+                            }
+
+                            let msg = UniversalConnectivityMessage::decode(event, &self.protocols)?;
                             self.msg(format!("{msg}")).await?;
                             match msg {
-                                UniversalConnectivityMessage::Chat { from, data, ..} => {
-                                    self.to_ui.send(Message::Chat{from, data}).await?;
+                                UniversalConnectivityMessage::Chat { from, envelope, ..} => {
+                                    self.to_ui.send(Message::Chat{from, envelope}).await?;
                                     if let Some(peer) = from {
                                         self.to_ui.send(Message::AddPeer(peer)).await?;
                                     }
                                 }
-                                // UniversalConnectivityMessage::File { from, data, .. } => {
-                                //     let file_id = String::from_utf8(data)?;
-                                //     if let Some(peer) = from {
-                                //         self.swarm.behaviour_mut().request_response.send_request(
-                                //             &peer.into(),
-                                //             FileRequest {
-                                //                 file_id: file_id.clone(),
-                                //             },
-                                //         );
-                                //         self.msg(format!("Sent file request to {peer} for {file_id}")).await?;
-                                //     }
-                                // }
-                                UniversalConnectivityMessage::PeerDiscovery { discovered_peer, discovered_addrs, .. } => {
+                                UniversalConnectivityMessage::File { from, offer, .. } => {
+                                    if !offer.is_of_interest(&self.file_topics_of_interest) {
+                                        debug!("Not fetching {}: topic {:?} is not of interest", offer.file_id, offer.topic);
+                                        continue;
+                                    }
+                                    if let Some(peer) = from {
+                                        self.fetch_offered_file(FileFetch { peer: peer.into(), file_id: offer.file_id }).await?;
+                                    }
+                                }
+                                UniversalConnectivityMessage::PeerDiscovery { from, discovered_peer: Some(peer), presence: Presence::LEAVE, .. } => {
+                                    // only a peer can announce that it leaves, anyone else could evict it
+                                    if from.as_ref() != Some(&peer) {
+                                        warn!("Ignoring the leave of {} announced by {:?}", peer.id(), from.map(|from| from.id()));
+                                        continue;
+                                    }
+                                    self.metrics.presence_event("leave");
+                                    self.provider_index.remove_peer(&peer.id());
+                                    self.clock_skew.remove(&peer.id());
+                                    self.msg(format!("{} ({}) left", peer.id(), peer)).await?;
+                                    if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                                        kad.remove_peer(&peer.id());
+                                    }
+                                    self.to_ui.send(Message::RemovePeer(peer)).await?;
+                                }
+                                UniversalConnectivityMessage::PeerDiscovery { from, discovered_peer, discovered_addrs, presence, timestamp, .. } => {
+                                    if let (Some(peer), Presence::JOIN) = (discovered_peer.as_ref(), presence) {
+                                        self.metrics.presence_event("join");
+                                        self.msg(format!("{} ({}) joined", peer.id(), peer)).await?;
+                                    }
+                                    // only the publisher's own announcements tell about its clock
+                                    if let (Some(from), Some(peer), true) = (from, discovered_peer, timestamp != 0) {
+                                        if from == peer {
+                                            let was_skewed = self.clock_skew.is_skewed(&peer.id());
+                                            let skew = self.clock_skew.record(peer.id(), timestamp, SystemTime::now());
+                                            if self.clock_skew.is_skewed(&peer.id()) && !was_skewed {
+                                                warn!("The clock of {} appears to be {}", peer.id(), clock_skew::describe_skew(skew));
+                                            }
+                                        }
+                                    }
                                     let mut msg = discovered_peer
                                         .map_or("\tDialing: Unknown".to_string(), |discovered_peer| {
                                             format!("\tDialing: {} ({})", discovered_peer.id(), discovered_peer)
                                         });
-                                    // attempt to dial the discovered peer
-                                    for addr in &discovered_addrs {
-                                        if let Err(e) = self.swarm.dial(addr.clone()) {
+                                    // attempt to dial the discovered peer, one address at a time when its id is known
+                                    let mut addrs = Vec::new();
+                                    for addr in discovered_addrs {
+                                        if !self.address_allowed(&addr) {
+                                            write!(msg, "\n\t\tSkipped private {addr}").unwrap();
+                                        } else if discovered_peer.is_some() {
+                                            addrs.push(addr);
+                                        } else if let Err(e) = self.swarm.dial(addr.clone()) {
                                             write!(msg, "\n\t\tError {e}").unwrap();
                                         } else {
                                             write!(msg, "\n\t\t{addr}").unwrap();
                                         }
                                     }
+                                    if let (Some(peer), false) = (discovered_peer.as_ref(), addrs.is_empty()) {
+                                        match self.dial_peer(peer.id(), addrs) {
+                                            Ok(addrs) => {
+                                                for addr in addrs {
+                                                    write!(msg, "\n\t\t{addr}").unwrap();
+                                                }
+                                            }
+                                            Err(e) => write!(msg, "\n\t\tError {e}").unwrap(),
+                                        }
+                                    }
                                     self.msg(msg).await?;
                                     if let Some(peer) = discovered_peer {
                                         self.to_ui.send(Message::AddPeer(peer)).await?;
                                     }
                                 }
+                                UniversalConnectivityMessage::FileProvider { from, advertisement, .. } => {
+                                    // peers may only advertise themselves
+                                    match (from, advertisement.provider.parse::<PeerId>()) {
+                                        (Some(from), Ok(provider)) if from.id() == provider => {
+                                            self.provider_index.insert(advertisement.file_id, provider, Instant::now());
+                                        }
+                                        _ => debug!("Ignoring advertisement of {} by another peer", advertisement.file_id),
+                                    }
+                                }
                                 _ => {} // Ignore other message types for now
                             }
                         }
                         GossipsubEvent::Subscribed { peer_id, topic } => {
                             debug!("{peer_id} subscribed to {topic}");
-                            if topic.as_str() == GOSSIPSUB_CHAT_TOPIC {
+                            // announce that we joined once there is someone to hear it
+                            if topic.as_str() == self.protocols.peer_discovery_topic && !self.joined && self.reachable {
+                                match self.presence_message(Presence::JOIN).and_then(|data| Ok(self.publish(topic.clone(), data, Instant::now())?)) {
+                                    Ok(()) => self.joined = true,
+                                    Err(e) => debug!("Failed to publish the join notification: {e}"),
+                                }
+                            }
+                            if topic.as_str() == self.protocols.chat_topic {
                                 self.to_ui.send(Message::AddPeer(peer_id.into())).await?;
                             }
                         }
                         GossipsubEvent::Unsubscribed { peer_id, topic } => {
                             debug!("{peer_id} unsubscribed from {topic}");
-                            if topic.as_str() == GOSSIPSUB_CHAT_TOPIC {
+                            if topic.as_str() == self.protocols.chat_topic {
                                 self.to_ui.send(Message::RemovePeer(peer_id.into())).await?;
                             }
                         }
@@ -665,30 +3535,69 @@ impl Peer {
 
                     // When we receive an identify event
                     SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => match event {
-                        IdentifyEvent::Received { info, .. } => {
+                        IdentifyEvent::Received { peer_id, info, .. } => {
+                            if let Some(reason) = self.agent_filtered(&info.agent_version) {
+                                info!("Disconnecting {peer_id} running {:?}: {reason}", info.agent_version);
+                                let _ = self.swarm.disconnect_peer_id(peer_id);
+                                continue;
+                            }
+                            self.peer_protocols.insert(peer_id, (info.protocols.clone(), Instant::now()));
+                            // any version of a family is enough for requests over it to be negotiated
+                            let families: Vec<_> = info.protocols.iter().filter_map(|protocol| self.protocols.family(protocol)).collect();
+                            self.unsupported_protocols.identified(&peer_id, &families);
                             //self.update_external_address(&info.observed_addr).await?;
                             if info.agent_version == UNIVERSAL_CONNECTIVITY_AGENT {
                                 let peer_id: PeerId = info.public_key.into();
                                 let agent = format!("{} version: {}", info.agent_version, info.protocol_version);
                                 let protocols = info.protocols.iter().map(|p| format!("\n\t\t{p}") ).collect::<Vec<String>>().join("");
                                 self.msg(format!("Identify {peer_id}:\n\tagent: {agent}\n\tprotocols: {protocols}")).await?;
-                                for addr in info.listen_addrs.iter() {
-                                    if !is_private_ip(addr) {
-                                        if let Err(e) = self.swarm.dial(addr.clone()) {
-                                            self.msg(format!("Failed to dial {addr}: {e}")).await?;
+                                let supports_kad = info.protocols.contains(&self.protocols.kademlia);
+                                let addrs: Vec<Multiaddr> = info.listen_addrs.into_iter().filter(|addr| self.address_allowed(addr)).collect();
+                                if supports_kad {
+                                    if let Some(kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                                        for addr in addrs.iter() {
+                                            kad.add_address(&peer_id, addr.clone());
                                         }
                                     }
                                 }
+                                if !addrs.is_empty() {
+                                    if let Err(e) = self.dial_peer(peer_id, addrs) {
+                                        self.msg(format!("Failed to dial {peer_id}: {e}")).await?;
+                                    }
+                                }
+                                // ask a newly identified peer for the peers it knows, once
+                                if self.pex_sample_size > 0
+                                    && info.protocols.contains(&self.protocols.pex)
+                                    && self.pex_asked.insert(peer_id)
+                                {
+                                    let request = PexRequest { max_peers: self.pex_sample_size };
+                                    self.swarm.behaviour_mut().pex.send_request(&peer_id, request);
+                                }
                             }
                         }
                         IdentifyEvent::Sent { .. } => {
                             debug!("identify::Event::Sent");
                         }
-                        IdentifyEvent::Pushed { .. } => {
-                            debug!("identify::Event::Pushed");
+                        IdentifyEvent::Pushed { peer_id, .. } => {
+                            debug!("identify::Event::Pushed to {peer_id}");
                         }
-                        IdentifyEvent::Error { peer_id, error, .. } => {
+                        IdentifyEvent::Error { peer_id, connection_id, error } => {
                             match error {
+                                // a persistent peer is worth a fresh connection, the same as an
+                                // expired one
+                                libp2p::swarm::StreamUpgradeError::Timeout
+                                    if self.redial_on_identify_timeout && self.persistent_peers.contains_key(&peer_id) =>
+                                {
+                                    warn!("Identify timed out on connection {connection_id:?} to persistent peer {peer_id}, reconnecting");
+                                    if self.swarm.close_connection(connection_id) {
+                                        self.expired_connections.insert(connection_id);
+                                    } else if let Some(addrs) = self.persistent_peers.get(&peer_id).cloned() {
+                                        // the connection is already gone, so nothing will dial the peer again
+                                        if let Err(e) = self.dial_peer(peer_id, addrs) {
+                                            warn!("Failed to dial {peer_id} again: {}", self.error_message(&e));
+                                        }
+                                    }
+                                }
                                 libp2p::swarm::StreamUpgradeError::Timeout => {
                                     // When a browser tab closes, we don't get a swarm event
                                     // maybe there's a way to get this with TransportEvent
@@ -708,28 +3617,47 @@ impl Peer {
 
                     // When we receive a kademlia event
                     SwarmEvent::Behaviour(BehaviourEvent::Kademlia(event)) => match event {
-                        KademliaEvent::OutboundQueryProgressed { id, result, step, .. } => match result {
+                        // a cancelled query only needs its tracking ended
+                        KademliaEvent::OutboundQueryProgressed { id, result, step, stats } if self.cancelled_queries.contains(&id) => {
+                            self.kad_query_progressed(id, &result, &step, &stats);
+                            if step.last {
+                                self.cancelled_queries.remove(&id);
+                            }
+                        }
+                        KademliaEvent::OutboundQueryProgressed { id, result, step, stats } => {
+                        self.kad_query_progressed(id, &result, &step, &stats);
+                        match result {
                             QueryResult::Bootstrap(result) => {
-                                if let Some(query_id) = self.bootstrap_query_id {
+                                if let Some(peers) = self.manual_bootstraps.get_mut(&id) {
+                                    match result {
+                                        Ok(bootstrap) => {
+                                            peers.insert(bootstrap.peer);
+                                            if step.last {
+                                                let found = peers.len();
+                                                self.manual_bootstraps.remove(&id);
+                                                let duration = stats.duration().unwrap_or_default();
+                                                self.msg(format!("Kademlia bootstrap {id:?} finished in {duration:?}: {found} peers found")).await?;
+                                            }
+                                        }
+                                        Err(e) => {
+                                            let found = peers.len();
+                                            self.manual_bootstraps.remove(&id);
+                                            self.msg(format!("Kademlia bootstrap {id:?} failed after finding {found} peers: {e}")).await?;
+                                        }
+                                    }
+                                } else if let Some(query_id) = self.bootstrap_query_id {
                                     if id == query_id {
                                         match result {
                                             Ok(bootstrap) => {
                                                 if step.last {
                                                     self.bootstrap_query_id = None;
-                                                    self.msg("Kademlia bootstrapped".to_string()).await?;
-
-                                                    let mut msgs = Vec::new();
-                                                    if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
-                                                        let key = RecordKey::new(&UNIVERSAL_CONNECTIVITY_AGENT);
-                                                        // start providing the universal connectivity agent string
-                                                        if let Ok(qid) = kad.start_providing(key.clone()) {
-                                                            msgs.push(format!("Kademlia providing: {}", hex::encode(key.clone())));
-                                                            self.start_providing_query_id = Some(qid);
-                                                        }
-                                                    }
-                                                    for msg in msgs.iter() {
-                                                        self.msg(msg).await?;
-                                                    }
+                                                    let duration = stats.duration().unwrap_or_default();
+                                                    self.msg(format!("Kademlia bootstrapped: {} buckets refreshed in {duration:?}", step.count)).await?;
+
+                                                    let key = RecordKey::new(&UNIVERSAL_CONNECTIVITY_AGENT);
+                                                    // start providing the universal connectivity agent string
+                                                    self.kad_query(KadQuery::StartProviding(key.clone()));
+                                                    self.msg(format!("Kademlia providing: {}", hex::encode(key))).await?;
                                                 } else {
                                                     self.msg(format!("Kademlia bootstrapping peer {}, remaining: {}", bootstrap.peer, bootstrap.num_remaining)).await?;
                                                 }
@@ -742,28 +3670,26 @@ impl Peer {
                                     }
                                 }
                             }
-                            QueryResult::GetClosestPeers(result) => {
-                                if self.get_closest_peers_query_id.contains(&id) {
-                                    match result {
-                                        Ok(GetClosestPeersOk { peers, .. }) => {
-                                            //if step.last {
-                                                self.get_closest_peers_query_id.remove(&id);
-                                                self.msg(format!("Kademlia {} potential universal connectivity peers:", peers.len())).await?;
-                                                for peer in peers.iter().cloned() {
-                                                    self.msg(format!("\t{}:", peer.peer_id)).await?;
-                                                    for addr in peer.addrs.iter().take(1) {
-                                                        self.msg(format!("\t\t{addr}")).await?;
-                                                    }
-                                                }
-                                            /*
-                                            } else {
-                                                self.msg(format!("Kademlia getting closest peers: {}", peers.len())).await?;
-                                            }                                            */
-                                        }
-                                        Err(e) => {
+                            QueryResult::GetClosestPeers(result) if self.get_closest_peers_query_id.contains(&id) => {
+                                match result {
+                                    Ok(GetClosestPeersOk { peers, .. }) => {
+                                        //if step.last {
                                             self.get_closest_peers_query_id.remove(&id);
-                                            self.msg(format!("Failed to get closest peers: {e}")).await?;
-                                        }
+                                            self.msg(format!("Kademlia {} potential universal connectivity peers:", peers.len())).await?;
+                                            for peer in peers.iter().cloned() {
+                                                self.msg(format!("\t{}:", peer.peer_id)).await?;
+                                                for addr in peer.addrs.iter().take(1) {
+                                                    self.msg(format!("\t\t{addr}")).await?;
+                                                }
+                                            }
+                                        /*
+                                        } else {
+                                            self.msg(format!("Kademlia getting closest peers: {}", peers.len())).await?;
+                                        }                                            */
+                                    }
+                                    Err(e) => {
+                                        self.get_closest_peers_query_id.remove(&id);
+                                        self.msg(format!("Failed to get closest peers: {e}")).await?;
                                     }
                                 }
                             }
@@ -774,16 +3700,9 @@ impl Peer {
                                             Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
                                                 //if step.last {
                                                     self.get_providers_query_id = None;
-                                                    let mut msgs = Vec::new();
-                                                    if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
-                                                        let peers: Vec<PeerId> = providers.iter().cloned().collect();
-                                                        msgs.push(format!("Kademlia {} found providers", peers.len()));
-                                                        for peer in peers.iter().cloned() {
-                                                            self.get_closest_peers_query_id.insert(kad.get_closest_peers(peer));
-                                                        }
-                                                    }
-                                                    for msg in msgs.iter() {
-                                                        self.msg(msg).await?;
+                                                    self.msg(format!("Kademlia {} found providers", providers.len())).await?;
+                                                    for peer in providers.iter().cloned() {
+                                                        self.kad_query(KadQuery::GetClosestPeers(peer));
                                                     }
                                                 /*
                                                 } else {
@@ -794,15 +3713,9 @@ impl Peer {
                                             Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { closest_peers }) => {
                                                 //if step.last {
                                                     self.get_providers_query_id = None;
-                                                    let mut msgs = Vec::new();
-                                                    if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
-                                                        msgs.push(format!("Kademlia {} found providers", closest_peers.len()));
-                                                        for peer in closest_peers.iter().cloned() {
-                                                            self.get_closest_peers_query_id.insert(kad.get_closest_peers(peer));
-                                                        }
-                                                    }
-                                                    for msg in msgs.iter() {
-                                                        self.msg(msg).await?;
+                                                    self.msg(format!("Kademlia {} found providers", closest_peers.len())).await?;
+                                                    for peer in closest_peers.iter().cloned() {
+                                                        self.kad_query(KadQuery::GetClosestPeers(peer));
                                                     }
                                                 /*
                                                 } else {
@@ -835,10 +3748,8 @@ impl Peer {
                                                 if step.last {
                                                     self.start_providing_query_id = None;
                                                     self.msg("Kademlia provider registered".to_string()).await?;
-                                                    if let Some(ref mut kad) = self.swarm.behaviour_mut().kademlia.as_mut() {
-                                                        // query for the providers of the universal connectivity agent string
-                                                        self.get_providers_query_id = Some(kad.get_providers(key.clone()));
-                                                    }
+                                                    // query for the providers of the universal connectivity agent string
+                                                    self.kad_query(KadQuery::GetProviders(key.clone()));
                                                     self.msg(format!("Kademlia getting providers for: {}", hex::encode(key.clone()))).await?;
                                                 } else {
                                                     self.msg(format!("Kademlia adding provider record: {}", step.count)).await?;
@@ -854,12 +3765,14 @@ impl Peer {
                             }
                             _ => {} // Ignore other query results
                         }
+                        }
                         ref _other => {} // Ignore other Kademlia events
                     }
 
                     // When we receive a relay client event
                     SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => match event {
                         RelayClientEvent::ReservationReqAccepted { relay_peer_id, renewal, limit } => {
+                            self.relay_loop_guard.add_relay(relay_peer_id);
                             self.msg(format!("Relay reservation request accepted:\n\tfrom: {relay_peer_id}\n\trenewed: {renewal}\n\tlimit: {limit:?}")).await?;
                         }
                         RelayClientEvent::OutboundCircuitEstablished { relay_peer_id, .. } => {
@@ -874,6 +3787,16 @@ impl Peer {
                     SwarmEvent::Behaviour(BehaviourEvent::RelayServer(event)) => match event {
                         RelayServerEvent::ReservationReqAccepted { src_peer_id, renewed } => {
                             self.msg(format!("Relay reservation request accepted:\n\tfrom: {src_peer_id}\n\trenewed: {renewed}")).await?;
+                            // the relay server can't refuse reservations at runtime, so a new one
+                            // made while draining is dropped with its connections
+                            if self.drain_started.is_some() && !renewed {
+                                if self.has_transfer_in_flight(&src_peer_id) {
+                                    warn!("Keeping the new reservation of {src_peer_id} while draining: a transfer is in flight");
+                                } else {
+                                    info!("Disconnecting {src_peer_id}: it made a new reservation while draining");
+                                    let _ = self.swarm.disconnect_peer_id(src_peer_id);
+                                }
+                            }
                         }
                         RelayServerEvent::ReservationReqDenied { src_peer_id } => {
                             self.msg(format!("Relay reservation request denied: {src_peer_id}")).await?;
@@ -895,102 +3818,344 @@ impl Peer {
 
                     // When we receive a request_response event
                     SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(event)) => match event {
-                                            RequestResponseEvent::Message { message, peer, connection_id } => match message {
-                                                RequestResponseMessage::Request { request, channel, .. } => {
-                                                    debug!("Received GitRequest from {}: {:?}", peer, request);
-                                                    let response = match request {
-                                                        GitRequest::Clone(repo_url) => {
-                                                            let clone_dir = PathBuf::from("./cloned_repos");
-                                                            let dir_creation_result: Result<(), String> = if !clone_dir.exists() {
-                                                                if let Err(e) = std::fs::create_dir_all(&clone_dir) {
-                                                                    error!("Failed to create clone directory {:?}: {}", clone_dir, e);
-                                                                    Err(format!("Failed to create clone directory: {}", e))
-                                                                } else {
-                                                                    info!("Clone directory created: {:?}", clone_dir);
-                                                                    Ok(())
-                                                                }
-                                                            } else {
-                                                                info!("Clone directory already exists: {:?}", clone_dir);
-                                                                Ok(())
-                                                            };
-
-                                                            match dir_creation_result {
-                                                                Ok(_) => {
-                                                                    let mut builder = RepoBuilder::new();
-                                                                    let repo_name = repo_url.split('/').last().unwrap_or("repo");
-                                                                    let repo_path = clone_dir.join(repo_name);
-                                                                    match builder.clone(&repo_url, &repo_path) {
-                                                                        Ok(_) => {
-                                                                            info!("Successfully cloned repository {} to {:?}", repo_url, repo_path);
-                                                                            GitResponse::Success(format!("Successfully cloned repository {}", repo_url))
-                                                                        }
-                                                                        Err(e) => {
-                                                                            error!("Failed to clone repository {}: {}", repo_url, e);
-                                                                            GitResponse::Error(format!("Failed to clone repository {}: {}", repo_url, e))
-                                                                        }
-                                                                    }
-                                                                }
-                                                                Err(e) => GitResponse::Error(e),
-                                                            }
-                                                        },
-                                                        GitRequest::Fetch(remote_name, refspecs) => {
-                                                            let clone_dir = PathBuf::from("./cloned_repos");
-                                                            let repo_name = remote_name.split('/').last().unwrap_or("repo"); // Assuming remote_name is part of the URL or a known name
-                                                            let repo_path = clone_dir.join(repo_name);
-                    
-                                                            match Repository::open(&repo_path) {
-                                                                Ok(repo) => {
-                                                                    match repo.find_remote(&remote_name) {
-                                                                        Ok(mut remote) => {
-                                                                            let mut fo = git2::FetchOptions::new();
-                                                                            // Configure fetch options if refspecs are provided
-                                                                            if refspecs.as_ref().map_or(true, |v| v.is_empty()) {
-    
-                                                                            }
-                                                                            
-                                                                            match remote.fetch(&refspecs.as_ref().map(|v| v.iter().map(|s| s.as_str()).collect::<Vec<&str>>()).unwrap_or_default(), Some(&mut fo), None) {
-                                                                                Ok(_bytes_transferred) => {
-                                                                                    info!("Fetched from {} for repo at {:?}", remote_name, repo_path);
-                                                                                    GitResponse::Success(format!("Fetched from {}", remote_name))
-                                                                                }
-                                                                                Err(e) => {
-                                                                                    GitResponse::Error(format!("Failed to fetch from remote {}: {}", remote_name, e))
-                                                                                }
-                                                                            }
-                                                                        }
-                                                                        Err(e) => GitResponse::Error(format!("Failed to find remote '{}' in repo at {:?}: {}", remote_name, repo_path, e))
-                                                                    }
-                                                                }
-                                                                Err(e) => GitResponse::Error(format!("Failed to open repository at {:?}: {}", repo_path, e))
-                                                            }
-                                                        },
-                                                        GitRequest::Push(remote, refspecs) => {
-                                                            GitResponse::Error(format!("Push not yet implemented for remote: {}, refspecs: {:?}", remote, refspecs))
-
-                                                        },
-                                                        GitRequest::LsRemote(remote) => {
-                                                            GitResponse::Error(format!("LsRemote not yet implemented for remote: {}", remote))
-                                                        },
-                                                        GitRequest::Status => {
-                                                            GitResponse::Error("Status not yet implemented".to_string())
-                                                        },
-                                                    };
-                                                    if let Err(e) = self.swarm.behaviour_mut().request_response.send_response(channel, response) {
-                                                        error!("Failed to send GitResponse: {:?}", e);
-                                                    }
+                        RequestResponseEvent::Message { message, peer, .. } => match message {
+                            RequestResponseMessage::Request { request_id, request, channel } => {
+                                debug!("Received GitRequest from {}: {:?}", peer, request);
+                                self.transfer_started(TransferProtocol::Git, TransferId::Inbound(request_id), peer, request.operation());
+                                if let Err(reason) = self.admit_inbound_request(protocol_names::GIT_EXCHANGE, request_id, peer) {
+                                    self.send_git_response(request_id, channel, GitResponse::Error(reason.to_string()));
+                                } else {
+                                    // clones, fetches, packfiles and status listings can take long, they
+                                    // run on the blocking pool so the swarm keeps being polled meanwhile
+                                    let statuses = self.status_snapshots.clone();
+                                    let config = self.git_server_config;
+                                    self.git_handlers.spawn_blocking(move || {
+                                        let response = git_server::respond(&statuses, &peer, request, &config);
+                                        (request_id, channel, response)
+                                    });
+                                }
+                            }
+                            RequestResponseMessage::Response { request_id, response } => {
+                                self.git_response_received(request_id, peer, response.summary(), response.is_error()).await?;
+                                match response {
+                                    chunk @ GitResponse::PackChunk { .. } => {
+                                        if let Some(repo) = self.pack_requests.remove(&request_id) {
+                                            self.pack_chunk_received(peer, repo, chunk).await?;
+                                        }
+                                    }
+                                    GitResponse::StatusChunk { seq, lines, total, done, truncated } => {
+                                        if let Some(repo) = self.status_requests.remove(&request_id) {
+                                            // render each chunk as it arrives
+                                            if !lines.is_empty() {
+                                                self.msg(format!("Status of {repo} from {peer}:\n\t{}", lines.join("\n\t"))).await?;
+                                            }
+                                            if !done {
+                                                self.request_status_chunk(peer, repo, seq + 1).await?;
+                                            } else if truncated {
+                                                self.msg(format!("Status of {repo} truncated: {total} changed files, only the first {GIT_MAX_STATUS_LINES} shown")).await?;
+                                            } else if total == 0 {
+                                                self.msg(format!("{repo} on {peer} has no changes")).await?;
+                                            }
+                                        }
+                                    }
+                                    GitResponse::LsRemoteChunk { refs, next } => {
+                                        if let Some(key) = self.ls_remote_requests.remove(&request_id) {
+                                            self.ls_remote_chunk_received(key, refs, next).await?;
+                                        }
+                                    }
+                                    GitResponse::Data(data) if self.archive_requests.contains_key(&request_id) => {
+                                        if let Some(path) = self.archive_requests.remove(&request_id) {
+                                            match fs::write(&path, &data) {
+                                                Ok(()) => self.msg(format!("Saved the {} byte archive from {peer} to {}", data.len(), path.display())).await?,
+                                                Err(e) => self.msg(format!("Failed to save the archive to {}: {e}", path.display())).await?,
+                                            }
+                                        }
+                                    }
+                                    response => {
+                                        if let Some(path) = self.archive_requests.remove(&request_id) {
+                                            self.msg(format!("Archive {} from {peer} failed: {response:?}", path.display())).await?;
+                                        } else if let Some(repo) = self.status_requests.remove(&request_id) {
+                                            self.msg(format!("Status of {repo} from {peer} failed: {response:?}")).await?;
+                                        } else if let Some((peer, repo)) = self.ls_remote_requests.remove(&request_id) {
+                                            self.ls_remote_failed(peer, repo, format!("{response:?}")).await?;
+                                        } else if let Some(repo) = self.pack_requests.remove(&request_id) {
+                                            self.pack_transfers.remove(&(peer, repo.clone()));
+                                            self.msg(format!("Clone of {repo} from {peer} failed: {response:?}")).await?;
+                                        } else {
+                                            debug!("Received GitResponse: {:?}", response);
+                                        }
+                                    }
+                                }
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure { peer, request_id, error, .. } => {
+                            if !self.unsupported_protocols.outbound_failed(peer, protocol_names::GIT_EXCHANGE, &error) {
+                                error!("request_response::Event::OutboundFailure for request {:?}: {}", request_id, self.error_message(&error));
+                            }
+                            self.git_response_received(request_id, peer, format!("Failed: {error}"), true).await?;
+                            if let Some(repo) = self.pack_requests.remove(&request_id) {
+                                self.pack_transfers.remove(&(peer, repo.clone()));
+                                self.msg(format!("Clone of {repo} from {peer} failed: {error}")).await?;
+                            } else if let Some(repo) = self.status_requests.remove(&request_id) {
+                                self.msg(format!("Status of {repo} from {peer} failed: {error}")).await?;
+                            } else if let Some(path) = self.archive_requests.remove(&request_id) {
+                                self.msg(format!("Archive {} from {peer} failed: {error}", path.display())).await?;
+                            } else if let Some((peer, repo)) = self.ls_remote_requests.remove(&request_id) {
+                                self.ls_remote_failed(peer, repo, error.to_string()).await?;
+                            }
+                        }
+                        RequestResponseEvent::InboundFailure { request_id, error, .. } => {
+                            debug!("request_response::Event::InboundFailure for request {:?}: {}", request_id, self.error_message(&error));
+                            self.inbound_requests.remove(&(protocol_names::GIT_EXCHANGE, request_id));
+                            self.transfer_finished(TransferProtocol::Git, TransferId::Inbound(request_id));
+                        }
+                        RequestResponseEvent::ResponseSent { request_id, .. } => {
+                            self.inbound_requests.remove(&(protocol_names::GIT_EXCHANGE, request_id));
+                            self.transfer_finished(TransferProtocol::Git, TransferId::Inbound(request_id));
+                        }
+                    },
+                    // When we receive a file exchange event
+                    SwarmEvent::Behaviour(BehaviourEvent::FileExchange(event)) => match event {
+                        RequestResponseEvent::Message { message, peer, .. } => match message {
+                            RequestResponseMessage::Request { request_id, request, channel } => {
+                                // dropping the channel of a duplicate fails it on the requester,
+                                // which ignores it since the original answers the same nonce
+                                if let Some(nonce) = request.nonce {
+                                    if !self.inflight_file_requests.start(request_id, peer, nonce) {
+                                        debug!("Collapsed duplicate request from {peer} for {}", request.file_id);
+                                        continue;
+                                    }
+                                }
+                                // there is no error response, dropping the channel fails the request
+                                if self.admit_inbound_request(protocol_names::FILE_EXCHANGE, request_id, peer).is_err() {
+                                    self.inflight_file_requests.finish(&request_id);
+                                    continue;
+                                }
+                                self.transfer_started(TransferProtocol::File, TransferId::Inbound(request_id), peer, "Get");
+                                let response = file_exchange::respond(&mut self.file_store, &peer, &request);
+                                if let FileResponse::File { file_body: data, .. } | FileResponse::Range { data, .. } = &response {
+                                    self.transfer_progressed(TransferProtocol::File, TransferId::Inbound(request_id), data.len() as u64);
+                                }
+                                if self.swarm.behaviour_mut().file_exchange.send_response(channel, response).is_err() {
+                                    warn!("Failed to send file {} to {peer}", request.file_id);
+                                    self.inflight_file_requests.finish(&request_id);
+                                    self.inbound_requests.remove(&(protocol_names::FILE_EXCHANGE, request_id));
+                                    self.transfer_finished(TransferProtocol::File, TransferId::Inbound(request_id));
+                                }
+                            }
+                            RequestResponseMessage::Response { request_id, response } => {
+                                self.transfer_finished(TransferProtocol::File, TransferId::Outbound(request_id));
+                                if let Some((file_id, first)) = self.file_requests.answered(&request_id) {
+                                    if !first {
+                                        debug!("Ignoring late duplicate response for {file_id} from {peer}");
+                                        continue;
+                                    }
+                                    // the ranges received so far stay on disk unless the fetch is over
+                                    let partial = self.partial_files.remove(&file_id);
+                                    let file_body = match response {
+                                        // a definitive answer, the request is over and nothing is stored
+                                        FileResponse::NotFound => {
+                                            self.msg(format!("{peer} doesn't have file {file_id}")).await?;
+                                            continue;
+                                        }
+                                        // a peer on an older version sends the whole file
+                                        FileResponse::File { file_body, encrypted } => {
+                                            if let Some(partial) = partial {
+                                                partial.discard();
+                                            }
+                                            info!("Received file {file_id} from {peer}: size:{}", file_body.len());
+                                            match self.decrypt_file(file_body, encrypted) {
+                                                Ok(file_body) => file_body,
+                                                Err(e) => {
+                                                    self.msg(format!("Discarding file {file_id} from {peer}: {e}")).await?;
+                                                    continue;
                                                 }
-                                                RequestResponseMessage::Response { response, .. } => {
-                                                    debug!("Received GitResponse: {:?}", response);
-                                                    // TODO: Handle the GitResponse from a sent request.
+                                            }
+                                        }
+                                        FileResponse::Range { session, offset, total_size, data, encrypted } => {
+                                            let Some(mut partial) = partial else {
+                                                warn!("Discarding range of {file_id} from {peer}: the file isn't being fetched in ranges");
+                                                continue;
+                                            };
+                                            // the fetch is aborted, the ranges received so far are kept to resume it
+                                            let data = match self.decrypt_file(data, encrypted) {
+                                                Ok(data) => data,
+                                                Err(e) => {
+                                                    self.msg(format!("Aborting the fetch of {file_id} from {peer}: {e}")).await?;
+                                                    continue;
+                                                }
+                                            };
+                                            if let Err(e) = partial.receive(session, offset, total_size, &data) {
+                                                // a range other than the one requested, or of a file too large
+                                                self.peer_misbehaved(peer);
+                                                self.msg(format!("Aborting the fetch of {file_id} from {peer}: {e:#}")).await?;
+                                                continue;
+                                            }
+                                            if let Some(range) = partial.next_range() {
+                                                debug!("Received {} of {total_size} bytes of {file_id} from {peer}", partial.received_bytes());
+                                                self.partial_files.insert(file_id.clone(), partial);
+                                                self.send_file_request(peer, file_id, Some(range));
+                                                continue;
+                                            }
+                                            info!("Received file {file_id} from {peer}: size:{total_size}");
+                                            match partial.finish() {
+                                                Ok(file_body) => file_body,
+                                                Err(e) => {
+                                                    self.msg(format!("Aborting the fetch of {file_id} from {peer}: {e:#}")).await?;
+                                                    continue;
                                                 }
-                                            },
-                                            RequestResponseEvent::OutboundFailure {
-                                                request_id, error, ..
-                                            } => {
-                                                error!("request_response::Event::OutboundFailure for request {:?}: {:?}", request_id, error)
                                             }
-                                            _ => {},
-                                        },
+                                        }
+                                    };
+                                    if ContentHash::verify(&file_id, &file_body) == Some(false) {
+                                        warn!("Discarding file {file_id} from {peer}: its content doesn't match its id");
+                                        self.peer_misbehaved(peer);
+                                        continue;
+                                    }
+                                    #[cfg(feature = "sqlite-index")]
+                                    if let Some(index) = self.file_index.as_ref() {
+                                        if let Err(e) = index.insert_received(&file_id, &file_body) {
+                                            warn!("Failed to add {file_id} to the file index: {e}");
+                                        }
+                                    }
+                                    // a file too large for the cache on its own isn't stored
+                                    if !self.file_store.fits(file_body.len() as u64) {
+                                        self.msg(format!("Discarding file {file_id} from {peer}: it is larger than --file-cache-max-bytes")).await?;
+                                        continue;
+                                    }
+                                    let new = self.file_store.insert(file_id.clone(), file_body);
+                                    self.evict_files().await?;
+                                    if new && self.file_store.contains(&file_id) {
+                                        self.provide_file(&file_id)?;
+                                        self.msg(format!("Stored and providing file {file_id}")).await?;
+                                    }
+                                }
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure { peer, request_id, error, .. } => {
+                            self.transfer_finished(TransferProtocol::File, TransferId::Outbound(request_id));
+                            let unsupported = self.unsupported_protocols.outbound_failed(peer, protocol_names::FILE_EXCHANGE, &error);
+                            // the nonce stays outstanding while a retry of the request is pending
+                            if let Some(file_id) = self.file_requests.failed(&request_id) {
+                                self.partial_files.remove(&file_id);
+                                if !unsupported {
+                                    error!("file request for {file_id} failed: {}", self.error_message(&error));
+                                }
+                            }
+                        }
+                        RequestResponseEvent::InboundFailure { request_id, .. }
+                        | RequestResponseEvent::ResponseSent { request_id, .. } => {
+                            self.inflight_file_requests.finish(&request_id);
+                            self.inbound_requests.remove(&(protocol_names::FILE_EXCHANGE, request_id));
+                            self.transfer_finished(TransferProtocol::File, TransferId::Inbound(request_id));
+                        }
+                    },
+                    // When a connection moves to a new remote address it is still the same
+                    // connection. QUIC in libp2p 0.55 disables connection migration and never
+                    // reports a change, so this only fires for transports whose muxer does
+                    SwarmEvent::Behaviour(BehaviourEvent::AddressChange(AddressChanged { peer_id, connection_id, old, new })) => {
+                        info!("Connection {connection_id:?} to {peer_id} migrated from {old} to {new}");
+                        if let Some(stats) = self.connections.get_mut(&connection_id) {
+                            stats.transport = metrics::connection_transport(&new);
+                            stats.remote_addr = new;
+                        }
+                    }
+                    // When we receive a peer exchange event
+                    SwarmEvent::Behaviour(BehaviourEvent::Pex(event)) => match event {
+                        RequestResponseEvent::Message { message, peer, .. } => match message {
+                            RequestResponseMessage::Request { request_id, request, channel } => {
+                                if self.admit_inbound_request(protocol_names::PEX, request_id, peer).is_err() {
+                                    continue;
+                                }
+                                let known = self.routing_table_peers().unwrap_or_default();
+                                let response = PexResponse::sample(&request, &peer, known);
+                                if self.swarm.behaviour_mut().pex.send_response(channel, response).is_err() {
+                                    warn!("Failed to send peer exchange sample to {peer}");
+                                    self.inbound_requests.remove(&(protocol_names::PEX, request_id));
+                                }
+                            }
+                            RequestResponseMessage::Response { response, .. } => {
+                                self.pex_received(peer, response).await?;
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                            let unsupported = self.unsupported_protocols.outbound_failed(peer, protocol_names::PEX, &error);
+                            if !unsupported {
+                                debug!("Peer exchange with {peer} failed: {}", self.error_message(&error));
+                            }
+                        }
+                        RequestResponseEvent::InboundFailure { request_id, .. }
+                        | RequestResponseEvent::ResponseSent { request_id, .. } => {
+                            self.inbound_requests.remove(&(protocol_names::PEX, request_id));
+                        }
+                    },
+                    // When we receive a file manifest event
+                    SwarmEvent::Behaviour(BehaviourEvent::FileManifest(event)) => match event {
+                        RequestResponseEvent::Message { message, peer, .. } => match message {
+                            RequestResponseMessage::Request { request_id, request, channel } => {
+                                if self.admit_inbound_request(protocol_names::FILE_MANIFEST, request_id, peer).is_err() {
+                                    continue;
+                                }
+                                let response = self.file_store.manifest(request.page);
+                                if self.swarm.behaviour_mut().file_manifest.send_response(channel, response).is_err() {
+                                    warn!("Failed to send file manifest to {peer}");
+                                    self.inbound_requests.remove(&(protocol_names::FILE_MANIFEST, request_id));
+                                }
+                            }
+                            RequestResponseMessage::Response { request_id, response } => {
+                                if self.manifest_requests.remove(&request_id) {
+                                    let mut msg = format!("Files of {peer}:");
+                                    for entry in response.entries.iter() {
+                                        let name = entry.name.as_deref().unwrap_or("");
+                                        write!(msg, "\n\t{} {} bytes {name}", entry.file_id, entry.size).unwrap();
+                                        if let Some(namespace) = entry.namespace.as_deref() {
+                                            write!(msg, " [{namespace}]").unwrap();
+                                        }
+                                    }
+                                    self.msg(msg).await?;
+                                    // keep going until the whole manifest is listed
+                                    if let Some(page) = response.next_page {
+                                        self.request_manifest_page(peer, page);
+                                    }
+                                }
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure { peer, request_id, error, .. } => {
+                            self.unsupported_protocols.outbound_failed(peer, protocol_names::FILE_MANIFEST, &error);
+                            if self.manifest_requests.remove(&request_id) {
+                                self.msg(format!("Listing the files of {peer} failed: {}", self.error_message(&error))).await?;
+                            }
+                        }
+                        RequestResponseEvent::InboundFailure { request_id, .. }
+                        | RequestResponseEvent::ResponseSent { request_id, .. } => {
+                            self.inbound_requests.remove(&(protocol_names::FILE_MANIFEST, request_id));
+                        }
+                    },
+                    // When we receive an echo event
+                    SwarmEvent::Behaviour(BehaviourEvent::Echo(event)) => match event {
+                        RequestResponseEvent::Message { message, peer, .. } => match message {
+                            RequestResponseMessage::Request { request, channel, .. } => {
+                                let response = EchoResponse { payload: request.payload };
+                                if self.swarm.behaviour_mut().echo.send_response(channel, response).is_err() {
+                                    warn!("Failed to send echo response to {peer}");
+                                }
+                            }
+                            RequestResponseMessage::Response { request_id, response } => {
+                                if let Some((payload, sent)) = self.echo_requests.remove(&request_id) {
+                                    let rtt = sent.elapsed();
+                                    let matched = if response.payload == payload { "matched" } else { "MISMATCHED" };
+                                    self.msg(format!("Echo from {peer}: {} bytes in {}ms, payload {matched}", payload.len(), rtt.as_millis())).await?;
+                                }
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure { peer, request_id, error, .. } => {
+                            self.unsupported_protocols.outbound_failed(peer, protocol_names::ECHO, &error);
+                            if self.echo_requests.remove(&request_id).is_some() {
+                                self.msg(format!("Echo to {peer} failed: {}", self.error_message(&error))).await?;
+                            }
+                        }
+                        _ => {}
+                    },
                     event => {
                         debug!("Other type of event: {:?}", event);
                     }
@@ -998,22 +4163,88 @@ impl Peer {
             }
         }
 
+        // announce that we are leaving while we are still subscribed, and give the swarm a moment
+        // to send it
+        if self.joined {
+            let published = self.presence_message(Presence::LEAVE).and_then(|data| {
+                self.swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(peer_discovery.clone(), data)?;
+                Ok(())
+            });
+            match published {
+                Ok(()) => {
+                    let _ = tokio::time::timeout(LEAVE_FLUSH_TIMEOUT, async {
+                        loop {
+                            self.swarm.select_next_some().await;
+                        }
+                    })
+                    .await;
+                }
+                Err(e) => debug!("Failed to publish the leave notification: {e}"),
+            }
+        }
+
+        info!("Unsubscribing from topics");
+        for topic in &[chat_topic, file_topic, peer_discovery, file_providers] {
+            if !self.swarm.behaviour_mut().gossipsub.unsubscribe(topic) {
+                debug!("Failed to unsubscribe from topic {topic}");
+            }
+        }
+
+        if let Err(e) = self.reputation.save() {
+            warn!("Failed to save the peer reputation: {e}");
+        }
+
+        info!("Shutting down the peer");
+        self.msg("Peer stopped").await?;
+
         Ok(())
     }
 }
 
+// SIGUSR1, which starts a drain. It never arrives where there are no unix signals or its handler
+// can't be installed, the drain command still works there.
+struct DrainSignal {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+}
+
+impl DrainSignal {
+    fn new() -> Self {
+        #[cfg(unix)]
+        let signal = signal(SignalKind::user_defined1())
+            .inspect_err(|e| warn!("Not draining on SIGUSR1, its handler can't be installed: {e}"))
+            .ok();
+        Self {
+            #[cfg(unix)]
+            signal,
+        }
+    }
+
+    // Wait for the next SIGUSR1
+    async fn recv(&mut self) -> Option<()> {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            return signal.recv().await;
+        }
+        std::future::pending().await
+    }
+}
+
 enum UniversalConnectivityMessage {
     Chat {
         propagation_source: PeerId,
         from: Option<ChatPeer>,
-        data: Vec<u8>,
+        envelope: ChatEnvelope,
         seq_no: Option<u64>,
         topic: TopicHash,
     },
     File {
         propagation_source: PeerId,
         from: Option<ChatPeer>,
-        data: Vec<u8>,
+        offer: FileOffer,
         seq_no: Option<u64>,
         topic: TopicHash,
     },
@@ -1022,6 +4253,15 @@ enum UniversalConnectivityMessage {
         from: Option<ChatPeer>,
         discovered_peer: Option<ChatPeer>,
         discovered_addrs: Vec<Multiaddr>,
+        presence: Presence,
+        timestamp: u64,
+        seq_no: Option<u64>,
+        topic: TopicHash,
+    },
+    FileProvider {
+        propagation_source: PeerId,
+        from: Option<ChatPeer>,
+        advertisement: ProviderAdvertisement,
         seq_no: Option<u64>,
         topic: TopicHash,
     },
@@ -1034,10 +4274,9 @@ enum UniversalConnectivityMessage {
     },
 }
 
-impl TryFrom<GossipsubEvent> for UniversalConnectivityMessage {
-    type Error = anyhow::Error;
-
-    fn try_from(event: GossipsubEvent) -> anyhow::Result<Self, Self::Error> {
+impl UniversalConnectivityMessage {
+    /// Decode a gossipsub message by the topic it was published on
+    fn decode(event: GossipsubEvent, protocols: &ProtocolNames) -> anyhow::Result<Self> {
         if let GossipsubEvent::Message {
             propagation_source,
             message,
@@ -1050,21 +4289,28 @@ impl TryFrom<GossipsubEvent> for UniversalConnectivityMessage {
             let topic = message.topic.clone();
 
             match topic.as_str() {
-                GOSSIPSUB_CHAT_TOPIC => Ok(Self::Chat {
+                name if name == protocols.chat_topic => Ok(Self::Chat {
                     propagation_source,
                     from,
-                    data,
+                    envelope: ChatEnvelope::decode(&data),
                     seq_no,
                     topic,
                 }),
-                GOSSIPSUB_CHAT_FILE_TOPIC => Ok(Self::File {
+                name if name == protocols.file_topic => Ok(Self::File {
                     propagation_source,
                     from,
-                    data,
+                    offer: FileOffer::decode(&data)?,
+                    seq_no,
+                    topic,
+                }),
+                name if name == protocols.file_providers_topic => Ok(Self::FileProvider {
+                    propagation_source,
+                    from,
+                    advertisement: serde_json::from_slice(&data)?,
                     seq_no,
                     topic,
                 }),
-                GOSSIPSUB_PEER_DISCOVERY => {
+                name if name == protocols.peer_discovery_topic => {
                     let mut reader = BytesReader::from_bytes(&data);
                     let peer = 
                         DiscoveredPeer::from_reader(&mut reader, &data).map_err(|_| fmt::Error)?;
@@ -1093,6 +4339,8 @@ impl TryFrom<GossipsubEvent> for UniversalConnectivityMessage {
                         from,
                         discovered_peer,
                         discovered_addrs,
+                        presence: peer.presence,
+                        timestamp: peer.timestamp,
                         seq_no,
                         topic,
                     })
@@ -1117,7 +4365,7 @@ impl fmt::Display for UniversalConnectivityMessage {
             Self::Chat {
                 propagation_source,
                 from,
-                data,
+                envelope,
                 seq_no,
                 topic,
             } => {
@@ -1132,14 +4380,12 @@ impl fmt::Display for UniversalConnectivityMessage {
                     format!("{} ({})", peer.id(), peer)
                 });
                 let seq_no = seq_no.map_or("Unknown".to_string(), |seq_no| seq_no.to_string());
-                let message =
-                    String::from_utf8(data.to_vec()).unwrap_or("invalid UTF-8".to_string());
-                write!(f, "Received chat message:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tmsg: {message}")
+                write!(f, "Received chat message:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tcontent type: {}\n\tmsg: {}", envelope.content_type, envelope.payload)
             }
             Self::File {
                 propagation_source,
                 from,
-                data,
+                offer,
                 seq_no,
                 topic,
             } => {
@@ -1154,17 +4400,35 @@ impl fmt::Display for UniversalConnectivityMessage {
                     format!("{} ({})", peer.id(), peer)
                 });
                 let seq_no = seq_no.map_or("Unknown".to_string(), |seq_no| seq_no.to_string());
-                let message =
-                    String::from_utf8(data.to_vec()).unwrap_or("invalid UTF-8".to_string());
-                write!(f, "Received file offer:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tfile id: {message}")
+                let file_topic = offer.topic.as_deref().unwrap_or("none");
+                write!(f, "Received file offer:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tfile id: {}\n\tfile topic: {file_topic}", offer.file_id)
+            }
+            Self::FileProvider {
+                propagation_source,
+                from,
+                advertisement,
+                seq_no,
+                topic,
+            } => {
+                let propagation_source = {
+                    let ps: ChatPeer = propagation_source.into();
+                    format!("{} ({})", ps.id(), ps)
+                };
+                let source = from.as_ref().map_or("Unknown".to_string(), |peer| {
+                    format!("{} ({})", peer.id(), peer)
+                });
+                let seq_no = seq_no.map_or("Unknown".to_string(), |seq_no| seq_no.to_string());
+                write!(f, "Received file provider advertisement:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfile id: {}\n\tprovider: {}", advertisement.file_id, advertisement.provider)
             }
             Self::PeerDiscovery {
                 propagation_source,
                 from,
                 discovered_peer,
                 discovered_addrs,
+                presence,
                 seq_no,
                 topic,
+                ..
             } => {
                 let propagation_source = {
                     let ps: ChatPeer = propagation_source.into();
@@ -1181,7 +4445,7 @@ impl fmt::Display for UniversalConnectivityMessage {
                     .map_or("Unknown".to_string(), |discovered_peer| {
                         format!("{} ({})", discovered_peer.id(), discovered_peer)
                     });
-                write!(f, "Received peer discovery:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tpeer: {discovered_peer}\n\tmultiaddrs: {}", discovered_addrs.len())
+                write!(f, "Received peer discovery:\n\tp source: {propagation_source}\n\tsource: {source}\n\tseq no: {seq_no}\n\ttopic: {topic}\n\tfrom: {chat_peer}\n\tpeer: {discovered_peer}\n\tmultiaddrs: {}\n\tpresence: {presence:?}", discovered_addrs.len())
             }
             Self::Unknown {
                 propagation_source,
@@ -0,0 +1,100 @@
+use crate::persisted::PersistedFile;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+/// Exports older than this are considered stale and are not imported
+pub const PEER_SEEDS_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// The format of an export
+const PEER_SEEDS_FILE: PersistedFile<PeerSeeds> = PersistedFile::new("peer-seeds", 1);
+
+/// A peer and the addresses it was known at
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerSeed {
+    /// The base58 peer id
+    pub peer_id: String,
+    /// The addresses of the peer
+    pub addrs: Vec<String>,
+}
+
+/// The routing table of a node, exported to seed the routing table of another so it doesn't have
+/// to wait for a cold bootstrap. Stored as a JSON [`PersistedFile`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerSeeds {
+    /// When the peers were exported, in seconds since the unix epoch
+    pub exported: u64,
+    /// The exported peers
+    pub peers: Vec<PeerSeed>,
+}
+
+impl PeerSeeds {
+    /// Collect peers and their addresses for export
+    pub fn new(peers: impl IntoIterator<Item = (PeerId, Vec<Multiaddr>)>) -> Self {
+        let peers = peers
+            .into_iter()
+            .filter(|(_, addrs)| !addrs.is_empty())
+            .map(|(peer_id, addrs)| PeerSeed {
+                peer_id: peer_id.to_base58(),
+                addrs: addrs.iter().map(Multiaddr::to_string).collect(),
+            })
+            .collect();
+        Self {
+            exported: now(),
+            peers,
+        }
+    }
+
+    /// Load exported peers from a file, with no peers if the file doesn't exist or is corrupt, see
+    /// [`PersistedFile::load_or_default`]
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        PEER_SEEDS_FILE.load_or_default(path)
+    }
+
+    /// Write the peers to a file
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        PEER_SEEDS_FILE.save(path, self)
+    }
+
+    /// How long ago the peers were exported
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.exported))
+    }
+
+    /// Check if the export is too old to be trusted, see [`PEER_SEEDS_MAX_AGE`]
+    pub fn is_expired(&self) -> bool {
+        self.age() > PEER_SEEDS_MAX_AGE
+    }
+
+    /// The peers with their addresses, skipping invalid peer ids and addresses and peers left with
+    /// no address
+    pub fn entries(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.peers
+            .iter()
+            .filter_map(|seed| {
+                let Ok(peer_id) = seed.peer_id.parse::<PeerId>() else {
+                    debug!("Skipping exported peer with invalid id {}", seed.peer_id);
+                    return None;
+                };
+                let addrs: Vec<Multiaddr> = seed
+                    .addrs
+                    .iter()
+                    .filter_map(|addr| addr.parse().ok())
+                    .collect();
+                (!addrs.is_empty()).then_some((peer_id, addrs))
+            })
+            .collect()
+    }
+}
+
+// The current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
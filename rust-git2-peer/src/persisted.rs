@@ -0,0 +1,329 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs, io,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// The magic that starts the header line of every persisted file
+pub const PERSISTED_MAGIC: &str = "#universal-connectivity";
+
+/// The format of a file holding state that is persisted across restarts.
+///
+/// The file starts with a header line of [`PERSISTED_MAGIC`], the kind of the file, its format
+/// version and the SHA-256 checksum of the payload, followed by the JSON encoded payload:
+///
+/// ```text
+/// #universal-connectivity reputation 1 sha256:<hex>
+/// { ... }
+/// ```
+///
+/// Loading a file of another kind, or of a version other than the current one, fails with an
+/// error explaining what to do instead of misreading it. A file whose checksum doesn't match, or
+/// that doesn't parse, fails with a [`CorruptFile`] error. Files written before the header was
+/// introduced have none and are read as version 1, files written before the checksum have a
+/// header without one.
+#[derive(Debug)]
+pub struct PersistedFile<T> {
+    kind: &'static str,
+    version: u32,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> PersistedFile<T> {
+    /// The format of the files of `kind` at format `version`
+    pub const fn new(kind: &'static str, version: u32) -> Self {
+        Self {
+            kind,
+            version,
+            _payload: PhantomData,
+        }
+    }
+
+    /// Encode a payload with its header
+    pub fn encode(&self, payload: &T) -> anyhow::Result<Vec<u8>> {
+        let payload = serde_json::to_vec_pretty(payload)?;
+        let mut out = format!(
+            "{PERSISTED_MAGIC} {} {} {}\n",
+            self.kind,
+            self.version,
+            checksum(&payload)
+        )
+        .into_bytes();
+        out.extend(payload);
+        Ok(out)
+    }
+
+    /// Decode a payload, checking its header
+    pub fn decode(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        let Some(rest) = bytes.strip_prefix(PERSISTED_MAGIC.as_bytes()) else {
+            // written before files had a header
+            if self.version != 1 {
+                anyhow::bail!(
+                    "Unversioned {} file is in the version 1 format, this build only reads version {}: {}",
+                    self.kind,
+                    self.version,
+                    self.migration_hint()
+                );
+            }
+            return parse(bytes);
+        };
+        let (header, payload) = match rest.iter().position(|b| *b == b'\n') {
+            Some(end) => (&rest[..end], &rest[end + 1..]),
+            None => (rest, &[][..]),
+        };
+        let header = std::str::from_utf8(header)
+            .map_err(|_| CorruptFile("Invalid persisted file header".to_string()))?;
+        let mut fields = header.split_whitespace();
+        let (Some(kind), Some(version), sum, None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(CorruptFile(format!("Invalid persisted file header {header:?}")).into());
+        };
+        let version: u32 = version
+            .parse()
+            .map_err(|_| CorruptFile(format!("Invalid persisted file version {version:?}")))?;
+
+        if kind != self.kind {
+            anyhow::bail!("This is a {kind} file, not a {} file", self.kind);
+        }
+        if version > self.version {
+            anyhow::bail!(
+                "This {kind} file is in the version {version} format of a newer build, this build only reads version {}: upgrade, or {}",
+                self.version,
+                self.migration_hint()
+            );
+        }
+        if version < self.version {
+            anyhow::bail!(
+                "This {kind} file is in the old version {version} format, this build only reads version {}: {}",
+                self.version,
+                self.migration_hint()
+            );
+        }
+        // files written before the checksum have none
+        if let Some(sum) = sum {
+            if sum != checksum(payload) {
+                return Err(CorruptFile(format!(
+                    "The checksum of this {kind} file doesn't match its contents"
+                ))
+                .into());
+            }
+        }
+        parse(payload)
+    }
+
+    /// Read a payload from a file
+    pub fn load(&self, path: &Path) -> anyhow::Result<T> {
+        let bytes = fs::read(path)?;
+        self.decode(&bytes)
+            .with_context(|| format!("Failed to load {}", path.display()))
+    }
+
+    /// Read a payload from a file, or the default payload if the file doesn't exist. A corrupt
+    /// file is moved aside, to the same path with `.corrupt` appended, and the default payload is
+    /// returned after warning about it, so a damaged file never keeps the node from starting.
+    pub fn load_or_default(&self, path: &Path) -> anyhow::Result<T>
+    where
+        T: Default,
+    {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+            Err(e) => return Err(e.into()),
+        };
+        match self.decode(&bytes) {
+            Ok(payload) => Ok(payload),
+            Err(e) if e.is::<CorruptFile>() => {
+                let aside = corrupt_path(path);
+                fs::rename(path, &aside).with_context(|| {
+                    format!("Failed to move the corrupt {} aside", path.display())
+                })?;
+                warn!(
+                    "{} is corrupt, moved it to {} and starting with an empty {}: {e}",
+                    path.display(),
+                    aside.display(),
+                    self.kind
+                );
+                Ok(T::default())
+            }
+            Err(e) => Err(e.context(format!("Failed to load {}", path.display()))),
+        }
+    }
+
+    /// Write a payload to a file. It is written to a temporary file first and then renamed over
+    /// the file, so a crash never leaves a partially written file behind.
+    pub fn save(&self, path: &Path, payload: &T) -> anyhow::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, self.encode(payload)?)?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+
+    fn migration_hint(&self) -> String {
+        format!(
+            "move the file aside to start with an empty {}, it isn't modified",
+            self.kind
+        )
+    }
+}
+
+/// The error of a persisted file that is damaged, like a truncated file or one whose checksum
+/// doesn't match its contents
+#[derive(Debug)]
+pub struct CorruptFile(String);
+
+impl fmt::Display for CorruptFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CorruptFile {}
+
+// The checksum of a payload as it appears in the header
+fn checksum(payload: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(payload)))
+}
+
+// Parse a JSON payload, a payload that doesn't parse being corrupt
+fn parse<T: DeserializeOwned>(payload: &[u8]) -> anyhow::Result<T> {
+    serde_json::from_slice(payload)
+        .map_err(|e| CorruptFile(format!("Invalid persisted file contents: {e}")).into())
+}
+
+// The path a corrupt file is moved aside to
+fn corrupt_path(path: &Path) -> PathBuf {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(".corrupt");
+    PathBuf::from(aside)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    const V1: PersistedFile<Vec<u32>> = PersistedFile::new("numbers", 1);
+    const V2: PersistedFile<Vec<u32>> = PersistedFile::new("numbers", 2);
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let bytes = V2.encode(&vec![1, 2, 3]).unwrap();
+        assert!(bytes.starts_with(b"#universal-connectivity numbers 2 sha256:"));
+        assert_eq!(V2.decode(&bytes).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_a_header_without_a_checksum() {
+        assert_eq!(
+            V2.decode(b"#universal-connectivity numbers 2\n[1, 2]")
+                .unwrap(),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn refuses_a_payload_that_does_not_match_its_checksum() {
+        // still valid JSON, but not what was written
+        let mut tampered = V2.encode(&vec![1, 2, 3]).unwrap();
+        let digit = tampered.iter().rposition(|b| *b == b'3').unwrap();
+        tampered[digit] = b'4';
+        let error = V2.decode(&tampered).unwrap_err();
+        assert!(error.is::<CorruptFile>(), "{error}");
+        assert!(error.to_string().contains("checksum"), "{error}");
+    }
+
+    #[test]
+    fn a_truncated_file_is_moved_aside_and_loads_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("numbers.json");
+        V2.save(&path, &vec![1, 2, 3]).unwrap();
+        assert_eq!(V2.load_or_default(&path).unwrap(), [1, 2, 3]);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(V2.load_or_default(&path).unwrap().is_empty());
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read(dir.path().join("numbers.json.corrupt")).unwrap(),
+            &bytes[..bytes.len() - 4]
+        );
+
+        // the file is gone now, so the next load starts empty without another warning
+        assert!(V2.load_or_default(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_file_of_another_version_is_not_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("numbers.json");
+        V1.save(&path, &vec![1]).unwrap();
+        assert!(V2.load_or_default(&path).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn refuses_a_future_version() {
+        let bytes = V2.encode(&vec![1]).unwrap();
+        let error = V1.decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("newer build"), "{error}");
+    }
+
+    #[test]
+    fn refuses_an_older_version() {
+        let bytes = V1.encode(&vec![1]).unwrap();
+        let error = V2.decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("old version 1"), "{error}");
+    }
+
+    #[test]
+    fn refuses_another_kind() {
+        let other: PersistedFile<Vec<u32>> = PersistedFile::new("letters", 2);
+        let bytes = other.encode(&vec![1]).unwrap();
+        let error = V2.decode(&bytes).unwrap_err().to_string();
+        assert!(error.contains("not a numbers file"), "{error}");
+    }
+
+    #[test]
+    fn reads_a_legacy_file_as_version_1() {
+        assert_eq!(V1.decode(b"[1, 2]").unwrap(), [1, 2]);
+        assert!(V2.decode(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn refuses_a_truncated_header() {
+        for bytes in [
+            &b"#universal-connectivity"[..],
+            b"#universal-connectivity\n[1]",
+            b"#universal-connectivity numbers\n[1]",
+            b"#universal-connectivity numbers 2",
+            b"#universal-connectivity numbers two\n[1]",
+            b"#universal-connectivity numbers 2 extra\n[1]",
+        ] {
+            assert!(
+                V2.decode(bytes).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_every_truncation_without_panicking() {
+        let bytes = V2.encode(&vec![1, 2, 3]).unwrap();
+        for end in 0..bytes.len() {
+            let result = panic::catch_unwind(|| V2.decode(&bytes[..end]));
+            assert!(
+                result.is_ok_and(|decoded| decoded.is_err()),
+                "{:?}",
+                String::from_utf8_lossy(&bytes[..end])
+            );
+        }
+    }
+}
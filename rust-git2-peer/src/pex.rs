@@ -0,0 +1,161 @@
+use crate::file_exchange::{read_length_prefixed, write_length_prefixed};
+use async_trait::async_trait;
+use futures::{io, AsyncRead, AsyncWrite};
+use libp2p::{request_response, Multiaddr, PeerId, StreamProtocol};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+// Peer exchange protocol, for jump-starting the connectivity of a new node. A newly connected peer
+// asks for a sample of the peers the responder knows about and dials some of them instead of
+// waiting for Kademlia to find them.
+//
+// The addresses come unverified from a single peer, which may lie about them. They are only ever
+// used to dial, the dialed peer proves its identity in the handshake, and they are never added to
+// the routing table or used for anything else.
+//
+// Request and Response:
+//  varuint - JSON length
+//  bytes - JSON encoded PexRequest or PexResponse
+//
+
+/// The most peers a response may contain, larger requests are capped.
+pub const MAX_PEX_PEERS: usize = 64;
+
+/// The most addresses of each peer a response may contain, the rest are dropped.
+pub const MAX_PEX_ADDRS: usize = 8;
+
+// A full sample is small, this only guards against a misbehaving peer
+const MAX_PEX_SIZE: usize = 1_000_000;
+
+/// The codec for the peer exchange protocol.
+#[derive(Default, Clone)]
+pub struct PexCodec;
+
+/// Requests a sample of the peers the responder knows about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexRequest {
+    /// The most peers to send, capped at [`MAX_PEX_PEERS`].
+    pub max_peers: u32,
+}
+
+/// A peer and its addresses, as sent in a [`PexResponse`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexPeer {
+    /// The base58 peer id.
+    pub peer_id: String,
+    /// The addresses of the peer.
+    pub addrs: Vec<String>,
+}
+
+/// A random sample of the peers the responder knows about.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexResponse {
+    /// The sampled peers.
+    pub peers: Vec<PexPeer>,
+}
+
+impl PexResponse {
+    /// Answer a request with a random sample of `known` peers, leaving out `requester` itself
+    pub fn sample(
+        request: &PexRequest,
+        requester: &PeerId,
+        mut known: Vec<(PeerId, Vec<Multiaddr>)>,
+    ) -> Self {
+        let max = (request.max_peers as usize).min(MAX_PEX_PEERS);
+        known.retain(|(peer, addrs)| peer != requester && !addrs.is_empty());
+        let peers = known
+            .choose_multiple(&mut rand::thread_rng(), max)
+            .map(|(peer, addrs)| PexPeer {
+                peer_id: peer.to_base58(),
+                addrs: addrs
+                    .iter()
+                    .take(MAX_PEX_ADDRS)
+                    .map(Multiaddr::to_string)
+                    .collect(),
+            })
+            .collect();
+        Self { peers }
+    }
+
+    /// The peers with their addresses, skipping invalid peer ids and addresses, addresses beyond
+    /// [`MAX_PEX_ADDRS`] and peers beyond [`MAX_PEX_PEERS`]
+    pub fn entries(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.peers
+            .iter()
+            .take(MAX_PEX_PEERS)
+            .filter_map(|peer| {
+                let Ok(peer_id) = peer.peer_id.parse::<PeerId>() else {
+                    debug!("Skipping exchanged peer with invalid id {}", peer.peer_id);
+                    return None;
+                };
+                let addrs: Vec<Multiaddr> = peer
+                    .addrs
+                    .iter()
+                    .take(MAX_PEX_ADDRS)
+                    .filter_map(|addr| addr.parse().ok())
+                    .collect();
+                (!addrs.is_empty()).then_some((peer_id, addrs))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for PexCodec {
+    type Protocol = StreamProtocol;
+    type Request = PexRequest;
+    type Response = PexResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, MAX_PEX_SIZE).await?;
+        serde_json::from_slice(&vec).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let vec = read_length_prefixed(io, MAX_PEX_SIZE).await?;
+        serde_json::from_slice(&vec).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: PexRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let vec = serde_json::to_vec(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_length_prefixed(io, vec).await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: PexResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let vec = serde_json::to_vec(&response)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_length_prefixed(io, vec).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use libp2p::StreamProtocol;
+
+/// The family of the file exchange protocol, covering all its versions
+pub const FILE_EXCHANGE: &str = "file";
+/// The family of the git exchange protocol, covering all its versions
+pub const GIT_EXCHANGE: &str = "git";
+/// The family of the echo protocol
+pub const ECHO: &str = "echo";
+/// The family of the file manifest protocol
+pub const FILE_MANIFEST: &str = "file-manifest";
+/// The family of the peer exchange protocol
+pub const PEX: &str = "pex";
+
+/// The protocol ids and gossipsub topics of a deployment.
+///
+/// Without a prefix they are the ones of the public universal connectivity network, so the peer
+/// interoperates with the JS and Go peers and bootstraps from the IPFS DHT. A prefix, such as
+/// `acme`, isolates a private deployment at the protocol level: the request-response protocols
+/// become `/acme/universal-connectivity-file/3` and so on, Kademlia `/acme/ipfs/kad/1.0.0` and the
+/// topics `acme/universal-connectivity`. Such a peer only talks to peers with the same prefix, it
+/// can't use the public bootstrap nodes and browsers need the same prefix in their code. Identify
+/// is left as is, libp2p needs it to learn the protocols of a peer in the first place.
+#[derive(Clone, Debug)]
+pub struct ProtocolNames {
+    /// The prefix, if this is a private deployment
+    pub prefix: Option<String>,
+    /// The Kademlia protocol
+    pub kademlia: StreamProtocol,
+    /// The versions of the file exchange protocol, newest first. Outbound requests propose them in
+    /// order, so the newest version both peers support is negotiated, and the codec handles each
+    /// stream according to its negotiated version.
+    pub file_exchange: [StreamProtocol; 3],
+    /// The versions of the git exchange protocol, newest first
+    pub git_exchange: [StreamProtocol; 1],
+    /// The echo diagnostics protocol
+    pub echo: StreamProtocol,
+    /// The file manifest protocol
+    pub file_manifest: StreamProtocol,
+    /// The peer exchange protocol
+    pub pex: StreamProtocol,
+    /// The chat topic
+    pub chat_topic: String,
+    /// The topic files are offered on
+    pub file_topic: String,
+    /// The topic peers announce themselves on
+    pub peer_discovery_topic: String,
+    /// The topic file providers are advertised on
+    pub file_providers_topic: String,
+}
+
+impl ProtocolNames {
+    /// The protocol ids and topics with a prefix, or the public ones without. The prefix must be
+    /// a non-empty path segment, like `acme` or `acme/staging`, without whitespace.
+    pub fn new(prefix: Option<&str>) -> anyhow::Result<Self> {
+        let prefix = match prefix.map(|prefix| prefix.trim_matches('/')) {
+            None => None,
+            Some("") => anyhow::bail!("The protocol prefix can't be empty"),
+            Some(prefix) if prefix.contains(char::is_whitespace) => {
+                anyhow::bail!("The protocol prefix {prefix} can't contain whitespace")
+            }
+            Some(prefix) => Some(prefix),
+        };
+
+        let protocol = |name: &str| match prefix {
+            Some(prefix) => StreamProtocol::try_from_owned(format!("/{prefix}{name}"))
+                .map_err(|e| anyhow::anyhow!("Invalid protocol prefix {prefix}: {e}")),
+            None => StreamProtocol::try_from_owned(name.to_string())
+                .map_err(|e| anyhow::anyhow!("Invalid protocol {name}: {e}")),
+        };
+        let topic = |name: &str| match prefix {
+            Some(prefix) => format!("{prefix}/{name}"),
+            None => name.to_string(),
+        };
+
+        Ok(Self {
+            prefix: prefix.map(str::to_string),
+            kademlia: protocol("/ipfs/kad/1.0.0")?,
+            file_exchange: [
+                protocol("/universal-connectivity-file/3")?,
+                protocol("/universal-connectivity-file/2")?,
+                protocol("/universal-connectivity-file/1")?,
+            ],
+            git_exchange: [protocol("/universal-connectivity-git/1")?],
+            echo: protocol("/universal-connectivity-echo/1")?,
+            file_manifest: protocol("/universal-connectivity-file-manifest/1")?,
+            pex: protocol("/universal-connectivity-pex/1")?,
+            chat_topic: topic("universal-connectivity"),
+            file_topic: topic("universal-connectivity-file"),
+            peer_discovery_topic: topic("universal-connectivity-browser-peer-discovery"),
+            file_providers_topic: topic("universal-connectivity-file-providers"),
+        })
+    }
+}
+
+impl ProtocolNames {
+    /// The versions of each request-response protocol family, newest first
+    pub fn families(&self) -> [(&'static str, &[StreamProtocol]); 5] {
+        [
+            (FILE_EXCHANGE, &self.file_exchange),
+            (GIT_EXCHANGE, &self.git_exchange),
+            (ECHO, std::slice::from_ref(&self.echo)),
+            (FILE_MANIFEST, std::slice::from_ref(&self.file_manifest)),
+            (PEX, std::slice::from_ref(&self.pex)),
+        ]
+    }
+
+    /// The family of a request-response protocol, the same for all its versions, if it is one of
+    /// ours
+    pub fn family(&self, protocol: &StreamProtocol) -> Option<&'static str> {
+        self.families()
+            .into_iter()
+            .find(|(_, versions)| versions.contains(protocol))
+            .map(|(family, _)| family)
+    }
+}
+
+impl Default for ProtocolNames {
+    fn default() -> Self {
+        Self::new(None).expect("the public protocol ids are valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_version_of_a_protocol_is_in_its_family() {
+        let protocols = ProtocolNames::new(Some("acme")).unwrap();
+        for file_exchange in protocols.file_exchange.iter() {
+            assert_eq!(protocols.family(file_exchange), Some(FILE_EXCHANGE));
+        }
+        for git_exchange in protocols.git_exchange.iter() {
+            assert_eq!(protocols.family(git_exchange), Some(GIT_EXCHANGE));
+        }
+        assert_eq!(protocols.family(&protocols.echo), Some(ECHO));
+        assert_eq!(
+            protocols.family(&protocols.file_manifest),
+            Some(FILE_MANIFEST)
+        );
+        assert_eq!(protocols.family(&protocols.pex), Some(PEX));
+    }
+
+    #[test]
+    fn foreign_protocols_have_no_family() {
+        let protocols = ProtocolNames::new(Some("acme")).unwrap();
+        let public = ProtocolNames::default();
+        assert_eq!(protocols.family(&public.file_exchange[0]), None);
+        assert_eq!(protocols.family(&protocols.kademlia), None);
+        assert_eq!(
+            protocols.family(&StreamProtocol::new("/ipfs/id/1.0.0")),
+            None
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// An advertisement that a peer provides a file, published as JSON on the file provider topic.
+/// Unlike a file offer on the file topic, receiving one doesn't fetch the file, it only records
+/// the provider in the [`ProviderIndex`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProviderAdvertisement {
+    /// The id of the file
+    pub file_id: String,
+    /// The base58 peer id of the provider, which must be the signer of the message
+    pub provider: String,
+    /// The namespace of the directory the provider serves the file from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// The most files the index holds providers of
+pub const MAX_INDEXED_FILES: usize = 10_000;
+
+/// The most providers the index holds for a file
+pub const MAX_PROVIDERS_PER_FILE: usize = 20;
+
+/// How long an advertisement is taken into account, providers that didn't advertise a file again
+/// within it are forgotten
+pub const PROVIDER_ADVERTISEMENT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The providers of files learned from advertisements, with when each was last advertised. The
+/// advertisements come from any peer on the topic, so the index is bounded: once full, the file
+/// or provider advertised least recently makes room for a new one.
+#[derive(Debug, Default)]
+pub struct ProviderIndex {
+    files: HashMap<String, HashMap<PeerId, Instant>>,
+}
+
+impl ProviderIndex {
+    /// Record that `provider` advertised `file_id` at `now`
+    pub fn insert(&mut self, file_id: String, provider: PeerId, now: Instant) {
+        if !self.files.contains_key(&file_id) && self.files.len() >= MAX_INDEXED_FILES {
+            self.expire(now);
+            if self.files.len() >= MAX_INDEXED_FILES {
+                // the file whose latest advertisement is the oldest
+                let oldest = self
+                    .files
+                    .iter()
+                    .min_by_key(|(_, providers)| providers.values().max().copied())
+                    .map(|(file_id, _)| file_id.clone());
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
+                }
+            }
+        }
+        let providers = self.files.entry(file_id).or_default();
+        if !providers.contains_key(&provider) && providers.len() >= MAX_PROVIDERS_PER_FILE {
+            providers.retain(|_, seen| {
+                now.saturating_duration_since(*seen) < PROVIDER_ADVERTISEMENT_TTL
+            });
+            if providers.len() >= MAX_PROVIDERS_PER_FILE {
+                let oldest = providers
+                    .iter()
+                    .min_by_key(|(_, seen)| **seen)
+                    .map(|(peer, _)| *peer);
+                if let Some(oldest) = oldest {
+                    providers.remove(&oldest);
+                }
+            }
+        }
+        providers.insert(provider, now);
+    }
+
+    /// Forget the advertisements older than [`PROVIDER_ADVERTISEMENT_TTL`] at `now`
+    pub fn expire(&mut self, now: Instant) {
+        self.files.retain(|_, providers| {
+            providers.retain(|_, seen| {
+                now.saturating_duration_since(*seen) < PROVIDER_ADVERTISEMENT_TTL
+            });
+            !providers.is_empty()
+        });
+    }
+
+    /// The known providers of a file and how long ago each advertised it, most recent first,
+    /// leaving out the expired advertisements
+    pub fn providers(&self, file_id: &str, now: Instant) -> Vec<(PeerId, Duration)> {
+        let mut providers: Vec<(PeerId, Duration)> = self
+            .files
+            .get(file_id)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .map(|(peer, seen)| (*peer, now.saturating_duration_since(*seen)))
+                    .filter(|(_, age)| *age < PROVIDER_ADVERTISEMENT_TTL)
+                    .collect()
+            })
+            .unwrap_or_default();
+        providers.sort_by_key(|(_, age)| *age);
+        providers
+    }
+
+    /// Forget everything a peer advertised, e.g. when it leaves
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.files.retain(|_, providers| {
+            providers.remove(peer);
+            !providers.is_empty()
+        });
+    }
+
+    /// The number of files with known providers
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Check if no providers are known
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn peer() -> PeerId {
+        Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    #[test]
+    fn advertisements_expire() {
+        let mut index = ProviderIndex::default();
+        let now = Instant::now();
+        let (old, new) = (peer(), peer());
+        index.insert("file".to_string(), old, now);
+        let later = now + PROVIDER_ADVERTISEMENT_TTL;
+        index.insert("file".to_string(), new, later);
+
+        let providers = index.providers("file", later);
+        assert_eq!(providers, [(new, Duration::ZERO)]);
+        index.expire(later + PROVIDER_ADVERTISEMENT_TTL);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn the_least_recent_provider_makes_room() {
+        let mut index = ProviderIndex::default();
+        let now = Instant::now();
+        let providers: Vec<PeerId> = (0..=MAX_PROVIDERS_PER_FILE).map(|_| peer()).collect();
+        for (i, provider) in providers.iter().enumerate() {
+            index.insert(
+                "file".to_string(),
+                *provider,
+                now + Duration::from_secs(i as u64),
+            );
+        }
+
+        let known = index.providers("file", now + Duration::from_secs(60));
+        assert_eq!(known.len(), MAX_PROVIDERS_PER_FILE);
+        assert!(!known.iter().any(|(peer, _)| *peer == providers[0]));
+        assert_eq!(known[0].0, providers[MAX_PROVIDERS_PER_FILE]);
+    }
+
+    #[test]
+    fn the_least_recent_file_makes_room() {
+        let mut index = ProviderIndex::default();
+        let now = Instant::now();
+        let provider = peer();
+        for i in 0..=MAX_INDEXED_FILES {
+            index.insert(
+                format!("file{i}"),
+                provider,
+                now + Duration::from_millis(i as u64),
+            );
+        }
+
+        assert_eq!(index.len(), MAX_INDEXED_FILES);
+        assert!(index.providers("file0", now).is_empty());
+        assert_eq!(
+            index
+                .providers("file1", now + Duration::from_millis(1))
+                .len(),
+            1
+        );
+    }
+}
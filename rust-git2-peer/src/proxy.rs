@@ -0,0 +1,363 @@
+//! Dialing TCP connections through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! Only the TCP transport can be proxied: QUIC and WebRTC run over UDP, which neither an HTTP
+//! CONNECT proxy nor a SOCKS5 proxy without UDP ASSOCIATE can carry, so those transports keep
+//! dialing directly. Listening is never proxied. When a proxy is configured the TCP connections
+//! are secured with Noise only, TLS is not offered on them.
+//!
+//! Host names are never resolved locally when a proxy is configured: the host name of a `/dns`
+//! TCP address is handed to the proxy, and the peer leaves out the DNS transport, so `/dns`
+//! addresses of the other transports can't be dialed.
+
+use base64::Engine;
+use futures::{future::BoxFuture, FutureExt};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, DialOpts, ListenerId, TransportError, TransportEvent},
+        upgrade,
+    },
+    identity::Keypair,
+    multiaddr::{Multiaddr, Protocol},
+    noise, tcp,
+    tcp::tokio::TcpStream,
+    yamux, PeerId, Transport,
+};
+use std::{
+    error::Error,
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// The kind of proxy server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// A SOCKS5 proxy, `socks5://` or `socks5h://`. Both hand host names to the proxy to resolve,
+    /// the way `socks5h` does elsewhere.
+    Socks5,
+    /// An HTTP proxy supporting the CONNECT method, `http://`
+    Http,
+}
+
+/// A proxy server to dial through, parsed from a url of the form
+/// `socks5://[user:password@]host:port` or `http://[user:password@]host:port`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proxy {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Proxy {
+    // keep the password out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("kind", &self.kind)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow::anyhow!("Proxy url {s} has no scheme"))?;
+        let (kind, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "http" => (ProxyKind::Http, 8080),
+            _ => anyhow::bail!("Unsupported proxy scheme {scheme}, expected socks5 or http"),
+        };
+
+        let authority = rest.trim_end_matches('/');
+        let (credentials, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (
+                    Some((percent_decode(user)?, percent_decode(password)?)),
+                    host_port,
+                )
+            }
+            None => (None, authority),
+        };
+
+        let (host, port) = match host_port.rsplit_once(':') {
+            // a bracketed IPv6 address without a port also contains colons
+            Some((host, port)) if !port.ends_with(']') => (host, port.parse()?),
+            _ => (host_port, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            anyhow::bail!("Proxy url {s} has no host");
+        }
+
+        Ok(Self {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+// Decode the %XX escapes in a url component
+fn percent_decode(s: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [
+                iter.next().unwrap_or_default(),
+                iter.next().unwrap_or_default(),
+            ];
+            let hex = std::str::from_utf8(&hex)?;
+            bytes.push(u8::from_str_radix(hex, 16)?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+impl Proxy {
+    /// Connect to `host`:`port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<tokio::net::TcpStream> {
+        let mut stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream.set_nodelay(true)?;
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_handshake(&mut stream, host, port).await?,
+            ProxyKind::Http => self.http_handshake(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    // Ask a SOCKS5 proxy to connect to the target, RFC 1928 and RFC 1929
+    async fn socks5_handshake(
+        &self,
+        stream: &mut tokio::net::TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        const NO_AUTH: u8 = 0x00;
+        const USER_PASS: u8 = 0x02;
+
+        let method = if self.credentials.is_some() {
+            USER_PASS
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[0x05, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(proxy_error(
+                "SOCKS5 proxy refused the authentication method",
+            ));
+        }
+
+        if let Some((user, password)) = &self.credentials {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("SOCKS5 credentials are too long"));
+            }
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(proxy_error("SOCKS5 proxy rejected the credentials"));
+            }
+        }
+
+        // CONNECT
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(std::net::IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(proxy_error("Host name is too long for SOCKS5"));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0x00 {
+            return Err(proxy_error(&format!(
+                "SOCKS5 proxy failed to connect, reply code {}",
+                header[1]
+            )));
+        }
+        // skip the bound address and port
+        let addr_len = match header[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            _ => return Err(proxy_error("SOCKS5 proxy sent an unknown address type")),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    // Ask an HTTP proxy to open a tunnel to the target with the CONNECT method
+    async fn http_handshake(
+        &self,
+        stream: &mut tokio::net::TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let authority = if host.contains(':') {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        };
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // read the response headers a line at a time so nothing after them is consumed
+        let mut reader = BufReader::with_capacity(1, stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).await?;
+        let code = status.split_whitespace().nth(1).unwrap_or_default();
+        if !code.starts_with('2') {
+            return Err(proxy_error(&format!(
+                "HTTP proxy refused to connect: {}",
+                status.trim_end()
+            )));
+        }
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if line == "\r\n" || line == "\n" {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn proxy_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg.to_string())
+}
+
+// Get the host and port of a plain TCP address, one with nothing layered on top of the TCP port
+fn tcp_target(addr: &Multiaddr) -> Option<(String, u16)> {
+    let mut iter = addr.iter();
+    let host = match iter.next()? {
+        Protocol::Ip4(ip) => ip.to_string(),
+        Protocol::Ip6(ip) => ip.to_string(),
+        Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => host.to_string(),
+        _ => return None,
+    };
+    let port = match iter.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    match iter.next() {
+        None | Some(Protocol::P2p(_)) => Some((host, port)),
+        _ => None,
+    }
+}
+
+/// Build a TCP transport that dials through `proxy`, secured with Noise and multiplexed with Yamux.
+/// Connections that aren't established and upgraded within `timeout` are aborted. The error is
+/// boxed the way the swarm builder's `with_other_transport` expects it.
+pub fn tcp_transport(
+    keypair: &Keypair,
+    proxy: Proxy,
+    timeout: Duration,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::new().nodelay(true));
+    Ok(ProxyTransport::new(tcp, proxy)
+        .upgrade(upgrade::Version::V1Lazy)
+        .authenticate(noise::Config::new(keypair)?)
+        .multiplex(yamux::Config::default())
+        .timeout(timeout)
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed())
+}
+
+/// A TCP transport that dials through a proxy and listens directly
+pub struct ProxyTransport<T> {
+    inner: T,
+    proxy: Proxy,
+}
+
+impl<T> ProxyTransport<T> {
+    /// Wrap a TCP transport, used for listening, so that dials go through `proxy`
+    pub fn new(inner: T, proxy: Proxy) -> Self {
+        Self { inner, proxy }
+    }
+}
+
+impl<T> Transport for ProxyTransport<T>
+where
+    T: Transport<Output = TcpStream, Error = io::Error> + Unpin,
+    T::Dial: Send + 'static,
+{
+    type Output = TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = BoxFuture<'static, io::Result<TcpStream>>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        _opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some((host, port)) = tcp_target(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        let proxy = self.proxy.clone();
+        Ok(async move { proxy.connect(&host, port).await.map(TcpStream) }.boxed())
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
@@ -0,0 +1,379 @@
+use libp2p::{
+    core::{transport::PortUse, Endpoint},
+    multiaddr::{Multiaddr, Protocol},
+    relay::{self, RateLimiter},
+    swarm::{
+        behaviour::ConnectionEstablished, CloseConnection, ConnectionClosed, ConnectionDenied,
+        ConnectionId, FromSwarm, NetworkBehaviour, NotifyHandler, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+use tracing::warn;
+
+/// Refuses relay circuits that would chain relays into loops.
+///
+/// A node that is both a relay server and a relay client can end up relaying traffic back to the
+/// relay it is itself reachable through: A holds a reservation on B, B opens a circuit through A,
+/// and the traffic bounces between them. Circuits are refused when the source is one of the relays
+/// this node holds a reservation on, or when the source is itself connected through a relay, which
+/// is how longer cycles are built.
+///
+/// It plugs into the relay server as a circuit source rate limiter, so a refused circuit shows up
+/// as a denied circuit request.
+#[derive(Clone, Debug, Default)]
+pub struct RelayLoopGuard {
+    own_relays: Arc<Mutex<HashSet<PeerId>>>,
+}
+
+impl RelayLoopGuard {
+    /// Record that this node holds a reservation on `relay`
+    pub fn add_relay(&self, relay: PeerId) {
+        self.own_relays.lock().unwrap().insert(relay);
+    }
+
+    /// Record that this node no longer holds a reservation on `relay`
+    pub fn remove_relay(&self, relay: &PeerId) {
+        self.own_relays.lock().unwrap().remove(relay);
+    }
+
+    /// Check if a circuit from `src`, connected from `src_addr`, can be relayed without creating a
+    /// loop
+    pub fn allows(&self, src: &PeerId, src_addr: &Multiaddr) -> bool {
+        if self.own_relays.lock().unwrap().contains(src) {
+            warn!("Refusing relay circuit from {src}: it is one of our own relays");
+            return false;
+        }
+        if src_addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            warn!(
+                "Refusing relay circuit from {src}: it is connected through a relay ({src_addr})"
+            );
+            return false;
+        }
+        true
+    }
+}
+
+impl RateLimiter for RelayLoopGuard {
+    fn try_next(&mut self, peer: PeerId, addr: &Multiaddr, _now: Instant) -> bool {
+        self.allows(&peer, addr)
+    }
+}
+
+/// Caps the relay circuits a peer takes part in, separately as the source and as the destination,
+/// so one peer can't use up the relay by opening circuits to many destinations.
+///
+/// The relay server only tells circuit source rate limiters the source of a circuit, so the source
+/// limit is applied as one, while the destination limit is applied by [`LimitedRelayServer`].
+/// Circuits are counted from the relay server events.
+#[derive(Clone, Debug)]
+pub struct RelayCircuitLimits {
+    max_per_source: usize,
+    max_per_destination: usize,
+    circuits: Arc<Mutex<CircuitCounts>>,
+}
+
+#[derive(Debug, Default)]
+struct CircuitCounts {
+    by_source: HashMap<PeerId, usize>,
+    by_destination: HashMap<PeerId, usize>,
+}
+
+impl RelayCircuitLimits {
+    /// Create limits of `max_per_source` circuits from a peer and `max_per_destination` circuits
+    /// to a peer
+    pub fn new(max_per_source: usize, max_per_destination: usize) -> Self {
+        Self {
+            max_per_source,
+            max_per_destination,
+            circuits: Arc::default(),
+        }
+    }
+
+    /// Check if `src` can open another circuit
+    pub fn allows_source(&self, src: &PeerId) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        let open = circuits.by_source.get(src).copied().unwrap_or_default();
+        if open >= self.max_per_source {
+            warn!(
+                "Refusing relay circuit from {src}: it is the source of {open} circuits, the limit is {}",
+                self.max_per_source
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Check if another circuit to `dst` can be opened
+    pub fn allows_destination(&self, dst: &PeerId) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        let open = circuits
+            .by_destination
+            .get(dst)
+            .copied()
+            .unwrap_or_default();
+        if open >= self.max_per_destination {
+            warn!(
+                "Refusing relay circuit to {dst}: it is the destination of {open} circuits, the limit is {}",
+                self.max_per_destination
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Record an accepted circuit. Returns false if it takes `dst` over the destination limit.
+    pub fn circuit_opened(&self, src: PeerId, dst: PeerId) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        *circuits.by_source.entry(src).or_default() += 1;
+        let open = circuits.by_destination.entry(dst).or_default();
+        *open += 1;
+        if *open > self.max_per_destination {
+            warn!(
+                "Relay circuit from {src} to {dst} is over the limit of {} circuits per destination",
+                self.max_per_destination
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Record a closed circuit
+    pub fn circuit_closed(&self, src: &PeerId, dst: &PeerId) {
+        let mut circuits = self.circuits.lock().unwrap();
+        decrement(&mut circuits.by_source, src);
+        decrement(&mut circuits.by_destination, dst);
+    }
+}
+
+impl RateLimiter for RelayCircuitLimits {
+    fn try_next(&mut self, peer: PeerId, _addr: &Multiaddr, _now: Instant) -> bool {
+        self.allows_source(&peer)
+    }
+}
+
+// Decrement the count of a peer, removing it when it reaches zero
+fn decrement(counts: &mut HashMap<PeerId, usize>, peer: &PeerId) {
+    if let Some(count) = counts.get_mut(peer) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(peer);
+        }
+    }
+}
+
+/// A relay server that admits circuits within [`RelayCircuitLimits`].
+///
+/// The relay server hides the destination of a circuit request from its rate limiters, so this
+/// wraps it and looks at what it does with each request instead: a request it doesn't deny is
+/// handed to a connection of the destination, which names both ends before the destination is
+/// contacted. A request to a destination at its limit is refused there by closing the connection
+/// of the source it came on, which drops the pending circuit from the relay server. The other
+/// connections of the source stay up. A circuit that still takes its destination over the limit,
+/// when several were requested at once, closes its connection once accepted.
+pub struct LimitedRelayServer {
+    inner: relay::Behaviour,
+    limits: RelayCircuitLimits,
+    // the peer of each established connection
+    connections: HashMap<ConnectionId, PeerId>,
+    actions: VecDeque<ToSwarm<relay::Event, THandlerInEvent<relay::Behaviour>>>,
+}
+
+impl LimitedRelayServer {
+    /// Create a relay server applying `limits`, which also has to be one of the circuit source
+    /// rate limiters of `config`
+    pub fn new(local_peer_id: PeerId, config: relay::Config, limits: RelayCircuitLimits) -> Self {
+        Self {
+            inner: relay::Behaviour::new(local_peer_id, config),
+            limits,
+            connections: HashMap::new(),
+            actions: VecDeque::new(),
+        }
+    }
+
+    // Take the actions the relay server queued while handling an event from `source`, the peer and
+    // connection the event came from if it came from a connection
+    fn take_actions(&mut self, source: Option<(PeerId, ConnectionId)>) {
+        // the relay server only hands out the actions it queued, it has nothing to wake
+        let mut cx = Context::from_waker(Waker::noop());
+        while let Poll::Ready(action) = self.inner.poll(&mut cx) {
+            self.admit(action, source);
+        }
+    }
+
+    // Apply the limits to an action of the relay server, and queue it unless it is refused
+    fn admit(
+        &mut self,
+        action: ToSwarm<relay::Event, THandlerInEvent<relay::Behaviour>>,
+        source: Option<(PeerId, ConnectionId)>,
+    ) {
+        match action {
+            // a circuit request from `peer` on `connection`, handed to the destination
+            ToSwarm::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(dst_connection),
+                event,
+            } if source.is_some_and(|(peer, connection)| {
+                peer == peer_id && connection != dst_connection
+            }) =>
+            {
+                let dst = self.connections.get(&dst_connection).copied();
+                if dst.is_some_and(|dst| !self.limits.allows_destination(&dst)) {
+                    let (_, connection) = source.expect("checked above");
+                    self.actions.push_back(ToSwarm::CloseConnection {
+                        peer_id,
+                        connection: CloseConnection::One(connection),
+                    });
+                    return;
+                }
+                self.actions.push_back(ToSwarm::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(dst_connection),
+                    event,
+                });
+            }
+            ToSwarm::GenerateEvent(relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            }) => {
+                if !self.limits.circuit_opened(src_peer_id, dst_peer_id) {
+                    if let Some((_, connection)) = source.filter(|(peer, _)| *peer == src_peer_id) {
+                        self.actions.push_back(ToSwarm::CloseConnection {
+                            peer_id: src_peer_id,
+                            connection: CloseConnection::One(connection),
+                        });
+                    }
+                }
+                self.actions
+                    .push_back(ToSwarm::GenerateEvent(relay::Event::CircuitReqAccepted {
+                        src_peer_id,
+                        dst_peer_id,
+                    }));
+            }
+            ToSwarm::GenerateEvent(relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            }) => {
+                self.limits.circuit_closed(&src_peer_id, &dst_peer_id);
+                self.actions
+                    .push_back(ToSwarm::GenerateEvent(relay::Event::CircuitClosed {
+                        src_peer_id,
+                        dst_peer_id,
+                        error,
+                    }));
+            }
+            action => self.actions.push_back(action),
+        }
+    }
+}
+
+impl NetworkBehaviour for LimitedRelayServer {
+    type ConnectionHandler = <relay::Behaviour as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = relay::Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+            port_use,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                self.connections.insert(connection_id, peer_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed { connection_id, .. }) => {
+                self.connections.remove(&connection_id);
+            }
+            _ => {}
+        }
+        self.inner.on_swarm_event(event);
+        self.take_actions(None);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event);
+        self.take_actions(Some((peer_id, connection_id)));
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Poll::Ready(action) = self.inner.poll(cx) {
+            self.admit(action, None);
+        }
+        match self.actions.pop_front() {
+            Some(action) => Poll::Ready(action),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::persisted::PersistedFile;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How much a single violation lowers a peer's score
+const VIOLATION_PENALTY: f64 = 1.0;
+/// The score at or below which a peer is no longer accepted
+const BANNED_SCORE: f64 = -10.0;
+/// The time it takes for a score to decay halfway back to zero
+const HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Scores that have decayed closer to zero than this are forgotten
+const MIN_SCORE: f64 = 0.1;
+/// The format of the reputation file
+const REPUTATION_FILE: PersistedFile<HashMap<String, Reputation>> =
+    PersistedFile::new("reputation", 1);
+
+/// The reputation of a single peer, as stored on disk
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reputation {
+    /// The score, negative for misbehaving peers, as of `updated`
+    pub score: f64,
+    /// The total number of violations recorded
+    pub violations: u64,
+    /// When the score was last updated, in seconds since the unix epoch
+    pub updated: u64,
+}
+
+impl Reputation {
+    /// The score decayed towards zero for the time elapsed since it was updated
+    fn decayed_score(&self, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.score * 0.5f64.powf(elapsed / HALF_LIFE.as_secs_f64())
+    }
+}
+
+/// The reputation of the peers this node has seen misbehave, optionally persisted to a file so it
+/// survives restarts.
+///
+/// The file is a [`PersistedFile`] holding a JSON object mapping each base58 peer id to its
+/// [`Reputation`]. Scores decay
+/// exponentially back towards zero with a half-life of a week, and peers whose score has decayed
+/// away are dropped from the file.
+#[derive(Debug, Default)]
+pub struct ReputationStore {
+    path: Option<PathBuf>,
+    peers: HashMap<String, Reputation>,
+}
+
+impl ReputationStore {
+    /// Load the store from `path`, starting empty if the file doesn't exist yet or is corrupt, see
+    /// [`PersistedFile::load_or_default`]
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let peers = REPUTATION_FILE.load_or_default(path)?;
+        let mut store = Self {
+            path: Some(path.to_path_buf()),
+            peers,
+        };
+        store.decay(now());
+        Ok(store)
+    }
+
+    /// Write the store to its file, if it has one
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        self.decay(now());
+        REPUTATION_FILE.save(&path, &self.peers)
+    }
+
+    /// Record a violation by the peer, returning its new score
+    pub fn violation(&mut self, peer: &PeerId) -> f64 {
+        let now = now();
+        let reputation = self.peers.entry(peer.to_base58()).or_insert(Reputation {
+            score: 0.0,
+            violations: 0,
+            updated: now,
+        });
+        reputation.score = reputation.decayed_score(now) - VIOLATION_PENALTY;
+        reputation.violations += 1;
+        reputation.updated = now;
+        reputation.score
+    }
+
+    /// The current score of the peer, zero if it hasn't misbehaved
+    pub fn score(&self, peer: &PeerId) -> f64 {
+        self.peers
+            .get(&peer.to_base58())
+            .map_or(0.0, |reputation| reputation.decayed_score(now()))
+    }
+
+    /// Check if the peer has misbehaved enough to be refused
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.score(peer) <= BANNED_SCORE
+    }
+
+    /// The number of peers with a reputation
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Check if the store is empty
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    // Apply the decay to every score and forget the ones that have decayed away
+    fn decay(&mut self, now: u64) {
+        self.peers.retain(|_, reputation| {
+            reputation.score = reputation.decayed_score(now);
+            reputation.updated = now;
+            reputation.score.abs() >= MIN_SCORE
+        });
+    }
+}
+
+// The current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,117 @@
+use futures::StreamExt;
+use libp2p::{
+    multiaddr::{Multiaddr, Protocol},
+    noise::Config as NoiseConfig,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        dummy, SwarmEvent,
+    },
+    tcp::Config as TcpConfig,
+    tls::Config as TlsConfig,
+    yamux::Config as YamuxConfig,
+    PeerId, SwarmBuilder,
+};
+use libp2p_webrtc as webrtc;
+use libp2p_webrtc::tokio::Certificate;
+use rand::rngs::OsRng;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// The outcome of dialing one of our own advertised addresses
+#[derive(Clone, Debug)]
+pub struct SelfTestResult {
+    /// The address that was dialed
+    pub address: Multiaddr,
+    /// How long the connection took to establish, or why it failed
+    pub result: Result<Duration, String>,
+}
+
+impl SelfTestResult {
+    /// Check if the address was reachable
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl fmt::Display for SelfTestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(elapsed) => write!(f, "PASS  {} ({}ms)", self.address, elapsed.as_millis()),
+            Err(e) => write!(f, "FAIL  {}: {e}", self.address),
+        }
+    }
+}
+
+/// Dial each of the addresses of `peer_id` from a separate, temporary swarm with its own identity
+/// so that the connections go through the OS network stack just like a remote peer's would.
+/// Relayed addresses are skipped since reaching them depends on the relay rather than this node.
+pub async fn run(
+    peer_id: PeerId,
+    addresses: Vec<Multiaddr>,
+    timeout: Duration,
+) -> anyhow::Result<Vec<SelfTestResult>> {
+    let cert = Certificate::generate(&mut OsRng)?;
+    let mut swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            TcpConfig::new().nodelay(true),
+            (TlsConfig::new, NoiseConfig::new),
+            YamuxConfig::default,
+        )?
+        .with_quic()
+        .with_other_transport(|id_keys| {
+            Ok(webrtc::tokio::Transport::new(id_keys.clone(), cert.clone()))
+        })?
+        .with_behaviour(|_| dummy::Behaviour)?
+        .build();
+
+    let mut results = Vec::new();
+    for address in addresses {
+        if address.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            continue;
+        }
+
+        let opts = DialOpts::peer_id(peer_id)
+            .addresses(vec![address.clone()])
+            .condition(PeerCondition::Always)
+            .build();
+        let connection_id = opts.connection_id();
+        let start = Instant::now();
+        if let Err(e) = swarm.dial(opts) {
+            results.push(SelfTestResult {
+                address,
+                result: Err(e.to_string()),
+            });
+            continue;
+        }
+
+        // wait for this dial to either connect or fail
+        let outcome = tokio::time::timeout(timeout, async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished {
+                        connection_id: id, ..
+                    } if id == connection_id => return Ok(start.elapsed()),
+                    SwarmEvent::OutgoingConnectionError {
+                        connection_id: id,
+                        error,
+                        ..
+                    } if id == connection_id => return Err(error.to_string()),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {timeout:?}")));
+
+        swarm.close_connection(connection_id);
+        results.push(SelfTestResult {
+            address,
+            result: outcome,
+        });
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,124 @@
+//! Addresses the local admin servers, such as the metrics server, listen on.
+//!
+//! Besides a TCP `host:port`, an address can be `unix:/path/to/socket` to serve on a Unix domain
+//! socket instead, which keeps the server off the network entirely. The socket is only accessible
+//! to the user running the peer.
+
+use std::{fmt, io, net::SocketAddr, path::PathBuf, str::FromStr};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// The address of a local server, parsed from `host:port` or `unix:/path/to/socket`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServeAddr {
+    /// A TCP address
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl FromStr for ServeAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(_) if !cfg!(unix) => {
+                anyhow::bail!("Unix socket address {s} is only supported on Unix platforms")
+            }
+            Some("") => anyhow::bail!("Unix socket address {s} has no path"),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => Ok(Self::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for ServeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection accepted by a [`ServeListener`]
+pub trait ServeStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ServeStream for T {}
+
+/// A listener bound to a [`ServeAddr`]
+#[derive(Debug)]
+pub enum ServeListener {
+    /// A TCP listener
+    Tcp(TcpListener),
+    /// A Unix domain socket listener
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl ServeAddr {
+    /// Bind a listener to the address. A stale socket file left behind by a previous run is
+    /// removed first, but a socket something still listens on is not.
+    pub async fn bind(&self) -> io::Result<ServeListener> {
+        match self {
+            Self::Tcp(addr) => Ok(ServeListener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            Self::Unix(path) => bind_unix(path).await,
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are only supported on Unix platforms",
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path) -> io::Result<ServeListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            tracing::info!("Removing stale socket {}", path.display());
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(ServeListener::Unix(listener))
+}
+
+impl ServeListener {
+    /// Accept a connection, returning it with a description of the peer for logging
+    pub async fn accept(&self) -> io::Result<(Box<dyn ServeStream>, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), "a unix socket client".to_string()))
+            }
+        }
+    }
+}
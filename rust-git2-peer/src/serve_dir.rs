@@ -0,0 +1,176 @@
+use anyhow::Context;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::warn;
+
+/// The separator between the namespace and the relative path in the file id of a served file
+pub const NAMESPACE_SEPARATOR: char = '/';
+
+/// A directory whose files are served under a namespace, parsed from `name=path`. Each file is
+/// served as `name/relative/path`, so files with the same relative path in different directories
+/// don't collide.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServeDir {
+    /// The namespace the files are served under
+    pub namespace: String,
+    /// The directory holding the files
+    pub path: PathBuf,
+}
+
+impl FromStr for ServeDir {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((namespace, path)) = s.split_once('=') else {
+            anyhow::bail!("Serve directory {s} is not of the form name=path");
+        };
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+        if namespace.is_empty() || !namespace.chars().all(valid) {
+            anyhow::bail!(
+                "Invalid namespace {namespace:?}, use letters, digits, '-', '_' and '.' only"
+            );
+        }
+        if path.is_empty() {
+            anyhow::bail!("Serve directory {s} has no path");
+        }
+        Ok(Self {
+            namespace: namespace.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl ServeDir {
+    /// Check that no two directories share a namespace
+    pub fn validate(dirs: &[ServeDir]) -> anyhow::Result<()> {
+        let mut namespaces = HashSet::new();
+        for dir in dirs {
+            if !namespaces.insert(dir.namespace.as_str()) {
+                anyhow::bail!(
+                    "Namespace {} is used by more than one --serve-dir",
+                    dir.namespace
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The file id a file is served as, from its path relative to the directory
+    pub fn file_id(&self, name: &str) -> String {
+        format!("{}{NAMESPACE_SEPARATOR}{name}", self.namespace)
+    }
+
+    /// List the files in the directory and its subdirectories, sorted by their relative path,
+    /// without reading them. Symbolic links are skipped so that nothing outside of the directory
+    /// is served, and so are empty files since they can't be exchanged.
+    pub fn list_files(&self) -> anyhow::Result<Vec<ServedFile>> {
+        let mut files = Vec::new();
+        list_dir(&self.path, &self.path, &mut files)
+            .with_context(|| format!("Failed to read serve directory {}", self.path.display()))?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+}
+
+/// A file in a served directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServedFile {
+    /// The path of the file relative to the directory, with forward slashes
+    pub name: String,
+    /// The path of the file
+    pub path: PathBuf,
+    /// The size of the file when it was listed
+    pub size: u64,
+}
+
+impl ServedFile {
+    /// Read the file, refusing it if it changed size since it was listed
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(self.size as usize);
+        // one byte more than listed to notice a file that grew
+        fs::File::open(&self.path)?
+            .take(self.size + 1)
+            .read_to_end(&mut body)?;
+        if body.len() as u64 != self.size {
+            return Err(io::Error::other(format!(
+                "{} changed size since it was listed",
+                self.path.display()
+            )));
+        }
+        Ok(body)
+    }
+}
+
+// Collect the files under `dir` with their paths relative to `root`
+fn list_dir(root: &Path, dir: &Path, files: &mut Vec<ServedFile>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            warn!("Not serving {}: it is a symbolic link", path.display());
+            continue;
+        }
+        if metadata.is_dir() {
+            list_dir(root, &path, files)?;
+            continue;
+        }
+        if !metadata.is_file() || metadata.len() == 0 {
+            continue;
+        }
+        // file ids use forward slashes on every platform
+        let name = path
+            .strip_prefix(root)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push(ServedFile {
+            name,
+            path,
+            size: metadata.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn list_files_skips_links_and_empty_files() {
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/b.txt"), b"bb").unwrap();
+        fs::write(dir.path().join("a.txt"), b"a").unwrap();
+        fs::write(dir.path().join("empty"), b"").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink(outside.path().join("secret"), dir.path().join("link")).unwrap();
+            symlink(outside.path(), dir.path().join("linked_dir")).unwrap();
+        }
+
+        let serve_dir = ServeDir {
+            namespace: "ns".to_string(),
+            path: dir.path().to_path_buf(),
+        };
+        let files = serve_dir.list_files().unwrap();
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "sub/b.txt"]);
+        assert_eq!(files[1].size, 2);
+        assert_eq!(files[1].read().unwrap(), b"bb");
+
+        // a file that changed since it was listed is refused
+        fs::write(dir.path().join("sub/b.txt"), b"bbb").unwrap();
+        assert!(files[1].read().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use libp2p::gossipsub::{
+    DataTransform, IdentTopic, Message, MessageAcceptance, RawMessage, TopicHash,
+};
+use std::{collections::HashMap, io};
+
+/// The authenticity a gossipsub topic requires of the messages received on it.
+///
+/// The policy only decides which messages from other peers are accepted and forwarded. A gossipsub
+/// behaviour has a single `MessageAuthenticity`, and this peer's is `Signed`, so everything it
+/// publishes is signed whatever the topic's policy: publishing anonymously is not supported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicAuth {
+    /// Messages must be signed by their author so that they can be attributed
+    Signed,
+    /// Messages without a signature or author are accepted too, signed ones are not required
+    AcceptUnsigned,
+}
+
+/// The authentication policy of each topic the peer is subscribed to
+#[derive(Clone, Debug, Default)]
+pub struct TopicPolicies(HashMap<TopicHash, TopicAuth>);
+
+impl TopicPolicies {
+    /// Declare the authentication policy for a topic
+    pub fn insert(&mut self, topic: &IdentTopic, auth: TopicAuth) {
+        self.0.insert(topic.hash(), auth);
+    }
+
+    /// Get the authentication policy for a topic, if one was declared
+    pub fn get(&self, topic: &TopicHash) -> Option<TopicAuth> {
+        self.0.get(topic).copied()
+    }
+
+    /// Check that publishing to the topic is allowed by its policy
+    pub fn check_publish(&self, topic: &TopicHash) -> anyhow::Result<()> {
+        match self.get(topic) {
+            // our messages are always signed, even on AcceptUnsigned topics, so they satisfy every
+            // policy
+            Some(_) => Ok(()),
+            None => anyhow::bail!("No authentication policy declared for topic {topic}"),
+        }
+    }
+
+    /// Validate a received message against the policy of its topic. A message only has a source
+    /// if it was signed by it, see `SignedAuthorTransform`.
+    pub fn validate(&self, message: &Message) -> MessageAcceptance {
+        match self.get(&message.topic) {
+            Some(TopicAuth::Signed) if message.source.is_none() => MessageAcceptance::Reject,
+            Some(_) => MessageAcceptance::Accept,
+            None => MessageAcceptance::Ignore,
+        }
+    }
+}
+
+/// Drops the author of a received gossipsub message that isn't signed.
+///
+/// Gossipsub's permissive validation checks the signature of a message that has one, but accepts a
+/// message naming an author without a signature, and `Message` doesn't tell them apart. Dropping
+/// such an unproven author makes the message anonymous, so `TopicPolicies::validate` can tell a
+/// signed message by its source alone.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignedAuthorTransform;
+
+impl DataTransform for SignedAuthorTransform {
+    fn inbound_transform(&self, raw_message: RawMessage) -> Result<Message, io::Error> {
+        let signed = raw_message.signature.is_some();
+        Ok(Message {
+            source: raw_message.source.filter(|_| signed),
+            data: raw_message.data,
+            sequence_number: raw_message.sequence_number,
+            topic: raw_message.topic,
+        })
+    }
+
+    fn outbound_transform(&self, _topic: &TopicHash, data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn raw_message(topic: &IdentTopic, signature: Option<Vec<u8>>) -> RawMessage {
+        RawMessage {
+            source: Some(Keypair::generate_ed25519().public().to_peer_id()),
+            data: b"data".to_vec(),
+            sequence_number: Some(1),
+            topic: topic.hash(),
+            signature,
+            key: None,
+            validated: false,
+        }
+    }
+
+    #[test]
+    fn signed_topics_only_accept_signed_messages() {
+        let signed = IdentTopic::new("signed");
+        let unsigned = IdentTopic::new("unsigned");
+        let mut policies = TopicPolicies::default();
+        policies.insert(&signed, TopicAuth::Signed);
+        policies.insert(&unsigned, TopicAuth::AcceptUnsigned);
+        let transform = SignedAuthorTransform;
+
+        // the signature itself was verified by gossipsub
+        let message = transform
+            .inbound_transform(raw_message(&signed, Some(vec![1; 64])))
+            .unwrap();
+        assert!(message.source.is_some());
+        assert!(matches!(
+            policies.validate(&message),
+            MessageAcceptance::Accept
+        ));
+
+        // an author without a signature is dropped, and with it the message
+        let message = transform
+            .inbound_transform(raw_message(&signed, None))
+            .unwrap();
+        assert_eq!(message.source, None);
+        assert!(matches!(
+            policies.validate(&message),
+            MessageAcceptance::Reject
+        ));
+
+        // but it is still accepted anonymously where that is allowed
+        let message = transform
+            .inbound_transform(raw_message(&unsigned, None))
+            .unwrap();
+        assert!(matches!(
+            policies.validate(&message),
+            MessageAcceptance::Accept
+        ));
+    }
+
+    #[test]
+    fn messages_on_undeclared_topics_are_ignored() {
+        let message = SignedAuthorTransform
+            .inbound_transform(raw_message(&IdentTopic::new("other"), Some(vec![1; 64])))
+            .unwrap();
+        assert!(matches!(
+            TopicPolicies::default().validate(&message),
+            MessageAcceptance::Ignore
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+use libp2p::{gossipsub::TopicHash, PeerId};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    time::Instant,
+};
+
+/// The activity on a single gossipsub topic
+#[derive(Clone, Debug, Default)]
+pub struct TopicActivity {
+    /// The number of messages received
+    pub messages: u64,
+    /// The authors of the received messages. Anonymous messages have no known author.
+    pub sources: HashSet<PeerId>,
+    /// When the last message was received
+    pub last_message: Option<Instant>,
+}
+
+/// The activity on each gossipsub topic since startup or the last reset
+#[derive(Clone, Debug, Default)]
+pub struct TopicStats {
+    topics: HashMap<TopicHash, TopicActivity>,
+}
+
+impl TopicStats {
+    /// Start tracking a topic so it is reported even before its first message
+    pub fn track(&mut self, topic: TopicHash) {
+        self.topics.entry(topic).or_default();
+    }
+
+    /// Record a message received on a topic at `now`
+    pub fn record(&mut self, topic: &TopicHash, source: Option<PeerId>, now: Instant) {
+        let activity = self.topics.entry(topic.clone()).or_default();
+        activity.messages += 1;
+        if let Some(source) = source {
+            activity.sources.insert(source);
+        }
+        activity.last_message = Some(now);
+    }
+
+    /// Get the activity on a topic
+    pub fn get(&self, topic: &TopicHash) -> Option<&TopicActivity> {
+        self.topics.get(topic)
+    }
+
+    /// Clear the counters of every topic, keeping the topics tracked
+    pub fn reset(&mut self) {
+        for activity in self.topics.values_mut() {
+            *activity = TopicActivity::default();
+        }
+    }
+
+    /// Describe the activity on each topic, one per line, relative to `now`
+    pub fn report(&self, now: Instant) -> Result<String, fmt::Error> {
+        let mut topics: Vec<(&TopicHash, &TopicActivity)> = self.topics.iter().collect();
+        topics.sort_by_key(|(topic, _)| topic.as_str());
+
+        let mut out = String::new();
+        for (topic, activity) in topics {
+            let last = activity.last_message.map_or("never".to_string(), |last| {
+                format!("{}s ago", now.saturating_duration_since(last).as_secs())
+            });
+            write!(
+                out,
+                "\n\t{topic}: {} messages from {} sources, last {last}",
+                activity.messages,
+                activity.sources.len()
+            )?;
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,136 @@
+use libp2p::{
+    request_response::{InboundRequestId, OutboundRequestId},
+    PeerId,
+};
+use std::{collections::HashMap, fmt, time::Instant};
+
+/// The request_response protocol a transfer is exchanged over
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TransferProtocol {
+    /// The file exchange protocol
+    File,
+    /// The git exchange protocol
+    Git,
+}
+
+impl TransferProtocol {
+    /// The name of the protocol, as shown in the transfers command and the metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferProtocol::File => "file",
+            TransferProtocol::Git => "git",
+        }
+    }
+}
+
+/// The request id of a transfer, which also tells its direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TransferId {
+    /// A request from another peer that we answer
+    Inbound(InboundRequestId),
+    /// A request we sent to another peer
+    Outbound(OutboundRequestId),
+}
+
+impl TransferId {
+    /// The direction of the transfer, `inbound` or `outbound`
+    pub fn direction(&self) -> &'static str {
+        match self {
+            TransferId::Inbound(_) => "inbound",
+            TransferId::Outbound(_) => "outbound",
+        }
+    }
+}
+
+impl fmt::Display for TransferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferId::Inbound(id) => write!(f, "{id}"),
+            TransferId::Outbound(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+/// A request_response exchange in flight
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    /// The protocol the exchange is over
+    pub protocol: TransferProtocol,
+    /// The request id of the exchange
+    pub id: TransferId,
+    /// The other peer of the exchange
+    pub peer: PeerId,
+    /// The operation requested, such as `PackChunk` for git
+    pub operation: &'static str,
+    /// The payload bytes transferred so far. Responses arrive whole, so this is the size of the
+    /// response being sent for an inbound exchange, and the progress of the larger transfer the
+    /// request is part of, like a packfile, for an outbound one.
+    pub bytes: u64,
+    /// When the exchange started
+    pub started: Instant,
+}
+
+/// The request_response exchanges in flight, by protocol and request id. The request ids of the
+/// protocols are counted separately, so the protocol is part of the key.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    transfers: HashMap<(TransferProtocol, TransferId), Transfer>,
+}
+
+impl Transfers {
+    /// Start tracking an exchange, returning it
+    pub fn start(
+        &mut self,
+        protocol: TransferProtocol,
+        id: TransferId,
+        peer: PeerId,
+        operation: &'static str,
+    ) -> &Transfer {
+        let transfer = Transfer {
+            protocol,
+            id,
+            peer,
+            operation,
+            bytes: 0,
+            started: Instant::now(),
+        };
+        self.transfers.insert((protocol, id), transfer);
+        &self.transfers[&(protocol, id)]
+    }
+
+    /// Record the bytes transferred so far by an exchange, returning it with how many more bytes
+    /// that is than before, or `None` if the exchange isn't tracked
+    pub fn progress(
+        &mut self,
+        protocol: TransferProtocol,
+        id: TransferId,
+        bytes: u64,
+    ) -> Option<(&Transfer, i64)> {
+        let transfer = self.transfers.get_mut(&(protocol, id))?;
+        let delta = bytes as i64 - transfer.bytes as i64;
+        transfer.bytes = bytes;
+        Some((transfer, delta))
+    }
+
+    /// Stop tracking an exchange that completed or failed, returning it if it was tracked
+    pub fn finish(&mut self, protocol: TransferProtocol, id: TransferId) -> Option<Transfer> {
+        self.transfers.remove(&(protocol, id))
+    }
+
+    /// The exchanges in flight, oldest first
+    pub fn snapshot(&self) -> Vec<&Transfer> {
+        let mut transfers: Vec<_> = self.transfers.values().collect();
+        transfers.sort_by_key(|transfer| transfer.started);
+        transfers
+    }
+
+    /// The number of exchanges in flight
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Check if no exchange is in flight
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+}
@@ -1,10 +1,10 @@
 #![allow(dead_code)]
-use crate::{log::Message as LogMessage, ChatPeer, Message, Ui};
+use crate::{log::Message as LogMessage, ChatPeer, LogControl, Message, Ui};
 use async_trait::async_trait;
 use libp2p::core::PeerId;
 use signal_hook::{consts::SIGTERM, iterator::Signals};
 use std::{collections::HashSet, time::Duration};
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::{self, error::TryRecvError, Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 
 /// A headless UI for the peer
@@ -13,6 +13,8 @@ pub struct Headless {
     me: ChatPeer,
     // we receive log messages from the log thread
     from_log: Receiver<LogMessage>,
+    // we throttle the log messages while we fall behind
+    log_control: LogControl,
     // we send UI messages to the peer thread
     to_peer: Sender<Message>,
     // we receive UI messages from the peer thread
@@ -28,6 +30,7 @@ impl Headless {
     pub fn build(
         me: PeerId,
         from_log: Receiver<LogMessage>,
+        log_control: LogControl,
         shutdown: CancellationToken,
     ) -> (Box<dyn Ui + Send>, Sender<Message>, Receiver<Message>) {
         // create a new channels for sending/receiving messages
@@ -38,6 +41,7 @@ impl Headless {
         let ui: Box<dyn Ui> = Box::new(Self {
             me: me.into(),
             from_log,
+            log_control,
             to_peer,
             from_peer,
             shutdown,
@@ -69,17 +73,28 @@ impl Ui for Headless {
                 }
             }
 
-            // Process peer messages
-            if let Ok(ui_message) = self.from_peer.try_recv() {
-                match ui_message {
-                    Message::Chat { from, data } => {
+            // Throttle the log while it floods us, and report what was dropped once caught up
+            let backlog = self.from_log.len();
+            self.log_control.adjust(backlog);
+            if backlog == 0 {
+                let suppressed = self.log_control.take_suppressed();
+                if suppressed > 0 {
+                    println!("{suppressed} log messages suppressed");
+                }
+            }
+
+            // Process peer messages, the peer is gone once the channel is closed and empty
+            match self.from_peer.try_recv() {
+                Err(TryRecvError::Disconnected) => break 'main,
+                Err(TryRecvError::Empty) => {}
+                Ok(ui_message) => match ui_message {
+                    Message::Chat { from, envelope } => {
                         let from = from.map_or("Unknown".to_string(), |peer| peer.to_string());
-                        let message =
-                            String::from_utf8(data).unwrap_or("Invalid UTF-8".to_string());
-                        println!("{}: {}", from, message);
+                        println!("{}: {}", from, envelope);
                     }
                     Message::AddPeer(peer) => {
-                        if self.peers.insert(peer) {
+                        let added = self.peers.insert(peer);
+                        if added {
                             println!(
                                 "Adding peer:\n\tpeer id: {}\n\tname: {}",
                                 peer.id(),
@@ -88,27 +103,47 @@ impl Ui for Headless {
                         }
                     }
                     Message::RemovePeer(peer) => {
-                        if self.peers.remove(&peer) {
+                        let removed = self.peers.remove(&peer);
+                        if removed {
                             println!("Removing peer: {peer:?}");
                         }
                     }
                     Message::Event(event) => {
                         println!("{}", event);
                     }
+                    Message::GitRequestSent { id, peer, summary } => {
+                        println!("[{id}] -> {peer}: {summary}");
+                    }
+                    Message::GitResponseReceived {
+                        id,
+                        peer,
+                        summary,
+                        elapsed,
+                        error,
+                    } => {
+                        let marker = if error { " ERROR" } else { "" };
+                        println!(
+                            "[{id}] <- {peer}:{marker} {summary} ({}ms)",
+                            elapsed.as_millis()
+                        );
+                    }
                     _ => {}
-                }
+                },
             }
 
-            // check if we have received the shutdown signal from the OS
+            // check if we have received the shutdown signal from the OS, the peer finishes its
+            // transfers and stops, then so do we
             if signals.pending().next() == Some(SIGTERM) {
                 println!("Received SIGTERM, shutting down");
                 self.shutdown.cancel();
-                break 'main;
             }
 
             tokio::time::sleep(Duration::from_millis(18)).await;
         }
 
+        // write the log messages we didn't print to stderr
+        self.log_control.flush(&mut self.from_log);
+
         Ok(())
     }
 }
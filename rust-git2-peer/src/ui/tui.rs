@@ -1,4 +1,4 @@
-use crate::{log::Message as LogMessage, ChatPeer, Message, Ui};
+use crate::{log::Message as LogMessage, ChatEnvelope, ChatPeer, LogControl, Message, Ui};
 use async_trait::async_trait;
 use crossterm::{
     event::{
@@ -13,7 +13,7 @@ use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     prelude::{Buffer, Rect, Widget},
-    style::{Modifier, Style},
+    style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Terminal,
@@ -24,7 +24,7 @@ use std::{
     option::Option,
     time::Duration,
 };
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::{self, error::TryRecvError, Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
@@ -34,6 +34,8 @@ pub struct Tui {
     me: ChatPeer,
     // we receive log messages from the log thread
     from_log: Receiver<LogMessage>,
+    // we throttle the log messages while we fall behind
+    log_control: LogControl,
     // we send UI messages to the peer thread
     to_peer: Sender<Message>,
     // we receive UI messages from the peer thread
@@ -47,6 +49,7 @@ impl Tui {
     pub fn build(
         me: PeerId,
         from_log: Receiver<LogMessage>,
+        log_control: LogControl,
         shutdown: CancellationToken,
     ) -> (Box<dyn Ui + Send>, Sender<Message>, Receiver<Message>) {
         // create a new channels for sending/receiving messages
@@ -57,6 +60,7 @@ impl Tui {
         let ui: Box<dyn Ui> = Box::new(Self {
             me: me.into(),
             from_log,
+            log_control,
             to_peer,
             from_peer,
             shutdown,
@@ -70,9 +74,6 @@ impl Tui {
 impl Ui for Tui {
     /// Run the UI
     async fn run(&mut self) -> anyhow::Result<()> {
-        // the currently selected tab
-        let mut selected_tab = 0;
-
         // TUI setup
         enable_raw_mode()?;
         let mut stdout = io::stdout();
@@ -80,12 +81,37 @@ impl Ui for Tui {
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
 
+        let result = self.main_loop(&mut terminal).await;
+
+        // Cleanup, also when the loop failed so the terminal isn't left in raw mode
+        disable_raw_mode()?;
+        execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+
+        // write the log messages we didn't show to stderr now that the terminal is restored
+        self.log_control.flush(&mut self.from_log);
+
+        result
+    }
+}
+
+impl Tui {
+    // Run the main loop until the peer has stopped, rendering its final state
+    async fn main_loop(
+        &mut self,
+        terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    ) -> anyhow::Result<()> {
+        // the currently selected tab
+        let mut selected_tab = 0;
+
         // Log Widget
         let mut log_widget = LinesWidget::new("Log", 200);
 
         // Chat Widget
         let mut chat_widget = ChatWidget::new(&self.me);
 
+        // Git request log Widget
+        let mut git_widget = LinesWidget::new("Git Requests", 200);
+
         // Main loop
         loop {
             // Process log messages
@@ -96,13 +122,24 @@ impl Ui for Tui {
                 }
             }
 
-            // Process peer messages
-            if let Ok(ui_message) = self.from_peer.try_recv() {
-                match ui_message {
-                    Message::Chat { from, data } => {
-                        let message =
-                            String::from_utf8(data).unwrap_or("Invalid UTF-8".to_string());
-                        chat_widget.add_chat(from, message);
+            // Throttle the log while it floods us, and report what was dropped once caught up
+            let backlog = self.from_log.len();
+            self.log_control.adjust(backlog);
+            if backlog == 0 {
+                let suppressed = self.log_control.take_suppressed();
+                if suppressed > 0 {
+                    log_widget.add_error_line(format!("{suppressed} log messages suppressed"));
+                }
+            }
+
+            // Process peer messages, the peer is gone once the channel is closed and empty
+            let mut peer_stopped = false;
+            match self.from_peer.try_recv() {
+                Err(TryRecvError::Disconnected) => peer_stopped = true,
+                Err(TryRecvError::Empty) => {}
+                Ok(ui_message) => match ui_message {
+                    Message::Chat { from, envelope } => {
+                        chat_widget.add_chat(from, envelope.to_string());
                     }
                     Message::AllPeers { peers } => {
                         for (peer, topics) in peers {
@@ -114,7 +151,8 @@ impl Ui for Tui {
                         }
                     }
                     Message::AddPeer(peer) => {
-                        if chat_widget.peers.insert(peer) {
+                        let added = chat_widget.peers.insert(peer);
+                        if added {
                             chat_widget.add_event(format!(
                                 "Adding peer:\n\tpeer id: {}\n\tname: {}",
                                 peer.id(),
@@ -123,23 +161,49 @@ impl Ui for Tui {
                         }
                     }
                     Message::RemovePeer(peer) => {
-                        if chat_widget.peers.remove(&peer) {
+                        let removed = chat_widget.peers.remove(&peer);
+                        if removed {
                             chat_widget.add_event(format!("Removing peer: {peer:?}"));
                         }
                     }
                     Message::Event(event) => {
                         chat_widget.add_event(event);
                     }
-                }
+                    Message::GitRequestSent { id, peer, summary } => {
+                        git_widget.add_line(format!("[{id}] -> {peer}: {summary}"));
+                    }
+                    Message::GitResponseReceived {
+                        id,
+                        peer,
+                        summary,
+                        elapsed,
+                        error,
+                    } => {
+                        let line =
+                            format!("[{id}] <- {peer}: {summary} ({}ms)", elapsed.as_millis());
+                        if error {
+                            git_widget.add_error_line(line);
+                        } else {
+                            git_widget.add_line(line);
+                        }
+                    }
+                    _ => {}
+                },
             }
 
             // Draw the UI
             terminal.draw(|f| match selected_tab {
                 0 => f.render_widget(&mut chat_widget, f.area()),
                 1 => f.render_widget(&mut log_widget, f.area()),
+                2 => f.render_widget(&mut git_widget, f.area()),
                 _ => {}
             })?;
 
+            // the final state has been drawn
+            if peer_stopped {
+                break;
+            }
+
             // Handle input events
             if event::poll(Duration::from_millis(18))? {
                 match event::read()? {
@@ -150,9 +214,10 @@ impl Ui for Tui {
                             modifiers: KeyModifiers::CONTROL,
                             ..
                         } => {
+                            // the peer finishes its transfers and stops, then so do we
                             info!("Received Ctrl+C, shutting down...");
+                            chat_widget.add_event("Shutting down...");
                             self.shutdown.cancel();
-                            break;
                         }
 
                         // Handle ctrl+shift+p
@@ -170,7 +235,7 @@ impl Ui for Tui {
                         // Handle all other key events
                         _ => match key.code {
                             KeyCode::Tab => {
-                                selected_tab = (selected_tab + 1) % 2;
+                                selected_tab = (selected_tab + 1) % 3;
                             }
                             KeyCode::Char(c) if selected_tab == 0 => {
                                 chat_widget.input.push(c);
@@ -178,13 +243,22 @@ impl Ui for Tui {
                             KeyCode::Backspace if selected_tab == 0 => {
                                 chat_widget.input.pop();
                             }
+                            KeyCode::Enter
+                                if selected_tab == 0 && chat_widget.input.starts_with('/') =>
+                            {
+                                // input starting with a slash is a command for the peer
+                                let command = chat_widget.input[1..].to_string();
+                                chat_widget.add_event(format!("> {command}"));
+                                self.to_peer.send(Message::Command(command)).await?;
+                                chat_widget.input.clear();
+                            }
                             KeyCode::Enter if selected_tab == 0 => {
                                 error!("chat sent");
                                 // send the chat message to the swarm to be gossiped
                                 self.to_peer
                                     .send(Message::Chat {
                                         from: Some(self.me),
-                                        data: chat_widget.input.clone().into_bytes(),
+                                        envelope: ChatEnvelope::text(chat_widget.input.clone()),
                                     })
                                     .await?;
 
@@ -204,6 +278,9 @@ impl Ui for Tui {
                         1 => {
                             let _ = log_widget.mouse_event(event);
                         }
+                        2 => {
+                            let _ = git_widget.mouse_event(event);
+                        }
                         _ => {}
                     },
                     _ => {}
@@ -211,16 +288,12 @@ impl Ui for Tui {
             }
         }
 
-        // Cleanup
-        disable_raw_mode()?;
-        execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
-
         Ok(())
     }
 }
 
 // Function to wrap text into multiple lines based on a max width
-fn wrap_text(text: &str, max_width: usize) -> Vec<Line> {
+fn wrap_text(text: &str, max_width: usize) -> Vec<Line<'_>> {
     let mut lines = Vec::new();
 
     // split the message into lines to preserve any newlines in the message
@@ -289,7 +362,7 @@ fn wrap_text(text: &str, max_width: usize) -> Vec<Line> {
 struct LinesWidget {
     title: String,
     max: usize,
-    lines: VecDeque<String>,
+    lines: VecDeque<(String, Style)>,
     scroll: usize,
     area: Rect,
 }
@@ -322,9 +395,7 @@ impl LinesWidget {
                     self.scroll += 1;
                 }
                 MouseEventKind::ScrollDown => {
-                    if self.scroll > 0 {
-                        self.scroll -= 1;
-                    }
+                    self.scroll = self.scroll.saturating_sub(1);
                 }
                 _ => {}
             }
@@ -336,7 +407,17 @@ impl LinesWidget {
 
     // Add a line to the widget
     fn add_line(&mut self, line: impl Into<String>) {
-        self.lines.push_back(line.into());
+        self.add_styled_line(line, Style::default());
+    }
+
+    // Add a line highlighted as an error to the widget
+    fn add_error_line(&mut self, line: impl Into<String>) {
+        self.add_styled_line(line, Style::default().fg(Color::Red));
+    }
+
+    // Add a line with a style to the widget
+    fn add_styled_line(&mut self, line: impl Into<String>, style: Style) {
+        self.lines.push_back((line.into(), style));
         if self.lines.len() > self.max {
             self.lines.drain(0..(self.lines.len() - self.max));
         }
@@ -357,11 +438,11 @@ impl Widget for &mut LinesWidget {
         let mut logs: Vec<ListItem> = self
             .lines
             .iter()
-            .flat_map(|l| {
+            .flat_map(|(l, style)| {
                 let wrapped_lines = wrap_text(l, inner_area.width as usize - 2);
                 wrapped_lines
                     .into_iter()
-                    .map(ListItem::new)
+                    .map(|line| ListItem::new(line).style(*style))
                     .collect::<Vec<_>>()
             })
             .collect();
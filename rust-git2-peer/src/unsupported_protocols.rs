@@ -0,0 +1,120 @@
+use libp2p::{request_response::OutboundFailure, PeerId};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// The request-response protocol families, such as "git" or "file", each connected peer failed to
+/// negotiate any version of, so requests over them aren't retried against it. A peer is forgotten
+/// once it disconnects, and a family once identify reports the peer supports a version of it after
+/// all.
+///
+/// Only a failed negotiation is remembered. A peer that stalls the negotiation instead is bounded
+/// by the timeout libp2p gives every outbound stream upgrade, 10 seconds, which the request-response
+/// behaviours report as [`OutboundFailure::Timeout`]. That is a transport failure like any other
+/// timeout, so it isn't remembered and the next request may succeed. `--upgrade-timeout` doesn't
+/// come into it: it bounds the upgrade of the connection, not of the streams on it.
+#[derive(Debug, Default)]
+pub struct UnsupportedProtocols {
+    peers: HashMap<PeerId, HashSet<&'static str>>,
+}
+
+impl UnsupportedProtocols {
+    /// Check that a request over the protocol `family` didn't already fail to negotiate with a peer
+    pub fn check(&self, peer: &PeerId, family: &'static str) -> anyhow::Result<()> {
+        if self.is_unsupported(peer, family) {
+            anyhow::bail!("{peer} doesn't support the {family} protocol");
+        }
+        Ok(())
+    }
+
+    /// Check if a request over the protocol `family` failed to negotiate with a peer
+    pub fn is_unsupported(&self, peer: &PeerId, family: &str) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|unsupported| unsupported.contains(family))
+    }
+
+    /// Classify a failed outbound request over the protocol `family`. The request proposed every
+    /// version of the family we speak, so a peer that supports none of them is remembered so the
+    /// request isn't retried against it, and true is returned. Other failures, such as dial
+    /// failures, closed connections and timeouts, are transport failures that may succeed when
+    /// retried.
+    pub fn outbound_failed(
+        &mut self,
+        peer: PeerId,
+        family: &'static str,
+        error: &OutboundFailure,
+    ) -> bool {
+        if !matches!(error, OutboundFailure::UnsupportedProtocols) {
+            return false;
+        }
+        info!(
+            "{peer} doesn't support the {family} protocol, not sending it further requests over it"
+        );
+        self.peers.entry(peer).or_default().insert(family);
+        true
+    }
+
+    /// Forget the families a peer was found not to support that identify reports it supports a
+    /// version of after all
+    pub fn identified(&mut self, peer: &PeerId, families: &[&'static str]) {
+        if let Some(unsupported) = self.peers.get_mut(peer) {
+            unsupported.retain(|family| !families.contains(family));
+            if unsupported.is_empty() {
+                self.peers.remove(peer);
+            }
+        }
+    }
+
+    /// Forget a peer, once its last connection closed
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+    use std::io;
+
+    fn peer() -> PeerId {
+        Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    #[test]
+    fn only_a_failed_negotiation_is_remembered() {
+        let mut unsupported = UnsupportedProtocols::default();
+        let peer = peer();
+        let transient = [
+            OutboundFailure::DialFailure,
+            OutboundFailure::Timeout,
+            OutboundFailure::ConnectionClosed,
+            OutboundFailure::Io(io::Error::other("reset")),
+        ];
+        for error in &transient {
+            assert!(!unsupported.outbound_failed(peer, "file", error));
+        }
+        assert!(unsupported.check(&peer, "file").is_ok());
+
+        assert!(unsupported.outbound_failed(peer, "file", &OutboundFailure::UnsupportedProtocols));
+        assert!(unsupported.check(&peer, "file").is_err());
+        // other families and other peers are unaffected
+        assert!(unsupported.check(&peer, "git").is_ok());
+        assert!(unsupported.check(&self::peer(), "file").is_ok());
+    }
+
+    #[test]
+    fn identify_clears_the_families_the_peer_supports() {
+        let mut unsupported = UnsupportedProtocols::default();
+        let peer = peer();
+        unsupported.outbound_failed(peer, "file", &OutboundFailure::UnsupportedProtocols);
+        unsupported.outbound_failed(peer, "git", &OutboundFailure::UnsupportedProtocols);
+
+        unsupported.identified(&peer, &["file", "echo"]);
+        assert!(!unsupported.is_unsupported(&peer, "file"));
+        assert!(unsupported.is_unsupported(&peer, "git"));
+
+        unsupported.forget(&peer);
+        assert!(!unsupported.is_unsupported(&peer, "git"));
+    }
+}
@@ -0,0 +1,123 @@
+use futures::{future::BoxFuture, Future, FutureExt};
+use libp2p::core::upgrade::{InboundConnectionUpgrade, OutboundConnectionUpgrade, UpgradeInfo};
+use std::{error::Error, fmt, time::Duration};
+
+/// A connection upgrade, such as a security handshake, that is aborted if it doesn't finish in
+/// time. Wraps the upgrades the swarm builder applies to TCP and relayed connections, which
+/// otherwise wait on a peer that never completes the handshake.
+#[derive(Clone, Debug)]
+pub struct UpgradeTimeout<U> {
+    inner: U,
+    timeout: Duration,
+}
+
+impl<U> UpgradeTimeout<U> {
+    /// Wrap `inner` so that it fails after `timeout`
+    pub fn new(inner: U, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<U: UpgradeInfo> UpgradeInfo for UpgradeTimeout<U> {
+    type Info = U::Info;
+    type InfoIter = U::InfoIter;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info()
+    }
+}
+
+impl<C, U> InboundConnectionUpgrade<C> for UpgradeTimeout<U>
+where
+    U: InboundConnectionUpgrade<C>,
+    U::Future: Send + 'static,
+    U::Output: 'static,
+    U::Error: 'static,
+{
+    type Output = U::Output;
+    type Error = UpgradeTimeoutError<U::Error>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        with_timeout(self.inner.upgrade_inbound(socket, info), self.timeout)
+    }
+}
+
+impl<C, U> OutboundConnectionUpgrade<C> for UpgradeTimeout<U>
+where
+    U: OutboundConnectionUpgrade<C>,
+    U::Future: Send + 'static,
+    U::Output: 'static,
+    U::Error: 'static,
+{
+    type Output = U::Output;
+    type Error = UpgradeTimeoutError<U::Error>;
+    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        with_timeout(self.inner.upgrade_outbound(socket, info), self.timeout)
+    }
+}
+
+fn with_timeout<F, O, E>(
+    future: F,
+    timeout: Duration,
+) -> BoxFuture<'static, Result<O, UpgradeTimeoutError<E>>>
+where
+    F: Future<Output = Result<O, E>> + Send + 'static,
+    O: 'static,
+    E: 'static,
+{
+    async move {
+        match tokio::time::timeout(timeout, future).await {
+            Ok(result) => result.map_err(UpgradeTimeoutError::Upgrade),
+            Err(_) => Err(UpgradeTimeoutError::Timeout(timeout)),
+        }
+    }
+    .boxed()
+}
+
+/// The error of an [`UpgradeTimeout`]
+#[derive(Debug)]
+pub enum UpgradeTimeoutError<E> {
+    /// The upgrade didn't finish in time
+    Timeout(Duration),
+    /// The upgrade itself failed
+    Upgrade(E),
+}
+
+impl<E: fmt::Display> fmt::Display for UpgradeTimeoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(timeout) => write!(f, "connection upgrade timed out after {timeout:?}"),
+            Self::Upgrade(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for UpgradeTimeoutError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Timeout(_) => None,
+            Self::Upgrade(e) => e.source(),
+        }
+    }
+}
+
+/// Check if a connection failed because its upgrade timed out, either in an [`UpgradeTimeout`]
+/// or in a transport wrapped with `Transport::timeout`
+pub fn is_upgrade_timeout(error: &(dyn Error + 'static)) -> bool {
+    // the transport errors are boxed into io::Errors along the way, which hides them from
+    // downcasting, so match on the messages instead
+    let mut next = Some(error);
+    while let Some(e) = next {
+        let message = e.to_string();
+        if message.contains("connection upgrade timed out")
+            || message.contains("Timeout has been reached")
+        {
+            return true;
+        }
+        next = e.source();
+    }
+    false
+}
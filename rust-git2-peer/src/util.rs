@@ -1,6 +1,15 @@
+use clap::ValueEnum;
 use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
 use quick_protobuf::reader::BytesReader;
-use std::{convert::TryFrom, fmt, net::IpAddr};
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    convert::TryFrom,
+    fmt::{self, Write},
+    fs, io,
+    net::IpAddr,
+    path::Path,
+};
+use tracing::warn;
 
 /// Define protobuf wire types since they are no longer in quick-protobuf
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -187,3 +196,244 @@ pub fn ipaddr_to_multiaddr(ip: &IpAddr) -> Multiaddr {
     };
     multiaddr
 }
+
+/// The transport to dial first when a peer is reachable over both QUIC and WebRTC
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PreferredTransport {
+    /// Dial QUIC addresses first
+    #[default]
+    Quic,
+    /// Dial WebRTC addresses first
+    Webrtc,
+}
+
+/// Rank an address by its transport, lower is better: the preferred transport first, then the
+/// other of QUIC and WebRTC, then TCP and anything else, and relayed addresses last.
+pub fn transport_rank(addr: &Multiaddr, preferred: PreferredTransport) -> u8 {
+    if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+        return 3;
+    }
+    let quic = addr.iter().any(|p| p == Protocol::QuicV1);
+    let webrtc = addr.iter().any(|p| p == Protocol::WebRTCDirect);
+    match (preferred, quic, webrtc) {
+        (PreferredTransport::Quic, true, _) | (PreferredTransport::Webrtc, _, true) => 0,
+        (_, true, _) | (_, _, true) => 1,
+        _ => 2,
+    }
+}
+
+/// The IP version to dial first when a peer has both IPv4 and IPv6 addresses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AddressFamilyPreference {
+    /// Dial IPv4 addresses first
+    V4,
+    /// Dial IPv6 addresses first
+    V6,
+    /// Dial the addresses in the order they are known, whatever their IP version
+    #[default]
+    Any,
+    /// Alternate IPv6 and IPv4 addresses, dialing the first address of the other version after a
+    /// short head start, like happy eyeballs
+    Auto,
+}
+
+/// The IP version of an address, `ip4` or `ip6`, or `None` if it has no IP, like a DNS address
+pub fn address_family(addr: &Multiaddr) -> Option<&'static str> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Ip4(_) => Some("ip4"),
+        Protocol::Ip6(_) => Some("ip6"),
+        _ => None,
+    })
+}
+
+// Rank an address by its IP version, lower is better, with addresses without an IP between the
+// preferred and the other version
+fn family_rank(addr: &Multiaddr, family: AddressFamilyPreference) -> u8 {
+    use AddressFamilyPreference::{Any, V4, V6};
+    match (family, address_family(addr)) {
+        (Any, _) => 0,
+        (V4, Some("ip4")) | (V6, Some("ip6")) => 0,
+        (_, None) => 1,
+        _ => 2,
+    }
+}
+
+/// Order the addresses of a peer for dialing by their [`transport_rank`] and IP version. Within
+/// each transport rank the preferred version comes first, or with `auto` the addresses alternate
+/// between IPv6 and the rest, starting with IPv6. With `any` and otherwise the order is kept.
+pub fn order_dial_addresses(
+    addrs: &mut [Multiaddr],
+    preferred: PreferredTransport,
+    family: AddressFamilyPreference,
+) {
+    if family != AddressFamilyPreference::Auto {
+        addrs.sort_by_key(|addr| (transport_rank(addr, preferred), family_rank(addr, family)));
+        return;
+    }
+    addrs.sort_by_key(|addr| transport_rank(addr, preferred));
+    let same_rank =
+        |a: &Multiaddr, b: &Multiaddr| transport_rank(a, preferred) == transport_rank(b, preferred);
+    addrs.chunk_by_mut(same_rank).for_each(interleave_families);
+}
+
+// Alternate the addresses between IPv6 and the rest, starting with IPv6, keeping the order of each
+fn interleave_families(addrs: &mut [Multiaddr]) {
+    let (ip6, rest): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .cloned()
+        .partition(|addr| address_family(addr) == Some("ip6"));
+    let (mut ip6, mut rest) = (ip6.into_iter(), rest.into_iter());
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    loop {
+        match (ip6.next(), rest.next()) {
+            (None, None) => break,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+    addrs.clone_from_slice(&interleaved);
+}
+
+/// Read a newline-delimited list of peers from a file. Blank lines and lines starting with `#` are
+/// skipped. Each entry must be a Multiaddr, or a PeerId if `allow_peer_ids` is set; invalid entries
+/// are reported with their line number and skipped.
+pub fn read_peer_list(path: &Path, allow_peer_ids: bool) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut peers = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let valid =
+            line.parse::<Multiaddr>().is_ok() || (allow_peer_ids && line.parse::<PeerId>().is_ok());
+        if valid {
+            peers.push(line.to_string());
+        } else {
+            warn!("{}:{}: invalid peer address {line}", path.display(), i + 1);
+        }
+    }
+
+    Ok(peers)
+}
+
+/// Describe why listening on an address failed, spelling out the common causes such as a port
+/// that is already in use. Other errors are described by their message.
+pub fn listen_error(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut next = Some(error);
+    while let Some(e) = next {
+        let Some(io_error) = e.downcast_ref::<io::Error>() else {
+            next = e.source();
+            continue;
+        };
+        let cause = match io_error.kind() {
+            io::ErrorKind::AddrInUse => "the address is already in use by another process",
+            io::ErrorKind::AddrNotAvailable => "the address is not available on this host",
+            io::ErrorKind::PermissionDenied => {
+                "permission denied, ports below 1024 need privileges"
+            }
+            _ => {
+                // the source of an io::Error skips the error it wraps, so check that one first
+                next = match io_error.get_ref() {
+                    Some(inner) => {
+                        let inner: &(dyn std::error::Error + 'static) = inner;
+                        Some(inner)
+                    }
+                    None => e.source(),
+                };
+                continue;
+            }
+        };
+        return cause.to_string();
+    }
+    error.to_string()
+}
+
+/// Format an error with its full chain of sources and, when backtraces are enabled with
+/// `RUST_BACKTRACE=1`, a backtrace of where it was reported. Only the Display form of each error
+/// is used, so no internal state such as keys is dumped the way a Debug form could.
+pub fn verbose_error(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut out = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let _ = write!(out, "\n\tcaused by: {cause}");
+        source = cause.source();
+    }
+
+    let backtrace = Backtrace::capture();
+    if backtrace.status() == BacktraceStatus::Captured {
+        let _ = write!(out, "\n{backtrace}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<Multiaddr> {
+        addrs.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    const QUIC4_A: &str = "/ip4/1.1.1.1/udp/1/quic-v1";
+    const QUIC4_B: &str = "/ip4/2.2.2.2/udp/1/quic-v1";
+    const QUIC6_A: &str = "/ip6/2001:db8::1/udp/1/quic-v1";
+    const QUIC6_B: &str = "/ip6/2001:db8::2/udp/1/quic-v1";
+    const TCP4: &str = "/ip4/3.3.3.3/tcp/1";
+    const TCP6: &str = "/ip6/2001:db8::3/tcp/1";
+
+    #[test]
+    fn any_keeps_the_order_within_a_transport() {
+        let mut ordered = addrs(&[TCP6, QUIC4_A, TCP4, QUIC6_A]);
+        order_dial_addresses(
+            &mut ordered,
+            PreferredTransport::Quic,
+            AddressFamilyPreference::Any,
+        );
+        assert_eq!(ordered, addrs(&[QUIC4_A, QUIC6_A, TCP6, TCP4]));
+    }
+
+    #[test]
+    fn the_preferred_family_comes_first_within_a_transport() {
+        let mut ordered = addrs(&[TCP6, QUIC4_A, TCP4, QUIC6_A]);
+        order_dial_addresses(
+            &mut ordered,
+            PreferredTransport::Quic,
+            AddressFamilyPreference::V4,
+        );
+        assert_eq!(ordered, addrs(&[QUIC4_A, QUIC6_A, TCP4, TCP6]));
+
+        order_dial_addresses(
+            &mut ordered,
+            PreferredTransport::Quic,
+            AddressFamilyPreference::V6,
+        );
+        assert_eq!(ordered, addrs(&[QUIC6_A, QUIC4_A, TCP6, TCP4]));
+    }
+
+    #[test]
+    fn auto_interleaves_the_families_within_a_transport() {
+        let mut ordered = addrs(&[QUIC4_A, QUIC4_B, TCP4, QUIC6_A, TCP6, QUIC6_B]);
+        order_dial_addresses(
+            &mut ordered,
+            PreferredTransport::Quic,
+            AddressFamilyPreference::Auto,
+        );
+        assert_eq!(
+            ordered,
+            addrs(&[QUIC6_A, QUIC4_A, QUIC6_B, QUIC4_B, TCP6, TCP4])
+        );
+    }
+
+    #[test]
+    fn interleaving_keeps_the_leftovers_of_the_larger_family_in_order() {
+        let mut interleaved = addrs(&[QUIC4_A, QUIC6_A, QUIC4_B, TCP4]);
+        interleave_families(&mut interleaved);
+        assert_eq!(interleaved, addrs(&[QUIC6_A, QUIC4_A, QUIC4_B, TCP4]));
+
+        let mut single = addrs(&[QUIC4_A, QUIC4_B]);
+        interleave_families(&mut single);
+        assert_eq!(single, addrs(&[QUIC4_A, QUIC4_B]));
+    }
+}
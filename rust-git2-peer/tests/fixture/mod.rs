@@ -0,0 +1,115 @@
+//! A git repository fixture, shared by the unit tests of the git server and the integration tests.
+
+#![allow(dead_code)]
+
+use git2::{Commit, Oid, Repository, RepositoryInitOptions, Signature, Time};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// The name of the fixture repository in the repos directory
+pub const REPO: &str = "fixture";
+
+/// A repos directory holding a repository with three commits on `main`, a `feature` branch one
+/// commit ahead of it, a lightweight tag `v1` on the first commit and an annotated tag `v2` on the
+/// tip of `main`
+pub struct Fixture {
+    dir: TempDir,
+    /// The commits on main, oldest first
+    pub main: Vec<Oid>,
+    /// The commit on feature
+    pub feature: Oid,
+    /// The tag object of v2
+    pub v2: Oid,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut opts = RepositoryInitOptions::new();
+        opts.initial_head("main");
+        let repo = Repository::init_opts(dir.path().join(REPO), &opts).unwrap();
+
+        let mut main = Vec::new();
+        for i in 0..3 {
+            let parent = main.last().copied();
+            let file = format!("file{i}");
+            main.push(commit(
+                &repo,
+                "refs/heads/main",
+                parent,
+                &file,
+                file.as_bytes(),
+            ));
+        }
+        let tip = repo.find_commit(main[2]).unwrap();
+        repo.branch("feature", &tip, false).unwrap();
+        let feature = commit(
+            &repo,
+            "refs/heads/feature",
+            Some(main[2]),
+            "feature",
+            b"feature",
+        );
+
+        let first = repo.find_object(main[0], None).unwrap();
+        repo.tag_lightweight("v1", &first, false).unwrap();
+        let v2 = repo
+            .tag("v2", tip.as_object(), &signature(), "release", false)
+            .unwrap();
+
+        Self {
+            dir,
+            main,
+            feature,
+            v2,
+        }
+    }
+
+    pub fn repos_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The path of the fixture repository
+    pub fn repo_path(&self) -> PathBuf {
+        self.dir.path().join(REPO)
+    }
+
+    /// Commit a file on main, returning the new commit
+    pub fn commit_on_main(&mut self, file: &str, contents: &[u8]) -> Oid {
+        let repo = Repository::open(self.repo_path()).unwrap();
+        let parent = self.main.last().copied();
+        let oid = commit(&repo, "refs/heads/main", parent, file, contents);
+        self.main.push(oid);
+        oid
+    }
+}
+
+pub fn signature() -> Signature<'static> {
+    Signature::new("Test", "test@example.com", &Time::new(1_700_000_000, 0)).unwrap()
+}
+
+/// Commit a file on top of `parent`, moving `branch` to the new commit
+pub fn commit(
+    repo: &Repository,
+    branch: &str,
+    parent: Option<Oid>,
+    file: &str,
+    contents: &[u8],
+) -> Oid {
+    let parent = parent.map(|oid| repo.find_commit(oid).unwrap());
+    let parent_tree = parent.as_ref().map(|commit| commit.tree().unwrap());
+    let mut builder = repo.treebuilder(parent_tree.as_ref()).unwrap();
+    let blob = repo.blob(contents).unwrap();
+    builder.insert(file, blob, 0o100644).unwrap();
+    let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+    let parents: Vec<&Commit> = parent.iter().collect();
+    repo.commit(
+        Some(branch),
+        &signature(),
+        &signature(),
+        file,
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
@@ -0,0 +1,182 @@
+//! Round trips of git requests through the server, against the repository fixture
+
+mod fixture;
+
+use fixture::{Fixture, REPO};
+use git2::{Oid, Repository, StatusOptions};
+use libp2p::{identity::Keypair, PeerId};
+use rust_libp2p_webrtc_peer::{
+    git_exchange::{GitRequest, GitResponse, GIT_STATUS_CHUNK_LINES},
+    git_server::{handle_request_in, PackStrategy, ServerConfig, StatusSnapshots},
+};
+use std::{fs, path::Path, time::Duration};
+use tempfile::TempDir;
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        pack_strategy: PackStrategy::Auto,
+        max_repo_size: u64::MAX,
+        clone_timeout: Duration::from_secs(60),
+        fetch_timeout: Duration::from_secs(60),
+        pack_timeout: Duration::from_secs(60),
+    }
+}
+
+// Handle a request for the repositories in `repos_dir`
+fn handle(
+    repos_dir: &Path,
+    statuses: &StatusSnapshots,
+    peer: &PeerId,
+    request: GitRequest,
+) -> GitResponse {
+    handle_request_in(repos_dir, statuses, peer, request, &config())
+}
+
+fn peer() -> PeerId {
+    Keypair::generate_ed25519().public().to_peer_id()
+}
+
+fn target(repo: &Repository, name: &str) -> Oid {
+    repo.find_reference(name).unwrap().target().unwrap()
+}
+
+#[test]
+fn clone_copies_the_branches_of_a_repository() {
+    let fixture = Fixture::new();
+    let clones = TempDir::new().unwrap();
+    let url = fixture.repo_path().to_str().unwrap().to_string();
+
+    let response = handle(
+        clones.path(),
+        &StatusSnapshots::default(),
+        &peer(),
+        GitRequest::Clone(url),
+    );
+    assert!(matches!(response, GitResponse::Success(_)), "{response:?}");
+
+    let clone = Repository::open(clones.path().join(REPO)).unwrap();
+    assert_eq!(target(&clone, "refs/remotes/origin/main"), fixture.main[2]);
+    assert_eq!(
+        target(&clone, "refs/remotes/origin/feature"),
+        fixture.feature
+    );
+    assert_eq!(clone.head().unwrap().target(), Some(fixture.main[2]));
+}
+
+#[test]
+fn clone_of_a_missing_repository_fails() {
+    let fixture = Fixture::new();
+    let clones = TempDir::new().unwrap();
+    let url = fixture.repos_dir().join("missing");
+
+    let response = handle(
+        clones.path(),
+        &StatusSnapshots::default(),
+        &peer(),
+        GitRequest::Clone(url.to_str().unwrap().to_string()),
+    );
+    assert!(matches!(response, GitResponse::Error(_)), "{response:?}");
+    assert!(!clones.path().join("missing").join(".git").exists());
+}
+
+// A repos directory with a clone of the fixture named after its remote, `origin`, as fetch
+// requests name the repository by its remote
+fn clone_as_origin(fixture: &Fixture) -> (TempDir, Repository) {
+    let clones = TempDir::new().unwrap();
+    let clone = Repository::clone(
+        fixture.repo_path().to_str().unwrap(),
+        clones.path().join("origin"),
+    )
+    .unwrap();
+    (clones, clone)
+}
+
+#[test]
+fn fetch_brings_in_new_commits() {
+    let mut fixture = Fixture::new();
+    let (clones, clone) = clone_as_origin(&fixture);
+    let new = fixture.commit_on_main("new", b"new");
+
+    let response = handle(
+        clones.path(),
+        &StatusSnapshots::default(),
+        &peer(),
+        GitRequest::Fetch("origin".to_string(), None),
+    );
+    assert!(matches!(response, GitResponse::Success(_)), "{response:?}");
+    assert_eq!(target(&clone, "refs/remotes/origin/main"), new);
+    assert!(clone.find_commit(new).is_ok());
+}
+
+#[test]
+fn fetch_follows_the_requested_refspecs() {
+    let mut fixture = Fixture::new();
+    let (clones, clone) = clone_as_origin(&fixture);
+    let new = fixture.commit_on_main("new", b"new");
+
+    let refspecs = vec!["refs/heads/main:refs/fetched/main".to_string()];
+    let response = handle(
+        clones.path(),
+        &StatusSnapshots::default(),
+        &peer(),
+        GitRequest::Fetch("origin".to_string(), Some(refspecs)),
+    );
+    assert!(matches!(response, GitResponse::Success(_)), "{response:?}");
+    assert_eq!(target(&clone, "refs/fetched/main"), new);
+}
+
+#[test]
+fn fetch_from_an_unknown_remote_fails() {
+    let fixture = Fixture::new();
+    let response = handle(
+        fixture.repos_dir(),
+        &StatusSnapshots::default(),
+        &peer(),
+        GitRequest::Fetch(REPO.to_string(), None),
+    );
+    assert!(matches!(response, GitResponse::Error(_)), "{response:?}");
+}
+
+#[test]
+fn status_chunks_list_every_change_of_the_worktree() {
+    let fixture = Fixture::new();
+    let dirty = fixture.repo_path().join("dirty");
+    fs::create_dir_all(&dirty).unwrap();
+    let count = GIT_STATUS_CHUNK_LINES + 10;
+    for i in 0..count {
+        fs::write(dirty.join(format!("{i:05}")), b"dirty").unwrap();
+    }
+    let repo = Repository::open(fixture.repo_path()).unwrap();
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let expected = repo.statuses(Some(&mut opts)).unwrap().len();
+
+    let (statuses, peer) = (StatusSnapshots::default(), peer());
+    let mut lines = Vec::new();
+    for seq in 0.. {
+        let request = GitRequest::StatusChunk {
+            repo: REPO.to_string(),
+            seq,
+        };
+        let GitResponse::StatusChunk {
+            lines: chunk,
+            total,
+            done,
+            ..
+        } = handle(fixture.repos_dir(), &statuses, &peer, request)
+        else {
+            panic!("expected a status chunk");
+        };
+        assert_eq!(total, expected as u64);
+        lines.extend(chunk);
+        if done {
+            break;
+        }
+    }
+    assert_eq!(lines.len(), expected);
+    for i in 0..count {
+        let line = format!("?? dirty/{i:05}");
+        assert!(lines.contains(&line), "{line} is missing");
+    }
+    assert!(statuses.is_empty());
+}
@@ -108,3 +108,29 @@ async fn read_or_create_identity(path: &Path) -> Result<identity::Keypair> {
 
     Ok(identity)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn created_certificate_round_trips_through_pem() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cert.pem");
+
+        let created = read_or_create_certificate(&path).await.unwrap();
+        let pem = fs::read_to_string(&path).await.unwrap();
+        let parsed = Certificate::from_pem(&pem).unwrap();
+        assert_eq!(
+            parsed.fingerprint().to_multihash(),
+            created.fingerprint().to_multihash()
+        );
+
+        // a restart reads the same certificate back, so the advertised certhash stays stable
+        let reread = read_or_create_certificate(&path).await.unwrap();
+        assert_eq!(
+            reread.fingerprint().to_multihash(),
+            created.fingerprint().to_multihash()
+        );
+    }
+}
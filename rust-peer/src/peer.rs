@@ -108,6 +108,11 @@ struct Behaviour {
 // - QUIC + TLS on UDP port 9091
 // - TCP + Noise on TCP port 9092
 // - TCP + TLS on TCP port 9092
+//
+// WebTransport is not supported: rust-libp2p only ships a browser (wasm) WebTransport client,
+// libp2p-webtransport-websys, and has no WebTransport listener that a native peer could add to the
+// SwarmBuilder. Browsers reach this peer over WebRTC-direct instead, which also uses a self-signed
+// certificate advertised by its /certhash in the multiaddr.
 
 /// The Peer state
 pub struct Peer {